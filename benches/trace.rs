@@ -118,12 +118,63 @@ fn trace_future_bench(c: &mut Criterion) {
     group.finish();
 }
 
+fn local_span_property_bench(c: &mut Criterion) {
+    // Times recording 100 spans, to compare the cost of each kind of property with `none`. A
+    // static property is budgeted under 50ns per span, see the `static_property_budget` test.
+    fn bench(b: &mut criterion::Bencher, f: impl Fn()) {
+        b.iter_custom(|iters| {
+            let mut elapsed = std::time::Duration::default();
+            for _ in 0..iters {
+                let local_collector = LocalCollector::start();
+                let now = std::time::Instant::now();
+                for _ in 0..100 {
+                    f();
+                }
+                elapsed += now.elapsed();
+                black_box(local_collector.collect());
+            }
+            elapsed
+        })
+    }
+
+    let mut group = c.benchmark_group("local_span_property");
+
+    group.bench_function("none", |b| {
+        bench(b, || {
+            let _g = LocalSpan::enter("");
+        })
+    });
+
+    group.bench_function("static", |b| {
+        bench(b, || {
+            let _g =
+                LocalSpan::enter("").with_static_property(black_box("key"), black_box("value"));
+        })
+    });
+
+    group.bench_function("static_on_enter", |b| {
+        bench(b, || {
+            let _g =
+                LocalSpan::enter_with_static_property("", black_box("key"), black_box("value"));
+        })
+    });
+
+    group.bench_function("owned", |b| {
+        bench(b, || {
+            let _g = LocalSpan::enter("").with_property(|| ("key", black_box("value").to_owned()));
+        })
+    });
+
+    group.finish();
+}
+
 criterion_group!(
     benches,
     trace_wide_raw_bench,
     trace_wide_bench,
     trace_deep_raw_bench,
     trace_deep_bench,
-    trace_future_bench
+    trace_future_bench,
+    local_span_property_bench
 );
 criterion_main!(benches);
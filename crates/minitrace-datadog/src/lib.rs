@@ -1,8 +1,13 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
+use minitrace::report::{self, ReportError};
 use minitrace::span::Span;
+use reqwest::blocking::Client;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use reqwest::{Certificate, Identity};
 use rmp_serde::Serializer;
 use serde::Serialize;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::error::Error;
 use std::net::SocketAddr;
@@ -12,23 +17,33 @@ pub struct Reporter;
 impl Reporter {
     pub fn encode(
         service_name: &str,
-        trace_id: u64,
+        trace_id: u128,
         root_parent_span_id: u64,
         span_id_prefix: u32,
         spans: &[Span],
     ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync + 'static>> {
+        // Datadog takes the lower 64 bits of a 128-bit trace id, and the higher ones as a tag
+        let trace_id_high = (trace_id >> 64) as u64;
         let spans = spans.iter().map(|s| MPSpan {
             name: s.event,
             service: service_name,
             start: s.begin_unix_time_ns as i64,
             duration: s.duration_ns as i64,
-            meta: if s.properties.is_empty() {
+            meta: if s.properties.is_empty() && trace_id_high == 0 {
                 None
             } else {
-                Some(s.properties.iter().map(|(k, v)| (*k, v.as_ref())).collect())
+                let mut meta: HashMap<_, _> = s
+                    .properties
+                    .iter()
+                    .map(|(k, v)| (*k, v.to_text()))
+                    .collect();
+                if trace_id_high != 0 {
+                    meta.insert("_dd.p.tid", Cow::Owned(format!("{:016x}", trace_id_high)));
+                }
+                Some(meta)
             },
             span_id: (span_id_prefix as u64) << 32 | s.id as u64,
-            trace_id,
+            trace_id: trace_id as u64,
             parent_id: if s.parent_id == 0 {
                 root_parent_span_id
             } else {
@@ -86,6 +101,144 @@ impl Reporter {
     }
 }
 
+/// A [`report::Reporter`] sending every trace to a Datadog agent over HTTP or HTTPS, e.g. for a
+/// [`ReportPipeline`](minitrace::report::ReportPipeline), built by a
+/// [`DatadogReporterBuilder`].
+pub struct DatadogReporter {
+    url: String,
+    service_name: String,
+    client: Client,
+}
+
+impl report::Reporter for DatadogReporter {
+    fn report(&self, trace_id: u128, spans: &[Span]) -> Result<(), ReportError> {
+        let bytes = Reporter::encode(&self.service_name, trace_id, 0, 0, spans)
+            .map_err(ReportError::permanent)?;
+
+        let rep = self
+            .client
+            .post(&self.url)
+            .header("Datadog-Meta-Tracer-Version", "v1.27.0")
+            .header("Content-Type", "application/msgpack")
+            .body(bytes)
+            .send()
+            .map_err(ReportError::new)?;
+
+        let status = rep.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let err = format!("{} (Status: {})", rep.text().unwrap_or_default(), status);
+        // A rejected request, e.g. for invalid credentials, would be rejected again
+        if status.is_client_error() && status.as_u16() != 429 {
+            Err(ReportError::permanent(err))
+        } else {
+            Err(ReportError::new(err))
+        }
+    }
+}
+
+/// Builds a [`DatadogReporter`], for multi-tenant clusters where the spans must be sent over
+/// TLS, possibly with a client certificate, and authenticated.
+///
+/// # Examples
+///
+/// ```no_run
+/// use minitrace_datadog::DatadogReporterBuilder;
+///
+/// let reporter = DatadogReporterBuilder::new("https://trace-agent.example:8126", "tikv")
+///     .root_certificate(std::fs::read("ca.pem").unwrap())
+///     .client_identity(std::fs::read("client.p12").unwrap(), "password")
+///     .bearer_token("token")
+///     .build()
+///     .unwrap();
+/// ```
+pub struct DatadogReporterBuilder {
+    endpoint: String,
+    service_name: String,
+    root_certificates: Vec<Vec<u8>>,
+    client_identity: Option<(Vec<u8>, String)>,
+    bearer_token: Option<String>,
+    headers: Vec<(String, String)>,
+}
+
+impl DatadogReporterBuilder {
+    /// `endpoint` is the base URL of the agent, e.g. `http://127.0.0.1:8126`, or `https://...`
+    /// to send over TLS.
+    pub fn new(endpoint: impl Into<String>, service_name: impl Into<String>) -> Self {
+        DatadogReporterBuilder {
+            endpoint: endpoint.into(),
+            service_name: service_name.into(),
+            root_certificates: vec![],
+            client_identity: None,
+            bearer_token: None,
+            headers: vec![],
+        }
+    }
+
+    /// Trust the CA certificate in PEM, in addition to the system's ones, to verify the agent.
+    pub fn root_certificate(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.root_certificates.push(pem.into());
+        self
+    }
+
+    /// Authenticate with the client certificate and private key in a PKCS #12 archive.
+    pub fn client_identity(
+        self,
+        pkcs12_der: impl Into<Vec<u8>>,
+        password: impl Into<String>,
+    ) -> Self {
+        Self {
+            client_identity: Some((pkcs12_der.into(), password.into())),
+            ..self
+        }
+    }
+
+    /// Send `Authorization: Bearer <token>` with every report.
+    pub fn bearer_token(self, token: impl Into<String>) -> Self {
+        Self {
+            bearer_token: Some(token.into()),
+            ..self
+        }
+    }
+
+    /// Send the header with every report, e.g. an API key or a tenant id.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Fails on invalid certificates or headers.
+    pub fn build(self) -> Result<DatadogReporter, Box<dyn Error + Send + Sync + 'static>> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        if let Some(token) = &self.bearer_token {
+            let mut value = HeaderValue::from_str(&format!("Bearer {}", token))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+
+        let mut client = Client::builder().default_headers(headers);
+        for pem in &self.root_certificates {
+            client = client.add_root_certificate(Certificate::from_pem(pem)?);
+        }
+        if let Some((der, password)) = &self.client_identity {
+            client = client.identity(Identity::from_pkcs12_der(der, password)?);
+        }
+
+        Ok(DatadogReporter {
+            url: format!("{}/v0.4/traces", self.endpoint.trim_end_matches('/')),
+            service_name: self.service_name,
+            client: client.build()?,
+        })
+    }
+}
+
 #[derive(Serialize)]
 struct MPSpan<'a> {
     name: &'a str,
@@ -93,8 +246,107 @@ struct MPSpan<'a> {
     start: i64,
     duration: i64,
     #[serde(skip_serializing_if = "Option::is_none")]
-    meta: Option<HashMap<&'a str, &'a str>>,
+    meta: Option<HashMap<&'a str, Cow<'a, str>>>,
     span_id: u64,
     trace_id: u64,
     parent_id: u64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use minitrace::report::Reporter as _;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    // Answer a request with each of `statuses` in turn, and return the requests
+    fn serve(
+        listener: TcpListener,
+        statuses: &'static [&str],
+    ) -> std::thread::JoinHandle<Vec<String>> {
+        std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                // The request is complete once the body of `Content-Length` bytes has arrived
+                let complete = |request: &[u8]| {
+                    let end = match request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        Some(i) => i + 4,
+                        None => return false,
+                    };
+                    let len: usize = String::from_utf8_lossy(&request[..end])
+                        .to_lowercase()
+                        .lines()
+                        .find_map(|l| l.strip_prefix("content-length: ").map(str::to_owned))
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    request.len() - end >= len
+                };
+                while !complete(&request) {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+                requests.push(String::from_utf8_lossy(&request).into_owned());
+            }
+            requests
+        })
+    }
+
+    #[test]
+    fn builder() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        let server = serve(
+            listener,
+            &["200 OK", "401 Unauthorized", "503 Service Unavailable"],
+        );
+
+        let reporter = DatadogReporterBuilder::new(endpoint, "tikv")
+            .header("x-tenant", "a")
+            .bearer_token("token")
+            .build()
+            .unwrap();
+        let spans = [Span {
+            id: 1,
+            event: "root",
+            ..Default::default()
+        }];
+        assert!(reporter.report(42, &spans).is_ok());
+        assert!(!reporter.report(42, &spans).unwrap_err().is_retryable());
+        assert!(reporter.report(42, &spans).unwrap_err().is_retryable());
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("POST /v0.4/traces HTTP/1.1\r\n"));
+        assert!(requests[0].contains("content-type: application/msgpack\r\n"));
+        assert!(requests[0].contains("x-tenant: a\r\n"));
+        assert!(requests[0].contains("authorization: Bearer token\r\n"));
+    }
+
+    #[test]
+    fn builder_invalid() {
+        let builder = || DatadogReporterBuilder::new("http://127.0.0.1:8126", "tikv");
+
+        // No header or request can be smuggled in a header
+        assert!(builder().header("x-tenant", "a\r\nx-b: c").build().is_err());
+        assert!(builder().header("x-tenant\r\nx-b", "c").build().is_err());
+        assert!(builder().bearer_token("a\r\nx-b: c").build().is_err());
+
+        assert!(builder()
+            .root_certificate("not a certificate")
+            .build()
+            .is_err());
+        assert!(builder()
+            .client_identity("not an archive", "password")
+            .build()
+            .is_err());
+    }
+}
@@ -2,14 +2,17 @@
 
 mod thrift;
 
-use minitrace::span::Span;
+use minitrace::report::{self, ReportError};
+use minitrace::semconv;
+use minitrace::span::{PropertyValue, Span};
+use minitrace::LocalSpan;
 use std::error::Error;
 use std::net::{SocketAddr, UdpSocket};
 use thrift_codec::message::Message;
 use thrift_codec::CompactEncode;
 
 use crate::thrift::{
-    Batch, EmitBatchNotification, Process, Span as JaegerSpan, SpanRef, SpanRefKind, Tag,
+    Batch, EmitBatchNotification, Log, Process, Span as JaegerSpan, SpanRef, SpanRefKind, Tag,
 };
 
 pub struct Reporter;
@@ -17,7 +20,7 @@ pub struct Reporter;
 impl Reporter {
     pub fn encode(
         service_name: String,
-        trace_id: u64,
+        trace_id: u128,
         root_parent_span_id: u64,
         span_id_prefix: u32,
         spans: &[Span],
@@ -32,7 +35,7 @@ impl Reporter {
                     .iter()
                     .map(|s| JaegerSpan {
                         trace_id_low: trace_id as i64,
-                        trace_id_high: 0,
+                        trace_id_high: (trace_id >> 64) as i64,
                         span_id: (span_id_prefix as i64) << 32 | s.id as i64,
                         parent_span_id: if s.parent_id == 0 {
                             root_parent_span_id as i64
@@ -40,28 +43,71 @@ impl Reporter {
                             (span_id_prefix as i64) << 32 | s.parent_id as i64
                         },
                         operation_name: s.event.to_string(),
-                        references: vec![SpanRef {
+                        references: std::iter::once(SpanRef {
                             kind: SpanRefKind::FollowsFrom,
                             trace_id_low: trace_id as i64,
-                            trace_id_high: 0,
+                            trace_id_high: (trace_id >> 64) as i64,
                             span_id: if s.parent_id == 0 {
                                 root_parent_span_id as i64
                             } else {
                                 (span_id_prefix as i64) << 32 | s.parent_id as i64
                             },
-                        }],
+                        })
+                        // The predecessor of a follow-up trace, assumed to be reported with the
+                        // same span id prefix
+                        .chain(
+                            s.properties
+                                .iter()
+                                .filter(|(k, _)| *k == semconv::LINK_PREDECESSOR)
+                                .filter_map(|(_, v)| v.as_str().and_then(semconv::parse_link))
+                                .map(|(trace_id, span_id)| SpanRef {
+                                    kind: SpanRefKind::FollowsFrom,
+                                    trace_id_low: trace_id as i64,
+                                    trace_id_high: (trace_id >> 64) as i64,
+                                    span_id: (span_id_prefix as i64) << 32 | span_id as i64,
+                                }),
+                        )
+                        .collect(),
                         flags: 1,
                         start_time: (s.begin_unix_time_ns / 1_000) as i64,
                         duration: (s.duration_ns / 1_000) as i64,
                         tags: s
                             .properties
                             .iter()
-                            .map(|p| Tag::String {
-                                key: p.0.to_owned(),
-                                value: p.1.to_owned(),
+                            .map(|(k, v)| match v {
+                                PropertyValue::String(s) => Tag::String {
+                                    key: (*k).to_owned(),
+                                    value: s.to_string(),
+                                },
+                                PropertyValue::I64(v) => Tag::Long {
+                                    key: (*k).to_owned(),
+                                    value: *v,
+                                },
+                                PropertyValue::F64(v) => Tag::Double {
+                                    key: (*k).to_owned(),
+                                    value: *v,
+                                },
+                                PropertyValue::Bool(v) => Tag::Bool {
+                                    key: (*k).to_owned(),
+                                    value: *v,
+                                },
+                                PropertyValue::Binary(b) => Tag::Binary {
+                                    key: (*k).to_owned(),
+                                    value: b.to_vec(),
+                                },
+                            })
+                            .collect(),
+                        logs: s
+                            .events
+                            .iter()
+                            .map(|e| Log {
+                                timestamp: (e.unix_time_ns / 1_000) as i64,
+                                fields: vec![Tag::String {
+                                    key: "event".to_owned(),
+                                    value: e.name.to_owned(),
+                                }],
                             })
                             .collect(),
-                        logs: vec![],
                     })
                     .collect(),
             },
@@ -91,3 +137,134 @@ impl Reporter {
         Ok(())
     }
 }
+
+type BoxError = Box<dyn Error + Send + Sync + 'static>;
+
+// The largest UDP payload the agent accepts by default
+const DEFAULT_MAX_PACKET_SIZE: usize = 65000;
+
+/// A [`report::Reporter`] sending every trace to a Jaeger agent, e.g. for a
+/// [`ReportPipeline`](minitrace::report::ReportPipeline). The spans of a trace are split into as
+/// many packets as needed to fit the agent's [`max_packet_size`](JaegerReporter::max_packet_size).
+pub struct JaegerReporter {
+    agent: SocketAddr,
+    service_name: String,
+    max_packet_size: usize,
+}
+
+impl JaegerReporter {
+    pub fn new(agent: SocketAddr, service_name: impl Into<String>) -> Self {
+        JaegerReporter {
+            agent,
+            service_name: service_name.into(),
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+        }
+    }
+
+    /// The largest payload sent at once, which should match the agent's
+    /// `--processor.jaeger-compact.server-max-packet-size`. Defaults to 65000 bytes.
+    pub fn max_packet_size(self, max_packet_size: usize) -> Self {
+        Self {
+            max_packet_size,
+            ..self
+        }
+    }
+
+    /// Encode the spans of the trace into packets. The spans too large to fit in a packet alone
+    /// are left out and counted by the second value.
+    pub fn encode(
+        &self,
+        trace_id: u128,
+        spans: &[Span],
+    ) -> Result<(Vec<Vec<u8>>, usize), BoxError> {
+        let mut packets = Vec::new();
+        let mut oversized = 0;
+        self.encode_split(trace_id, spans, &mut packets, &mut oversized)?;
+        Ok((packets, oversized))
+    }
+
+    // Encode the spans in a packet, or else the halves of them in turn
+    fn encode_split(
+        &self,
+        trace_id: u128,
+        spans: &[Span],
+        packets: &mut Vec<Vec<u8>>,
+        oversized: &mut usize,
+    ) -> Result<(), BoxError> {
+        if spans.is_empty() {
+            return Ok(());
+        }
+        let packet = Reporter::encode(self.service_name.clone(), trace_id, 0, 0, spans)?;
+        if packet.len() <= self.max_packet_size {
+            packets.push(packet);
+        } else if spans.len() == 1 {
+            *oversized += 1;
+        } else {
+            let (left, right) = spans.split_at(spans.len() / 2);
+            self.encode_split(trace_id, left, packets, oversized)?;
+            self.encode_split(trace_id, right, packets, oversized)?;
+        }
+        Ok(())
+    }
+}
+
+impl report::Reporter for JaegerReporter {
+    fn report(&self, trace_id: u128, spans: &[Span]) -> Result<(), ReportError> {
+        let (packets, oversized) = {
+            let _guard = LocalSpan::enter("jaeger.encode")
+                .with_typed_property("jaeger.spans", spans.len() as i64);
+            self.encode(trace_id, spans)
+                .map_err(ReportError::permanent)?
+        };
+
+        for packet in &packets {
+            let _guard = LocalSpan::enter("jaeger.send")
+                .with_typed_property("jaeger.bytes", packet.len() as i64);
+            Reporter::report(self.agent, packet).map_err(ReportError::new)?;
+        }
+
+        if oversized > 0 {
+            return Err(ReportError::permanent(format!(
+                "{} spans exceed the max packet size of {} bytes",
+                oversized, self.max_packet_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(n: u32) -> Vec<Span> {
+        (1..=n)
+            .map(|id| Span {
+                id,
+                parent_id: id - 1,
+                begin_unix_time_ns: 1_000_000,
+                duration_ns: 2_000,
+                event: "span",
+                properties: vec![("k", PropertyValue::from("v".repeat(100)))],
+                events: vec![],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_packets() {
+        let reporter =
+            JaegerReporter::new("127.0.0.1:6831".parse().unwrap(), "tikv").max_packet_size(1000);
+
+        let (packets, oversized) = reporter.encode(42, &spans(20)).unwrap();
+        assert_eq!(oversized, 0);
+        assert!(packets.len() > 1);
+        assert!(packets.iter().all(|p| p.len() <= 1000));
+
+        let mut big = spans(2);
+        big[1].properties = vec![("k", PropertyValue::from("v".repeat(2000)))];
+        let (packets, oversized) = reporter.encode(42, &big).unwrap();
+        assert_eq!(oversized, 1);
+        assert_eq!(packets.len(), 1);
+    }
+}
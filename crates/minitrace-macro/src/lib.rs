@@ -8,13 +8,172 @@ extern crate proc_macro;
 extern crate proc_macro_error;
 
 use proc_macro::TokenStream;
+use syn::parse::discouraged::Speculative;
+use syn::parse::{Parse, ParseStream};
 use syn::spanned::Spanned;
+use syn::visit_mut::VisitMut;
+
+// Arguments of `trace` and `trace_async`: an event, optionally followed by `root` to start a new
+// trace, `collector = <callback>` receiving its collector, `target = "<subsystem>"` filtered at
+// runtime and, for `trace_async`, `await_spans` to trace every `.await` of the body.
+#[derive(Default)]
+struct Args {
+    event: Option<syn::Expr>,
+    root: bool,
+    collector: Option<syn::Path>,
+    target: Option<syn::Expr>,
+    await_spans: bool,
+}
+
+impl Parse for Args {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = Args::default();
+        while !input.is_empty() {
+            let fork = input.fork();
+            match fork.parse::<syn::Ident>() {
+                Ok(ident) if ident == "root" && (fork.is_empty() || fork.peek(syn::Token![,])) => {
+                    input.advance_to(&fork);
+                    args.root = true;
+                }
+                Ok(ident)
+                    if ident == "await_spans" && (fork.is_empty() || fork.peek(syn::Token![,])) =>
+                {
+                    input.advance_to(&fork);
+                    args.await_spans = true;
+                }
+                Ok(ident) if ident == "collector" && fork.peek(syn::Token![=]) => {
+                    fork.parse::<syn::Token![=]>()?;
+                    args.collector = Some(fork.parse()?);
+                    input.advance_to(&fork);
+                }
+                Ok(ident) if ident == "target" && fork.peek(syn::Token![=]) => {
+                    fork.parse::<syn::Token![=]>()?;
+                    args.target = Some(fork.parse()?);
+                    input.advance_to(&fork);
+                }
+                _ => args.event = Some(input.parse()?),
+            }
+            if !input.is_empty() {
+                input.parse::<syn::Token![,]>()?;
+            }
+        }
+        Ok(args)
+    }
+}
+
+impl Args {
+    // Returns the event, defaulting to the function name for root spans, and the collector
+    // callback if a root span is requested
+    fn resolve(self, ident: &syn::Ident) -> (proc_macro2::TokenStream, Option<syn::Path>) {
+        let event = match (self.event, self.root) {
+            (Some(event), _) => quote::quote!(#event),
+            (None, true) => {
+                let name = ident.to_string();
+                quote::quote!(#name)
+            }
+            (None, false) => abort_call_site!("Expect an event, e.g. `#[trace(\"event\")]`"),
+        };
+        let collector = match (self.root, self.collector) {
+            (true, Some(collector)) => Some(collector),
+            (true, None) => abort_call_site!(
+                "Expect a callback receiving the collector of the root span, e.g. `#[trace(root, collector = report)]`"
+            ),
+            (false, Some(collector)) => abort!(collector, "`collector` is only valid with `root`"),
+            (false, None) => None,
+        };
+        (event, collector)
+    }
+}
+
+// The expressions starting the root span and entering the local span, checking the target if any
+fn span_exprs(
+    target: Option<syn::Expr>,
+    name: &proc_macro2::TokenStream,
+) -> (
+    Option<proc_macro2::TokenStream>,
+    proc_macro2::TokenStream,
+    proc_macro2::TokenStream,
+) {
+    match target {
+        Some(target) => {
+            let target = quote::quote!(minitrace::__target!(#target));
+            let root = quote::quote!(#target.root(#name));
+            let local = quote::quote!(#target.local_span(#name));
+            (Some(target), root, local)
+        }
+        None => (
+            None,
+            quote::quote!(Span::root(#name)),
+            quote::quote!(LocalSpan::enter(#name)),
+        ),
+    }
+}
+
+// Wraps the awaited futures marked by `#[trace_await("event")]`, or all of them if `all` is set,
+// in child spans named after the event or the awaited expression
+struct AwaitSpans {
+    all: bool,
+}
+
+impl AwaitSpans {
+    // The name of the function or method called to build the future, or of the future itself
+    fn name_of(expr: &syn::Expr) -> String {
+        match expr {
+            syn::Expr::MethodCall(call) => call.method.to_string(),
+            syn::Expr::Call(call) => match &*call.func {
+                syn::Expr::Path(path) => path.path.segments.last().unwrap().ident.to_string(),
+                _ => "await".to_owned(),
+            },
+            syn::Expr::Path(path) => path.path.segments.last().unwrap().ident.to_string(),
+            syn::Expr::Field(field) => match &field.member {
+                syn::Member::Named(ident) => ident.to_string(),
+                syn::Member::Unnamed(_) => "await".to_owned(),
+            },
+            syn::Expr::Paren(paren) => Self::name_of(&paren.expr),
+            _ => "await".to_owned(),
+        }
+    }
+}
+
+impl VisitMut for AwaitSpans {
+    fn visit_expr_mut(&mut self, expr: &mut syn::Expr) {
+        syn::visit_mut::visit_expr_mut(self, expr);
+
+        let await_expr = match expr {
+            syn::Expr::Await(await_expr) => await_expr,
+            _ => return,
+        };
+        let explicit = await_expr
+            .attrs
+            .iter()
+            .position(|attr| attr.path.is_ident("trace_await"));
+        let event = match explicit {
+            Some(i) => {
+                let attr = await_expr.attrs.remove(i);
+                match attr.parse_args::<syn::LitStr>() {
+                    Ok(event) => event.value(),
+                    Err(_) => abort!(attr, "Expect an event, e.g. `#[trace_await(\"fetch\")]`"),
+                }
+            }
+            None if self.all => Self::name_of(&await_expr.base),
+            None => return,
+        };
+
+        let base = &await_expr.base;
+        await_expr.base = syn::parse_quote_spanned! {base.span() =>
+            minitrace::FutureExt::in_span(#base, minitrace::Span::from_local_parent(#event))
+        };
+    }
+
+    // Nested functions are traced by their own attributes
+    fn visit_item_mut(&mut self, _: &mut syn::Item) {}
+}
 
 #[proc_macro_attribute]
 #[proc_macro_error]
 pub fn trace(args: TokenStream, item: TokenStream) -> TokenStream {
     let input = syn::parse_macro_input!(item as syn::ItemFn);
-    let event = syn::parse_macro_input!(args as syn::Expr);
+    let mut args = syn::parse_macro_input!(args as Args);
 
     let syn::ItemFn {
         attrs,
@@ -46,14 +205,46 @@ pub fn trace(args: TokenStream, item: TokenStream) -> TokenStream {
             "Unexpected async\nIf want to trace async function, consider `minitrace::trace_async`"
         );
     };
+    if args.await_spans {
+        abort_call_site!("`await_spans` is only valid with `trace_async`");
+    }
+
+    let target = args.target.take();
+    let (event, collector) = args.resolve(&ident);
+    let name = quote::quote!(minitrace::__event_name!(#event));
+    let (_, root, local) = span_exprs(target, &name);
+    let body = match collector {
+        // The callback runs on drop so that early returns are covered. Locals are dropped in
+        // reverse order: the guard, the root span and then the callback.
+        Some(collector) => quote::quote!(
+            struct __OnDrop<F: FnOnce()>(Option<F>);
+            impl<F: FnOnce()> Drop for __OnDrop<F> {
+                fn drop(&mut self) {
+                    if let Some(f) = self.0.take() {
+                        f()
+                    }
+                }
+            }
+
+            let (__root_span, __collector) = #root;
+            let __report = __OnDrop(Some(move || #collector(__collector)));
+            let __root_span = __root_span;
+            let _guard = __root_span.enter();
+            #block
+        ),
+        None => quote::quote!(
+            let _guard = #local;
+            #block
+        ),
+    };
 
     quote::quote!(
         #(#attrs) *
         #vis #constness #unsafety #asyncness #abi fn #ident<#gen_params>(#params) #return_type
         #where_clause
         {
-            let _guard = LocalSpan::enter(#event);
-            #block
+            minitrace::__register_event!(#event);
+            #body
         }
     )
     .into()
@@ -63,15 +254,20 @@ pub fn trace(args: TokenStream, item: TokenStream) -> TokenStream {
 #[proc_macro_error]
 pub fn trace_async(args: TokenStream, item: TokenStream) -> TokenStream {
     let input = syn::parse_macro_input!(item as syn::ItemFn);
-    let event = syn::parse_macro_input!(args as syn::Expr);
+    let mut args = syn::parse_macro_input!(args as Args);
 
     let syn::ItemFn {
         attrs,
         vis,
-        block,
+        mut block,
         sig,
     } = input;
 
+    AwaitSpans {
+        all: args.await_spans,
+    }
+    .visit_block_mut(&mut block);
+
     let syn::Signature {
         output: return_type,
         inputs: params,
@@ -89,20 +285,58 @@ pub fn trace_async(args: TokenStream, item: TokenStream) -> TokenStream {
         ..
     } = sig;
 
-    let body = if asyncness.is_some() {
-        let async_kwd = syn::token::Async { span: block.span() };
-        let await_kwd = syn::Ident::new("await", block.span());
-        quote::quote_spanned! {block.span() =>
-            #async_kwd move { #block }
-                .in_local_span(#event)
-                .#await_kwd
+    let target = args.target.take();
+    let (event, collector) = args.resolve(&ident);
+    let name = quote::quote!(minitrace::__event_name!(#event));
+    let (target, root, _) = span_exprs(target, &name);
+    let body = match (asyncness.is_some(), collector) {
+        // The callback is not called if the future is dropped before completion
+        (true, Some(collector)) => {
+            let async_kwd = syn::token::Async { span: block.span() };
+            let await_kwd = syn::Ident::new("await", block.span());
+            quote::quote_spanned! {block.span() =>
+                let (__root_span, __collector) = #root;
+                let __ret = #async_kwd move { #block }
+                    .in_span(__root_span)
+                    .#await_kwd;
+                #collector(__collector);
+                __ret
+            }
+        }
+        (true, None) => {
+            let async_kwd = syn::token::Async { span: block.span() };
+            let await_kwd = syn::Ident::new("await", block.span());
+            match target {
+                Some(target) => quote::quote_spanned! {block.span() =>
+                    #target.in_local_span(#async_kwd move { #block }, #name)
+                        .#await_kwd
+                },
+                None => quote::quote_spanned! {block.span() =>
+                    #async_kwd move { #block }
+                        .in_local_span(#name)
+                        .#await_kwd
+                },
+            }
         }
-    } else {
         // hack for `async_trait`
         // See https://docs.rs/async-trait/0.1.31/async_trait/
-        quote::quote_spanned! {block.span() =>
-            std::boxed::Box::pin(#block.in_new_span(#event))
-        }
+        (false, Some(collector)) => quote::quote_spanned! {block.span() =>
+            let __fut = #block;
+            std::boxed::Box::pin(async move {
+                let (__root_span, __collector) = #root;
+                let __ret = __fut.in_span(__root_span).await;
+                #collector(__collector);
+                __ret
+            })
+        },
+        (false, None) => match target {
+            Some(target) => quote::quote_spanned! {block.span() =>
+                std::boxed::Box::pin(#target.in_local_span(#block, #name))
+            },
+            None => quote::quote_spanned! {block.span() =>
+                std::boxed::Box::pin(#block.in_new_span(#name))
+            },
+        },
     };
 
     quote::quote!(
@@ -110,6 +344,7 @@ pub fn trace_async(args: TokenStream, item: TokenStream) -> TokenStream {
         #vis #constness #unsafety #asyncness #abi fn #ident<#gen_params>(#params) #return_type
         #where_clause
         {
+            minitrace::__register_event!(#event);
             #body
         }
     )
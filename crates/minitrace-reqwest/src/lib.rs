@@ -0,0 +1,197 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Instrumented `reqwest` clients.
+//!
+//! Every request executed through the clients in this crate is recorded as a child span of the
+//! span attached to the current thread, carrying the method, host and status of the request. The
+//! context of that span is injected into the request as a W3C `traceparent` header, so that the
+//! server can continue the trace under it.
+
+use minitrace::propagation::SpanContext;
+use minitrace::{semconv, Span};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Method, Request, Response, StatusCode, Url};
+
+const EVENT: &str = "http request";
+const TRACEPARENT: &str = "traceparent";
+
+pub struct Client {
+    inner: reqwest::Client,
+}
+
+impl Client {
+    pub fn new(inner: reqwest::Client) -> Self {
+        Client { inner }
+    }
+
+    /// The wrapped client, used to build requests.
+    pub fn inner(&self) -> &reqwest::Client {
+        &self.inner
+    }
+
+    /// Execute `request` within a child span of the span attached to the current thread.
+    pub async fn execute(&self, mut request: Request) -> reqwest::Result<Response> {
+        let (method, url) = (request.method().clone(), request.url().clone());
+        let span = start_span(&method, &url, request.headers_mut());
+
+        let res = self.inner.execute(request).await;
+
+        let _span = span.with_properties(|| result_properties(res.as_ref().map(|r| r.status())));
+        res
+    }
+}
+
+#[cfg(feature = "blocking")]
+pub mod blocking {
+    use super::*;
+
+    pub struct Client {
+        inner: reqwest::blocking::Client,
+    }
+
+    impl Client {
+        pub fn new(inner: reqwest::blocking::Client) -> Self {
+            Client { inner }
+        }
+
+        /// The wrapped client, used to build requests.
+        pub fn inner(&self) -> &reqwest::blocking::Client {
+            &self.inner
+        }
+
+        /// Execute `request` within a child span of the span attached to the current thread.
+        pub fn execute(
+            &self,
+            mut request: reqwest::blocking::Request,
+        ) -> reqwest::Result<reqwest::blocking::Response> {
+            let (method, url) = (request.method().clone(), request.url().clone());
+            let span = start_span(&method, &url, request.headers_mut());
+
+            let res = self.inner.execute(request);
+
+            let _span =
+                span.with_properties(|| result_properties(res.as_ref().map(|r| r.status())));
+            res
+        }
+    }
+}
+
+// Start the span of the request, and inject its context into the headers of the request
+fn start_span(method: &Method, url: &Url, headers: &mut HeaderMap) -> Span {
+    let span = Span::from_local_parent(EVENT).with_properties(|| {
+        let mut properties = vec![semconv::http_method(method.as_str())];
+        if let Some(host) = url.host_str() {
+            properties.push(semconv::http_host(host));
+        }
+        properties
+    });
+
+    if let Some(context) = SpanContext::from_span(&span) {
+        if let Ok(value) = HeaderValue::from_str(&context.to_traceparent()) {
+            headers.insert(HeaderName::from_static(TRACEPARENT), value);
+        }
+    }
+    span
+}
+
+fn result_properties(result: Result<StatusCode, &reqwest::Error>) -> Vec<(&'static str, String)> {
+    match result {
+        Ok(status) => vec![semconv::http_status_code(status.as_u16())],
+        Err(err) => semconv::error("reqwest", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    use minitrace::CollectArgs;
+
+    use super::*;
+
+    // Answer a request with `200 OK` and return it
+    fn serve_once(listener: TcpListener) -> std::thread::JoinHandle<String> {
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        })
+    }
+
+    #[test]
+    fn inject_request_span() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = serve_once(listener);
+
+        let (root_span, collector) = Span::root("root");
+        let client = Client::new(reqwest::Client::new());
+        let request = client.inner().get(&url).build().unwrap();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let status = {
+            let _guard = root_span.enter();
+            runtime.block_on(client.execute(request)).unwrap().status()
+        };
+        assert_eq!(status, StatusCode::OK);
+        drop(root_span);
+
+        let trace_id = collector.trace_id();
+        let spans = collector.collect_with_args(CollectArgs::default().sync(true));
+        let span = spans.iter().find(|s| s.event == EVENT).unwrap();
+        let root = spans.iter().find(|s| s.event == "root").unwrap();
+        assert_eq!(span.parent_id, root.id);
+        let (key, value) = semconv::http_status_code(200);
+        assert!(span.properties.contains(&(key, value.into())));
+
+        // The server continues the trace under the span of the request, not its parent
+        let request = server.join().unwrap();
+        let traceparent = request
+            .lines()
+            .find_map(|l| l.strip_prefix("traceparent: "))
+            .unwrap();
+        assert_eq!(
+            SpanContext::from_traceparent(traceparent),
+            Some(SpanContext::new(trace_id, span.id as u64))
+        );
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn blocking_inject_request_span() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = serve_once(listener);
+
+        let (root_span, collector) = Span::root("root");
+        let client = blocking::Client::new(reqwest::blocking::Client::new());
+        let request = client.inner().get(&url).build().unwrap();
+        {
+            let _guard = root_span.enter();
+            client.execute(request).unwrap();
+        }
+        drop(root_span);
+
+        let trace_id = collector.trace_id();
+        let spans = collector.collect_with_args(CollectArgs::default().sync(true));
+        let span = spans.iter().find(|s| s.event == EVENT).unwrap();
+
+        let request = server.join().unwrap();
+        let traceparent = request
+            .lines()
+            .find_map(|l| l.strip_prefix("traceparent: "))
+            .unwrap();
+        assert_eq!(
+            SpanContext::from_traceparent(traceparent),
+            Some(SpanContext::new(trace_id, span.id as u64))
+        );
+    }
+}
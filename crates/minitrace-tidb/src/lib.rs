@@ -0,0 +1,255 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Convert collected spans into rows shaped like the output of TiDB's `TRACE FORMAT='row'`
+//! statement, so they can be served from diagnostics tables without bespoke conversion code.
+
+use std::collections::{HashMap, HashSet};
+
+use minitrace::span::Span;
+
+/// A row of the trace table.
+///
+/// Maps to TiDB's `(operation, startTS, duration)` columns via [`TraceRow::operation`],
+/// [`TraceRow::start_ts`] and [`TraceRow::duration`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRow {
+    /// The span event prefixed by the tree drawing of its position, e.g. `│ └─event`.
+    pub operation: String,
+    /// The depth of the span in the span tree. Roots are at depth 0.
+    pub depth: usize,
+    pub span_id: u32,
+    pub parent_id: u32,
+    pub begin_unix_time_ns: u64,
+    pub duration_ns: u64,
+    pub properties: Vec<(&'static str, String)>,
+}
+
+impl TraceRow {
+    /// The start time formatted as `HH:MM:SS.ffffff` in UTC.
+    pub fn start_ts(&self) -> String {
+        let micros = self.begin_unix_time_ns / 1_000;
+        let secs_of_day = (micros / 1_000_000) % 86_400;
+        format!(
+            "{:02}:{:02}:{:02}.{:06}",
+            secs_of_day / 3_600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+            micros % 1_000_000
+        )
+    }
+
+    /// The duration formatted the way TiDB prints `time.Duration`s, e.g. `1.5ms` or
+    /// `1m30.5s`.
+    pub fn duration(&self) -> String {
+        const MINUTE: u64 = 60_000_000_000;
+        const HOUR: u64 = 60 * MINUTE;
+
+        let ns = self.duration_ns as f64;
+        if self.duration_ns < 1_000 {
+            format!("{}ns", self.duration_ns)
+        } else if self.duration_ns < 1_000_000 {
+            format!("{}µs", trim(ns / 1e3))
+        } else if self.duration_ns < 1_000_000_000 {
+            format!("{}ms", trim(ns / 1e6))
+        } else if self.duration_ns < MINUTE {
+            format!("{}s", trim(ns / 1e9))
+        } else {
+            let hours = self.duration_ns / HOUR;
+            let minutes = self.duration_ns % HOUR / MINUTE;
+            let secs = self.duration_ns % MINUTE;
+            // Exact digits, lest the seconds round up to 60
+            let secs = format!("{}.{:09}", secs / 1_000_000_000, secs % 1_000_000_000);
+            let secs = secs.trim_end_matches('0').trim_end_matches('.');
+            if hours > 0 {
+                format!("{}h{}m{}s", hours, minutes, secs)
+            } else {
+                format!("{}m{}s", minutes, secs)
+            }
+        }
+    }
+}
+
+pub struct Reporter;
+
+impl Reporter {
+    /// Flatten `spans` into rows in depth-first order. Siblings are ordered by begin time.
+    ///
+    /// Spans whose parent isn't in `spans` are treated as roots, and so are the spans not
+    /// reachable from a root, e.g. in a cycle of parents, each listed once.
+    pub fn encode(spans: &[Span]) -> Vec<TraceRow> {
+        let ids: HashSet<u32> = spans.iter().map(|s| s.id).collect();
+        let mut children: HashMap<u32, Vec<&Span>> = HashMap::new();
+        let mut roots = Vec::new();
+        for span in spans {
+            if span.parent_id != 0 && ids.contains(&span.parent_id) {
+                children.entry(span.parent_id).or_default().push(span);
+            } else {
+                roots.push(span);
+            }
+        }
+        for siblings in children.values_mut() {
+            siblings.sort_by_key(|s| s.begin_unix_time_ns);
+        }
+        roots.sort_by_key(|s| s.begin_unix_time_ns);
+
+        let mut rows = Vec::with_capacity(spans.len());
+        let mut visited = HashSet::with_capacity(spans.len());
+        for root in roots {
+            Self::encode_subtree(
+                root,
+                &children,
+                &mut String::new(),
+                0,
+                None,
+                &mut visited,
+                &mut rows,
+            );
+        }
+        if visited.len() < spans.len() {
+            let mut unreached: Vec<_> = spans.iter().collect();
+            unreached.sort_by_key(|s| s.begin_unix_time_ns);
+            for span in unreached {
+                if !visited.contains(&(span as *const Span)) {
+                    Self::encode_subtree(
+                        span,
+                        &children,
+                        &mut String::new(),
+                        0,
+                        None,
+                        &mut visited,
+                        &mut rows,
+                    );
+                }
+            }
+        }
+        rows
+    }
+
+    fn encode_subtree(
+        span: &Span,
+        children: &HashMap<u32, Vec<&Span>>,
+        prefix: &mut String,
+        depth: usize,
+        is_last: Option<bool>,
+        visited: &mut HashSet<*const Span>,
+        rows: &mut Vec<TraceRow>,
+    ) {
+        // Guards against cycles, e.g. of spans with duplicated ids
+        if !visited.insert(span as *const Span) {
+            return;
+        }
+
+        let branch = match is_last {
+            None => "",
+            Some(true) => "└─",
+            Some(false) => "├─",
+        };
+        rows.push(TraceRow {
+            operation: format!("{}{}{}", prefix, branch, span.event),
+            depth,
+            span_id: span.id,
+            parent_id: span.parent_id,
+            begin_unix_time_ns: span.begin_unix_time_ns,
+            duration_ns: span.duration_ns,
+            properties: span
+                .properties
+                .iter()
+                .map(|(k, v)| (*k, v.to_string()))
+                .collect(),
+        });
+
+        if let Some(spans) = children.get(&span.id) {
+            let prefix_len = prefix.len();
+            match is_last {
+                None => {}
+                Some(true) => prefix.push_str("  "),
+                Some(false) => prefix.push_str("│ "),
+            }
+            for (i, child) in spans.iter().enumerate() {
+                let is_last = i + 1 == spans.len();
+                Self::encode_subtree(
+                    child,
+                    children,
+                    prefix,
+                    depth + 1,
+                    Some(is_last),
+                    visited,
+                    rows,
+                );
+            }
+            prefix.truncate(prefix_len);
+        }
+    }
+}
+
+fn trim(v: f64) -> String {
+    let s = format!("{:.6}", v);
+    s.trim_end_matches('0').trim_end_matches('.').to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(id: u32, parent_id: u32, begin_unix_time_ns: u64, event: &'static str) -> Span {
+        Span {
+            id,
+            parent_id,
+            begin_unix_time_ns,
+            duration_ns: 1_500_000,
+            event,
+            properties: vec![],
+            events: vec![],
+        }
+    }
+
+    #[test]
+    fn tree_rows() {
+        let spans = vec![
+            span(4, 2, 4, "d"),
+            span(2, 1, 2, "b"),
+            span(1, 0, 1, "a"),
+            span(3, 2, 3, "c"),
+            span(5, 1, 5, "e"),
+        ];
+
+        let rows = Reporter::encode(&spans);
+        let operations: Vec<_> = rows.iter().map(|r| r.operation.as_str()).collect();
+        assert_eq!(operations, vec!["a", "├─b", "│ ├─c", "│ └─d", "└─e"]);
+        let depths: Vec<_> = rows.iter().map(|r| r.depth).collect();
+        assert_eq!(depths, vec![0, 1, 2, 2, 1]);
+
+        assert_eq!(rows[0].duration(), "1.5ms");
+        assert_eq!(rows[0].start_ts(), "00:00:00.000000");
+    }
+
+    #[test]
+    fn cyclic_rows() {
+        let spans = vec![
+            span(1, 0, 1, "a"),
+            // The duplicated id makes `a` a descendant of itself
+            span(2, 1, 2, "b"),
+            span(1, 2, 3, "a again"),
+            // A cycle not reachable from a root
+            span(3, 4, 4, "c"),
+            span(4, 3, 5, "d"),
+        ];
+
+        let rows = Reporter::encode(&spans);
+        let operations: Vec<_> = rows.iter().map(|r| r.operation.as_str()).collect();
+        assert_eq!(operations, vec!["a", "└─b", "  └─a again", "c", "└─d"]);
+    }
+
+    #[test]
+    fn long_durations() {
+        let row = |duration_ns| TraceRow {
+            duration_ns,
+            ..Reporter::encode(&[span(1, 0, 0, "a")]).remove(0)
+        };
+        assert_eq!(row(59_500_000_000).duration(), "59.5s");
+        assert_eq!(row(90_500_000_000).duration(), "1m30.5s");
+        assert_eq!(row(119_999_999_999).duration(), "1m59.999999999s");
+        assert_eq!(row(3_600_000_000_000).duration(), "1h0m0s");
+        assert_eq!(row(3_723_250_000_000).duration(), "1h2m3.25s");
+    }
+}
@@ -17,7 +17,7 @@ fn main() {
 
     let socket = SocketAddr::new("127.0.0.1".parse().unwrap(), 6831);
 
-    const TRACE_ID: u64 = 42;
+    const TRACE_ID: u128 = 42;
     const SPAN_ID_PREFIX: u32 = 42;
     const ROOT_PARENT_SPAN_ID: u64 = 0;
     let bytes = Reporter::encode(
@@ -0,0 +1,204 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Building blocks of a sidecar or aggregator collecting traces from several processes: a binary
+//! wire format for collected traces, and an [`Aggregator`] merging the spans of a trace sent by
+//! different sources, e.g. the spans of a request handled by TiDB and then by several TiKVs.
+//!
+//! The merged traces can be re-exported as they are, e.g. encoded by [`otlp`](crate::otlp) or
+//! submitted to a [`ReportPipeline`](crate::report::ReportPipeline) of a Jaeger reporter.
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use minitrace::agent::{self, Aggregator};
+//! use minitrace::Span;
+//!
+//! // In the traced process
+//! let (root_span, collector) = Span::root("root");
+//! drop(root_span);
+//! let frame = agent::encode(collector.trace_id(), &collector.collect());
+//!
+//! // In the agent
+//! let mut aggregator = Aggregator::new(Duration::from_secs(5));
+//! let (trace_id, spans) = agent::decode(&frame).unwrap();
+//! aggregator.push(trace_id, spans);
+//! let bytes = minitrace::otlp::encode(&aggregator.take_all());
+//! assert!(!bytes.is_empty());
+//! ```
+//!
+//! A frame is the magic `mtr3`, the 128-bit trace id as two 64-bit halves, the lower first, and
+//! the spans, in the encoding of [`FileSpanStorage`](crate::FileSpanStorage). Frames are
+//! self-delimiting, so they can be streamed back to back, e.g. over TCP, and read one by one by
+//! [`read_trace`]. The frames of older processes are read too: `mtr2` ones, without the events
+//! of the spans, and `mtr1` ones, without them and with a 64-bit trace id.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::span::Span;
+use crate::trace::storage::{read_spans, read_u64, write_spans, write_u64};
+
+const MAGIC: [u8; 4] = *b"mtr3";
+const MAGIC_V2: [u8; 4] = *b"mtr2";
+const MAGIC_V1: [u8; 4] = *b"mtr1";
+
+/// Encode the spans of the trace `trace_id` as a frame.
+pub fn encode(trace_id: u128, spans: &[Span]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_trace(&mut buf, trace_id, spans).unwrap();
+    buf
+}
+
+/// Decode a frame encoded by [`encode`], ignoring any bytes after it.
+pub fn decode(mut frame: &[u8]) -> io::Result<(u128, Vec<Span>)> {
+    read_trace(&mut frame)?.ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+}
+
+/// Write the spans of the trace `trace_id` as a frame.
+pub fn write_trace(w: &mut impl Write, trace_id: u128, spans: &[Span]) -> io::Result<()> {
+    w.write_all(&MAGIC)?;
+    write_u64(w, trace_id as u64)?;
+    write_u64(w, (trace_id >> 64) as u64)?;
+    write_spans(w, spans, true)
+}
+
+/// Read the next frame, or `None` at the end of the stream.
+///
+/// Event names and property keys are interned, leaking each distinct one once, up to a bound on
+/// the distinct names of the process past which reading fails.
+pub fn read_trace(r: &mut impl Read) -> io::Result<Option<(u128, Vec<Span>)>> {
+    let mut magic = [0; 4];
+    match r.read(&mut magic[..1])? {
+        0 => return Ok(None),
+        _ => r.read_exact(&mut magic[1..])?,
+    }
+    let (trace_id, with_events) = match magic {
+        MAGIC => (read_u64(r)? as u128 | (read_u64(r)? as u128) << 64, true),
+        MAGIC_V2 => (read_u64(r)? as u128 | (read_u64(r)? as u128) << 64, false),
+        MAGIC_V1 => (read_u64(r)? as u128, false),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad magic")),
+    };
+    let spans = read_spans(r, with_events)?;
+    Ok(Some((trace_id, spans)))
+}
+
+/// Merges the spans of each trace pushed by any number of sources, until the trace has been
+/// idle, i.e. received no spans, for a while.
+#[derive(Debug)]
+pub struct Aggregator {
+    idle: Duration,
+    traces: HashMap<u128, (Instant, Vec<Span>)>,
+}
+
+impl Aggregator {
+    /// Consider a trace complete once it has been idle for `idle`, which should cover the delay
+    /// between the reports of the slowest sources.
+    pub fn new(idle: Duration) -> Self {
+        Aggregator {
+            idle,
+            traces: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, trace_id: u128, spans: Vec<Span>) {
+        let now = Instant::now();
+        let (updated_at, trace) = self
+            .traces
+            .entry(trace_id)
+            .or_insert_with(|| (now, Vec::new()));
+        *updated_at = now;
+        trace.extend(spans);
+    }
+
+    /// The number of traces being aggregated.
+    pub fn len(&self) -> usize {
+        self.traces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.traces.is_empty()
+    }
+
+    /// Take the traces which have been idle, e.g. to be re-exported periodically.
+    pub fn take_idle(&mut self) -> Vec<(u128, Vec<Span>)> {
+        let now = Instant::now();
+        let idle = self.idle;
+        let idle_ids: Vec<u128> = self
+            .traces
+            .iter()
+            .filter(|(_, (updated_at, _))| now.duration_since(*updated_at) >= idle)
+            .map(|(trace_id, _)| *trace_id)
+            .collect();
+        idle_ids
+            .into_iter()
+            .filter_map(|trace_id| {
+                let (_, spans) = self.traces.remove(&trace_id)?;
+                Some((trace_id, spans))
+            })
+            .collect()
+    }
+
+    /// Take all traces, e.g. on shutdown.
+    pub fn take_all(&mut self) -> Vec<(u128, Vec<Span>)> {
+        self.traces
+            .drain()
+            .map(|(trace_id, (_, spans))| (trace_id, spans))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::PropertyValue;
+
+    fn span(id: u32, parent_id: u32, event: &'static str) -> Span {
+        Span {
+            id,
+            parent_id,
+            begin_unix_time_ns: 1,
+            duration_ns: 2,
+            event,
+            properties: vec![("k", PropertyValue::I64(3))],
+            events: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn aggregate() {
+        let mut stream = Vec::new();
+        write_trace(&mut stream, 1, &[span(1, 0, "tidb")]).unwrap();
+        write_trace(&mut stream, 2 | 7 << 64, &[span(2, 0, "other")]).unwrap();
+        write_trace(&mut stream, 1, &[span(3, 1, "tikv")]).unwrap();
+        // From an older process
+        stream.extend_from_slice(b"mtr1");
+        write_u64(&mut stream, 1).unwrap();
+        write_spans(&mut stream, &[span(4, 1, "tikv")], false).unwrap();
+
+        let mut aggregator = Aggregator::new(Duration::from_secs(0));
+        let mut r = stream.as_slice();
+        while let Some((trace_id, spans)) = read_trace(&mut r).unwrap() {
+            aggregator.push(trace_id, spans);
+        }
+        assert_eq!(aggregator.len(), 2);
+
+        let mut traces = aggregator.take_idle();
+        traces.sort_by_key(|(trace_id, _)| *trace_id);
+        let events = |spans: &[Span]| spans.iter().map(|s| (s.id, s.event)).collect::<Vec<_>>();
+        assert_eq!(
+            events(&traces[0].1),
+            vec![(1, "tidb"), (3, "tikv"), (4, "tikv")]
+        );
+        assert_eq!(traces[1].0, 2 | 7 << 64);
+        assert_eq!(events(&traces[1].1), vec![(2, "other")]);
+        assert_eq!(traces[0].1[1].parent_id, 1);
+        assert_eq!(
+            traces[0].1[1].properties,
+            vec![("k", PropertyValue::I64(3))]
+        );
+        assert!(aggregator.is_empty());
+
+        assert!(decode(b"mtr").is_err());
+        assert!(decode(b"xxxx").is_err());
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Counting the allocations made in local spans, enabled by the `alloc-counters` feature, to
+//! find the allocation-heavy code paths in traces.
+//!
+//! Install the [`CountingAllocator`] as the global allocator and enable
+//! [`Config::record_allocations`](crate::Config::record_allocations). Each local span then
+//! carries the number of allocations and the bytes allocated by its thread while it was open as
+//! [`alloc.count`](crate::semconv::ALLOC_COUNT) and [`alloc.bytes`](crate::semconv::ALLOC_BYTES).
+//!
+//! ```rust
+//! use minitrace::alloc::CountingAllocator;
+//!
+//! #[global_allocator]
+//! static GLOBAL: CountingAllocator = CountingAllocator::system();
+//! # fn main() {
+//! # minitrace::set_config(minitrace::Config::default().record_allocations(true));
+//! # }
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+thread_local! {
+    // Without destructors, so that they stay usable while the thread is torn down
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+    static ALLOCATED_BYTES: Cell<u64> = const { Cell::new(0) };
+}
+
+/// A global allocator wrapping another one, counting the allocations of each thread.
+/// Reallocations count as allocations of their new size.
+#[derive(Debug, Default)]
+pub struct CountingAllocator<A = System> {
+    inner: A,
+}
+
+impl CountingAllocator<System> {
+    pub const fn system() -> Self {
+        CountingAllocator { inner: System }
+    }
+}
+
+impl<A> CountingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        CountingAllocator { inner }
+    }
+}
+
+#[inline]
+fn count(size: usize) {
+    let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+    let _ = ALLOCATED_BYTES.try_with(|n| n.set(n.get() + size as u64));
+}
+
+// Safety: the allocations are forwarded to the inner allocator
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.inner.alloc(layout)
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.inner.alloc_zeroed(layout)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout)
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        self.inner.realloc(ptr, layout, new_size)
+    }
+}
+
+/// The number of allocations and the bytes allocated by the current thread so far, zero if
+/// the [`CountingAllocator`] isn't installed.
+#[inline]
+pub fn thread_allocations() -> (u64, u64) {
+    (
+        ALLOCATIONS.try_with(Cell::get).unwrap_or(0),
+        ALLOCATED_BYTES.try_with(Cell::get).unwrap_or(0),
+    )
+}
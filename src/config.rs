@@ -0,0 +1,278 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::sync::{Arc, RwLock};
+
+use crate::local::span_guard;
+use crate::stats;
+use crate::trace::hashed_properties::HashedProperties;
+use crate::trace::naming::{self, NamingPolicy};
+use crate::trace::orphan;
+use crate::trace::partial::{self, PartialExport, PartialTrace};
+use crate::trace::rate_limit::RateLimit;
+use crate::trace::target::{self, TargetFilter};
+
+lazy_static! {
+    static ref CONFIG: RwLock<Config> = RwLock::new(Config::default());
+}
+
+/// Set the process-wide configuration.
+///
+/// It should be called before tracing starts. Threads which have already recorded spans keep
+/// using the thread-local buffers created with the previous configuration.
+pub fn set_config(config: Config) {
+    orphan::set_enabled(config.collect_orphan_spans);
+    span_guard::set_misuse_policy(config.nested_enter, config.panic_on_misuse);
+    naming::set_enabled(config.naming_policy.is_some());
+    stats::set_enabled(config.live_histograms);
+    partial::set(config.partial_export.clone());
+    *CONFIG.write().unwrap() = config;
+    target::invalidate();
+}
+
+pub(crate) fn config() -> Config {
+    CONFIG.read().unwrap().clone()
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub(crate) span_queue_capacity: usize,
+    pub(crate) span_queue_growth: SpanQueueGrowth,
+    pub(crate) pretouch_span_queue: bool,
+    pub(crate) collector_pool_capacity: usize,
+    pub(crate) nested_enter: NestedEnter,
+    pub(crate) aggregate_late_spans: bool,
+    pub(crate) collect_orphan_spans: bool,
+    pub(crate) live_histograms: bool,
+    pub(crate) panic_on_misuse: bool,
+    pub(crate) hashed_properties: Option<Arc<HashedProperties>>,
+    pub(crate) naming_policy: Option<Arc<NamingPolicy>>,
+    pub(crate) rate_limits: Vec<(&'static str, RateLimit)>,
+    pub(crate) target_filter: Option<Arc<TargetFilter>>,
+    pub(crate) partial_export: Option<Arc<PartialExport>>,
+    #[cfg(feature = "cpu-time")]
+    pub(crate) record_cpu_time: bool,
+    #[cfg(feature = "alloc-counters")]
+    pub(crate) record_allocations: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            span_queue_capacity: 1024,
+            span_queue_growth: SpanQueueGrowth::Double,
+            pretouch_span_queue: false,
+            collector_pool_capacity: 32,
+            nested_enter: NestedEnter::Panic,
+            aggregate_late_spans: false,
+            collect_orphan_spans: false,
+            live_histograms: false,
+            panic_on_misuse: true,
+            hashed_properties: None,
+            naming_policy: None,
+            rate_limits: Vec::new(),
+            target_filter: None,
+            partial_export: None,
+            #[cfg(feature = "cpu-time")]
+            record_cpu_time: false,
+            #[cfg(feature = "alloc-counters")]
+            record_allocations: false,
+        }
+    }
+}
+
+impl Config {
+    /// The initial number of spans the per-thread span queue can hold without reallocation.
+    pub fn span_queue_capacity(self, span_queue_capacity: usize) -> Self {
+        Self {
+            span_queue_capacity,
+            ..self
+        }
+    }
+
+    pub fn span_queue_growth(self, span_queue_growth: SpanQueueGrowth) -> Self {
+        Self {
+            span_queue_growth,
+            ..self
+        }
+    }
+
+    /// Write the whole per-thread span queue when it's created, i.e. on the first use of
+    /// tracing on a thread, so that the first traced request doesn't pay for page faults. The
+    /// queue then keeps its buffer across collections, moving the spans collected out of it.
+    pub fn pretouch_span_queue(self, pretouch_span_queue: bool) -> Self {
+        Self {
+            pretouch_span_queue,
+            ..self
+        }
+    }
+
+    /// The number of finished collectors whose channels each thread keeps for reuse by later
+    /// root spans. A channel is only reused when no span of its previous trace is alive.
+    /// Setting it to zero disables the reuse.
+    pub fn collector_pool_capacity(self, collector_pool_capacity: usize) -> Self {
+        Self {
+            collector_pool_capacity,
+            ..self
+        }
+    }
+
+    /// What entering a span does while another span is attached to the current thread, which
+    /// typically happens when a callback framework runs a traced callback from inside a traced
+    /// request.
+    pub fn nested_enter(self, nested_enter: NestedEnter) -> Self {
+        Self {
+            nested_enter,
+            ..self
+        }
+    }
+
+    /// Aggregate the durations of spans finishing after their collector has been collected or
+    /// cancelled into process-wide per-event histograms, see
+    /// [`late_span_histograms`](crate::late_span_histograms), instead of discarding them. Local
+    /// spans shared by several traces are counted once per trace.
+    pub fn aggregate_late_spans(self, aggregate_late_spans: bool) -> Self {
+        Self {
+            aggregate_late_spans,
+            ..self
+        }
+    }
+
+    /// Report the spans created without a parent, i.e. [`Span::from_local_parent`] spans and
+    /// [`LocalSpan`](crate::LocalSpan)s on a thread without an attached span, as the roots of
+    /// their own traces to a process-wide collector instead of discarding them, so that
+    /// forgotten instrumentation still shows up. They are returned, grouped by thread, by
+    /// [`collect_orphan_spans`](crate::collect_orphan_spans), which should then be called
+    /// periodically. Only the last 1024 orphan spans of a thread are kept between two calls.
+    ///
+    /// [`Span::from_local_parent`]: crate::Span::from_local_parent
+    pub fn collect_orphan_spans(self, collect_orphan_spans: bool) -> Self {
+        Self {
+            collect_orphan_spans,
+            ..self
+        }
+    }
+
+    /// Record the durations of the spans into process-wide per-event histograms as they
+    /// finish, whether their traces are collected or not, see [`stats`](crate::stats).
+    pub fn live_histograms(self, live_histograms: bool) -> Self {
+        Self {
+            live_histograms,
+            ..self
+        }
+    }
+
+    /// Whether [`Span::enter`](crate::Span::enter) with [`NestedEnter::Panic`] and
+    /// [`LocalCollector::start`](crate::LocalCollector::start) panic on an occupied thread.
+    /// Disable it where unwinding is not allowed, e.g. in FFI callbacks, to get a guard or a
+    /// collector doing nothing instead. Their `try_` variants return the errors anyway.
+    pub fn panic_on_misuse(self, panic_on_misuse: bool) -> Self {
+        Self {
+            panic_on_misuse,
+            ..self
+        }
+    }
+
+    /// Record the values of the given property keys only as keyed hashes when the spans are
+    /// collected, so that sensitive data doesn't reach the tracing backend.
+    pub fn hashed_properties(self, hashed_properties: HashedProperties) -> Self {
+        Self {
+            hashed_properties: Some(Arc::new(hashed_properties)),
+            ..self
+        }
+    }
+
+    /// Name the events of the functions instrumented by `#[trace]` and `#[trace_async]` in all
+    /// crates by the policy, e.g. prefixed with their crate and in snake case.
+    pub fn naming_policy(self, naming_policy: NamingPolicy) -> Self {
+        Self {
+            naming_policy: Some(Arc::new(naming_policy)),
+            ..self
+        }
+    }
+
+    /// Limit the number of spans of `event`, e.g. of a hot call site in a loop. It can be called
+    /// again to add limits, including another kind of limit for the same event.
+    pub fn rate_limit(mut self, event: &'static str, limit: RateLimit) -> Self {
+        self.rate_limits.push((event, limit));
+        self
+    }
+
+    /// Trace only the targets of instrumented functions enabled by the filter, e.g. to trace a
+    /// single subsystem. It takes effect immediately, even on the threads tracing already.
+    pub fn target_filter(self, target_filter: TargetFilter) -> Self {
+        Self {
+            target_filter: Some(Arc::new(target_filter)),
+            ..self
+        }
+    }
+
+    /// Bound the spans each collector holds before collection to about `max_pending_spans`:
+    /// past it, the spans received so far are handed to `export` right away as a
+    /// [`PartialTrace`](crate::PartialTrace) numbered by its sequence, instead of piling up.
+    /// The spans collected at last are marked as the final part, so that consumers can stitch
+    /// the parts or at least tell that a trace is partial.
+    ///
+    /// `export` runs on the thread submitting the span in excess and should only hand the part
+    /// off, e.g. to a reporter. The duration threshold, the subtree filters and the truncation
+    /// of the collection apply to the final part only.
+    pub fn partial_export(
+        self,
+        max_pending_spans: usize,
+        export: impl Fn(PartialTrace) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            partial_export: Some(Arc::new(PartialExport {
+                max_pending_spans,
+                export: Box::new(export),
+            })),
+            ..self
+        }
+    }
+
+    /// Record the CPU time the thread spends in each local span as the property
+    /// [`cpu_time_ns`](crate::semconv::CPU_TIME_NS), at the cost of two `clock_gettime` calls
+    /// per span. Zero where the thread CPU time isn't available.
+    #[cfg(feature = "cpu-time")]
+    pub fn record_cpu_time(self, record_cpu_time: bool) -> Self {
+        Self {
+            record_cpu_time,
+            ..self
+        }
+    }
+
+    /// Record the allocations the thread makes in each local span, counted by the
+    /// [`CountingAllocator`](crate::alloc::CountingAllocator), see [`alloc`](crate::alloc).
+    #[cfg(feature = "alloc-counters")]
+    pub fn record_allocations(self, record_allocations: bool) -> Self {
+        Self {
+            record_allocations,
+            ..self
+        }
+    }
+}
+
+/// The behavior of [`Span::enter`](crate::Span::enter) when another span is attached to the
+/// current thread. [`Span::try_enter`](crate::Span::try_enter) returns an
+/// [`EnterError`](crate::EnterError) instead of panicking.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NestedEnter {
+    /// Panic with an [`EnterError`](crate::EnterError) naming both spans.
+    Panic,
+    /// Leave the attached span in place and return a guard attaching nothing.
+    Error,
+    /// Attach the span on top of the attached one until its guard is dropped, e.g. a statement
+    /// on top of its transaction on top of its session. Meanwhile, the span is the parent of
+    /// [`Span::from_local_parent`](crate::Span::from_local_parent) spans and local spans, and
+    /// the spans below are suspended.
+    Stack,
+}
+
+/// How the per-thread span queue grows once its capacity is exhausted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpanQueueGrowth {
+    /// Double the capacity.
+    Double,
+    /// Grow by a fixed number of spans, or of properties for the buffer of their properties,
+    /// trading more frequent but smaller reallocations for a bounded memory overhead.
+    Linear(usize),
+}
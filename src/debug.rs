@@ -0,0 +1,72 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Introspection of the tracing state of the current thread, e.g. for printing in assertion
+//! failures.
+//!
+//! ```rust
+//! use minitrace::{LocalSpan, Span};
+//!
+//! let (root_span, _collector) = Span::root("root");
+//! let _g = root_span.enter();
+//! let _l = LocalSpan::enter("local");
+//!
+//! let dump = minitrace::debug::dump_current_thread();
+//! assert!(dump.contains("\"root\""));
+//! assert!(dump.contains("open local spans: [\"local\"]"));
+//! ```
+
+use std::fmt::{self, Write};
+
+use crate::local::local_span_line::dump_local_span_line;
+use crate::local::span_guard::dump_attached_spans;
+use crate::trace::registry::active_tasks;
+
+/// Describe the spans attached to the current thread, innermost last, with their trace ids,
+/// and the local spans entered but not exited yet.
+pub fn dump_current_thread() -> String {
+    let mut out = String::new();
+    // Writing into a string never fails
+    let _ = dump_attached_spans(&mut out).and_then(|_| dump_local_span_line(&mut out));
+    out
+}
+
+/// Describe the in-flight futures bound to spans by [`in_span`](crate::FutureExt::in_span),
+/// grouped by their traces, with their ages and the futures they are pending on. Only the
+/// futures created while [`set_active_traces_enabled`](crate::set_active_traces_enabled) is on
+/// are listed.
+///
+/// ```text
+/// trace 0000000000000000000000000000002a "request" age=1.2s
+///   "request" age=1.2s idle=1.1s pending on "handle" > "rpc"
+///   "prefetch" age=1.0s polling
+/// ```
+pub fn dump_tasks() -> String {
+    let mut out = String::new();
+    // Writing into a string never fails
+    let _ = write_tasks(&mut out);
+    out
+}
+
+fn write_tasks(out: &mut String) -> fmt::Result {
+    for trace_tasks in active_tasks() {
+        write!(out, "trace {:032x}", trace_tasks.trace_id)?;
+        if let Some(trace) = &trace_tasks.trace {
+            write!(out, " {:?} age={:?}", trace.event, trace.age())?;
+        }
+        writeln!(out)?;
+
+        for task in &trace_tasks.tasks {
+            write!(out, "  {:?} age={:?}", task.event, task.age())?;
+            match task.idle() {
+                Some(idle) => write!(out, " idle={:?}", idle)?,
+                None => write!(out, " polling")?,
+            }
+            if !task.stack.is_empty() {
+                let stack: Vec<_> = task.stack.iter().map(|e| format!("{:?}", e)).collect();
+                write!(out, " pending on {}", stack.join(" > "))?;
+            }
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Attach trace ids as [OpenMetrics exemplars](https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md#exemplars)
+//! to histograms, so that a metric spike can link to a representative trace.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use std::sync::{Arc, Mutex};
+//! use minitrace::exemplar::{Exemplar, ExemplarHistogram};
+//! use minitrace::Span;
+//!
+//! #[derive(Default)]
+//! struct LastExemplar(Mutex<Option<Exemplar>>);
+//!
+//! impl ExemplarHistogram for LastExemplar {
+//!     fn observe_with_exemplar(&self, exemplar: &Exemplar) {
+//!         // observe `exemplar.value` and keep the exemplar for exposition
+//!         *self.0.lock().unwrap() = Some(exemplar.clone());
+//!     }
+//! }
+//!
+//! let histogram = Arc::new(LastExemplar::default());
+//! let (span, collector) = Span::root("request");
+//! drop(span.with_exemplar_histogram(histogram.clone()));
+//!
+//! let exemplar = histogram.0.lock().unwrap().clone().unwrap();
+//! assert_eq!(exemplar.trace_id, collector.trace_id());
+//! ```
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::span::{Cycle, DefaultClock, RawSpan, SpanId};
+use crate::trace::acquirer::Acquirer;
+use crate::Span;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exemplar {
+    pub trace_id: u128,
+    pub span_id: u32,
+    /// The duration of the span in seconds.
+    pub value: f64,
+    pub timestamp_unix_ns: u64,
+}
+
+impl Exemplar {
+    /// Format the exemplar as the suffix of an OpenMetrics sample line, e.g.
+    /// ` # {trace_id="00000000000000000000000000000abc"} 0.5 1617000000.123`.
+    pub fn to_openmetrics(&self) -> String {
+        format!(
+            " # {{trace_id=\"{:032x}\"}} {} {}.{:03}",
+            self.trace_id,
+            self.value,
+            self.timestamp_unix_ns / 1_000_000_000,
+            self.timestamp_unix_ns / 1_000_000 % 1_000
+        )
+    }
+}
+
+/// A histogram accepting observations with exemplars, implemented by users for the histogram
+/// type of their metrics library.
+pub trait ExemplarHistogram: Send + Sync {
+    fn observe_with_exemplar(&self, exemplar: &Exemplar);
+}
+
+impl Span {
+    /// Observe the duration of the span into `histogram` with the trace id as the exemplar when
+    /// the span finishes.
+    #[inline]
+    pub fn with_exemplar_histogram(mut self, histogram: Arc<dyn ExemplarHistogram>) -> Self {
+        if let Some(inner) = &mut self.inner {
+            inner.exemplar_histograms.0.push(histogram);
+        }
+        self
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ExemplarHistograms(Vec<Arc<dyn ExemplarHistogram>>);
+
+impl ExemplarHistograms {
+    pub(crate) fn observe(&self, span_id: SpanId, to_report: &[(RawSpan, Acquirer)], end: Cycle) {
+        if self.0.is_empty() {
+            return;
+        }
+
+        if let Some((span, acq)) = to_report.first() {
+            let anchor = DefaultClock::anchor();
+            let begin_unix_time_ns = DefaultClock::cycle_to_unix_time_ns(span.begin_cycle, anchor);
+            let end_unix_time_ns = DefaultClock::cycle_to_unix_time_ns(end, anchor);
+            let exemplar = Exemplar {
+                trace_id: acq.trace_id(),
+                span_id: span_id.0,
+                value: end_unix_time_ns.saturating_sub(begin_unix_time_ns) as f64 / 1e9,
+                timestamp_unix_ns: end_unix_time_ns,
+            };
+            for histogram in &self.0 {
+                histogram.observe_with_exemplar(&exemplar);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ExemplarHistograms {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ExemplarHistograms({})", self.0.len())
+    }
+}
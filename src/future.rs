@@ -1,8 +1,21 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
-use std::task::Poll;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::ThreadId;
 
-use crate::{LocalSpan, Span};
+use crate::span::{RawEvent, RawSpan};
+use crate::trace::registry;
+use crate::{semconv, LocalCollector, LocalSpan, LocalSpans, Span};
+
+const FORCED_YIELD: &str = "forced yield";
+
+thread_local! {
+    // The events of the futures pending in the poll of a registered task, innermost first
+    static PENDING_STACK: RefCell<Option<Vec<&'static str>>> = const { RefCell::new(None) };
+}
 
 impl<T: std::future::Future> FutureExt for T {}
 
@@ -30,6 +43,7 @@ pub trait FutureExt: Sized {
     fn in_span(self, span: Span) -> InSpan<Self> {
         InSpan {
             inner: self,
+            task: registry::register_task(&span),
             span: Some(span),
         }
     }
@@ -61,29 +75,127 @@ pub trait FutureExt: Sized {
     /// ```
     #[inline]
     fn in_local_span(self, event: &'static str) -> InLocalSpan<Self> {
-        InLocalSpan { inner: self, event }
+        InLocalSpan::new(self, Some(event), None)
+    }
+
+    /// Return a future adaptor `RecordForcedYields`. It records a zero-length local span named
+    /// `forced yield` whenever the future wakes itself up during its own poll and returns
+    /// [`Poll::Pending`](Poll::Pending), which is how tokio's cooperative budgeting forces a
+    /// task to yield. Voluntary yields such as `tokio::task::yield_now` are recorded as well,
+    /// but not the wakes from other threads during the poll, e.g. a completed I/O.
+    ///
+    /// Wrap it inside [`in_span`](FutureExt::in_span) so that the local spans are collected.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// use minitrace::{Span, FutureExt};
+    ///
+    /// let (span, _collector) = Span::root("Task");
+    /// let task = async {
+    ///     tokio::task::yield_now().await;
+    /// };
+    ///
+    /// tokio::spawn(task.record_forced_yields().in_span(span));
+    /// # }
+    /// ```
+    #[inline]
+    fn record_forced_yields(self) -> RecordForcedYields<Self> {
+        RecordForcedYields::new(self)
+    }
+
+    /// Return a future adaptor `CollectLocalSpans`. It collects the local spans recorded during
+    /// every poll of the future, on whichever threads poll it, and returns them together with
+    /// the output, e.g. to profile an async code path without a trace.
+    ///
+    /// The spans recorded during the polls are not reported to the span attached to the
+    /// polling thread, if any.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// use minitrace::{FutureExt, LocalSpan};
+    ///
+    /// let task = async {
+    ///     let _guard = LocalSpan::enter("work");
+    ///     42
+    /// };
+    ///
+    /// let (output, local_spans) = task.collect_local_spans().await;
+    /// assert_eq!(output, 42);
+    /// assert_eq!(local_spans.spans.len(), 1);
+    /// # }
+    /// ```
+    #[inline]
+    fn collect_local_spans(self) -> CollectLocalSpans<Self> {
+        CollectLocalSpans {
+            inner: self,
+            spans: Vec::new(),
+            events: Vec::new(),
+        }
     }
 }
 
-#[pin_project::pin_project]
+#[pin_project::pin_project(PinnedDrop)]
 pub struct InSpan<T> {
     #[pin]
     inner: T,
     span: Option<Span>,
+    // The key of the task in the registry of active tasks
+    task: Option<usize>,
+}
+
+#[pin_project::pinned_drop]
+impl<T> PinnedDrop for InSpan<T> {
+    fn drop(self: std::pin::Pin<&mut Self>) {
+        let this = self.project();
+        if let Some(key) = this.task.take() {
+            registry::unregister_task(key);
+        }
+
+        // The future is dropped before completion, i.e. cancelled.
+        if let Some(span) = this.span.take() {
+            drop(span.with_property(|| (semconv::CANCELLED, "true".to_owned())));
+        }
+    }
 }
 
 impl<T: std::future::Future> std::future::Future for InSpan<T> {
     type Output = T::Output;
 
-    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
 
-        let _guard = this.span.as_ref().map(|s| s.try_enter());
+        let outer_stack = this.task.map(|key| {
+            registry::task_polling(key);
+            PENDING_STACK.with(|stack| stack.replace(Some(Vec::new())))
+        });
+
+        let _guard = this.span.as_ref().and_then(|s| s.try_enter().ok());
         let res = this.inner.poll(cx);
 
+        if let (Some(key), Some(outer_stack)) = (*this.task, outer_stack) {
+            let mut stack = PENDING_STACK
+                .with(|stack| stack.replace(outer_stack))
+                .unwrap_or_default();
+            if res.is_pending() {
+                let event = this.span.as_ref().map(Span::event).unwrap_or_default();
+                push_pending(stack.iter().copied().chain(Some(event)));
+                stack.reverse();
+                registry::task_pending(key, stack);
+            }
+        }
+
         match res {
             r @ Poll::Pending => r,
             other => {
+                if let Some(key) = this.task.take() {
+                    registry::unregister_task(key);
+                }
                 this.span.take();
                 other
             }
@@ -95,15 +207,165 @@ impl<T: std::future::Future> std::future::Future for InSpan<T> {
 pub struct InLocalSpan<T> {
     #[pin]
     inner: T,
-    event: &'static str,
+    // `None` if the target of the instrumented function is disabled
+    event: Option<&'static str>,
+    target: Option<&'static str>,
+}
+
+impl<T> InLocalSpan<T> {
+    pub(crate) fn new(inner: T, event: Option<&'static str>, target: Option<&'static str>) -> Self {
+        InLocalSpan {
+            inner,
+            event,
+            target,
+        }
+    }
 }
 
 impl<T: std::future::Future> std::future::Future for InLocalSpan<T> {
     type Output = T::Output;
 
-    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let target = *this.target;
+        let _guard = this.event.map(|event| match target {
+            Some(target) => LocalSpan::enter(event).with_static_property(semconv::TARGET, target),
+            None => LocalSpan::enter(event),
+        });
+        let res = this.inner.poll(cx);
+        if res.is_pending() && registry::enabled() {
+            push_pending(*this.event);
+        }
+        res
+    }
+}
+
+#[pin_project::pin_project]
+pub struct RecordForcedYields<T> {
+    #[pin]
+    inner: T,
+    // Allocated once, and pointed at the waker of the latest poll
+    detector: Arc<SelfWakeDetector>,
+    waker: Waker,
+}
+
+impl<T> RecordForcedYields<T> {
+    fn new(inner: T) -> Self {
+        let detector = Arc::new(SelfWakeDetector {
+            state: Mutex::new(DetectorState {
+                waker: None,
+                polling: None,
+            }),
+            woken: AtomicBool::new(false),
+        });
+        let waker = Waker::from(detector.clone());
+        RecordForcedYields {
+            inner,
+            detector,
+            waker,
+        }
+    }
+}
+
+impl<T: std::future::Future> std::future::Future for RecordForcedYields<T> {
+    type Output = T::Output;
+
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        {
+            let mut state = this.detector.state.lock().unwrap();
+            let stale = match &state.waker {
+                Some(waker) => !waker.will_wake(cx.waker()),
+                None => true,
+            };
+            if stale {
+                state.waker = Some(cx.waker().clone());
+            }
+            state.polling = Some(std::thread::current().id());
+        }
+        this.detector.woken.store(false, Ordering::Relaxed);
+        let res = this.inner.poll(&mut Context::from_waker(this.waker));
+        this.detector.state.lock().unwrap().polling = None;
+
+        if res.is_pending() && this.detector.woken.load(Ordering::Relaxed) {
+            let _guard = LocalSpan::enter(FORCED_YIELD);
+        }
+        res
+    }
+}
+
+#[pin_project::pin_project]
+pub struct CollectLocalSpans<T> {
+    #[pin]
+    inner: T,
+    spans: Vec<RawSpan>,
+    events: Vec<RawEvent>,
+}
+
+impl<T: std::future::Future> std::future::Future for CollectLocalSpans<T> {
+    type Output = (T::Output, LocalSpans);
+
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
-        let _guard = LocalSpan::enter(this.event);
-        this.inner.poll(cx)
+
+        let local_collector = LocalCollector::start_nested();
+        let res = this.inner.poll(cx);
+        let LocalSpans {
+            spans,
+            end_time,
+            events,
+        } = local_collector.collect();
+        this.spans.extend(spans);
+        this.events.extend(events);
+
+        let (spans, events) = (this.spans, this.events);
+        res.map(|output| {
+            let local_spans = LocalSpans {
+                spans: std::mem::take(spans),
+                end_time,
+                events: std::mem::take(events),
+            };
+            (output, local_spans)
+        })
+    }
+}
+
+// Record the futures pending in the poll of the registered task being polled, if any
+fn push_pending(events: impl IntoIterator<Item = &'static str>) {
+    PENDING_STACK.with(|stack| {
+        if let Some(stack) = &mut *stack.borrow_mut() {
+            stack.extend(events);
+        }
+    });
+}
+
+struct SelfWakeDetector {
+    state: Mutex<DetectorState>,
+    // Whether the future has woken itself up during the current poll
+    woken: AtomicBool,
+}
+
+struct DetectorState {
+    // The waker of the latest poll
+    waker: Option<Waker>,
+    // The thread polling the future, during a poll
+    polling: Option<ThreadId>,
+}
+
+impl Wake for SelfWakeDetector {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let state = self.state.lock().unwrap();
+        // Another thread waking the task, e.g. a completed I/O, doesn't force the task to yield
+        if state.polling == Some(std::thread::current().id()) {
+            self.woken.store(true, Ordering::Relaxed);
+        }
+        if let Some(waker) = &state.waker {
+            waker.wake_by_ref()
+        }
     }
 }
@@ -0,0 +1,169 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Process-wide hooks called when spans start and end, letting external profilers piggyback on
+//! the instrumentation points. An unset hook costs a relaxed atomic load per span boundary.
+//!
+//! Hooks are called on the thread starting or ending the span, so they should be quick and must
+//! not start spans themselves. They are called once the local spans of the thread are released,
+//! so they may log, e.g. through the `MinitraceLogger` of the `log` feature, and read the state of
+//! minitrace.
+//!
+//! ```rust
+//! use minitrace::hooks::{self, RawSpanMeta};
+//! use minitrace::LocalSpan;
+//!
+//! fn on_start(meta: &RawSpanMeta) {
+//!     let _ = meta.event;
+//! }
+//!
+//! hooks::on_span_start(on_start);
+//! let _l = LocalSpan::enter("hooked");
+//! hooks::clear_span_hooks();
+//! ```
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::span::{Cycle, SpanId};
+
+// The hooks as `fn(&RawSpanMeta)` pointers casted to `usize`, or zero if unset
+static SPAN_START: AtomicUsize = AtomicUsize::new(0);
+static SPAN_END: AtomicUsize = AtomicUsize::new(0);
+
+/// A span starting or ending, passed to the hooks.
+#[derive(Clone, Copy, Debug)]
+pub struct RawSpanMeta {
+    pub id: SpanId,
+    /// The parent in the first trace of the span, or zero for a root span.
+    pub parent_id: SpanId,
+    pub event: &'static str,
+    /// The time the span starts at for [`on_span_start`], or ends at for [`on_span_end`].
+    pub cycle: Cycle,
+}
+
+/// Call `hook` whenever a span starts, replacing the previous one.
+pub fn on_span_start(hook: fn(&RawSpanMeta)) {
+    SPAN_START.store(hook as usize, Ordering::Relaxed);
+}
+
+/// Call `hook` whenever a span ends, replacing the previous one.
+pub fn on_span_end(hook: fn(&RawSpanMeta)) {
+    SPAN_END.store(hook as usize, Ordering::Relaxed);
+}
+
+/// Unset the hooks of [`on_span_start`] and [`on_span_end`].
+pub fn clear_span_hooks() {
+    SPAN_START.store(0, Ordering::Relaxed);
+    SPAN_END.store(0, Ordering::Relaxed);
+}
+
+#[inline]
+pub(crate) fn span_started(meta: &RawSpanMeta) {
+    #[cfg(feature = "usdt")]
+    crate::usdt::span_start(meta.id, meta.parent_id, meta.event);
+
+    let hook = SPAN_START.load(Ordering::Relaxed);
+    if hook != 0 {
+        call(hook, meta);
+    }
+}
+
+#[inline]
+pub(crate) fn span_ended(meta: &RawSpanMeta) {
+    #[cfg(feature = "usdt")]
+    crate::usdt::span_end(meta.id, meta.parent_id, meta.event);
+
+    let hook = SPAN_END.load(Ordering::Relaxed);
+    if hook != 0 {
+        call(hook, meta);
+    }
+}
+
+#[cold]
+fn call(hook: usize, meta: &RawSpanMeta) {
+    // Safety: non-zero values are only stored from `fn(&RawSpanMeta)` pointers
+    let hook = unsafe { std::mem::transmute::<usize, fn(&RawSpanMeta)>(hook) };
+    hook(meta);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    use crate::trace::collector::CollectArgs;
+    use crate::{LocalSpan, Span};
+
+    lazy_static! {
+        static ref SEEN: Mutex<Vec<(&'static str, &'static str)>> = Mutex::new(Vec::new());
+    }
+
+    fn record(boundary: &'static str, meta: &RawSpanMeta) {
+        // Other tests may run concurrently
+        if meta.event.starts_with("hooked") {
+            SEEN.lock().unwrap().push((boundary, meta.event));
+        }
+    }
+
+    #[test]
+    fn hooks() {
+        let _lock = crate::tests::CONFIG_LOCK.lock().unwrap();
+        on_span_start(|meta| record("start", meta));
+        on_span_end(|meta| record("end", meta));
+        {
+            let (root_span, collector) = Span::root("hooked root");
+            {
+                let _g = root_span.enter();
+                let _l = LocalSpan::enter("hooked local");
+            }
+            drop(root_span);
+            collector.collect_with_args(CollectArgs::default().sync(true));
+        }
+        clear_span_hooks();
+        drop(LocalSpan::enter("hooked after"));
+
+        assert_eq!(
+            *SEEN.lock().unwrap(),
+            vec![
+                ("start", "hooked root"),
+                ("start", "hooked local"),
+                ("end", "hooked local"),
+                ("end", "hooked root"),
+            ]
+        );
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn hook_logging() {
+        use log::Log;
+
+        let _lock = crate::tests::CONFIG_LOCK.lock().unwrap();
+        on_span_start(|meta| {
+            if meta.event == "hooked logging" {
+                crate::log_bridge::MinitraceLogger::new().log(
+                    &log::Record::builder()
+                        .level(log::Level::Warn)
+                        .target("hook")
+                        .args(format_args!("started"))
+                        .build(),
+                );
+            }
+        });
+        let spans = {
+            let (root_span, collector) = Span::root("root");
+            let _g = root_span.enter();
+            drop(LocalSpan::enter("hooked logging"));
+            drop(_g);
+            drop(root_span);
+            collector.collect_with_args(CollectArgs::default().sync(true))
+        };
+        clear_span_hooks();
+
+        // Recorded on the span started, which is open when the hook is called
+        let span = spans.iter().find(|s| s.event == "hooked logging").unwrap();
+        assert_eq!(
+            span.properties,
+            vec![(crate::semconv::LOG_RECORD, "WARN hook: started".into())]
+        );
+    }
+}
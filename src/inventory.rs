@@ -0,0 +1,55 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! The inventory of the events of the functions instrumented by `#[trace]` and `#[trace_async]`
+//! throughout the binary, gathered at link time. Enabled by the `inventory` feature, without
+//! which it's empty.
+//!
+//! Event names used by several functions are ambiguous in filters and dashboards. Check them in
+//! a test of the binary, which sees the events of every crate linked:
+//!
+//! ```rust
+//! assert!(minitrace::inventory::duplicate_events().is_empty());
+//! ```
+
+use std::collections::BTreeMap;
+
+/// An event registered by `#[trace]` or `#[trace_async]`, with where the function is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventMeta {
+    pub name: &'static str,
+    pub module: &'static str,
+    pub file: &'static str,
+    pub line: u32,
+}
+
+#[cfg(feature = "inventory")]
+#[linkme::distributed_slice]
+pub static EVENTS: [EventMeta];
+
+/// The events instrumented in the binary, in no particular order, e.g. for admin tooling to
+/// show the instrumentation or to build filters against the real event names.
+///
+/// Without the `inventory` feature nothing is registered and the slice is always empty, so an
+/// empty inventory doesn't tell that the binary is uninstrumented.
+pub fn events() -> &'static [EventMeta] {
+    #[cfg(feature = "inventory")]
+    {
+        &EVENTS
+    }
+    #[cfg(not(feature = "inventory"))]
+    {
+        &[]
+    }
+}
+
+/// The event names registered by more than one function, with the functions, sorted by name.
+pub fn duplicate_events() -> Vec<(&'static str, Vec<&'static EventMeta>)> {
+    let mut by_name: BTreeMap<&'static str, Vec<&'static EventMeta>> = BTreeMap::new();
+    for event in events() {
+        by_name.entry(event.name).or_default().push(event);
+    }
+    by_name
+        .into_iter()
+        .filter(|(_, events)| events.len() > 1)
+        .collect()
+}
@@ -1,26 +1,105 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
+#[macro_use]
+extern crate lazy_static;
+
+// Lets the code generated by `minitrace-macro` refer to `minitrace` in this crate too
+extern crate self as minitrace;
+
+#[cfg(feature = "inventory")]
+#[doc(hidden)]
+pub use linkme as __linkme;
+
+#[doc(hidden)]
+pub use crate::trace::naming::EventName as __EventName;
+#[doc(hidden)]
+pub use crate::trace::target::Target as __Target;
+
+pub use crate::config::{set_config, Config, NestedEnter, SpanQueueGrowth};
 pub use crate::future::FutureExt;
-pub use crate::local::local_collector::{LocalCollector, LocalSpans};
+pub use crate::inventory::{events, EventMeta};
+pub use crate::local::local_collector::{LocalCollector, LocalCollectorError, LocalSpans};
 pub use crate::local::local_span_guard::LocalSpanGuard;
-pub use crate::local::span_guard::SpanGuard;
-pub use crate::trace::collector::{CollectArgs, Collector};
+pub use crate::local::span_guard::{EnterError, SpanGuard};
+pub use crate::trace::acquirer::TraceSummary;
+pub use crate::trace::baggage::{baggage, flag};
+pub use crate::trace::collector::{CollectArgs, Collector, CollectorHandle, Trace};
+pub use crate::trace::event::event;
+pub use crate::trace::exec_summary::ExecSummary;
+pub use crate::trace::hashed_properties::HashedProperties;
+pub use crate::trace::late_spans::{
+    late_span_histograms, reset_late_span_histograms, LateSpanHistogram,
+};
 pub use crate::trace::local_span::LocalSpan;
+pub use crate::trace::manual_span::ManualSpan;
+pub use crate::trace::naming::NamingPolicy;
+pub use crate::trace::normalizer::{Normalizer, Rule};
+pub use crate::trace::orphan::{collect_orphan_spans, OrphanSpans};
+pub use crate::trace::partial::PartialTrace;
+pub use crate::trace::rate_limit::RateLimit;
+pub use crate::trace::registry::{
+    active_tasks, active_traces, set_active_traces_enabled, ActiveTask, ActiveTrace, TraceTasks,
+};
+pub use crate::trace::root_spans::{
+    subscribe_root_spans, RootSpanStatus, RootSpanSummary, RootSpans,
+};
+pub use crate::trace::sampler::{
+    context_hash, trace_hash, ContextSampler, Decision, TraceIdRatioSampler,
+};
+pub use crate::trace::shared_collector::SharedCollector;
+pub use crate::trace::snapshot::{clear_snapshots, register_snapshot};
 pub use crate::trace::span::Span;
+pub use crate::trace::span_handle::SpanHandle;
+pub use crate::trace::span_token::SpanToken;
+pub use crate::trace::storage::{Batches, FileSpanStorage, SpanStorage};
+pub use crate::trace::target::TargetFilter;
+pub use crate::trace::trace_result::TraceResult;
+pub use crate::trace::truncation::{CollapseRepeated, KeepFirst, TruncationStrategy};
+pub use crate::trace::validate::{validate, Violation};
 
+pub mod agent;
+#[cfg(feature = "alloc-counters")]
+pub mod alloc;
+pub mod debug;
+#[cfg(feature = "exemplar")]
+pub mod exemplar;
+pub mod hooks;
+pub mod inventory;
+#[cfg(feature = "log")]
+pub mod log_bridge;
+pub mod otlp;
+pub mod propagation;
+pub mod report;
+pub mod retry;
+pub mod semconv;
 pub mod span;
+pub mod stats;
+#[cfg(feature = "tracing")]
+pub mod tracing_bridge;
 
+pub(crate) mod config;
 pub(crate) mod future;
 pub(crate) mod local;
+mod macros;
 pub(crate) mod trace;
+#[cfg(feature = "usdt")]
+mod usdt;
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::local::local_collector::LocalCollector;
+    use crate::span::PropertyValue;
     use crate::trace::collector::CollectArgs;
-    use minitrace_macro::trace;
-    use std::sync::Arc;
+    use minitrace_macro::{trace, trace_async};
+    use std::future::Future;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    lazy_static! {
+        // Serializes the tests changing the global config or collecting the orphan spans
+        pub(crate) static ref CONFIG_LOCK: Mutex<()> = Mutex::new(());
+    }
 
     fn four_spans() {
         {
@@ -61,6 +140,1464 @@ mod tests {
         assert_eq!(spans.len(), 5);
     }
 
+    #[test]
+    fn summary() {
+        let (root_span, collector) = Span::root("root");
+        {
+            let _g = root_span.enter();
+            four_spans();
+        }
+        assert_eq!(collector.summary(), None);
+        drop(root_span);
+
+        let summary = collector.summary().unwrap();
+        assert_eq!(summary.span_count, 5);
+        assert_eq!(collector.collect().len(), 5);
+    }
+
+    #[test]
+    fn suppress_children() {
+        let spans = {
+            let (root_span, collector) = Span::root("root");
+            let worker_span = Span::from_parent("worker", &root_span);
+            let _g = root_span.enter();
+            {
+                let _l = LocalSpan::enter("checksum").suppress_children();
+                let _l = LocalSpan::enter("noise");
+                let _s = Span::from_local_parent("noise");
+            }
+            let _l = LocalSpan::enter("after");
+
+            std::thread::spawn(move || {
+                let _g = worker_span.enter().suppress_children();
+                let _l = LocalSpan::enter("noise");
+            })
+            .join()
+            .unwrap();
+
+            collector
+        }
+        .collect_with_args(CollectArgs::default().sync(true));
+
+        let mut events: Vec<_> = spans.iter().map(|s| s.event).collect();
+        events.sort_unstable();
+        assert_eq!(events, vec!["after", "checksum", "root", "worker"]);
+    }
+
+    #[test]
+    fn detail_after() {
+        let (root_span, collector) = Span::root("root");
+        {
+            let _g = root_span.enter().detail_after(Duration::from_millis(20));
+            drop(LocalSpan::enter("fast"));
+            drop(Span::from_local_parent("fast"));
+            {
+                // Suppressed children stay suppressed past the threshold
+                let _l = LocalSpan::enter("checksum").suppress_children();
+                std::thread::sleep(Duration::from_millis(30));
+                drop(LocalSpan::enter("noise"));
+            }
+            let _l = LocalSpan::enter("slow");
+            drop(Span::from_local_parent("slow"));
+        }
+        {
+            let _g = root_span.enter();
+            let _l = LocalSpan::enter("next").detail_after(Duration::from_secs(60));
+            drop(LocalSpan::enter("fast"));
+        }
+        drop(root_span);
+
+        let spans = collector.collect();
+        let mut events: Vec<_> = spans.iter().map(|s| s.event).collect();
+        events.sort_unstable();
+        assert_eq!(events, vec!["next", "root", "slow", "slow"]);
+    }
+
+    #[test]
+    fn span_events() {
+        // Outside of a trace, nothing is recorded
+        event("outside");
+
+        let (root_span, collector) = Span::root("root");
+        {
+            let _g = root_span.enter();
+            event("attached");
+            {
+                let _l = LocalSpan::enter("outer");
+                let _l = LocalSpan::enter("inner");
+                event("inner");
+            }
+            let _l = LocalSpan::enter("outer");
+            event("outer");
+        }
+        drop(root_span);
+
+        let spans = collector.collect();
+        let names = |event| {
+            let span = spans.iter().find(|s| s.event == event).unwrap();
+            assert!(span.events.iter().all(|e| {
+                span.begin_unix_time_ns <= e.unix_time_ns
+                    && e.unix_time_ns <= span.begin_unix_time_ns + span.duration_ns
+            }));
+            span.events.iter().map(|e| e.name).collect::<Vec<_>>()
+        };
+        assert_eq!(names("root"), vec!["attached"]);
+        assert_eq!(names("inner"), vec!["inner"]);
+        let mut outer: Vec<_> = spans
+            .iter()
+            .filter(|s| s.event == "outer")
+            .flat_map(|s| s.events.iter().map(|e| e.name))
+            .collect();
+        outer.sort_unstable();
+        assert_eq!(outer, vec!["outer"]);
+    }
+
+    #[test]
+    fn orphan_spans() {
+        let _lock = CONFIG_LOCK.lock().unwrap();
+        set_config(Config::default().collect_orphan_spans(true));
+
+        let thread_id = std::thread::current().id();
+        {
+            let span = Span::from_local_parent("orphan");
+            let _g = span.enter();
+            let _l = LocalSpan::enter("child");
+        }
+        drop(LocalSpan::enter("local orphan").with_property(|| ("k", "v".to_owned())));
+
+        set_config(Config::default());
+        drop(LocalSpan::enter("discarded"));
+
+        let orphans = collect_orphan_spans();
+        let group = orphans.iter().find(|g| g.thread_id == thread_id).unwrap();
+        let mut events: Vec<_> = group.spans.iter().map(|s| s.event).collect();
+        events.sort_unstable();
+        assert_eq!(events, vec!["child", "local orphan", "orphan"]);
+    }
+
+    #[test]
+    fn panic_on_misuse() {
+        let _lock = CONFIG_LOCK.lock().unwrap();
+        set_config(Config::default().panic_on_misuse(false));
+
+        let (root_span, collector) = Span::root("root");
+        {
+            let local_collector = LocalCollector::start();
+            assert_eq!(
+                LocalCollector::start_checked().unwrap_err(),
+                LocalCollectorError
+            );
+            let detached = LocalCollector::start();
+            let _l = LocalSpan::enter("local");
+            assert!(detached.collect().spans.is_empty());
+            assert_eq!(local_collector.collect().spans.len(), 1);
+        }
+        {
+            let _g = root_span.enter();
+            let other = Span::from_parent("other", &root_span);
+            assert!(other.try_enter().is_err());
+            let _g2 = other.enter();
+            let _l = LocalSpan::enter("child");
+        }
+        drop(root_span);
+
+        set_config(Config::default());
+        let mut events: Vec<_> = collector.collect().iter().map(|s| s.event).collect();
+        events.sort_unstable();
+        assert_eq!(events, vec!["child", "other", "root"]);
+    }
+
+    #[test]
+    fn hashed_properties() {
+        let _lock = CONFIG_LOCK.lock().unwrap();
+        let hashed_properties = HashedProperties::new([7; 16]).key("user.key");
+        let hash = hashed_properties.hash(&"alice".into());
+        set_config(Config::default().hashed_properties(hashed_properties));
+
+        let (root_span, collector) = Span::root("root");
+        {
+            let _g = root_span.enter();
+            let _l = LocalSpan::enter("get")
+                .with_property(|| ("user.key", "alice".to_owned()))
+                .with_property(|| ("region.id", "1".to_owned()));
+        }
+        drop(root_span);
+        let spans = collector.collect();
+        set_config(Config::default());
+
+        let get = spans.iter().find(|s| s.event == "get").unwrap();
+        assert!(hash.ends_with(":5"));
+        assert_eq!(
+            get.properties,
+            vec![("user.key", hash.into()), ("region.id", "1".into())]
+        );
+    }
+
+    #[test]
+    fn record_batch() {
+        let (root_span, collector) = Span::root("scan");
+        let begin = crate::span::DefaultClock::now();
+        let end = crate::span::DefaultClock::now();
+        root_span.record_batch("get", &[(begin, end), (begin, end), (end, begin)]);
+        Span::empty().record_batch("get", &[(begin, end)]);
+        drop(root_span);
+
+        let spans = collector.collect();
+        let root = spans.iter().find(|s| s.event == "scan").unwrap();
+        let gets: Vec<_> = spans.iter().filter(|s| s.event == "get").collect();
+        assert_eq!(gets.len(), 3);
+        assert!(gets.iter().all(|s| s.parent_id == root.id));
+        // The interval ending before it begins
+        assert_eq!(gets[2].duration_ns, 0);
+    }
+
+    #[test]
+    fn exec_summaries() {
+        let (root_span, collector) = Span::root("coprocessor");
+        let end = std::time::SystemTime::now();
+        let summaries = [
+            ExecSummary::new("scan")
+                .produced_rows(100)
+                .time_processed(Duration::from_millis(3)),
+            ExecSummary::new("selection").time_processed(Duration::from_millis(2)),
+            ExecSummary::new("aggregation").time_processed(Duration::from_millis(4)),
+        ];
+        root_span.record_exec_summaries(&summaries, end);
+        drop(root_span);
+
+        let spans = collector.collect();
+        let span = |event| spans.iter().find(|s| s.event == event).unwrap();
+        assert_eq!(span("aggregation").parent_id, span("coprocessor").id);
+        assert_eq!(span("selection").parent_id, span("aggregation").id);
+        assert_eq!(span("scan").parent_id, span("selection").id);
+        // The scan is clamped into the selection
+        assert_eq!(
+            span("scan").begin_unix_time_ns,
+            span("selection").begin_unix_time_ns
+        );
+        assert!(span("scan")
+            .properties
+            .contains(&(semconv::EXEC_PRODUCED_ROWS, PropertyValue::I64(100))));
+    }
+
+    #[test]
+    fn baggage_flags() {
+        let (root_span, _collector) = Span::root("root");
+        let root_span = root_span.with_baggage("capture_sql", "true");
+        let worker_span = Span::from_parent("worker", &root_span);
+        root_span.set_baggage("tenant", "a");
+
+        let flags = std::thread::spawn(move || {
+            let _g = worker_span.enter();
+            (flag("capture_sql"), flag("tenant"), baggage("tenant"))
+        })
+        .join()
+        .unwrap();
+        assert_eq!(flags, (true, false, Some("a".to_owned())));
+        assert!(!flag("capture_sql"));
+        assert_eq!(Span::empty().with_baggage("k", "v").baggage("k"), None);
+    }
+
+    #[test]
+    fn root_spans() {
+        let root_spans = subscribe_root_spans(1024);
+        let full = subscribe_root_spans(0);
+
+        let (root_span, collector) = Span::root("failed");
+        let trace_id = collector.trace_id();
+        {
+            let _g = root_span.enter();
+            let _l = LocalSpan::enter("child");
+        }
+        drop(root_span.with_property(|| semconv::error_kind("timeout")));
+
+        let summaries: Vec<_> = root_spans
+            .drain()
+            .into_iter()
+            .filter(|s| s.trace_id == trace_id)
+            .collect();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].event, "failed");
+        assert_eq!(summaries[0].status, RootSpanStatus::Error);
+        assert!(full.dropped() >= 1);
+    }
+
+    #[test]
+    fn span_token() {
+        extern "C" fn callback(userdata: *mut std::os::raw::c_void) {
+            let span = Span::from_raw(userdata.cast()).unwrap();
+            let _g = span.enter();
+            let _l = LocalSpan::enter("callback");
+        }
+
+        let (root_span, collector) = Span::root("root");
+        let token = Span::from_parent("ffi", &root_span).into_raw();
+        let thread_token = token as usize;
+        std::thread::spawn(move || callback(thread_token as *mut _))
+            .join()
+            .unwrap();
+
+        // Taking it back twice doesn't free it twice
+        assert!(Span::from_raw(token).is_none());
+        assert!(Span::from_raw(12345 as *mut SpanToken).is_none());
+        assert!(Span::empty().into_raw().is_null());
+        assert!(Span::from_raw(std::ptr::null_mut()).unwrap().is_empty());
+
+        drop(root_span);
+        let mut events: Vec<_> = collector.collect().iter().map(|s| s.event).collect();
+        events.sort_unstable();
+        assert_eq!(events, vec!["callback", "ffi", "root"]);
+    }
+
+    #[test]
+    fn aggregate_late_spans() {
+        let _lock = CONFIG_LOCK.lock().unwrap();
+        set_config(Config::default().aggregate_late_spans(true));
+
+        let (root_span, collector) = Span::root("root");
+        let late_span = Span::from_parent("late span", &root_span);
+        drop(root_span);
+        assert_eq!(collector.collect().len(), 1);
+        drop(late_span);
+
+        set_config(Config::default());
+        let histogram = late_span_histograms()
+            .into_iter()
+            .find(|h| h.event == "late span")
+            .unwrap();
+        assert_eq!(histogram.count, 1);
+        assert_eq!(histogram.buckets.iter().map(|(_, c)| c).sum::<u64>(), 1);
+    }
+
+    #[test]
+    fn manual_span() {
+        use std::time::{Duration, SystemTime};
+
+        let (root_span, collector) = Span::root("root");
+        let inside = root_span.child_manual("inside");
+        let skewed = root_span.child_manual("skewed");
+        let now = SystemTime::now();
+        inside.finish(now, now);
+        skewed.finish(now - Duration::from_secs(10), now + Duration::from_secs(10));
+        drop(root_span);
+
+        let spans = collector.collect_with_args(CollectArgs::default().sync(true));
+        assert_eq!(spans.len(), 3);
+        let root = spans.iter().find(|s| s.event == "root").unwrap();
+        let skewed = spans.iter().find(|s| s.event == "skewed").unwrap();
+        assert_eq!(skewed.parent_id, root.id);
+        assert_eq!(skewed.begin_unix_time_ns, root.begin_unix_time_ns);
+        assert_eq!(skewed.duration_ns, root.duration_ns);
+    }
+
+    #[test]
+    fn clock_anomaly() {
+        use std::time::{Duration, SystemTime};
+
+        let anomalies = span::clock_anomalies();
+        let (root_span, collector) = Span::root("root");
+        let now = SystemTime::now();
+        root_span
+            .child_manual("inverted")
+            .finish(now, now - Duration::from_secs(1));
+        drop(root_span);
+
+        let spans = collector.collect_with_args(CollectArgs::default().sync(true));
+        let inverted = spans.iter().find(|s| s.event == "inverted").unwrap();
+        assert_eq!(inverted.duration_ns, 0);
+        assert!(inverted
+            .properties
+            .contains(&(semconv::CLOCK_ANOMALY, "true".into())));
+        assert!(span::clock_anomalies() > anomalies);
+    }
+
+    #[test]
+    fn pipelined_span() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let (root_span, collector) = Span::root("root");
+        let mut span = Span::from_parent("write", &root_span);
+        assert_send_sync(&span);
+
+        // Proposed on this thread, applied on another and acknowledged on a third one
+        let span = std::thread::spawn(move || {
+            span.add_property(|| ("applied_index", "42".to_owned()));
+            span
+        })
+        .join()
+        .unwrap();
+        std::thread::spawn(move || {
+            let mut span = span;
+            span.add_typed_property("acked", true);
+            span.finish();
+        })
+        .join()
+        .unwrap();
+        drop(root_span);
+
+        let spans = collector.collect_with_args(CollectArgs::default().sync(true));
+        let write = spans.iter().find(|s| s.event == "write").unwrap();
+        assert_eq!(
+            write.properties,
+            vec![
+                ("applied_index", "42".into()),
+                ("acked", PropertyValue::Bool(true))
+            ]
+        );
+    }
+
+    #[test]
+    fn root_with_trace_id() {
+        let (root_span, collector) = Span::root_with_trace_id("root", 42);
+        assert_eq!(collector.trace_id(), 42);
+        assert_eq!(root_span.trace_id(), Some(42));
+
+        let child_span = Span::from_parent("child", &root_span);
+        assert_eq!(child_span.trace_id(), Some(42));
+
+        let (_, collector1) = Span::root("root1");
+        let (_, collector2) = Span::root("root2");
+        assert_ne!(collector1.trace_id(), collector2.trace_id());
+    }
+
+    #[test]
+    fn live_references() {
+        let (root_span, collector) = Span::root("root");
+        assert_eq!(collector.live_references(), 1);
+
+        let child_span = Span::from_parent("child", &root_span);
+        assert_eq!(collector.live_references(), 2);
+        assert_eq!(child_span.references(), vec![(collector.trace_id(), 2)]);
+        assert!(child_span.dump().contains("\"child\""));
+
+        drop(root_span);
+        drop(child_span);
+        assert_eq!(collector.live_references(), 0);
+    }
+
+    #[test]
+    fn cancel_collector() {
+        let (root_span, collector) = Span::root("root");
+        let handle = collector.handle();
+
+        let child_span = Span::from_parent("child", &root_span);
+        drop(child_span);
+
+        std::thread::spawn(move || handle.cancel());
+
+        // would wait forever for the root span without the cancellation
+        let spans = collector.collect_with_args(CollectArgs::default().sync(true));
+        assert_eq!(spans.len(), 2);
+        let child = spans.iter().find(|s| s.event == "child").unwrap();
+        assert!(child.properties.is_empty());
+
+        // The root span kept alive is reported as running until the cancellation, once
+        let root = spans.iter().find(|s| s.event == "root").unwrap();
+        assert_eq!(child.parent_id, root.id);
+        assert_eq!(root.properties, vec![("cancelled", "true".into())]);
+
+        drop(root_span);
+    }
+
+    #[test]
+    fn cancel_future() {
+        let spans = {
+            let (root_span, collector) = Span::root("root");
+
+            let mut fut = Box::pin(futures::future::pending::<()>().in_span(root_span));
+            let waker = futures::task::noop_waker();
+            let mut cx = std::task::Context::from_waker(&waker);
+            assert!(fut.as_mut().poll(&mut cx).is_pending());
+            drop(fut);
+
+            collector
+        }
+        .collect_with_args(CollectArgs::default().sync(true));
+
+        assert_eq!(spans.len(), 1);
+        assert!(spans[0].properties.contains(&("cancelled", "true".into())));
+    }
+
+    #[test]
+    fn record_forced_yields() {
+        let spans = {
+            let (root_span, collector) = Span::root("root");
+
+            let mut yielded = false;
+            let fut = futures::future::poll_fn(move |cx| {
+                if yielded {
+                    std::task::Poll::Ready(())
+                } else {
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    std::task::Poll::Pending
+                }
+            });
+            futures::executor::block_on(fut.record_forced_yields().in_span(root_span));
+
+            collector
+        }
+        .collect_with_args(CollectArgs::default().sync(true));
+
+        assert_eq!(spans.len(), 2);
+        assert!(spans.iter().any(|s| s.event == "forced yield"));
+
+        // The waker handed to the future outlives the poll, waking the task polling it last
+        struct Flag(std::sync::atomic::AtomicBool);
+        impl std::task::Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+        let woken = Arc::new(Flag(Default::default()));
+        let wake_later = Arc::new(Mutex::new(None));
+        let fut = {
+            let wake_later = wake_later.clone();
+            futures::future::poll_fn(move |cx| {
+                *wake_later.lock().unwrap() = Some(cx.waker().clone());
+                std::task::Poll::<()>::Pending
+            })
+        };
+        let mut fut = Box::pin(fut.record_forced_yields());
+        let waker = std::task::Waker::from(woken.clone());
+        assert!(fut
+            .as_mut()
+            .poll(&mut std::task::Context::from_waker(&waker))
+            .is_pending());
+        wake_later.lock().unwrap().take().unwrap().wake();
+        assert!(woken.0.load(std::sync::atomic::Ordering::Relaxed));
+    }
+
+    #[test]
+    fn record_forced_yields_cross_thread_wake() {
+        let spans = {
+            let (root_span, collector) = Span::root("root");
+
+            // Woken up during the poll by another thread, e.g. completing an I/O
+            let mut woken = false;
+            let fut = futures::future::poll_fn(move |cx| {
+                if woken {
+                    std::task::Poll::Ready(())
+                } else {
+                    woken = true;
+                    let waker = cx.waker().clone();
+                    std::thread::spawn(move || waker.wake()).join().unwrap();
+                    std::task::Poll::Pending
+                }
+            });
+            futures::executor::block_on(fut.record_forced_yields().in_span(root_span));
+
+            collector
+        }
+        .collect_with_args(CollectArgs::default().sync(true));
+
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].event, "root");
+    }
+
+    #[test]
+    fn collect_local_spans() {
+        let mut polls = 0;
+        let fut = futures::future::poll_fn(move |_| {
+            let _g = LocalSpan::enter("poll");
+            polls += 1;
+            if polls == 2 {
+                std::task::Poll::Ready(polls)
+            } else {
+                std::task::Poll::Pending
+            }
+        });
+        let mut fut = Box::pin(fut.collect_local_spans());
+
+        // The first poll on this thread, the second on another one
+        let waker = futures::task::noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+        let (polls, local_spans) = std::thread::spawn(move || futures::executor::block_on(fut))
+            .join()
+            .unwrap();
+
+        assert_eq!(polls, 2);
+        assert_eq!(local_spans.spans.len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "inventory")]
+    fn inventory() {
+        #[trace("inventoried")]
+        fn inventoried() {}
+        inventoried();
+
+        let event = events().iter().find(|e| e.name == "inventoried").unwrap();
+        assert_eq!(event.module, module_path!());
+        assert!(event.file.ends_with("lib.rs"));
+    }
+
+    #[test]
+    #[cfg(not(feature = "inventory"))]
+    fn inventory_disabled() {
+        #[trace("inventoried")]
+        fn inventoried() {}
+        inventoried();
+
+        assert!(events().is_empty());
+        assert!(inventory::duplicate_events().is_empty());
+    }
+
+    #[test]
+    fn await_spans() {
+        use minitrace_macro::trace_async;
+
+        async fn fetch() -> u32 {
+            1
+        }
+
+        #[trace_async("handle", await_spans)]
+        async fn handle() -> u32 {
+            fetch().await + async { 2 }.await
+        }
+
+        #[trace_async("explicit")]
+        async fn explicit() -> u32 {
+            let loaded = #[trace_await("load")]
+            fetch().await;
+            loaded + fetch().await
+        }
+
+        let (root_span, collector) = Span::root("root");
+        let res = futures::executor::block_on(
+            async { handle().await + explicit().await }.in_span(root_span),
+        );
+        assert_eq!(res, 5);
+
+        let spans = collector.collect_with_args(CollectArgs::default().sync(true));
+        let mut events: Vec<_> = spans.iter().map(|s| s.event).collect();
+        events.sort_unstable();
+        assert_eq!(
+            events,
+            vec!["await", "explicit", "fetch", "handle", "load", "root"]
+        );
+    }
+
+    #[test]
+    fn forest() {
+        let collector = Collector::forest();
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let root_span = collector.start_root("chunk");
+                std::thread::spawn(move || {
+                    let _g = root_span.enter();
+                    let _l = LocalSpan::enter("upload");
+                    let _s = Span::from_local_parent("verify");
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let traces = collector.collect_forest(CollectArgs::default().sync(true));
+        assert_eq!(traces.len(), 3);
+        for trace in &traces {
+            assert_eq!(trace.root.event, "chunk");
+            let mut events: Vec<_> = trace.spans.iter().map(|s| s.event).collect();
+            events.sort_unstable();
+            assert_eq!(events, vec!["upload", "verify"]);
+        }
+    }
+
+    #[test]
+    fn active_tasks() {
+        use futures::task::noop_waker_ref;
+        use std::task::{Context, Poll};
+
+        let _enabled = ActiveTracesEnabled::new();
+
+        let (root_span, collector) = Span::root("dumped root");
+        let trace_id = collector.trace_id();
+        let mut pending = true;
+        let task = futures::future::poll_fn(move |_| {
+            if std::mem::take(&mut pending) {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+        .in_local_span("rpc")
+        .in_local_span("handle")
+        .in_span(root_span);
+        let mut task = Box::pin(task);
+        let find = || {
+            crate::active_tasks()
+                .into_iter()
+                .find(|t| t.trace_id == trace_id)
+        };
+
+        assert!(find().unwrap().tasks[0].idle().is_none());
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(task.as_mut().poll(&mut cx).is_pending());
+
+        let trace_tasks = find().unwrap();
+        assert_eq!(trace_tasks.trace.unwrap().event, "dumped root");
+        let active_task = &trace_tasks.tasks[0];
+        assert_eq!(active_task.event, "dumped root");
+        assert!(active_task.idle().is_some());
+        assert_eq!(active_task.stack, vec!["handle", "rpc"]);
+        assert!(debug::dump_tasks().contains("\"dumped root\""));
+
+        assert!(task.as_mut().poll(&mut cx).is_ready());
+        assert!(find().is_none());
+    }
+
+    // Enables the registry of active traces, which is disabled again once dropped
+    struct ActiveTracesEnabled {
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl ActiveTracesEnabled {
+        fn new() -> Self {
+            let lock = CONFIG_LOCK.lock().unwrap();
+            set_active_traces_enabled(true);
+            ActiveTracesEnabled { _lock: lock }
+        }
+    }
+
+    impl Drop for ActiveTracesEnabled {
+        fn drop(&mut self) {
+            set_active_traces_enabled(false);
+        }
+    }
+
+    #[test]
+    fn active_traces() {
+        let _enabled = ActiveTracesEnabled::new();
+
+        let (root_span, collector) = Span::root("active root");
+        let trace_id = collector.trace_id();
+        let find = || {
+            crate::active_traces()
+                .into_iter()
+                .find(|t| t.trace_id == trace_id)
+        };
+
+        let active_trace = find().unwrap();
+        assert_eq!(active_trace.event, "active root");
+        assert_eq!(active_trace.live_references, 1);
+
+        drop(root_span);
+        assert_eq!(find().unwrap().live_references, 0);
+
+        collector.collect();
+        assert!(find().is_none());
+    }
+
+    #[test]
+    fn static_property() {
+        let local_spans = {
+            let local_collector = LocalCollector::start();
+            let _g = LocalSpan::enter("span")
+                .with_static_property("k1", "v1")
+                .with_property(|| ("k2", "v2".to_owned()));
+            drop(_g);
+            local_collector.collect()
+        };
+
+        let properties: Vec<_> = local_spans.spans[0]
+            .properties
+            .iter()
+            .map(|(k, v)| (*k, v.as_str().unwrap()))
+            .collect();
+        assert_eq!(properties, vec![("k1", "v1"), ("k2", "v2")]);
+
+        let local_spans = {
+            let local_collector = LocalCollector::start();
+            drop(LocalSpan::enter_with_static_property("span", "k1", "v1"));
+            local_collector.collect()
+        };
+        assert_eq!(local_spans.spans[0].properties.len(), 1);
+        assert_eq!(local_spans.spans[0].properties[0].0, "k1");
+        assert_eq!(local_spans.spans[0].properties[0].1.as_str(), Some("v1"));
+    }
+
+    // Timing dependent, run by CI in release with `--ignored`
+    #[test]
+    #[ignore]
+    fn static_property_budget() {
+        const SPANS: u32 = 10_000;
+
+        fn time(f: impl Fn()) -> Duration {
+            (0..20)
+                .map(|_| {
+                    let local_collector = LocalCollector::start();
+                    let now = std::time::Instant::now();
+                    for _ in 0..SPANS {
+                        f();
+                    }
+                    let elapsed = now.elapsed();
+                    drop(local_collector.collect());
+                    elapsed
+                })
+                .min()
+                .unwrap()
+        }
+
+        let none = time(|| drop(LocalSpan::enter("span")));
+        let with_static = time(|| drop(LocalSpan::enter_with_static_property("span", "k", "v")));
+        let cost = with_static.saturating_sub(none) / SPANS;
+        assert!(cost < Duration::from_nanos(50), "{:?} per property", cost);
+    }
+
+    #[test]
+    fn binary_property() {
+        let key: Arc<[u8]> = Arc::from(&b"\x00\xffkey"[..]);
+
+        let spans = {
+            let (root_span, collector) = Span::root("root");
+            let root_span = root_span.with_binary_property("start", key.clone());
+            let _g = root_span.enter();
+            let _l = LocalSpan::enter("local").with_binary_property("end", key.clone());
+            collector
+        }
+        .collect_with_args(CollectArgs::default().sync(true));
+
+        assert_eq!(spans.len(), 2);
+        for span in &spans {
+            let bytes = span
+                .properties
+                .iter()
+                .find_map(|(_, v)| match v {
+                    PropertyValue::Binary(bytes) => Some(bytes),
+                    _ => None,
+                })
+                .unwrap();
+            assert!(Arc::ptr_eq(bytes, &key));
+        }
+        assert_eq!(PropertyValue::from(key).to_text(), "00ff6b6579");
+    }
+
+    #[test]
+    fn typed_property() {
+        // No snapshot is captured on the root span meanwhile
+        let _lock = CONFIG_LOCK.lock().unwrap();
+        let spans = {
+            let (root_span, collector) = Span::root("root");
+            let root_span = root_span.with_typed_property("retries", 3);
+            let now = std::time::SystemTime::now();
+            root_span
+                .child_manual("dma")
+                .with_typed_property("queue", 2_u32)
+                .finish(now, now);
+            let _g = root_span.enter();
+            let _l = LocalSpan::enter("local")
+                .with_typed_property("ratio", 0.5)
+                .with_typed_property("hit", true);
+            collector
+        }
+        .collect_with_args(CollectArgs::default().sync(true));
+
+        let root = spans.iter().find(|s| s.event == "root").unwrap();
+        assert_eq!(root.properties, vec![("retries", PropertyValue::I64(3))]);
+        let local = spans.iter().find(|s| s.event == "local").unwrap();
+        assert_eq!(
+            local.properties,
+            vec![
+                ("ratio", PropertyValue::F64(0.5)),
+                ("hit", PropertyValue::Bool(true))
+            ]
+        );
+        assert_eq!(local.properties[0].1.to_text(), "0.5");
+        let dma = spans.iter().find(|s| s.event == "dma").unwrap();
+        assert_eq!(dma.properties, vec![("queue", PropertyValue::I64(2))]);
+    }
+
+    #[test]
+    fn typed_property_32_bit() {
+        let _lock = CONFIG_LOCK.lock().unwrap();
+        let spans = {
+            let (root_span, collector) = Span::root("root");
+            let root_span = root_span
+                .with_typed_property("min", i32::MIN)
+                .with_typed_property("max", u32::MAX);
+            let now = std::time::SystemTime::now();
+            root_span
+                .child_manual("dma")
+                .with_typed_property("offset", -4_i32)
+                .with_typed_property("len", 4096_u32)
+                .with_typed_property("ratio", 0.25)
+                .with_typed_property("hit", false)
+                .with_property(|| ("device", "nvme0".to_owned()))
+                .finish(now, now);
+            let _g = root_span.enter();
+            let _l = LocalSpan::enter("local")
+                .with_typed_property("delta", -1_i32)
+                .with_typed_property("count", 7_u32);
+            collector
+        }
+        .collect_with_args(CollectArgs::default().sync(true));
+
+        let root = spans.iter().find(|s| s.event == "root").unwrap();
+        assert_eq!(
+            root.properties,
+            vec![
+                ("min", PropertyValue::I64(i32::MIN as i64)),
+                ("max", PropertyValue::I64(u32::MAX as i64))
+            ]
+        );
+        let dma = spans.iter().find(|s| s.event == "dma").unwrap();
+        assert_eq!(
+            dma.properties,
+            vec![
+                ("offset", PropertyValue::I64(-4)),
+                ("len", PropertyValue::I64(4096)),
+                ("ratio", PropertyValue::F64(0.25)),
+                ("hit", PropertyValue::Bool(false)),
+                ("device", PropertyValue::from("nvme0".to_owned()))
+            ]
+        );
+        let local = spans.iter().find(|s| s.event == "local").unwrap();
+        assert_eq!(
+            local.properties,
+            vec![
+                ("delta", PropertyValue::I64(-1)),
+                ("count", PropertyValue::I64(7))
+            ]
+        );
+    }
+
+    // Registers a snapshot, which is unregistered once dropped. The other tests whose root spans
+    // must not capture it hold `CONFIG_LOCK` too.
+    struct SnapshotRegistered {
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl SnapshotRegistered {
+        fn new(name: &'static str, capture: fn() -> String) -> Self {
+            let lock = CONFIG_LOCK.lock().unwrap();
+            register_snapshot(name, capture);
+            SnapshotRegistered { _lock: lock }
+        }
+    }
+
+    impl Drop for SnapshotRegistered {
+        fn drop(&mut self) {
+            clear_snapshots();
+        }
+    }
+
+    #[test]
+    fn snapshot() {
+        let _registered = SnapshotRegistered::new("snapshot_test", || "captured".to_owned());
+
+        let spans = {
+            let (_root_span, collector) = Span::root("root");
+            collector
+        }
+        .collect_with_args(CollectArgs::default().sync(true));
+
+        assert!(spans[0]
+            .properties
+            .contains(&("snapshot_test", "captured".into())));
+    }
+
+    #[test]
+    fn reuse_collector_channel() {
+        let mut straggler = None;
+        for i in 0..4 {
+            let (root_span, collector) = Span::root("root");
+            let child_span = Span::from_parent("child", &root_span);
+            if i == 1 {
+                // keeps the channel of this trace out of the pool
+                straggler = Some(Span::from_parent("straggler", &root_span));
+            }
+            drop(child_span);
+            drop(root_span);
+
+            let spans = collector.collect_with_args(CollectArgs::default().sync(i != 1));
+            assert_eq!(spans.len(), 2);
+        }
+        drop(straggler);
+    }
+
+    #[test]
+    fn trace_root() {
+        use crate::trace::collector::Collector;
+        use minitrace_macro::trace_async;
+
+        lazy_static! {
+            static ref SPANS: Mutex<Vec<span::Span>> = Mutex::new(Vec::new());
+        }
+
+        fn report(collector: Collector) {
+            SPANS.lock().unwrap().extend(collector.collect());
+        }
+
+        #[trace(root, collector = report)]
+        fn handle(early: bool) -> u32 {
+            four_spans();
+            if early {
+                return 1;
+            }
+            2
+        }
+
+        #[trace_async("handle async", root, collector = report)]
+        async fn handle_async() {
+            four_spans();
+        }
+
+        assert_eq!(handle(true), 1);
+        assert_eq!(handle(false), 2);
+        futures::executor::block_on(handle_async());
+
+        let spans = SPANS.lock().unwrap();
+        assert_eq!(spans.len(), 15);
+        assert_eq!(spans.iter().filter(|s| s.event == "handle").count(), 2);
+        assert_eq!(
+            spans.iter().filter(|s| s.event == "handle async").count(),
+            1
+        );
+    }
+
+    #[test]
+    fn naming_policy() {
+        use minitrace_macro::trace_async;
+
+        #[trace("ReadIndex")]
+        fn read_index() {}
+
+        #[trace_async("ApplyEntries")]
+        async fn apply_entries() {}
+
+        // Renames only the events above, since tests running meanwhile keep their names
+        let policy = NamingPolicy::new()
+            .custom(|_, event| {
+                if event == "ReadIndex" || event == "ApplyEntries" {
+                    Some(format!("raft {}", event))
+                } else {
+                    None
+                }
+            })
+            .snake_case(true);
+        let _lock = CONFIG_LOCK.lock().unwrap();
+        set_config(Config::default().naming_policy(policy));
+        let (root_span, collector) = Span::root("root");
+        {
+            let _g = root_span.enter();
+            read_index();
+            futures::executor::block_on(apply_entries());
+        }
+        drop(root_span);
+        set_config(Config::default());
+
+        let mut events: Vec<_> = collector.collect().iter().map(|s| s.event).collect();
+        events.sort_unstable();
+        assert_eq!(
+            events,
+            vec!["raft_apply_entries", "raft_read_index", "root"]
+        );
+    }
+
+    #[test]
+    fn root_with_remote_parent() {
+        let context = crate::propagation::SpanContext::new(42, 7);
+        let (root_span, collector) = Span::root_with_context("rpc", context);
+        {
+            let _g = root_span.enter();
+            let _l = LocalSpan::enter("get");
+        }
+        drop(root_span);
+        assert_eq!(collector.trace_id(), 42);
+        assert_eq!(collector.remote_parent_span_id(), Some(7));
+
+        // The remote parent is carried out of band, the spans keep pointing within the trace
+        let spans = collector.collect();
+        let root = spans.iter().find(|s| s.event == "rpc").unwrap();
+        let get = spans.iter().find(|s| s.event == "get").unwrap();
+        assert_eq!(root.parent_id, 0);
+        assert_eq!(get.parent_id, root.id);
+        assert_eq!(validate(&spans), vec![]);
+
+        let (_, collector) = Span::root("rpc");
+        assert_eq!(collector.remote_parent_span_id(), None);
+    }
+
+    #[test]
+    fn span_handle() {
+        let (root_span, collector) = Span::root("root");
+        let handle = root_span.handle();
+        let child = Span::from_handle("child", &handle.clone());
+        drop(root_span);
+        assert!(!Span::from_handle("late", &handle).is_empty());
+
+        drop(child);
+        let spans = collector.collect_with_args(CollectArgs::default().sync(true));
+        assert_eq!(spans.len(), 3);
+        let root = spans.iter().find(|s| s.event == "root").unwrap();
+        let child = spans.iter().find(|s| s.event == "child").unwrap();
+        assert_eq!(child.parent_id, root.id);
+
+        // The trace is complete
+        assert!(Span::from_handle("after", &handle).is_empty());
+        assert!(SpanHandle::default().is_empty());
+        assert!(Span::empty().handle().is_empty());
+    }
+
+    #[test]
+    fn closed_collector() {
+        let (root_span, collector) = Span::root("root");
+        let other = Span::from_parent("other", &root_span);
+        {
+            let _g = root_span.enter();
+            assert!(!Span::from_local_parent("child").is_empty());
+            drop(collector.collect());
+
+            // The closed acquirers are dropped by the first span after the close
+            assert!(Span::from_local_parent("late").is_empty());
+            assert!(debug::dump_current_thread().contains("traces=[]"));
+            for _ in 0..1000 {
+                assert!(Span::from_local_parent("late").is_empty());
+                let _l = LocalSpan::enter("late");
+            }
+        }
+
+        // Entering a span of a collected trace attaches nothing, so local spans cost nothing
+        let _g = other.enter();
+        assert!(debug::dump_current_thread().contains("attached spans: 0"));
+        assert!(LocalCollector::try_start().is_some());
+        assert!(Span::from_local_parent("late").is_empty());
+    }
+
+    #[test]
+    fn deadline() {
+        let (root_span, collector) = Span::root_with_deadline("root", Duration::from_millis(10));
+        let running = Span::from_parent("running", &root_span);
+        drop(Span::from_parent("done", &root_span));
+        std::thread::sleep(Duration::from_millis(20));
+
+        // The first span submitted past the deadline finishes the outstanding spans
+        drop(Span::from_parent("late", &root_span));
+        assert!(Span::from_parent("closed", &root_span).is_empty());
+        drop((root_span, running));
+
+        let spans = collector.collect();
+        let mut events: Vec<_> = spans.iter().map(|s| s.event).collect();
+        events.sort_unstable();
+        assert_eq!(events, vec!["done", "late", "root", "running"]);
+        let exceeded = (semconv::DEADLINE_EXCEEDED, PropertyValue::from("true"));
+        for span in &spans {
+            let finished_at_deadline = span.event == "root" || span.event == "running";
+            assert_eq!(span.properties.contains(&exceeded), finished_at_deadline);
+        }
+    }
+
+    #[test]
+    fn live_histograms() {
+        use crate::span::TestClock;
+
+        let _lock = CONFIG_LOCK.lock().unwrap();
+        set_config(Config::default().live_histograms(true));
+
+        let clock = TestClock::new(1);
+        clock.attach();
+        let (root_span, collector) = Span::root("live-root");
+        let worker = {
+            let span = Span::from_parent("live-worker", &root_span);
+            std::thread::spawn(move || {
+                // Exits before the histograms are read
+                let clock = TestClock::new(1);
+                clock.attach();
+                // Ends before the clock is dropped
+                let span = span;
+                let _g = span.enter();
+                for _ in 0..2 {
+                    let _l = LocalSpan::enter("live-get");
+                    clock.advance(1000);
+                }
+            })
+        };
+        {
+            let _g = root_span.enter();
+            for _ in 0..2 {
+                let _l = LocalSpan::enter("live-get");
+                clock.advance(10);
+            }
+        }
+        worker.join().unwrap();
+        drop(root_span);
+        // Not collected
+        drop(collector);
+        set_config(Config::default());
+
+        let get = stats::histogram("live-get").unwrap();
+        assert_eq!(get.count(), 4);
+        assert_eq!(get.min(), Duration::from_nanos(10));
+        assert_eq!(get.max(), Duration::from_nanos(1000));
+        assert_eq!(get.sum(), Duration::from_nanos(2020));
+        assert_eq!(get.quantile(0.5), Duration::from_nanos(10));
+        assert_eq!(get.quantile(0.99), Duration::from_nanos(1000));
+        assert_eq!(
+            stats::histogram("live-worker").unwrap().max(),
+            Duration::from_nanos(2000)
+        );
+        assert!(stats::events().contains(&"live-root"));
+        assert!(stats::histogram("live-put").is_none());
+    }
+
+    #[test]
+    fn target_filter() {
+        #[trace("apply", target = "raftstore::apply")]
+        fn apply() {}
+
+        #[trace("store", target = "raftstore::store")]
+        fn store() {
+            apply();
+        }
+
+        #[trace_async("copr", target = "coprocessor")]
+        async fn copr() {}
+
+        let run = || {
+            let (root_span, collector) = Span::root("root");
+            {
+                let _g = root_span.enter();
+                store();
+                futures::executor::block_on(copr());
+            }
+            drop(root_span);
+            collector.collect_with_args(CollectArgs::default().sync(true))
+        };
+        let events = |spans: &[span::Span]| {
+            let mut events: Vec<_> = spans.iter().map(|s| s.event).collect();
+            events.sort_unstable();
+            events
+        };
+
+        let _lock = CONFIG_LOCK.lock().unwrap();
+        let spans = run();
+        assert_eq!(events(&spans), vec!["apply", "copr", "root", "store"]);
+        let store = spans.iter().find(|s| s.event == "store").unwrap();
+        assert_eq!(
+            store.properties,
+            vec![(semconv::TARGET, "raftstore::store".into())]
+        );
+
+        set_config(
+            Config::default().target_filter(
+                TargetFilter::none()
+                    .enable("raftstore")
+                    .disable("raftstore::apply"),
+            ),
+        );
+        let spans = run();
+        set_config(Config::default());
+        assert_eq!(events(&spans), vec!["root", "store"]);
+
+        assert_eq!(events(&run()), vec!["apply", "copr", "root", "store"]);
+    }
+
+    #[test]
+    fn nested_enter() {
+        // A callback framework running a traced callback within a traced request
+        fn callback(collector_of_callback: &mut Option<Collector>) {
+            let (span, collector) = Span::root("callback");
+            *collector_of_callback = Some(collector);
+            let _g = span.enter();
+            let _child = Span::from_local_parent("callback child");
+        }
+
+        fn request(nested_enter: NestedEnter) -> (Vec<span::Span>, Vec<span::Span>) {
+            let _lock = CONFIG_LOCK.lock().unwrap();
+            set_config(Config::default().nested_enter(nested_enter));
+            let mut collector_of_callback = None;
+            let collector = {
+                let (span, collector) = Span::root("request");
+                let _g = span.enter();
+                callback(&mut collector_of_callback);
+                let _child = Span::from_local_parent("request child");
+                collector
+            };
+            set_config(Config::default());
+            (
+                collector.collect(),
+                collector_of_callback.unwrap().collect(),
+            )
+        }
+
+        let err = {
+            let (span, _collector) = Span::root("outer");
+            let _g = span.enter();
+            let (inner, _inner_collector) = Span::root("inner");
+            inner.try_enter().err().unwrap()
+        };
+        assert_eq!(err.event, "inner");
+        assert_eq!(err.attached_event, "outer");
+
+        let events = |spans: &[span::Span]| {
+            let mut events: Vec<_> = spans.iter().map(|s| s.event).collect();
+            events.sort_unstable();
+            events
+        };
+
+        let (request_spans, callback_spans) = request(NestedEnter::Error);
+        assert_eq!(
+            events(&request_spans),
+            vec!["callback child", "request", "request child"]
+        );
+        assert_eq!(events(&callback_spans), vec!["callback"]);
+
+        let (request_spans, callback_spans) = request(NestedEnter::Stack);
+        assert_eq!(events(&request_spans), vec!["request", "request child"]);
+        assert_eq!(events(&callback_spans), vec!["callback", "callback child"]);
+    }
+
+    #[test]
+    fn stacked_guards_dropped_out_of_order() {
+        let (txn, txn_collector) = Span::root("txn");
+        let (stmt, stmt_collector) = Span::root("stmt");
+
+        let _lock = CONFIG_LOCK.lock().unwrap();
+        set_config(Config::default().nested_enter(NestedEnter::Stack));
+        let txn_guard = txn.enter();
+        let stmt_guard = stmt.enter();
+        drop(LocalSpan::enter("stmt local"));
+        // Dropping the guard below detaches the span on top too
+        drop(txn_guard);
+        assert!(Span::from_local_parent("detached").is_empty());
+        assert!(LocalCollector::try_start().is_some());
+        drop(stmt_guard);
+        set_config(Config::default());
+        drop((txn, stmt));
+
+        assert_eq!(txn_collector.collect().len(), 1);
+        let mut events: Vec<_> = stmt_collector.collect().iter().map(|s| s.event).collect();
+        events.sort_unstable();
+        assert_eq!(events, vec!["stmt", "stmt local"]);
+    }
+
+    #[test]
+    fn stacked_spans() {
+        let (session, session_collector) = Span::root("session");
+        let (txn, txn_collector) = Span::root("txn");
+        let (stmt, stmt_collector) = Span::root("stmt");
+
+        let _lock = CONFIG_LOCK.lock().unwrap();
+        set_config(Config::default().nested_enter(NestedEnter::Stack));
+        {
+            let _session_guard = session.enter();
+            let _l = LocalSpan::enter("session local");
+            {
+                let _txn_guard = txn.enter();
+                let _l = LocalSpan::enter("txn local");
+                {
+                    let _stmt_guard = stmt.enter();
+                    let _l = LocalSpan::enter("stmt local");
+                    let _child = Span::from_local_parent("stmt child");
+                }
+                let _child = Span::from_local_parent("txn child");
+            }
+        }
+        set_config(Config::default());
+        drop((session, txn, stmt));
+
+        for (collector, level) in [
+            (session_collector, "session"),
+            (txn_collector, "txn"),
+            (stmt_collector, "stmt"),
+        ] {
+            let spans = collector.collect();
+            let root = spans.iter().find(|s| s.event == level).unwrap();
+            let local = spans
+                .iter()
+                .find(|s| s.event == format!("{} local", level))
+                .unwrap();
+            assert_eq!(local.parent_id, root.id);
+            if level == "session" {
+                assert_eq!(spans.len(), 2);
+            } else {
+                assert_eq!(spans.len(), 3);
+                let child = spans
+                    .iter()
+                    .find(|s| s.event == format!("{} child", level))
+                    .unwrap();
+                assert_eq!(child.parent_id, root.id);
+            }
+        }
+    }
+
+    #[test]
+    fn follow_up() {
+        let (span, collector1) = Span::root("job");
+        let (span, collector2) = span.follow_up("job");
+        drop(span);
+
+        let (trace_id1, trace_id2) = (collector1.trace_id(), collector2.trace_id());
+        let spans1 = collector1.collect();
+        let spans2 = collector2.collect();
+        let link = |spans: &[span::Span], key| {
+            let (_, value) = spans[0].properties.iter().find(|(k, _)| *k == key).unwrap();
+            value.as_str().and_then(semconv::parse_link).unwrap()
+        };
+        assert_eq!(
+            link(&spans1, semconv::LINK_SUCCESSOR),
+            (trace_id2, spans2[0].id)
+        );
+        assert_eq!(
+            link(&spans2, semconv::LINK_PREDECESSOR),
+            (trace_id1, spans1[0].id)
+        );
+    }
+
+    #[test]
+    fn shared_collector() {
+        let mut collector = SharedCollector::new();
+
+        let root1 = collector.start_root_with_trace_id("root1", 1);
+        let root2 = collector.start_root_with_trace_id("root2", 2);
+        let child2 = Span::from_parent("child2", &root2);
+        drop(root1);
+        drop(root2);
+
+        let traces = collector.collect_grouped();
+        assert_eq!(traces.len(), 2);
+        for (trace_id, spans) in traces {
+            assert_eq!(spans.len(), 1);
+            assert_eq!(
+                spans[0].event,
+                if trace_id == 1 { "root1" } else { "root2" }
+            );
+        }
+
+        // spans finishing after their root come with a later collection
+        drop(child2);
+        let traces = collector.collect_grouped();
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0].0, 2);
+        assert_eq!(traces[0].1[0].event, "child2");
+    }
+
+    #[test]
+    fn shared_collector_eviction() {
+        let mut collector = SharedCollector::new().max_pending(Duration::from_secs(60), 1);
+        let roots: Vec<_> = (1..=3)
+            .map(|trace_id| collector.start_root_with_trace_id("root", trace_id))
+            .collect();
+        for root in &roots {
+            drop(Span::from_parent("child", root));
+        }
+        assert!(collector.collect_grouped().is_empty());
+        assert_eq!(collector.evicted_spans(), 2);
+
+        // Kept unfinished for too long
+        let mut collector = collector.max_pending(Duration::from_millis(0), 10);
+        let root = collector.start_root_with_trace_id("root", 4);
+        drop(Span::from_parent("child", &root));
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(collector.collect_grouped().is_empty());
+        // With the child of the trace left above
+        assert_eq!(collector.evicted_spans(), 4);
+        drop(roots);
+    }
+
     #[test]
     fn single_thread_multiple_spans() {
         let (spans1, spans2, spans3) = {
@@ -156,6 +1693,61 @@ mod tests {
         assert_eq!(spans2.len(), 25);
     }
 
+    #[test]
+    fn multiple_threads_test_clock() {
+        use crate::span::TestClock;
+
+        // The main thread and two producers
+        let clock = Arc::new(TestClock::new(3));
+        clock.attach();
+        let spans = {
+            let (span, collector) = Span::root("root");
+            let _g = span.enter();
+
+            let producers: Vec<_> = (0..2)
+                .map(|_| {
+                    let child_span = Span::from_local_parent("cross-thread");
+                    let clock = clock.clone();
+                    std::thread::spawn(move || {
+                        clock.attach();
+                        let _g = child_span.enter();
+                        {
+                            let _g = LocalSpan::enter("produce");
+                            clock.advance(100);
+                        }
+                        let _g = LocalSpan::enter("flush");
+                        clock.advance(50);
+                    })
+                })
+                .collect();
+
+            clock.advance(100);
+            clock.advance(50);
+            for producer in producers {
+                producer.join().unwrap();
+            }
+
+            collector
+        }
+        .collect_with_args(CollectArgs::default().sync(true));
+
+        let timeline = |event| {
+            let mut timeline: Vec<_> = spans
+                .iter()
+                .filter(|s| s.event == event)
+                .map(|s| (s.begin_unix_time_ns, s.duration_ns))
+                .collect();
+            timeline.dedup();
+            timeline
+        };
+        assert_eq!(spans.len(), 7);
+        assert_eq!(clock.now_ns(), 150);
+        assert_eq!(timeline("root"), vec![(0, 150)]);
+        assert_eq!(timeline("cross-thread"), vec![(0, 150)]);
+        assert_eq!(timeline("produce"), vec![(0, 100)]);
+        assert_eq!(timeline("flush"), vec![(100, 50)]);
+    }
+
     #[test]
     fn multiple_spans_without_local_spans() {
         let (spans1, spans2, spans3) = {
@@ -1,16 +1,20 @@
 // Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
 
+use std::fmt;
 use std::marker::PhantomData;
 
 use crate::local::local_span_line::LOCAL_SPAN_LINE;
-use crate::span::RawSpan;
-use crate::span::{Cycle, DefaultClock};
+use crate::local::span_guard;
+use crate::span::{self, PropertyValue, RawEvent, RawSpan, Span};
+use crate::span::{Anchor, Cycle, DefaultClock};
 
 #[must_use]
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq)]
 pub struct LocalCollector {
     pub(crate) collected: bool,
     pub(crate) local_collector_epoch: usize,
+    // Returned by `start` on an occupied thread if panicking is disabled, collecting nothing
+    detached: bool,
 
     // Identical to
     // ```
@@ -22,10 +26,25 @@ pub struct LocalCollector {
     _p: PhantomData<*const ()>,
 }
 
+/// The error of starting a local collector on a thread where another one is collecting.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalCollectorError;
+
+impl fmt::Display for LocalCollectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the current thread is occupied by another local collector")
+    }
+}
+
+impl std::error::Error for LocalCollectorError {}
+
 #[derive(Debug)]
 pub struct LocalSpans {
     pub spans: Vec<RawSpan>,
     pub end_time: Cycle,
+    // The events recorded while no local span was open, which belong to the span the local
+    // spans are mounted onto
+    pub(crate) events: Vec<RawEvent>,
 }
 
 impl LocalCollector {
@@ -33,14 +52,30 @@ impl LocalCollector {
         Self {
             collected: false,
             local_collector_epoch,
+            detached: false,
             _p: Default::default(),
         }
     }
 
+    /// Start collecting the local spans of the current thread.
+    ///
+    /// If another local collector is collecting, it panics, or returns a collector collecting
+    /// nothing if [`Config::panic_on_misuse`](crate::Config::panic_on_misuse) is disabled.
     pub fn start() -> Self {
-        Self::try_start().expect("Current thread is occupied by another local collector")
+        match Self::start_checked() {
+            Ok(local_collector) => local_collector,
+            Err(err) if span_guard::panic_on_misuse() => panic!("{}", err),
+            Err(_) => Self {
+                collected: false,
+                local_collector_epoch: 0,
+                detached: true,
+                _p: Default::default(),
+            },
+        }
     }
 
+    /// Like [`start`](LocalCollector::start), but return `None` if another local collector is
+    /// collecting.
     pub fn try_start() -> Option<Self> {
         LOCAL_SPAN_LINE.with(|span_line| {
             let s = &mut *span_line.borrow_mut();
@@ -48,21 +83,116 @@ impl LocalCollector {
         })
     }
 
+    /// Like [`try_start`](LocalCollector::try_start), but return an error describing why the
+    /// local collector can't start.
+    pub fn start_checked() -> Result<Self, LocalCollectorError> {
+        Self::try_start().ok_or(LocalCollectorError)
+    }
+
+    pub(crate) fn start_nested() -> Self {
+        LOCAL_SPAN_LINE.with(|span_line| {
+            let s = &mut *span_line.borrow_mut();
+            s.register_nested_local_collector()
+        })
+    }
+
     pub fn collect(mut self) -> LocalSpans {
+        if self.detached {
+            return LocalSpans {
+                spans: Vec::new(),
+                end_time: DefaultClock::now(),
+                events: Vec::new(),
+            };
+        }
+
         LOCAL_SPAN_LINE.with(|span_line| {
             let s = &mut *span_line.borrow_mut();
             self.collected = true;
+            let (spans, events) = s.unregister_and_collect(self);
             LocalSpans {
-                spans: s.unregister_and_collect(self),
+                spans,
                 end_time: DefaultClock::now(),
+                events,
             }
         })
     }
 }
 
+impl LocalSpans {
+    /// Convert the spans into the form taken by reporters, without mounting them onto a
+    /// [`Span`](crate::Span), e.g. to profile a routine with a standalone
+    /// [`LocalCollector`](LocalCollector).
+    ///
+    /// Top-level spans take `parent_id` as their parent id; 0 means no parent. Spans still
+    /// running at collection end at [`end_time`](LocalSpans::end_time). The events recorded
+    /// while no local span was open are left out.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minitrace::span::DefaultClock;
+    /// use minitrace::{LocalCollector, LocalSpan};
+    ///
+    /// let local_collector = LocalCollector::start();
+    /// {
+    ///     let _guard = LocalSpan::enter("a");
+    ///     let _guard = LocalSpan::enter("b");
+    /// }
+    /// let spans = local_collector
+    ///     .collect()
+    ///     .into_spans(DefaultClock::anchor(), 0);
+    ///
+    /// assert_eq!(spans.len(), 2);
+    /// assert_eq!(spans[0].parent_id, 0);
+    /// assert_eq!(spans[1].parent_id, spans[0].id);
+    /// ```
+    pub fn into_spans(self, anchor: Anchor, parent_id: u32) -> Vec<Span> {
+        let end_time = self.end_time;
+        self.spans
+            .into_iter()
+            .map(|mut span| {
+                let properties = std::mem::take(&mut span.properties);
+                Self::convert(&span, end_time, anchor, parent_id, properties)
+            })
+            .collect()
+    }
+
+    #[inline]
+    pub(crate) fn convert(
+        span: &RawSpan,
+        end_time: Cycle,
+        anchor: Anchor,
+        parent_id_of_root: u32,
+        mut properties: Vec<(&'static str, PropertyValue)>,
+    ) -> Span {
+        let begin_unix_time_ns = DefaultClock::cycle_to_unix_time_ns(span.begin_cycle, anchor);
+        let end_unix_time_ns = if span.end_cycle.is_zero() {
+            DefaultClock::cycle_to_unix_time_ns(end_time, anchor)
+        } else {
+            DefaultClock::cycle_to_unix_time_ns(span.end_cycle, anchor)
+        };
+        let parent_id = if span.parent_id.0 == 0 {
+            parent_id_of_root
+        } else {
+            span.parent_id.0
+        };
+        let duration_ns =
+            span::checked_duration_ns(begin_unix_time_ns, end_unix_time_ns, &mut properties);
+        Span {
+            id: span.id.0,
+            parent_id,
+            begin_unix_time_ns,
+            duration_ns,
+            event: span.event,
+            properties,
+            events: span::convert_events(&span.events, anchor),
+        }
+    }
+}
+
 impl Drop for LocalCollector {
     fn drop(&mut self) {
-        if !self.collected {
+        if !self.collected && !self.detached {
             self.collected = true;
             LOCAL_SPAN_LINE.with(|span_line| {
                 let s = &mut *span_line.borrow_mut();
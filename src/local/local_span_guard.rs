@@ -1,12 +1,23 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::local::local_span_line::{LocalSpanHandle, LocalSpanLine, LOCAL_SPAN_LINE};
+use crate::hooks;
+use crate::local::local_span_line::{Children, LocalSpanHandle, LocalSpanLine, LOCAL_SPAN_LINE};
+use crate::span::PropertyValue;
+use crate::trace::orphan;
+use crate::Span;
 
 #[must_use]
 pub struct LocalSpanGuard {
     span_handle: Option<LocalSpanHandle>,
+    // The span reported to the orphan collector in place of the local span when there's no
+    // local collector
+    orphan: Option<Span>,
+    // The children recorded before `suppress_children` or `detail_after`, restored on drop
+    restore_children: Option<Children>,
 
     // Identical to
     // ```
@@ -21,21 +32,63 @@ pub struct LocalSpanGuard {
 impl LocalSpanGuard {
     #[inline]
     pub(crate) fn new(event: &'static str) -> Self {
-        LOCAL_SPAN_LINE.with(|span_line| {
+        Self::new_with_property(event, None)
+    }
+
+    // Record the property in the same borrow of the span line as entering the span
+    #[inline]
+    pub(crate) fn new_with_property(
+        event: &'static str,
+        mut property: Option<(&'static str, PropertyValue)>,
+    ) -> Self {
+        let (span_handle, started, orphan) = LOCAL_SPAN_LINE.with(|span_line| {
             let mut span_line = span_line.borrow_mut();
             let span_handle = span_line.enter_span(event);
-            Self {
-                span_handle,
-                _p: Default::default(),
-            }
-        })
+            let started = span_handle.as_ref().map(|span_handle| {
+                if let Some((key, value)) = property.take() {
+                    span_line.add_property_value(span_handle, key, value);
+                }
+                span_line.started(span_handle)
+            });
+            let orphan = span_handle.is_none()
+                && orphan::enabled()
+                && !span_line.local_collector_existing()
+                && !span_line.children_suppressed();
+            (span_handle, started, orphan)
+        });
+
+        // The hooks are called with the span line released, see `hooks`
+        if let Some(started) = &started {
+            hooks::span_started(started);
+        }
+        let orphan = if orphan {
+            let orphan = orphan::start_orphan(event);
+            Some(match property {
+                Some((key, value)) => orphan.with_typed_property(key, value),
+                None => orphan,
+            })
+        } else {
+            None
+        };
+
+        Self {
+            span_handle,
+            orphan,
+            restore_children: None,
+            _p: Default::default(),
+        }
     }
 
     #[inline]
     pub fn with_properties<I: IntoIterator<Item = (&'static str, String)>, F: FnOnce() -> I>(
-        self,
+        mut self,
         properties: F,
     ) -> Self {
+        if let Some(orphan) = self.orphan.take() {
+            self.orphan = Some(orphan.with_properties(properties));
+            return self;
+        }
+
         self.with_span_line(move |span_handle, span_line| {
             span_line.add_properties(span_handle, properties)
         });
@@ -43,15 +96,80 @@ impl LocalSpanGuard {
     }
 
     #[inline]
-    pub fn with_property<F: FnOnce() -> (&'static str, String)>(self, property: F) -> Self {
+    pub fn with_property<F: FnOnce() -> (&'static str, String)>(mut self, property: F) -> Self {
+        if let Some(orphan) = self.orphan.take() {
+            self.orphan = Some(orphan.with_property(property));
+            return self;
+        }
+
         self.with_span_line(move |span_handle, span_line| {
             span_line.add_property(span_handle, property);
         });
         self
     }
+
+    /// Add a property whose value is known at compile time. Unlike
+    /// [`with_property`](LocalSpanGuard::with_property), it doesn't allocate. Prefer
+    /// [`LocalSpan::enter_with_static_property`](crate::LocalSpan::enter_with_static_property)
+    /// on hot paths, which records it for less than 50ns.
+    #[inline]
+    pub fn with_static_property(self, key: &'static str, value: &'static str) -> Self {
+        self.with_typed_property(key, value)
+    }
+
+    /// Add a property holding bytes, which are shared instead of copied until a reporter
+    /// serializes them.
+    #[inline]
+    pub fn with_binary_property(self, key: &'static str, value: impl Into<Arc<[u8]>>) -> Self {
+        self.with_typed_property(key, PropertyValue::Binary(value.into()))
+    }
+
+    /// Add a property keeping the type of its value, e.g. `i64`, so that reporters supporting
+    /// typed values don't report it as a string.
+    #[inline]
+    pub fn with_typed_property(
+        mut self,
+        key: &'static str,
+        value: impl Into<PropertyValue>,
+    ) -> Self {
+        if let Some(orphan) = self.orphan.take() {
+            self.orphan = Some(orphan.with_typed_property(key, value));
+            return self;
+        }
+
+        let value = value.into();
+        self.with_span_line(move |span_handle, span_line| {
+            span_line.add_property_value(span_handle, key, value);
+        });
+        self
+    }
 }
 
 impl LocalSpanGuard {
+    /// Stop recording the descendants of the span, i.e. local spans and
+    /// [`Span::from_local_parent`] spans, until the guard is dropped, e.g. for a checksum
+    /// routine whose helper spans are noise.
+    #[inline]
+    pub fn suppress_children(mut self) -> Self {
+        let children = LOCAL_SPAN_LINE
+            .with(|span_line| span_line.borrow_mut().set_children(Children::Suppressed));
+        self.restore_children.get_or_insert(children);
+        self
+    }
+
+    /// Record the descendants of the span created on the current thread, i.e. local spans and
+    /// [`Span::from_local_parent`] spans, only once the local span has run for `threshold`, until
+    /// the guard is dropped, so that the slow tail of an operation gets detailed spans without
+    /// paying for them on the fast majority. The descendants started before are not recorded.
+    #[inline]
+    pub fn detail_after(mut self, threshold: Duration) -> Self {
+        let from = Instant::now() + threshold;
+        let children =
+            LOCAL_SPAN_LINE.with(|span_line| span_line.borrow_mut().record_children_from(from));
+        self.restore_children.get_or_insert(children);
+        self
+    }
+
     #[inline]
     fn with_span_line(&self, f: impl FnOnce(&LocalSpanHandle, &mut LocalSpanLine)) {
         if let Some(local_span_handle) = &self.span_handle {
@@ -66,11 +184,16 @@ impl LocalSpanGuard {
 impl Drop for LocalSpanGuard {
     #[inline]
     fn drop(&mut self) {
+        if let Some(children) = self.restore_children {
+            LOCAL_SPAN_LINE.with(|span_line| span_line.borrow_mut().set_children(children));
+        }
+
         if let Some(span_handle) = self.span_handle.take() {
-            LOCAL_SPAN_LINE.with(|span_line| {
-                let mut span_line = span_line.borrow_mut();
-                span_line.exit_span(span_handle);
-            });
+            let ended =
+                LOCAL_SPAN_LINE.with(|span_line| span_line.borrow_mut().exit_span(span_handle));
+            if let Some(ended) = &ended {
+                hooks::span_ended(ended);
+            }
         }
     }
 }
@@ -1,13 +1,17 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
 use std::cell::RefCell;
+use std::fmt::{self, Write};
+use std::time::Instant;
 
+use crate::config::{config, Config};
+use crate::hooks::RawSpanMeta;
 use crate::local::local_collector::LocalCollector;
-use crate::span::span_queue::{SpanHandle, SpanQueue};
-use crate::span::RawSpan;
+use crate::span::span_queue::{Frame, SpanHandle, SpanQueue};
+use crate::span::{PropertyValue, RawEvent, RawSpan};
 
 thread_local! {
-    pub(super) static LOCAL_SPAN_LINE: RefCell<LocalSpanLine> = RefCell::new(LocalSpanLine::with_capacity(1024));
+    pub(super) static LOCAL_SPAN_LINE: RefCell<LocalSpanLine> = RefCell::new(LocalSpanLine::new(&config()));
 }
 
 pub struct LocalSpanLine {
@@ -15,6 +19,21 @@ pub struct LocalSpanLine {
 
     local_collector_existing: bool,
     current_local_collector_epoch: usize,
+
+    // The epochs and frames of the local collectors suspended by nested ones, innermost last
+    suspended: Vec<(usize, Frame)>,
+
+    // Set by `suppress_children` and `detail_after` of the guards
+    children: Children,
+}
+
+// Which descendants of the spans open on the thread are recorded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Children {
+    Recorded,
+    Suppressed,
+    // Recorded once the instant has passed
+    RecordedFrom(Instant),
 }
 
 pub struct LocalSpanHandle {
@@ -24,17 +43,22 @@ pub struct LocalSpanHandle {
 
 impl LocalSpanLine {
     #[inline]
-    pub fn with_capacity(capacity: usize) -> Self {
+    pub fn new(config: &Config) -> Self {
         Self {
-            span_queue: SpanQueue::with_capacity(capacity),
+            span_queue: SpanQueue::new(config),
             local_collector_existing: false,
             current_local_collector_epoch: 0,
+            suspended: Vec::new(),
+            children: Children::Recorded,
         }
     }
 
     #[inline]
     pub fn enter_span(&mut self, event: &'static str) -> Option<LocalSpanHandle> {
-        if !self.local_collector_existing {
+        if !self.local_collector_existing
+            || self.children_suppressed()
+            || !self.span_queue.admit(event)
+        {
             return None;
         }
 
@@ -44,10 +68,19 @@ impl LocalSpanLine {
         })
     }
 
+    // The span entered, for the hooks to be called once the span line is released
+    #[inline]
+    pub fn started(&self, local_span_handle: &LocalSpanHandle) -> RawSpanMeta {
+        self.span_queue.started(&local_span_handle.span_handle)
+    }
+
+    // Exit the span, returning it for the hooks to be called once the span line is released
     #[inline]
-    pub fn exit_span(&mut self, local_span_handle: LocalSpanHandle) {
+    pub fn exit_span(&mut self, local_span_handle: LocalSpanHandle) -> Option<RawSpanMeta> {
         if self.is_valid(&local_span_handle) {
-            self.span_queue.finish_span(local_span_handle.span_handle);
+            Some(self.span_queue.finish_span(local_span_handle.span_handle))
+        } else {
+            None
         }
     }
 
@@ -64,20 +97,80 @@ impl LocalSpanLine {
         Some(LocalCollector::new(self.current_local_collector_epoch))
     }
 
-    pub fn unregister_and_collect(&mut self, local_collector: LocalCollector) -> Vec<RawSpan> {
+    // Start a local collector even if one exists, which is then suspended until the new one
+    // is collected or dropped
+    #[inline]
+    pub fn register_nested_local_collector(&mut self) -> LocalCollector {
+        if self.local_collector_existing {
+            let frame = self.span_queue.push_frame();
+            self.suspended
+                .push((self.current_local_collector_epoch, frame));
+            self.current_local_collector_epoch = self.current_local_collector_epoch.wrapping_add(1);
+            LocalCollector::new(self.current_local_collector_epoch)
+        } else {
+            self.register_local_collector().unwrap()
+        }
+    }
+
+    #[inline]
+    pub fn local_collector_existing(&self) -> bool {
+        self.local_collector_existing
+    }
+
+    #[inline]
+    pub fn children_suppressed(&self) -> bool {
+        match self.children {
+            Children::Recorded => false,
+            Children::Suppressed => true,
+            Children::RecordedFrom(from) => Instant::now() < from,
+        }
+    }
+
+    // Return the previous state to be restored later
+    #[inline]
+    pub fn set_children(&mut self, children: Children) -> Children {
+        std::mem::replace(&mut self.children, children)
+    }
+
+    // Record the children only from `from` on, unless they're suppressed or deferred further
+    // already, returning the previous state to be restored later
+    #[inline]
+    pub fn record_children_from(&mut self, from: Instant) -> Children {
+        let children = match self.children {
+            Children::Suppressed => Children::Suppressed,
+            Children::RecordedFrom(later) if later > from => Children::RecordedFrom(later),
+            _ => Children::RecordedFrom(from),
+        };
+        self.set_children(children)
+    }
+
+    pub fn unregister_and_collect(
+        &mut self,
+        local_collector: LocalCollector,
+    ) -> (Vec<RawSpan>, Vec<RawEvent>) {
         debug_assert!(self.local_collector_existing);
         debug_assert_eq!(
             local_collector.local_collector_epoch,
             self.current_local_collector_epoch
         );
 
-        self.local_collector_existing = false;
-        self.span_queue.take_queue()
+        if let Some((epoch, frame)) = self.suspended.pop() {
+            self.current_local_collector_epoch = epoch;
+            self.span_queue.take_frame(frame)
+        } else {
+            self.local_collector_existing = false;
+            self.span_queue.take_queue()
+        }
     }
 
     pub fn clear(&mut self) {
-        self.local_collector_existing = false;
-        self.span_queue.clear();
+        if let Some((epoch, frame)) = self.suspended.pop() {
+            self.current_local_collector_epoch = epoch;
+            self.span_queue.discard_frame(frame);
+        } else {
+            self.local_collector_existing = false;
+            self.span_queue.clear();
+        }
     }
 
     #[inline]
@@ -97,18 +190,115 @@ impl LocalSpanLine {
         &mut self,
         local_span_handle: &LocalSpanHandle,
         property: F,
+    ) {
+        if self.is_valid(local_span_handle) {
+            let (key, value) = property();
+            self.span_queue
+                .add_property(&local_span_handle.span_handle, key, value.into());
+        }
+    }
+
+    // Add a property whose value is cheap to build, e.g. a static string
+    #[inline]
+    pub fn add_property_value(
+        &mut self,
+        local_span_handle: &LocalSpanHandle,
+        key: &'static str,
+        value: PropertyValue,
     ) {
         if self.is_valid(local_span_handle) {
             self.span_queue
-                .add_property(&local_span_handle.span_handle, property());
+                .add_property(&local_span_handle.span_handle, key, value);
         }
     }
 }
 
 impl LocalSpanLine {
+    fn dump(&self, out: &mut String) -> fmt::Result {
+        if !self.local_collector_existing {
+            return writeln!(out, "local collector: none");
+        }
+        writeln!(
+            out,
+            "local collector: active, {} suspended",
+            self.suspended.len()
+        )?;
+        writeln!(
+            out,
+            "open local spans: [{}]",
+            self.span_queue
+                .open_spans()
+                .iter()
+                .map(|event| format!("{:?}", event))
+                .collect::<Vec<_>>()
+                .join(" > ")
+        )
+    }
+
     #[inline]
     fn is_valid(&self, local_span_handle: &LocalSpanHandle) -> bool {
         self.local_collector_existing
             && local_span_handle.local_collector_epoch == self.current_local_collector_epoch
     }
 }
+
+pub(crate) fn dump_local_span_line(out: &mut String) -> fmt::Result {
+    LOCAL_SPAN_LINE.with(|span_line| match span_line.try_borrow() {
+        Ok(span_line) => span_line.dump(out),
+        Err(_) => writeln!(out, "local collector: <in use>"),
+    })
+}
+
+// Add a property to the innermost local span open on the current thread, or else to an instant
+// local span `event`. Nothing is recorded without a local collector, nor while the span line is
+// borrowed, e.g. by the caller of a property closure which logs.
+#[cfg(feature = "log")]
+pub(crate) fn add_property_to_open_span<F: FnOnce() -> (&'static str, String)>(
+    event: &'static str,
+    property: F,
+) {
+    LOCAL_SPAN_LINE.with(|span_line| {
+        let mut span_line = match span_line.try_borrow_mut() {
+            Ok(span_line)
+                if span_line.local_collector_existing && !span_line.children_suppressed() =>
+            {
+                span_line
+            }
+            _ => return,
+        };
+        let (key, value) = property();
+        match span_line.span_queue.open_span() {
+            Some(span_handle) => span_line
+                .span_queue
+                .add_property(&span_handle, key, value.into()),
+            // The hooks aren't called for the instant span, lest a hook logging loops
+            None => {
+                if let Some(span_handle) = span_line.enter_span(event) {
+                    span_line.add_property_value(&span_handle, key, value.into());
+                    span_line.exit_span(span_handle);
+                }
+            }
+        }
+    })
+}
+
+// Record an event in the innermost local span open on the current thread, or else in the span
+// the local spans are collected under
+pub(crate) fn add_event(name: &'static str) {
+    LOCAL_SPAN_LINE.with(|span_line| {
+        if let Ok(mut span_line) = span_line.try_borrow_mut() {
+            if span_line.local_collector_existing {
+                span_line.span_queue.add_event(name);
+            }
+        }
+    })
+}
+
+// Run `f` recording no span on the current thread, neither local spans nor orphans
+pub(crate) fn with_children_suppressed<R>(f: impl FnOnce() -> R) -> R {
+    let restore =
+        LOCAL_SPAN_LINE.with(|span_line| span_line.borrow_mut().set_children(Children::Suppressed));
+    let res = f();
+    LOCAL_SPAN_LINE.with(|span_line| span_line.borrow_mut().set_children(restore));
+    res
+}
@@ -4,3 +4,4 @@ pub mod local_collector;
 pub mod local_span_guard;
 pub mod local_span_line;
 pub mod span_guard;
+pub mod wire;
@@ -1,53 +1,182 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
 use std::cell::RefCell;
+use std::fmt::{self, Write};
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::config::NestedEnter;
 use crate::local::local_collector::LocalCollector;
+use crate::local::local_span_line::{Children, LOCAL_SPAN_LINE};
 use crate::span::SpanId;
-use crate::trace::acquirer::{Acquirer, SpanCollection};
+use crate::trace::acquirer::{self, Acquirer, SpanCollection};
+use crate::trace::orphan;
 use crate::Span;
 
 thread_local! {
-    static ATTACHED_SPAN: RefCell<Option<AttachedSpan>> = RefCell::new(None);
+    // The attached spans, the innermost last. More than one only with `NestedEnter::Stack`.
+    static ATTACHED_SPAN: RefCell<Vec<AttachedSpan>> = RefCell::new(Vec::new());
+}
+
+// Mirror `Config::nested_enter` and `Config::panic_on_misuse`, sparing `enter` a read of the
+// config
+static NESTED_ENTER: AtomicU8 = AtomicU8::new(NestedEnter::Panic as u8);
+static PANIC_ON_MISUSE: AtomicBool = AtomicBool::new(true);
+
+pub(crate) fn set_misuse_policy(nested_enter: NestedEnter, panic_on_misuse: bool) {
+    NESTED_ENTER.store(nested_enter as u8, Ordering::Relaxed);
+    PANIC_ON_MISUSE.store(panic_on_misuse, Ordering::Relaxed);
+}
+
+#[inline]
+fn nested_enter() -> NestedEnter {
+    match NESTED_ENTER.load(Ordering::Relaxed) {
+        x if x == NestedEnter::Stack as u8 => NestedEnter::Stack,
+        x if x == NestedEnter::Error as u8 => NestedEnter::Error,
+        _ => NestedEnter::Panic,
+    }
+}
+
+#[inline]
+pub(crate) fn panic_on_misuse() -> bool {
+    PANIC_ON_MISUSE.load(Ordering::Relaxed)
 }
 
 pub struct AttachedSpan {
     span_id: SpanId,
+    event: &'static str,
     acquirers: Vec<Acquirer>,
+    // The close generation at which the acquirers were open
+    generation: usize,
 
     local_collector: Option<LocalCollector>,
 }
 
 impl AttachedSpan {
     pub fn new_child_span(event: &'static str) -> Span {
+        if LOCAL_SPAN_LINE.with(|span_line| span_line.borrow().children_suppressed()) {
+            return Span::empty();
+        }
+
         ATTACHED_SPAN.with(|attached_span| {
+            // Drop the acquirers closed since they were found open, if any collector has closed
+            let generation = acquirer::close_generation();
+            if let Some(attached) = attached_span.borrow_mut().last_mut() {
+                if attached.generation != generation {
+                    attached.acquirers.retain(|acq| !acq.is_shutdown());
+                    attached.generation = generation;
+                }
+            }
+
             let attached_span = attached_span.borrow();
             if let Some(AttachedSpan {
                 span_id: parent_span_id,
                 acquirers,
+                generation,
                 ..
-            }) = attached_span.as_ref()
+            }) = attached_span.last()
             {
-                Span::new(acquirers.iter().map(|acq| (*parent_span_id, acq)), event)
+                Span::new_with_generation(
+                    acquirers.iter().map(|acq| (*parent_span_id, acq)),
+                    event,
+                    *generation,
+                )
+            } else if orphan::enabled() {
+                orphan::start_orphan(event)
             } else {
                 Span::empty()
             }
         })
     }
 
-    #[inline]
-    pub fn is_occupied() -> bool {
+    // The baggage of the traces of the innermost attached span, the first trace having the key
+    pub(crate) fn baggage(key: &str) -> Option<String> {
         ATTACHED_SPAN.with(|attached_span| {
             let attached_span = attached_span.borrow();
-            attached_span.is_some()
+            attached_span
+                .last()?
+                .acquirers
+                .iter()
+                .find_map(|acq| acq.baggage(key))
         })
     }
+
+    // The ids of the first trace of the innermost attached span and of the span
+    pub(crate) fn context() -> Option<(u128, SpanId)> {
+        ATTACHED_SPAN.with(|attached_span| {
+            let attached_span = attached_span.borrow();
+            let attached = attached_span.last()?;
+            let trace_id = attached.acquirers.first()?.trace_id();
+            Some((trace_id, attached.span_id))
+        })
+    }
+
+    fn attached_event() -> Option<&'static str> {
+        ATTACHED_SPAN.with(|attached_span| attached_span.borrow().last().map(|s| s.event))
+    }
 }
 
+pub(crate) fn dump_attached_spans(out: &mut String) -> fmt::Result {
+    ATTACHED_SPAN.with(|attached_span| {
+        let attached_span = match attached_span.try_borrow() {
+            Ok(attached_span) => attached_span,
+            Err(_) => return writeln!(out, "attached spans: <in use>"),
+        };
+        writeln!(out, "attached spans: {}", attached_span.len())?;
+        for (depth, span) in attached_span.iter().enumerate() {
+            writeln!(
+                out,
+                "  #{} {:?} span_id={} traces=[{}]{}",
+                depth,
+                span.event,
+                span.span_id.0,
+                span.acquirers
+                    .iter()
+                    .map(|acq| format!("{:032x}", acq.trace_id()))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                if span.local_collector.is_some() {
+                    " collecting local spans"
+                } else {
+                    ""
+                }
+            )?;
+        }
+        Ok(())
+    })
+}
+
+/// The error of entering a span on a thread which already has an attached span, unless
+/// [`NestedEnter::Stack`](crate::NestedEnter::Stack) is configured.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EnterError {
+    pub event: &'static str,
+    pub attached_event: &'static str,
+}
+
+impl fmt::Display for EnterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cannot enter span {:?} because span {:?} is attached to the current thread; \
+             drop the guard of {:?} first, or allow nested spans by `Config::nested_enter`",
+            self.event, self.attached_event, self.attached_event
+        )
+    }
+}
+
+impl std::error::Error for EnterError {}
+
 #[must_use]
 pub struct SpanGuard {
+    // The depth in the stack of attached spans of the span attached by the guard, which it
+    // detaches on drop together with those stacked on top of it
+    depth: Option<usize>,
+    // The children recorded before `suppress_children` or `detail_after`, restored on drop
+    restore_children: Option<Children>,
+
     // Identical to
     // ```
     // impl !Sync for SpanGuard {}
@@ -60,19 +189,41 @@ pub struct SpanGuard {
 
 impl Drop for SpanGuard {
     fn drop(&mut self) {
+        if let Some(children) = self.restore_children {
+            LOCAL_SPAN_LINE.with(|span_line| span_line.borrow_mut().set_children(children));
+        }
+
+        let depth = match self.depth {
+            Some(depth) => depth,
+            None => return,
+        };
+
         ATTACHED_SPAN.with(|attached_span| {
-            if let Some(AttachedSpan {
-                span_id,
-                acquirers,
-                local_collector: Some(local_collector),
-            }) = attached_span.borrow_mut().take()
-            {
-                let raw_spans = Arc::new(local_collector.collect());
-                for acq in acquirers {
-                    acq.submit(SpanCollection::LocalSpans {
-                        local_spans: raw_spans.clone(),
-                        parent_id_of_root: span_id,
-                    })
+            // The guards of stacked spans may be dropped out of order, e.g. when moved into
+            // different scopes, so the spans on top are detached first.
+            loop {
+                let popped = {
+                    let mut attached_span = attached_span.borrow_mut();
+                    if attached_span.len() <= depth {
+                        break;
+                    }
+                    attached_span.pop()
+                };
+
+                if let Some(AttachedSpan {
+                    span_id,
+                    acquirers,
+                    local_collector: Some(local_collector),
+                    ..
+                }) = popped
+                {
+                    let raw_spans = Arc::new(local_collector.collect());
+                    for acq in acquirers {
+                        acq.submit(SpanCollection::LocalSpans {
+                            local_spans: raw_spans.clone(),
+                            parent_id_of_root: span_id,
+                        })
+                    }
                 }
             }
         })
@@ -85,45 +236,107 @@ impl SpanGuard {
         span: &Span,
         local_collector: Option<LocalCollector>,
     ) -> Self {
-        ATTACHED_SPAN.with(|attached_span| {
+        let depth = ATTACHED_SPAN.with(|attached_span| {
             let mut attached_span = attached_span.borrow_mut();
 
-            if attached_span.is_some() {
-                panic!("Attach too much spans: > 1")
-            }
-
-            if let Some(inner) = &span.inner {
-                *attached_span = Some(AttachedSpan {
+            span.inner.as_ref().map(|inner| {
+                attached_span.push(AttachedSpan {
                     span_id: inner.span_id,
+                    event: span.event(),
                     acquirers: inner.to_report.iter().map(|(_, acq)| acq.clone()).collect(),
+                    generation: inner.generation,
                     local_collector,
-                })
-            }
+                });
+                attached_span.len() - 1
+            })
         });
 
         SpanGuard {
+            depth,
+            restore_children: None,
+            _p: Default::default(),
+        }
+    }
+
+    /// Stop recording the descendants of the span created on the current thread, i.e. local
+    /// spans and [`Span::from_local_parent`] spans, until the guard is dropped, e.g. for a
+    /// checksum routine whose helper spans are noise.
+    #[inline]
+    pub fn suppress_children(mut self) -> Self {
+        let children = LOCAL_SPAN_LINE
+            .with(|span_line| span_line.borrow_mut().set_children(Children::Suppressed));
+        self.restore_children.get_or_insert(children);
+        self
+    }
+
+    /// Record the descendants of the span created on the current thread, i.e. local spans and
+    /// [`Span::from_local_parent`] spans, only once the span has been attached for `threshold`,
+    /// until the guard is dropped, so that the slow tail of an operation gets detailed spans
+    /// without paying for them on the fast majority. The descendants started before are not
+    /// recorded.
+    #[inline]
+    pub fn detail_after(mut self, threshold: Duration) -> Self {
+        let from = Instant::now() + threshold;
+        let children =
+            LOCAL_SPAN_LINE.with(|span_line| span_line.borrow_mut().record_children_from(from));
+        self.restore_children.get_or_insert(children);
+        self
+    }
+
+    #[inline]
+    fn detached() -> Self {
+        SpanGuard {
+            depth: None,
+            restore_children: None,
             _p: Default::default(),
         }
     }
 }
 
 impl Span {
+    /// Attach the span to the current thread, making it the parent of
+    /// [`from_local_parent`](Span::from_local_parent) spans and collecting local spans.
+    ///
+    /// If another span is attached already, it panics, returns a guard attaching nothing or
+    /// stacks the span onto the attached one depending on
+    /// [`Config::nested_enter`](crate::Config::nested_enter). It never panics if
+    /// [`Config::panic_on_misuse`](crate::Config::panic_on_misuse) is disabled.
     #[inline]
     pub fn enter(&self) -> SpanGuard {
-        self.try_enter()
-            .expect("Current thread is occupied by another span")
+        match self.try_enter() {
+            Ok(guard) => guard,
+            Err(err) => match nested_enter() {
+                NestedEnter::Panic if panic_on_misuse() => panic!("{}", err),
+                _ => SpanGuard::detached(),
+            },
+        }
     }
 
+    /// Like [`enter`](Span::enter), but return an error instead of panicking when another span
+    /// is attached and nested spans are not stacked.
     #[inline]
-    pub fn try_enter(&self) -> Option<SpanGuard> {
-        if AttachedSpan::is_occupied() {
-            None
-        } else {
-            Some(SpanGuard::new_with_local_collector(
-                self,
-                LocalCollector::try_start(),
-            ))
+    pub fn try_enter(&self) -> Result<SpanGuard, EnterError> {
+        let attached_event = AttachedSpan::attached_event();
+        if let Some(attached_event) = attached_event {
+            if nested_enter() != NestedEnter::Stack {
+                return Err(EnterError {
+                    event: self.event(),
+                    attached_event,
+                });
+            }
         }
+
+        // Nothing would be collected, e.g. the trace has been collected already
+        if !self.is_open() {
+            return Ok(SpanGuard::detached());
+        }
+
+        let local_collector = if attached_event.is_some() {
+            Some(LocalCollector::start_nested())
+        } else {
+            LocalCollector::try_start()
+        };
+        Ok(SpanGuard::new_with_local_collector(self, local_collector))
     }
 
     #[inline]
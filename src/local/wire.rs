@@ -0,0 +1,270 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+
+use crate::local::local_collector::LocalSpans;
+use crate::span::{DefaultClock, DefaultIdGenerator, Span, SpanEvent};
+use crate::trace::storage::{
+    read_name, read_u32, read_u64, read_value, write_bytes, write_u32, write_u64, write_value,
+};
+use crate::trace::validate::{validate, Violation};
+
+const MAGIC: [u8; 4] = *b"mls2";
+// Of older processes, recording no events
+const MAGIC_V1: [u8; 4] = *b"mls1";
+
+impl LocalSpans {
+    /// Encode the spans to be mounted onto a span of another process by
+    /// [`Span::mount_remote_spans`](crate::Span::mount_remote_spans), e.g. the spans of the
+    /// part of an operation handed off to another process.
+    ///
+    /// The spans are timed relative to the end of the collection, which is the only absolute
+    /// time encoded, and event names and property keys are written once each. The events
+    /// recorded while no local span was open are left out.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minitrace::{LocalCollector, LocalSpan, Span};
+    ///
+    /// // In the process doing the work
+    /// let local_collector = LocalCollector::start();
+    /// {
+    ///     let _guard = LocalSpan::enter("apply");
+    ///     let _guard = LocalSpan::enter("write");
+    /// }
+    /// let bytes = local_collector.collect().encode();
+    ///
+    /// // In the process owning the trace
+    /// let (root_span, collector) = Span::root("propose");
+    /// root_span.mount_remote_spans(&bytes).unwrap();
+    /// drop(root_span);
+    ///
+    /// let spans = collector.collect();
+    /// let root = spans.iter().find(|s| s.event == "propose").unwrap();
+    /// let apply = spans.iter().find(|s| s.event == "apply").unwrap();
+    /// assert_eq!(apply.parent_id, root.id);
+    /// ```
+    pub fn encode(&self) -> Vec<u8> {
+        let anchor = DefaultClock::anchor();
+        let end_unix_time_ns = DefaultClock::cycle_to_unix_time_ns(self.end_time, anchor);
+
+        let mut names = Vec::new();
+        let mut name_index = HashMap::new();
+        let mut index = |name: &'static str| {
+            *name_index.entry(name).or_insert_with(|| {
+                names.push(name);
+                names.len() as u32 - 1
+            })
+        };
+
+        let mut body = Vec::new();
+        write_u32(&mut body, self.spans.len() as u32).unwrap();
+        for raw in &self.spans {
+            let span = LocalSpans::convert(raw, self.end_time, anchor, 0, raw.properties.clone());
+            write_u32(&mut body, span.id).unwrap();
+            write_u32(&mut body, span.parent_id).unwrap();
+            write_u64(
+                &mut body,
+                end_unix_time_ns.saturating_sub(span.begin_unix_time_ns),
+            )
+            .unwrap();
+            write_u64(&mut body, span.duration_ns).unwrap();
+            write_u32(&mut body, index(span.event)).unwrap();
+            write_u32(&mut body, span.properties.len() as u32).unwrap();
+            for (key, value) in &span.properties {
+                write_u32(&mut body, index(key)).unwrap();
+                write_value(&mut body, value).unwrap();
+            }
+            write_u32(&mut body, span.events.len() as u32).unwrap();
+            for event in &span.events {
+                write_u64(
+                    &mut body,
+                    end_unix_time_ns.saturating_sub(event.unix_time_ns),
+                )
+                .unwrap();
+                write_u32(&mut body, index(event.name)).unwrap();
+            }
+        }
+
+        let mut buf = MAGIC.to_vec();
+        write_u64(&mut buf, end_unix_time_ns).unwrap();
+        write_u32(&mut buf, names.len() as u32).unwrap();
+        for name in names {
+            write_bytes(&mut buf, name.as_bytes()).unwrap();
+        }
+        buf.extend(body);
+        buf
+    }
+}
+
+// Decode the spans encoded by `LocalSpans::encode`, whose top-level spans have the parent id 0.
+//
+// Event names and property keys are interned, leaking each distinct one once, up to a bound on
+// the distinct names of the process past which decoding fails.
+pub(crate) fn decode(mut r: &[u8]) -> io::Result<Vec<Span>> {
+    let r = &mut r;
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    let with_events = match magic {
+        MAGIC => true,
+        MAGIC_V1 => false,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad magic")),
+    };
+    let end_unix_time_ns = read_u64(r)?;
+
+    // Capacities are bounded since the lengths may come from an untrusted source
+    let names_len = read_u32(r)? as usize;
+    let mut names = Vec::with_capacity(names_len.min(1024));
+    for _ in 0..names_len {
+        names.push(read_name(r)?);
+    }
+    let name = |r: &mut &[u8]| {
+        names
+            .get(read_u32(r)? as usize)
+            .copied()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad name index"))
+    };
+
+    let len = read_u32(r)? as usize;
+    let mut spans = Vec::with_capacity(len.min(1024));
+    for _ in 0..len {
+        let id = read_u32(r)?;
+        let parent_id = read_u32(r)?;
+        let begin_unix_time_ns = end_unix_time_ns.saturating_sub(read_u64(r)?);
+        let duration_ns = read_u64(r)?;
+        let event = name(r)?;
+        let properties_len = read_u32(r)? as usize;
+        let mut properties = Vec::with_capacity(properties_len.min(64));
+        for _ in 0..properties_len {
+            let key = name(r)?;
+            properties.push((key, read_value(r)?));
+        }
+        let mut events = Vec::new();
+        if with_events {
+            let events_len = read_u32(r)? as usize;
+            events.reserve(events_len.min(64));
+            for _ in 0..events_len {
+                let unix_time_ns = end_unix_time_ns.saturating_sub(read_u64(r)?);
+                events.push(SpanEvent {
+                    unix_time_ns,
+                    name: name(r)?,
+                });
+            }
+        }
+        spans.push(Span {
+            id,
+            parent_id,
+            begin_unix_time_ns,
+            duration_ns,
+            event,
+            properties,
+            events,
+        });
+    }
+    Ok(spans)
+}
+
+impl crate::Span {
+    /// Mount the spans encoded by [`LocalSpans::encode`](LocalSpans::encode) in another
+    /// process under the span. Their top-level spans become children of the span, and the spans
+    /// get new ids so that they don't collide with the ones of this process.
+    ///
+    /// The spans keep the times of the clock of the other process. Fails with
+    /// [`InvalidData`](io::ErrorKind::InvalidData) if the spans don't form a tree, e.g. a span
+    /// is its own ancestor.
+    pub fn mount_remote_spans(&self, bytes: &[u8]) -> io::Result<()> {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return Ok(()),
+        };
+        let mut spans = decode(bytes)?;
+        if let Some(violation) = validate(&spans).into_iter().find(Violation::is_structural) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                violation.to_string(),
+            ));
+        }
+
+        let ids: HashMap<u32, u32> = spans
+            .iter()
+            .map(|s| (s.id, DefaultIdGenerator::next_id().0))
+            .collect();
+        for span in &mut spans {
+            span.id = ids[&span.id];
+            span.parent_id = ids.get(&span.parent_id).copied().unwrap_or(inner.span_id.0);
+        }
+
+        self.add_remote_spans(spans);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LocalCollector, LocalSpan};
+
+    #[test]
+    fn round_trip() {
+        let local_collector = LocalCollector::start();
+        {
+            let _g = LocalSpan::enter("a").with_property(|| ("k", "v".to_owned()));
+            let _g = LocalSpan::enter("b").with_property(|| ("k", "w".to_owned()));
+            crate::event("e");
+        }
+        let local_spans = local_collector.collect();
+        let bytes = local_spans.encode();
+        let spans = decode(&bytes).unwrap();
+        let expected = local_spans.into_spans(DefaultClock::anchor(), 0);
+        assert_eq!(spans.len(), 2);
+        for (span, expected) in spans.iter().zip(&expected) {
+            assert_eq!(span.id, expected.id);
+            assert_eq!(span.parent_id, expected.parent_id);
+            assert_eq!(span.duration_ns, expected.duration_ns);
+            assert_eq!(span.event, expected.event);
+            assert_eq!(span.properties, expected.properties);
+            assert_eq!(span.events.len(), expected.events.len());
+        }
+        assert_eq!(spans[1].events[0].name, "e");
+        assert_eq!(spans[1].parent_id, spans[0].id);
+
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode(b"mtr2").is_err());
+    }
+
+    #[test]
+    fn mount_cyclic_spans() {
+        // Spans of the given ids and parent ids, as encoded by another process
+        let encode = |spans: &[(u32, u32)]| {
+            let mut buf = MAGIC.to_vec();
+            write_u64(&mut buf, 1000).unwrap();
+            write_u32(&mut buf, 1).unwrap();
+            write_bytes(&mut buf, b"remote").unwrap();
+            write_u32(&mut buf, spans.len() as u32).unwrap();
+            for &(id, parent_id) in spans {
+                write_u32(&mut buf, id).unwrap();
+                write_u32(&mut buf, parent_id).unwrap();
+                write_u64(&mut buf, 10).unwrap();
+                write_u64(&mut buf, 1).unwrap();
+                write_u32(&mut buf, 0).unwrap();
+                write_u32(&mut buf, 0).unwrap();
+                write_u32(&mut buf, 0).unwrap();
+            }
+            buf
+        };
+
+        let (root_span, collector) = crate::Span::root("root");
+        for spans in &[&[(5, 6), (6, 5)][..], &[(5, 5)], &[(5, 0), (5, 0)]] {
+            let err = root_span.mount_remote_spans(&encode(spans)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        root_span
+            .mount_remote_spans(&encode(&[(5, 0), (6, 5)]))
+            .unwrap();
+        drop(root_span);
+
+        assert_eq!(collector.collect().len(), 3);
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Record the [`log`](https://docs.rs/log) records emitted inside traced code, by default the
+//! `warn!` and `error!` ones, on the spans active at the time, so that the collected traces
+//! carry the log lines explaining slow spans. Enabled by the `log` feature.
+//!
+//! A record becomes the property [`log.record`](semconv::LOG_RECORD) of the innermost local
+//! span open on the current thread, formatted as `<level> <target>: <message>`. With none open
+//! but a span attached by [`Span::enter`](crate::Span::enter), the record becomes an instant
+//! local span `log` instead. Outside of a trace, or when logging from code called by minitrace,
+//! e.g. a property closure, the record is only passed to the wrapped logger.
+//!
+//! ```rust
+//! use minitrace::log_bridge::MinitraceLogger;
+//! use minitrace::{semconv, LocalSpan, Span};
+//!
+//! MinitraceLogger::new().install().unwrap();
+//!
+//! let (root_span, collector) = Span::root("request");
+//! {
+//!     let _guard = root_span.enter();
+//!     let _local_guard = LocalSpan::enter("append");
+//!     log::warn!(target: "raft", "slow fsync");
+//! }
+//! drop(root_span);
+//!
+//! let spans = collector.collect();
+//! let append = spans.iter().find(|s| s.event == "append").unwrap();
+//! assert_eq!(
+//!     append.properties,
+//!     vec![(semconv::LOG_RECORD, "WARN raft: slow fsync".into())]
+//! );
+//! ```
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use crate::local::local_span_line::add_property_to_open_span;
+use crate::semconv;
+
+/// A logger recording log records on the active spans, and passing them to the logger it wraps,
+/// if any.
+pub struct MinitraceLogger {
+    inner: Option<Box<dyn Log>>,
+    level: LevelFilter,
+}
+
+impl Default for MinitraceLogger {
+    fn default() -> Self {
+        MinitraceLogger {
+            inner: None,
+            level: LevelFilter::Warn,
+        }
+    }
+}
+
+impl MinitraceLogger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pass the records to `inner` too, e.g. the logger the application would install
+    /// otherwise.
+    pub fn wrap(self, inner: impl Log + 'static) -> Self {
+        Self {
+            inner: Some(Box::new(inner)),
+            ..self
+        }
+    }
+
+    /// The most verbose level recorded on the spans, `Warn` by default.
+    pub fn level(self, level: LevelFilter) -> Self {
+        Self { level, ..self }
+    }
+
+    /// Install the logger as the logger of the process, raising the maximum level of `log` to
+    /// the level recorded if needed. The maximum level a wrapped logger needs has to be set by
+    /// `log::set_max_level`, as usual.
+    pub fn install(self) -> Result<(), SetLoggerError> {
+        let level = self.level.max(log::max_level());
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+
+    fn record(&self, record: &Record<'_>) {
+        let property = || {
+            (
+                semconv::LOG_RECORD,
+                format!("{} {}: {}", record.level(), record.target(), record.args()),
+            )
+        };
+        add_property_to_open_span("log", property);
+    }
+}
+
+impl Log for MinitraceLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+            || matches!(&self.inner, Some(inner) if inner.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if record.level() <= self.level {
+            self.record(record);
+        }
+        if let Some(inner) = &self.inner {
+            if inner.enabled(record.metadata()) {
+                inner.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(inner) = &self.inner {
+            inner.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use log::Level;
+
+    use super::*;
+    use crate::span::PropertyValue;
+    use crate::{LocalSpan, Span};
+
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<String>>>);
+
+    impl Log for Lines {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.level() <= Level::Info
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn records() {
+        let lines = Lines::default();
+        let logger = MinitraceLogger::new().wrap(lines.clone());
+        let log = |level, message: &str| {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .target("raft")
+                    .args(format_args!("{}", message))
+                    .build(),
+            )
+        };
+
+        // Outside of a trace, the records only reach the wrapped logger
+        log(Level::Error, "outside");
+
+        let (root_span, collector) = Span::root("root");
+        {
+            let _g = root_span.enter();
+            log(Level::Warn, "attached");
+            let _l = LocalSpan::enter("append");
+            log(Level::Info, "info");
+            log(Level::Debug, "debug");
+            log(Level::Error, "local");
+        }
+        drop(root_span);
+
+        let spans = collector.collect();
+        let record = |s| (semconv::LOG_RECORD, PropertyValue::from(s));
+        let find = |event| spans.iter().find(|s| s.event == event).unwrap();
+        assert_eq!(find("log").properties, vec![record("WARN raft: attached")]);
+        assert_eq!(find("append").properties, vec![record("ERROR raft: local")]);
+        assert_eq!(
+            *lines.0.lock().unwrap(),
+            vec!["outside", "attached", "info", "local"]
+        );
+    }
+
+    fn warn(logger: &MinitraceLogger, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(Level::Warn)
+                .target("raft")
+                .args(format_args!("{}", message))
+                .build(),
+        )
+    }
+
+    #[test]
+    fn outside_of_trace() {
+        let _lock = crate::tests::CONFIG_LOCK.lock().unwrap();
+        crate::set_config(crate::Config::default().collect_orphan_spans(true));
+        let lines = Lines::default();
+        let logger = MinitraceLogger::new().wrap(lines.clone());
+
+        let thread_id = std::thread::spawn(move || {
+            warn(&logger, "outside");
+            std::thread::current().id()
+        })
+        .join()
+        .unwrap();
+        crate::set_config(crate::Config::default());
+
+        // No orphan span is created for the record
+        let orphans = crate::collect_orphan_spans();
+        assert!(orphans.iter().all(|o| o.thread_id != thread_id));
+        assert_eq!(*lines.0.lock().unwrap(), vec!["outside"]);
+    }
+
+    #[test]
+    fn from_property_closure() {
+        let lines = Lines::default();
+        let logger = MinitraceLogger::new().wrap(lines.clone());
+
+        let (root_span, collector) = Span::root("root");
+        {
+            let _g = root_span.enter();
+            // The span line is borrowed while the closure runs
+            let _l = LocalSpan::enter("append").with_property(|| {
+                warn(&logger, "nested");
+                ("k", "v".to_owned())
+            });
+        }
+        drop(root_span);
+
+        let spans = collector.collect();
+        let events: Vec<_> = spans.iter().map(|s| s.event).collect();
+        assert!(!events.contains(&"log"));
+        let append = spans.iter().find(|s| s.event == "append").unwrap();
+        assert_eq!(append.properties, vec![("k", "v".into())]);
+        assert_eq!(*lines.0.lock().unwrap(), vec!["nested"]);
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+/// Trace a block or an expression by a [`LocalSpan`](crate::LocalSpan), evaluating to its value.
+///
+/// The span also finishes when the block is left early by `?`, `return`, `break` or
+/// `continue`, since the block is inlined rather than wrapped in a closure.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::{trace_block, LocalCollector};
+///
+/// fn parse(input: &str) -> Result<u32, std::num::ParseIntError> {
+///     let n = trace_block!("parse", { input.trim().parse::<u32>()? });
+///     Ok(trace_block!("double", n * 2))
+/// }
+///
+/// let local_collector = LocalCollector::start();
+/// assert!(parse("x").is_err());
+/// assert_eq!(parse("21").unwrap(), 42);
+/// let spans = local_collector.collect();
+/// assert_eq!(spans.spans.len(), 3);
+/// ```
+#[macro_export]
+macro_rules! trace_block {
+    ($event:expr, $body:expr $(,)?) => {{
+        let _guard = $crate::LocalSpan::enter($event);
+        $body
+    }};
+}
+
+// Called by `#[trace]` and `#[trace_async]` for the event named by the naming policy
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __event_name {
+    ($event:expr) => {{
+        static __MINITRACE_EVENT_NAME: $crate::__EventName =
+            $crate::__EventName::new($event, module_path!());
+        __MINITRACE_EVENT_NAME.get()
+    }};
+}
+
+// Called by `#[trace]` and `#[trace_async]` with a target for whether it's enabled
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __target {
+    ($target:expr) => {{
+        static __MINITRACE_TARGET: $crate::__Target = $crate::__Target::new($target);
+        &__MINITRACE_TARGET
+    }};
+}
+
+// Called by `#[trace]` and `#[trace_async]` to add the event to the inventory
+#[cfg(feature = "inventory")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __register_event {
+    ($event:expr) => {
+        #[$crate::__linkme::distributed_slice($crate::inventory::EVENTS)]
+        #[linkme(crate = $crate::__linkme)]
+        static __MINITRACE_EVENT: $crate::inventory::EventMeta = $crate::inventory::EventMeta {
+            name: $event,
+            module: module_path!(),
+            file: file!(),
+            line: line!(),
+        };
+    };
+}
+
+#[cfg(not(feature = "inventory"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __register_event {
+    ($event:expr) => {};
+}
@@ -0,0 +1,494 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Encoding collected traces as an OTLP `ExportTraceServiceRequest` in protobuf, without any
+//! transport, e.g. to send them over a gRPC service of the application itself.
+//!
+//! ```rust
+//! use minitrace::Span;
+//!
+//! let (root_span, collector) = Span::root("root");
+//! drop(root_span);
+//! let trace_id = collector.trace_id();
+//!
+//! let bytes = minitrace::otlp::encode(&[(trace_id, collector.collect())]);
+//! assert!(!bytes.is_empty());
+//! ```
+//!
+//! The 32-bit span ids are widened into the lower bytes of the OTLP span ids.
+//!
+//! With the `otlp` feature, an `OtlpHttpReporter` exports them to an OpenTelemetry collector
+//! over OTLP/HTTP with protobuf payloads.
+
+#[cfg(feature = "otlp")]
+use std::io;
+#[cfg(feature = "otlp")]
+use std::time::Duration;
+
+#[cfg(feature = "otlp")]
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+
+#[cfg(feature = "otlp")]
+use crate::report::{ReportError, Reporter};
+use crate::span::{PropertyValue, Span};
+
+const SCOPE_NAME: &str = "minitrace";
+const SCOPE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Encode the traces, as `(trace id, spans)` pairs, with an empty resource.
+pub fn encode(traces: &[(u128, Vec<Span>)]) -> Vec<u8> {
+    encode_with_resource(&[], traces)
+}
+
+/// Encode the traces with the attributes of the resource producing them, e.g.
+/// `("service.name", "tikv".into())`.
+pub fn encode_with_resource(
+    resource: &[(&str, PropertyValue)],
+    traces: &[(u128, Vec<Span>)],
+) -> Vec<u8> {
+    encode_request(
+        resource,
+        traces
+            .iter()
+            .map(|(trace_id, spans)| (*trace_id, spans.as_slice())),
+    )
+}
+
+fn encode_request<'a>(
+    resource: &[(&str, PropertyValue)],
+    traces: impl Iterator<Item = (u128, &'a [Span])>,
+) -> Vec<u8> {
+    // ExportTraceServiceRequest
+    let mut request = Vec::new();
+    // .resource_spans
+    message(&mut request, 1, |resource_spans| {
+        // .resource
+        message(resource_spans, 1, |res| {
+            for (key, value) in resource {
+                key_value(res, 1, key, value);
+            }
+        });
+        // .scope_spans
+        message(resource_spans, 2, |scope_spans| {
+            // .scope
+            message(scope_spans, 1, |scope| {
+                string(scope, 1, SCOPE_NAME);
+                string(scope, 2, SCOPE_VERSION);
+            });
+            for (trace_id, spans) in traces {
+                for span in spans {
+                    // .spans
+                    message(scope_spans, 2, |buf| encode_span(buf, trace_id, span));
+                }
+            }
+        });
+    });
+    request
+}
+
+/// A [`Reporter`] exporting traces to an OpenTelemetry collector over OTLP/HTTP, e.g. to
+/// `http://otel-collector:4318/v1/traces`, one request per trace. Enabled by the `otlp` feature.
+///
+/// Both HTTP and HTTPS endpoints are supported, but not OTLP/gRPC. Responses `429`, `502`, `503`
+/// and `504` are retried by a [`ReportPipeline`](crate::report::ReportPipeline), other failed
+/// responses aren't.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::otlp::OtlpHttpReporter;
+/// use minitrace::report::ReportPipeline;
+///
+/// let reporter = OtlpHttpReporter::new("http://127.0.0.1:4318/v1/traces")
+///     .unwrap()
+///     .resource("service.name", "tikv");
+/// let pipeline = ReportPipeline::new(reporter).spawn();
+/// ```
+#[cfg(feature = "otlp")]
+#[derive(Clone, Debug)]
+pub struct OtlpHttpReporter {
+    client: reqwest::blocking::Client,
+    url: reqwest::Url,
+    resource: Vec<(String, PropertyValue)>,
+    headers: HeaderMap,
+    timeout: Duration,
+}
+
+#[cfg(feature = "otlp")]
+impl OtlpHttpReporter {
+    /// Export to the URL `endpoint`, e.g. `https://localhost:4318/v1/traces`. The path defaults
+    /// to `/v1/traces`.
+    pub fn new(endpoint: &str) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let mut url = reqwest::Url::parse(endpoint)
+            .map_err(|err| invalid(format!("invalid OTLP endpoint: {}", err)))?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(invalid(
+                "the OTLP endpoint must be an http:// or https:// URL".to_owned(),
+            ));
+        }
+        if url.path() == "/" {
+            url.set_path("/v1/traces");
+        }
+        let client = reqwest::blocking::Client::builder()
+            .build()
+            .map_err(|err| invalid(format!("failed to build the HTTP client: {}", err)))?;
+
+        Ok(OtlpHttpReporter {
+            client,
+            url,
+            resource: Vec::new(),
+            headers: HeaderMap::new(),
+            timeout: Duration::from_secs(10),
+        })
+    }
+
+    /// Add an attribute of the resource producing the traces, e.g. `service.name`.
+    pub fn resource(mut self, key: impl Into<String>, value: impl Into<PropertyValue>) -> Self {
+        self.resource.push((key.into(), value.into()));
+        self
+    }
+
+    /// Add a header to the requests, e.g. an API key of the collector. Fails if the name or
+    /// the value isn't valid in a header, e.g. contains a line break.
+    pub fn header(mut self, name: &str, value: &str) -> io::Result<Self> {
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|err| invalid_header(&err))?;
+        let value = HeaderValue::from_str(value).map_err(|err| invalid_header(&err))?;
+        self.headers.append(name, value);
+        Ok(self)
+    }
+
+    /// Send `Authorization: Bearer <token>` with the requests. Fails if the token isn't valid
+    /// in a header.
+    pub fn bearer_token(mut self, token: &str) -> io::Result<Self> {
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|err| invalid_header(&err))?;
+        value.set_sensitive(true);
+        self.headers.insert(AUTHORIZATION, value);
+        Ok(self)
+    }
+
+    /// The timeout of connecting, sending and receiving the response. Defaults to 10s.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+
+    /// Send the spans of the trace and return the status code of the response.
+    pub fn send(&self, trace_id: u128, spans: &[Span]) -> reqwest::Result<u16> {
+        let resource: Vec<(&str, PropertyValue)> = self
+            .resource
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.clone()))
+            .collect();
+        let body = encode_request(&resource, std::iter::once((trace_id, spans)));
+
+        let response = self
+            .client
+            .post(self.url.clone())
+            .timeout(self.timeout)
+            .header(CONTENT_TYPE, "application/x-protobuf")
+            .headers(self.headers.clone())
+            .body(body)
+            .send()?;
+        Ok(response.status().as_u16())
+    }
+}
+
+#[cfg(feature = "otlp")]
+fn invalid_header(err: &dyn std::error::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid header: {}", err),
+    )
+}
+
+#[cfg(feature = "otlp")]
+impl Reporter for OtlpHttpReporter {
+    fn report(&self, trace_id: u128, spans: &[Span]) -> Result<(), ReportError> {
+        let status = self.send(trace_id, spans).map_err(ReportError::new)?;
+        match status {
+            200..=299 => Ok(()),
+            429 | 502 | 503 | 504 => Err(ReportError::new(format!(
+                "the OTLP collector responded {}",
+                status
+            ))),
+            _ => Err(ReportError::permanent(format!(
+                "the OTLP collector responded {}",
+                status
+            ))),
+        }
+    }
+}
+
+fn encode_span(buf: &mut Vec<u8>, trace_id: u128, span: &Span) {
+    bytes(buf, 1, &trace_id.to_be_bytes());
+    bytes(buf, 2, &span_id(span.id));
+    if span.parent_id != 0 {
+        bytes(buf, 4, &span_id(span.parent_id));
+    }
+    string(buf, 5, span.event);
+    // SPAN_KIND_INTERNAL
+    tag(buf, 6, WIRE_VARINT);
+    varint(buf, 1);
+    tag(buf, 7, WIRE_FIXED64);
+    buf.extend_from_slice(&span.begin_unix_time_ns.to_le_bytes());
+    tag(buf, 8, WIRE_FIXED64);
+    let end_unix_time_ns = span.begin_unix_time_ns.saturating_add(span.duration_ns);
+    buf.extend_from_slice(&end_unix_time_ns.to_le_bytes());
+    for (key, value) in &span.properties {
+        key_value(buf, 9, key, value);
+    }
+    for event in &span.events {
+        message(buf, 11, |e| {
+            tag(e, 1, WIRE_FIXED64);
+            e.extend_from_slice(&event.unix_time_ns.to_le_bytes());
+            string(e, 2, event.name);
+        });
+    }
+}
+
+fn span_id(id: u32) -> [u8; 8] {
+    let mut span_id = [0; 8];
+    span_id[4..].copy_from_slice(&id.to_be_bytes());
+    span_id
+}
+
+fn key_value(buf: &mut Vec<u8>, field: u32, key: &str, value: &PropertyValue) {
+    message(buf, field, |kv| {
+        string(kv, 1, key);
+        // AnyValue
+        message(kv, 2, |any| match value {
+            PropertyValue::String(s) => string(any, 1, s),
+            PropertyValue::Bool(v) => {
+                tag(any, 2, WIRE_VARINT);
+                varint(any, *v as u64);
+            }
+            PropertyValue::I64(v) => {
+                tag(any, 3, WIRE_VARINT);
+                varint(any, *v as u64);
+            }
+            PropertyValue::F64(v) => {
+                tag(any, 4, WIRE_FIXED64);
+                any.extend_from_slice(&v.to_le_bytes());
+            }
+            PropertyValue::Binary(b) => bytes(any, 7, b),
+        });
+    });
+}
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_FIXED64: u32 = 1;
+const WIRE_LEN: u32 = 2;
+
+fn tag(buf: &mut Vec<u8>, field: u32, wire_type: u32) {
+    varint(buf, (field << 3 | wire_type) as u64);
+}
+
+fn varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn bytes(buf: &mut Vec<u8>, field: u32, b: &[u8]) {
+    tag(buf, field, WIRE_LEN);
+    varint(buf, b.len() as u64);
+    buf.extend_from_slice(b);
+}
+
+fn string(buf: &mut Vec<u8>, field: u32, s: &str) {
+    bytes(buf, field, s.as_bytes());
+}
+
+fn message(buf: &mut Vec<u8>, field: u32, encode: impl FnOnce(&mut Vec<u8>)) {
+    let mut msg = Vec::new();
+    encode(&mut msg);
+    bytes(buf, field, &msg);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::SpanEvent;
+
+    // Decode the fields of a message as (field, wire type, value or bytes)
+    fn fields(mut buf: &[u8]) -> Vec<(u32, u32, u64, &[u8])> {
+        fn read_varint(buf: &mut &[u8]) -> u64 {
+            let mut v = 0;
+            for shift in (0..).step_by(7) {
+                let b = buf[0];
+                *buf = &buf[1..];
+                v |= ((b & 0x7f) as u64) << shift;
+                if b < 0x80 {
+                    break;
+                }
+            }
+            v
+        }
+
+        let mut res = Vec::new();
+        while !buf.is_empty() {
+            let key = read_varint(&mut buf) as u32;
+            let (field, wire_type) = (key >> 3, key & 7);
+            match wire_type {
+                WIRE_VARINT => res.push((field, wire_type, read_varint(&mut buf), &[][..])),
+                WIRE_FIXED64 => {
+                    let mut v = [0; 8];
+                    v.copy_from_slice(&buf[..8]);
+                    buf = &buf[8..];
+                    res.push((field, wire_type, u64::from_le_bytes(v), &[][..]));
+                }
+                _ => {
+                    let len = read_varint(&mut buf) as usize;
+                    res.push((field, wire_type, 0, &buf[..len]));
+                    buf = &buf[len..];
+                }
+            }
+        }
+        res
+    }
+
+    fn field(buf: &[u8], field: u32) -> Vec<(u64, &[u8])> {
+        fields(buf)
+            .into_iter()
+            .filter(|(f, _, _, _)| *f == field)
+            .map(|(_, _, v, b)| (v, b))
+            .collect()
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn http_reporter() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in &["200 OK", "503 Service Unavailable", "400 Bad Request"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                // The request is complete once the body of `Content-Length` bytes has arrived
+                let complete = |request: &[u8]| {
+                    let end = match request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        Some(i) => i + 4,
+                        None => return false,
+                    };
+                    let len: usize = String::from_utf8_lossy(&request[..end])
+                        .to_lowercase()
+                        .lines()
+                        .find_map(|l| l.strip_prefix("content-length: ").map(str::to_owned))
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    request.len() - end >= len
+                };
+                while !complete(&request) {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+                requests.push(String::from_utf8_lossy(&request).into_owned());
+            }
+            requests
+        });
+
+        let reporter = OtlpHttpReporter::new(&endpoint)
+            .unwrap()
+            .resource("service.name", "tikv")
+            .header("x-api-key", "secret")
+            .unwrap()
+            .bearer_token("token")
+            .unwrap();
+        let spans = [Span {
+            id: 1,
+            event: "root",
+            ..Default::default()
+        }];
+        assert!(reporter.report(42, &spans).is_ok());
+        assert!(reporter.report(42, &spans).unwrap_err().is_retryable());
+        assert!(!reporter.report(42, &spans).unwrap_err().is_retryable());
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("POST /v1/traces HTTP/1.1\r\n"));
+        assert!(requests[0]
+            .to_lowercase()
+            .contains("content-type: application/x-protobuf\r\n"));
+        assert!(requests[0].contains("x-api-key: secret\r\n"));
+        assert!(requests[0].contains("authorization: Bearer token\r\n"));
+        assert!(requests[0].contains("service.name"));
+
+        // No header or request can be smuggled in a header
+        let reporter = OtlpHttpReporter::new(&endpoint).unwrap();
+        assert!(reporter.clone().header("x-api-key", "a\r\nx-b: c").is_err());
+        assert!(reporter.clone().header("x-api-key\r\nx-b", "c").is_err());
+        assert!(reporter.bearer_token("a\r\nx-b: c").is_err());
+
+        assert!(OtlpHttpReporter::new("ftp://collector").is_err());
+        let reporter = OtlpHttpReporter::new("https://collector").unwrap();
+        assert_eq!(reporter.url.as_str(), "https://collector/v1/traces");
+    }
+
+    #[test]
+    fn encode_spans() {
+        let spans = vec![
+            Span {
+                id: 1,
+                begin_unix_time_ns: 1000,
+                duration_ns: 300,
+                event: "root",
+                properties: vec![("n", PropertyValue::I64(-1))],
+                events: vec![SpanEvent {
+                    unix_time_ns: 1100,
+                    name: "retry",
+                }],
+                ..Default::default()
+            },
+            Span {
+                id: 2,
+                parent_id: 1,
+                event: "child",
+                ..Default::default()
+            },
+        ];
+        let bytes =
+            encode_with_resource(&[("service.name", "tikv".into())], &[(7 << 64 | 42, spans)]);
+
+        let resource_spans = field(&bytes, 1)[0].1;
+        let resource = field(resource_spans, 1)[0].1;
+        let attribute = field(resource, 1)[0].1;
+        assert_eq!(field(attribute, 1)[0].1, b"service.name");
+
+        let scope_spans = field(resource_spans, 2)[0].1;
+        assert_eq!(field(field(scope_spans, 1)[0].1, 1)[0].1, b"minitrace");
+        let spans = field(scope_spans, 2);
+        assert_eq!(spans.len(), 2);
+
+        let root = spans[0].1;
+        assert_eq!(
+            field(root, 1)[0].1,
+            &[0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 42]
+        );
+        assert_eq!(field(root, 2)[0].1, &[0, 0, 0, 0, 0, 0, 0, 1]);
+        assert!(field(root, 4).is_empty());
+        assert_eq!(field(root, 5)[0].1, b"root");
+        assert_eq!(field(root, 7)[0].0, 1000);
+        assert_eq!(field(root, 8)[0].0, 1300);
+        let value = field(field(root, 9)[0].1, 2)[0].1;
+        assert_eq!(field(value, 3)[0].0 as i64, -1);
+        let event = field(root, 11)[0].1;
+        assert_eq!(field(event, 1)[0].0, 1100);
+        assert_eq!(field(event, 2)[0].1, b"retry");
+
+        let child = spans[1].1;
+        assert_eq!(field(child, 4)[0].1, &[0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,26 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Codec for Kafka record headers.
+//!
+//! Kafka headers are an ordered list of key-value pairs where keys may repeat and values may be
+//! null. Following Kafka's `lastHeader` semantics, the last header of a key wins on extraction.
+
+use crate::propagation::{Extractor, Injector};
+
+pub type Headers = Vec<(String, Option<Vec<u8>>)>;
+
+impl Injector for Headers {
+    fn set(&mut self, key: &str, value: &[u8]) {
+        self.retain(|(k, _)| k != key);
+        self.push((key.to_owned(), Some(value.to_vec())));
+    }
+}
+
+impl Extractor for Headers {
+    fn get(&self, key: &str) -> Option<&[u8]> {
+        self.iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .and_then(|(_, v)| v.as_deref())
+    }
+}
@@ -0,0 +1,331 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Carry a trace across process boundaries.
+//!
+//! A [`SpanContext`](SpanContext) holds the identifiers a remote process needs to continue a
+//! trace: the id of the trace and the id of the span that the remote spans should be attached
+//! to. It can be injected into and extracted from any key-value carrier implementing
+//! [`Injector`](Injector) and [`Extractor`](Extractor), such as message queue record headers,
+//! or embedded in a protobuf message, see [`protobuf`](protobuf).
+//!
+//! Across RPCs, the context of the current span is usually sent as a
+//! [W3C `traceparent`](https://www.w3.org/TR/trace-context/) header, and the server continues
+//! the trace with [`Span::root_with_context`](crate::Span::root_with_context):
+//!
+//! ```rust
+//! use std::collections::HashMap;
+//! use minitrace::propagation::SpanContext;
+//! use minitrace::Span;
+//!
+//! // The client
+//! let (root_span, collector) = Span::root("request");
+//! let mut headers: HashMap<String, String> = HashMap::new();
+//! {
+//!     let _guard = root_span.enter();
+//!     SpanContext::current().unwrap().inject_traceparent(&mut headers);
+//! }
+//!
+//! // The server
+//! let context = SpanContext::extract_traceparent(&headers).unwrap();
+//! let (server_span, server_collector) = Span::root_with_context("rpc", context);
+//! assert_eq!(server_collector.trace_id(), collector.trace_id());
+//! ```
+//!
+//! A [`TraceContext`](TraceContext) adds the sampling decision and baggage, and can be persisted
+//! with a versioned encoding to resume a trace after a process restart.
+
+pub mod kafka;
+mod persistence;
+pub mod protobuf;
+pub mod pulsar;
+
+pub use self::persistence::TraceContext;
+
+use crate::local::span_guard::AttachedSpan;
+use crate::Span;
+
+pub const TRACE_ID_KEY: &str = "minitrace-trace-id";
+pub const SPAN_ID_KEY: &str = "minitrace-span-id";
+pub const TRACEPARENT_KEY: &str = "traceparent";
+
+/// A writable key-value carrier, e.g. the headers of an outgoing message.
+pub trait Injector {
+    fn set(&mut self, key: &str, value: &[u8]);
+}
+
+/// A readable key-value carrier, e.g. the headers of an incoming message.
+pub trait Extractor {
+    fn get(&self, key: &str) -> Option<&[u8]>;
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct SpanContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+}
+
+impl SpanContext {
+    pub fn new(trace_id: u128, span_id: u64) -> Self {
+        SpanContext { trace_id, span_id }
+    }
+
+    /// The context of the span, or `None` if it's empty. If the span belongs to multiple traces,
+    /// the first one is taken.
+    pub fn from_span(span: &Span) -> Option<Self> {
+        let span_id = span.inner.as_ref()?.span_id;
+        let trace_id = span.trace_id()?;
+        Some(SpanContext::new(trace_id, span_id.0 as u64))
+    }
+
+    /// The context of the span attached to the current thread, if any.
+    pub fn current() -> Option<Self> {
+        let (trace_id, span_id) = AttachedSpan::context()?;
+        Some(SpanContext::new(trace_id, span_id.0 as u64))
+    }
+
+    /// Write the context into `carrier` as hex encoded values. A trace id fitting in 64 bits is
+    /// written with 16 digits, as the services still using 64-bit trace ids expect.
+    pub fn inject(&self, carrier: &mut impl Injector) {
+        let trace_id = if self.trace_id >> 64 == 0 {
+            format!("{:016x}", self.trace_id)
+        } else {
+            format!("{:032x}", self.trace_id)
+        };
+        carrier.set(TRACE_ID_KEY, trace_id.as_bytes());
+        carrier.set(SPAN_ID_KEY, format!("{:016x}", self.span_id).as_bytes());
+    }
+
+    /// Read the context from `carrier`, with a trace id of either 16 or 32 digits. Returns `None`
+    /// if any of the keys is missing or malformed.
+    pub fn extract(carrier: &impl Extractor) -> Option<Self> {
+        let trace_id = decode_hex_u128(carrier.get(TRACE_ID_KEY)?, false)?;
+        let span_id = decode_hex_u64(carrier.get(SPAN_ID_KEY)?, false)?;
+        Some(SpanContext { trace_id, span_id })
+    }
+
+    /// Write the context into `carrier` as a `traceparent` header.
+    pub fn inject_traceparent(&self, carrier: &mut impl Injector) {
+        carrier.set(TRACEPARENT_KEY, self.to_traceparent().as_bytes());
+    }
+
+    /// Read the context from the `traceparent` header of `carrier`.
+    pub fn extract_traceparent(carrier: &impl Extractor) -> Option<Self> {
+        let traceparent = std::str::from_utf8(carrier.get(TRACEPARENT_KEY)?).ok()?;
+        Self::from_traceparent(traceparent)
+    }
+}
+
+impl SpanContext {
+    /// Encode the context as a [W3C `traceparent`](https://www.w3.org/TR/trace-context/) header
+    /// value. The sampled flag is always set.
+    pub fn to_traceparent(&self) -> String {
+        format!("00-{:032x}-{:016x}-01", self.trace_id, self.span_id)
+    }
+
+    /// Decode a W3C `traceparent` header value. Returns `None` if the value is malformed.
+    pub fn from_traceparent(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+
+        if version.len() != 2 || version == "ff" || trace_id.len() != 32 || flags.len() != 2 {
+            return None;
+        }
+        if version == "00" && parts.next().is_some() {
+            return None;
+        }
+        if !is_hex(version.as_bytes(), true) || !is_hex(flags.as_bytes(), true) {
+            return None;
+        }
+
+        if span_id.len() != 16 {
+            return None;
+        }
+        let trace_id = decode_hex_u128(trace_id.as_bytes(), true)?;
+        let span_id = decode_hex_u64(span_id.as_bytes(), true)?;
+        if trace_id == 0 || span_id == 0 {
+            return None;
+        }
+
+        Some(SpanContext { trace_id, span_id })
+    }
+}
+
+// Whether the bytes are hex digits only, unlike what `from_str_radix` accepts, e.g. a leading
+// `+`. The `traceparent` header allows lowercase digits only.
+fn is_hex(bytes: &[u8], lowercase: bool) -> bool {
+    bytes.iter().all(|b| {
+        b.is_ascii_digit() || (b'a'..=b'f').contains(b) || (!lowercase && (b'A'..=b'F').contains(b))
+    })
+}
+
+fn decode_hex_u64(bytes: &[u8], lowercase: bool) -> Option<u64> {
+    if bytes.is_empty() || bytes.len() > 16 || !is_hex(bytes, lowercase) {
+        return None;
+    }
+    let s = std::str::from_utf8(bytes).ok()?;
+    u64::from_str_radix(s, 16).ok()
+}
+
+fn decode_hex_u128(bytes: &[u8], lowercase: bool) -> Option<u128> {
+    if bytes.is_empty() || bytes.len() > 32 || !is_hex(bytes, lowercase) {
+        return None;
+    }
+    let s = std::str::from_utf8(bytes).ok()?;
+    u128::from_str_radix(s, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::semconv;
+    use std::collections::HashMap;
+
+    #[test]
+    fn kafka_round_trip() {
+        let ctx = SpanContext::new(0xff << 64 | 0x1234_5678_9abc_def0, 42);
+
+        let mut headers: kafka::Headers = vec![("other".to_owned(), None)];
+        ctx.inject(&mut headers);
+        // injecting twice doesn't produce duplicated headers
+        ctx.inject(&mut headers);
+
+        assert_eq!(headers.len(), 3);
+        assert_eq!(SpanContext::extract(&headers), Some(ctx));
+    }
+
+    #[test]
+    fn pulsar_round_trip() {
+        let ctx = SpanContext::new(7, 0xffff_ffff_ffff_ffff);
+
+        let mut properties = HashMap::new();
+        ctx.inject(&mut properties);
+
+        // A 64-bit trace id is written like the services using 64-bit trace ids do
+        assert_eq!(properties[TRACE_ID_KEY], "0000000000000007");
+        assert_eq!(SpanContext::extract(&properties), Some(ctx));
+
+        let wide = SpanContext::new(7 << 64 | 7, 42);
+        wide.inject(&mut properties);
+        assert_eq!(properties[TRACE_ID_KEY], "00000000000000070000000000000007");
+        assert_eq!(SpanContext::extract(&properties), Some(wide));
+    }
+
+    #[test]
+    fn traceparent_round_trip() {
+        let ctx = SpanContext::new(0x4bf9_2f35_77b3_4da6, 0x00f0_67aa_0ba9_02b7);
+        let traceparent = ctx.to_traceparent();
+
+        assert_eq!(
+            traceparent,
+            "00-00000000000000004bf92f3577b34da6-00f067aa0ba902b7-01"
+        );
+        assert_eq!(SpanContext::from_traceparent(&traceparent), Some(ctx));
+
+        // The example of the specification, with a 128-bit trace id
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let ctx = SpanContext::from_traceparent(traceparent).unwrap();
+        assert_eq!(ctx.trace_id, 0x4bf9_2f35_77b3_4da6_a3ce_929d_0e0e_4736);
+        assert_eq!(ctx.to_traceparent(), traceparent);
+
+        assert_eq!(
+            SpanContext::from_traceparent(
+                "00-00000000000000004bf92f3577b34da6-0000000000000000-01"
+            ),
+            None
+        );
+        assert_eq!(
+            SpanContext::from_traceparent("00-4bf92f3577b34da6-01"),
+            None
+        );
+
+        // Signs and uppercase digits are rejected
+        for malformed in &[
+            "00-+bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-+0f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00F067AA0BA902B7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-+1",
+            "+0-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(
+                SpanContext::from_traceparent(malformed),
+                None,
+                "{}",
+                malformed
+            );
+        }
+    }
+
+    #[test]
+    fn continue_remote_trace() {
+        let (root_span, collector) = Span::root("request");
+        let mut headers = HashMap::new();
+        {
+            let _g = root_span.enter();
+            let ctx = SpanContext::current().unwrap();
+            assert_eq!(SpanContext::from_span(&root_span), Some(ctx));
+            ctx.inject_traceparent(&mut headers);
+        }
+        assert_eq!(SpanContext::current(), None);
+        assert_eq!(SpanContext::from_span(&Span::empty()), None);
+
+        let ctx = SpanContext::extract_traceparent(&headers).unwrap();
+        assert_eq!(ctx.trace_id, collector.trace_id());
+        let (server_span, server_collector) = Span::root_with_context("rpc", ctx);
+        drop(server_span);
+        assert_eq!(server_collector.trace_id(), collector.trace_id());
+        let spans = server_collector.collect();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].parent_id, 0);
+        let remote_parent = spans[0]
+            .properties
+            .iter()
+            .find(|(k, _)| *k == semconv::REMOTE_PARENT)
+            .map(|(_, v)| v.to_string());
+        assert_eq!(remote_parent, Some(format!("{:016x}", ctx.span_id)));
+    }
+
+    #[test]
+    fn protobuf_round_trip() {
+        let ctx = SpanContext::new(0x1234_5678_9abc_def0, 42);
+        assert_eq!(ctx.to_protobuf().len(), 18);
+        assert_eq!(SpanContext::from_protobuf(&ctx.to_protobuf()), Some(ctx));
+        let wide = SpanContext::new(7 << 64 | 0x1234_5678_9abc_def0, 42);
+        assert_eq!(SpanContext::from_protobuf(&wide.to_protobuf()), Some(wide));
+
+        // A raft message with a varint field 1, then the context as field 15, then bytes field 2
+        let mut message = vec![0x08, 0x96, 0x01];
+        ctx.inject_protobuf(&mut message, 15);
+        message.extend_from_slice(&[0x12, 0x02, b'h', b'i']);
+        assert_eq!(SpanContext::extract_protobuf(&message, 15), Some(ctx));
+        assert_eq!(SpanContext::extract_protobuf(&message, 3), None);
+        assert_eq!(SpanContext::extract_protobuf(&message[..8], 15), None);
+    }
+
+    #[test]
+    fn extract_malformed() {
+        let mut properties = HashMap::new();
+        assert_eq!(SpanContext::extract(&properties), None);
+
+        properties.insert(TRACE_ID_KEY.to_owned(), "not hex".to_owned());
+        properties.insert(SPAN_ID_KEY.to_owned(), "2a".to_owned());
+        assert_eq!(SpanContext::extract(&properties), None);
+
+        properties.insert(TRACE_ID_KEY.to_owned(), "+2a".to_owned());
+        assert_eq!(SpanContext::extract(&properties), None);
+        properties.insert(TRACE_ID_KEY.to_owned(), "2a".to_owned());
+        properties.insert(SPAN_ID_KEY.to_owned(), "+2a".to_owned());
+        assert_eq!(SpanContext::extract(&properties), None);
+
+        // Uppercase digits are accepted outside of `traceparent`
+        properties.insert(TRACE_ID_KEY.to_owned(), "2A".to_owned());
+        properties.insert(SPAN_ID_KEY.to_owned(), "2B".to_owned());
+        assert_eq!(
+            SpanContext::extract(&properties),
+            Some(SpanContext::new(0x2a, 0x2b))
+        );
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A versioned encoding of a trace context for persistence, e.g. in the meta of a DDL job, so
+//! that the trace can be resumed after a process restart.
+//!
+//! Version 2 is `2:<trace id>:<span id>:<flags>:<baggage>`, with ids and flags in hex and the
+//! baggage as comma separated `key=value` pairs whose `%`, `:`, `,` and `=` are percent-encoded.
+//! Version 1 is the same with a 64-bit trace id. An encoding, once released, is never changed:
+//! new fields come with a new version, and older versions stay decodable.
+
+use crate::propagation::{decode_hex_u128, decode_hex_u64, is_hex, SpanContext};
+
+const VERSION: &str = "2";
+const FLAG_SAMPLED: u8 = 0x01;
+
+/// A trace context to persist across restarts.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::propagation::{SpanContext, TraceContext};
+///
+/// let context = TraceContext::new(SpanContext::new(42, 7)).with_baggage("job id", "113");
+/// let encoded = context.encode();
+///
+/// // After a restart, continue the trace with
+/// // `Span::root_with_trace_id(event, context.span_context.trace_id)`, reporting
+/// // `context.span_context.span_id` as the parent of its root span.
+/// assert_eq!(TraceContext::decode(&encoded), Some(context));
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct TraceContext {
+    pub span_context: SpanContext,
+    pub sampled: bool,
+    pub baggage: Vec<(String, String)>,
+}
+
+impl TraceContext {
+    pub fn new(span_context: SpanContext) -> Self {
+        TraceContext {
+            span_context,
+            sampled: true,
+            baggage: Vec::new(),
+        }
+    }
+
+    pub fn sampled(self, sampled: bool) -> Self {
+        Self { sampled, ..self }
+    }
+
+    pub fn with_baggage(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.baggage.push((key.into(), value.into()));
+        self
+    }
+
+    /// Encode the context with the latest version.
+    pub fn encode(&self) -> String {
+        let flags = if self.sampled { FLAG_SAMPLED } else { 0 };
+        let baggage: Vec<_> = self
+            .baggage
+            .iter()
+            .map(|(k, v)| format!("{}={}", escape(k), escape(v)))
+            .collect();
+        format!(
+            "{}:{:032x}:{:016x}:{:02x}:{}",
+            VERSION,
+            self.span_context.trace_id,
+            self.span_context.span_id,
+            flags,
+            baggage.join(",")
+        )
+    }
+
+    /// Decode a context encoded by any version. Returns `None` if the version is unknown or the
+    /// context is malformed.
+    pub fn decode(encoded: &str) -> Option<Self> {
+        let mut parts = encoded.trim().split(':');
+        let version = parts.next()?;
+        match version {
+            "1" | "2" => {
+                let trace_id = parts.next()?.as_bytes();
+                let trace_id = if version == "1" {
+                    decode_hex_u64(trace_id, false)? as u128
+                } else {
+                    decode_hex_u128(trace_id, false)?
+                };
+                let span_id = decode_hex_u64(parts.next()?.as_bytes(), false)?;
+                let flags = parts.next()?;
+                if !is_hex(flags.as_bytes(), false) {
+                    return None;
+                }
+                let flags = u8::from_str_radix(flags, 16).ok()?;
+                let baggage = parts.next()?;
+                if parts.next().is_some() {
+                    return None;
+                }
+
+                let baggage = if baggage.is_empty() {
+                    Vec::new()
+                } else {
+                    baggage
+                        .split(',')
+                        .map(|pair| {
+                            let mut kv = pair.split('=');
+                            let key = unescape(kv.next()?)?;
+                            let value = unescape(kv.next()?)?;
+                            if kv.next().is_some() {
+                                return None;
+                            }
+                            Some((key, value))
+                        })
+                        .collect::<Option<_>>()?
+                };
+
+                Some(TraceContext {
+                    span_context: SpanContext::new(trace_id, span_id),
+                    sampled: flags & FLAG_SAMPLED != 0,
+                    baggage,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' | ':' | ',' | '=' => res.push_str(&format!("%{:02X}", c as u8)),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+fn unescape(s: &str) -> Option<String> {
+    let mut res = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            res.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            res.push(b);
+        }
+    }
+    String::from_utf8(res).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let context = TraceContext::new(SpanContext::new(0xff << 64 | 0x1234_5678_9abc_def0, 42))
+            .sampled(false)
+            .with_baggage("k:1", "a=b,c%d")
+            .with_baggage("empty", "");
+        let encoded = context.encode();
+
+        assert_eq!(
+            encoded,
+            "2:00000000000000ff123456789abcdef0:000000000000002a:00:k%3A1=a%3Db%2Cc%25d,empty="
+        );
+        assert_eq!(TraceContext::decode(&encoded), Some(context));
+    }
+
+    #[test]
+    fn decode_stable() {
+        // Encodings persisted by released versions must stay decodable.
+        assert_eq!(
+            TraceContext::decode("1:0000000000000007:0000000000000008:01:"),
+            Some(TraceContext::new(SpanContext::new(7, 8)))
+        );
+
+        assert_eq!(
+            TraceContext::decode("2:00000000000000070000000000000009:0000000000000008:01:"),
+            Some(TraceContext::new(SpanContext::new(7 << 64 | 9, 8)))
+        );
+
+        assert_eq!(TraceContext::decode("3:7:8:01:"), None);
+        assert_eq!(
+            TraceContext::decode("1:00000000000000070000000000000009:8:01:"),
+            None
+        );
+        assert_eq!(TraceContext::decode("1:7:8:01"), None);
+        assert_eq!(TraceContext::decode("1:7:8:01:k"), None);
+        assert_eq!(TraceContext::decode("1:7:8:01:k=%2"), None);
+        assert_eq!(TraceContext::decode("1:+7:8:01:"), None);
+        assert_eq!(TraceContext::decode("1:7:+8:01:"), None);
+        assert_eq!(TraceContext::decode("1:7:8:+1:"), None);
+    }
+}
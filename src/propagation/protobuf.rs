@@ -0,0 +1,142 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Codec for protobuf messages, for internal RPC protocols without metadata, e.g. raft messages
+//! or custom TCP protocols, which reserve a `bytes` field for the context.
+//!
+//! The context is encoded as the message
+//!
+//! ```protobuf
+//! message SpanContext {
+//!     // The lower 64 bits of the trace id
+//!     fixed64 trace_id = 1;
+//!     fixed64 span_id = 2;
+//!     // The higher 64 bits of the trace id, omitted if zero
+//!     fixed64 trace_id_high = 3;
+//! }
+//! ```
+//!
+//! Unknown fields are skipped on decoding, so fields may be added later.
+
+use crate::propagation::SpanContext;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+impl SpanContext {
+    /// Encode the context as a `SpanContext` message, e.g. to store in a reserved `bytes` field.
+    pub fn to_protobuf(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(27);
+        varint(&mut buf, 1 << 3 | WIRE_FIXED64);
+        buf.extend_from_slice(&(self.trace_id as u64).to_le_bytes());
+        varint(&mut buf, 2 << 3 | WIRE_FIXED64);
+        buf.extend_from_slice(&self.span_id.to_le_bytes());
+        let trace_id_high = (self.trace_id >> 64) as u64;
+        if trace_id_high != 0 {
+            varint(&mut buf, 3 << 3 | WIRE_FIXED64);
+            buf.extend_from_slice(&trace_id_high.to_le_bytes());
+        }
+        buf
+    }
+
+    /// Decode a `SpanContext` message. Returns `None` if it's malformed or lacks an id.
+    pub fn from_protobuf(mut buf: &[u8]) -> Option<Self> {
+        let mut trace_id = None;
+        let mut span_id = None;
+        let mut trace_id_high = 0;
+        while !buf.is_empty() {
+            let (field, value) = next_field(&mut buf)?;
+            match (field, value) {
+                (1, Value::Fixed64(v)) => trace_id = Some(v),
+                (2, Value::Fixed64(v)) => span_id = Some(v),
+                (3, Value::Fixed64(v)) => trace_id_high = v,
+                _ => {}
+            }
+        }
+        let trace_id = (trace_id_high as u128) << 64 | trace_id? as u128;
+        Some(SpanContext::new(trace_id, span_id?))
+    }
+
+    /// Append the context to an encoded message as its `bytes` or `SpanContext` field `field`.
+    pub fn inject_protobuf(&self, message: &mut Vec<u8>, field: u32) {
+        let context = self.to_protobuf();
+        varint(message, (field as u64) << 3 | WIRE_LEN);
+        varint(message, context.len() as u64);
+        message.extend_from_slice(&context);
+    }
+
+    /// Read the context from the field `field` of an encoded message. The last occurrence of the
+    /// field wins, as protobuf merges repeated singular fields.
+    pub fn extract_protobuf(mut message: &[u8], field: u32) -> Option<Self> {
+        let mut context = None;
+        while !message.is_empty() {
+            match next_field(&mut message)? {
+                (f, Value::Len(bytes)) if f == field as u64 => context = Some(bytes),
+                _ => {}
+            }
+        }
+        Self::from_protobuf(context?)
+    }
+}
+
+enum Value<'a> {
+    Other,
+    Fixed64(u64),
+    Len(&'a [u8]),
+}
+
+fn next_field<'a>(buf: &mut &'a [u8]) -> Option<(u64, Value<'a>)> {
+    let key = read_varint(buf)?;
+    let value = match key & 7 {
+        WIRE_VARINT => {
+            read_varint(buf)?;
+            Value::Other
+        }
+        WIRE_FIXED64 => {
+            let mut v = [0; 8];
+            v.copy_from_slice(take(buf, 8)?);
+            Value::Fixed64(u64::from_le_bytes(v))
+        }
+        WIRE_LEN => {
+            let len = read_varint(buf)?;
+            Value::Len(take(buf, len as usize)?)
+        }
+        WIRE_FIXED32 => {
+            take(buf, 4)?;
+            Value::Other
+        }
+        _ => return None,
+    };
+    Some((key >> 3, value))
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (taken, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(taken)
+}
+
+fn varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut v = 0;
+    for shift in (0..64).step_by(7) {
+        let (&b, rest) = buf.split_first()?;
+        *buf = rest;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b < 0x80 {
+            return Some(v);
+        }
+    }
+    None
+}
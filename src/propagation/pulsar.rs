@@ -0,0 +1,21 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Codec for Pulsar message properties, which are a map of UTF-8 strings.
+
+use std::collections::HashMap;
+
+use crate::propagation::{Extractor, Injector};
+
+pub type Properties = HashMap<String, String>;
+
+impl Injector for Properties {
+    fn set(&mut self, key: &str, value: &[u8]) {
+        self.insert(key.to_owned(), String::from_utf8_lossy(value).into_owned());
+    }
+}
+
+impl Extractor for Properties {
+    fn get(&self, key: &str) -> Option<&[u8]> {
+        HashMap::get(self, key).map(|v| v.as_bytes())
+    }
+}
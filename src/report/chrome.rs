@@ -0,0 +1,204 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Exporting traces in the [Chrome Trace Event format], to be opened in `chrome://tracing` or
+//! [Perfetto](https://ui.perfetto.dev) for local debugging without a tracing backend.
+//!
+//! Each trace is shown as a process named after its trace id. As the viewers expect the events
+//! of a thread to nest, the spans of a trace are spread over as few threads as possible, such
+//! that concurrent spans are on different threads.
+//!
+//! [Chrome Trace Event format]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::report::file::{write_properties, write_str};
+use crate::report::{ReportError, Reporter};
+use crate::span::Span;
+
+/// Encode the traces as a JSON array of trace events.
+pub fn encode(traces: &[(u128, Vec<Span>)]) -> String {
+    let mut out = String::from("[\n");
+    for (trace_id, spans) in traces {
+        write_events(&mut out, *trace_id, spans);
+    }
+    // The separator after the last event
+    if out.ends_with(",\n") {
+        out.truncate(out.len() - 2);
+        out.push('\n');
+    }
+    out.push_str("]\n");
+    out
+}
+
+/// A [`Reporter`] appending traces to a file of trace events, which can be opened at any time
+/// as the closing bracket of the array is optional in the format.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::report::chrome::ChromeReporter;
+/// use minitrace::report::ReportPipeline;
+///
+/// let reporter = ChromeReporter::new(std::env::temp_dir().join("trace.json"));
+/// let pipeline = ReportPipeline::new(reporter).spawn();
+/// ```
+pub struct ChromeReporter {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+}
+
+impl ChromeReporter {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        ChromeReporter {
+            path: path.into(),
+            file: Mutex::new(None),
+        }
+    }
+
+    fn write(&self, trace_id: u128, spans: &[Span]) -> io::Result<()> {
+        let mut events = String::new();
+        write_events(&mut events, trace_id, spans);
+
+        let mut file = self.file.lock().unwrap();
+        let file = match &mut *file {
+            Some(file) => file,
+            None => {
+                let mut opened = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?;
+                if opened.metadata()?.len() == 0 {
+                    opened.write_all(b"[\n")?;
+                }
+                file.get_or_insert(opened)
+            }
+        };
+        file.write_all(events.as_bytes())
+    }
+}
+
+impl Reporter for ChromeReporter {
+    fn report(&self, trace_id: u128, spans: &[Span]) -> Result<(), ReportError> {
+        self.write(trace_id, spans).map_err(ReportError::new)
+    }
+}
+
+// Write the events of the trace, each followed by a separator
+fn write_events(out: &mut String, trace_id: u128, spans: &[Span]) {
+    // Unique enough among the traces opened together
+    let pid = trace_id as u32;
+    let _ = writeln!(
+        out,
+        "{{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":{},\"args\":{{\"name\":\"trace {:032x}\"}}}},",
+        pid, trace_id
+    );
+
+    let tids = assign_threads(spans);
+    for (span, tid) in spans.iter().zip(tids) {
+        out.push_str("{\"name\":");
+        write_str(out, span.event);
+        out.push_str(",\"cat\":\"minitrace\",\"ph\":\"X\",\"ts\":");
+        write_us(out, span.begin_unix_time_ns);
+        out.push_str(",\"dur\":");
+        write_us(out, span.duration_ns);
+        let _ = write!(out, ",\"pid\":{},\"tid\":{},\"args\":", pid, tid);
+        write_properties(out, &span.properties);
+        out.push_str("},\n");
+    }
+}
+
+// Timestamps are in microseconds
+fn write_us(out: &mut String, ns: u64) {
+    let _ = write!(out, "{}.{:03}", ns / 1000, ns % 1000);
+}
+
+// The thread of each span, from 1, such that the spans of a thread nest, preferring the thread
+// of the parent
+pub(super) fn assign_threads(spans: &[Span]) -> Vec<u32> {
+    let mut order: Vec<usize> = (0..spans.len()).collect();
+    // Parents before their children
+    order.sort_by_key(|&i| (spans[i].begin_unix_time_ns, Reverse(spans[i].duration_ns)));
+
+    // The ends of the spans open on each thread, the innermost last
+    let mut threads: Vec<Vec<u64>> = Vec::new();
+    let mut thread_of: HashMap<u32, usize> = HashMap::new();
+    let mut tids = vec![0; spans.len()];
+    for i in order {
+        let begin = spans[i].begin_unix_time_ns;
+        let end = begin.saturating_add(spans[i].duration_ns);
+        let fits = |open: &mut Vec<u64>| {
+            while matches!(open.last(), Some(&open_end) if open_end <= begin) {
+                open.pop();
+            }
+            match open.last() {
+                Some(&open_end) => end <= open_end,
+                None => true,
+            }
+        };
+        let thread = match thread_of.get(&spans[i].parent_id) {
+            Some(&parent) if fits(&mut threads[parent]) => parent,
+            _ => match threads.iter_mut().position(fits) {
+                Some(thread) => thread,
+                None => {
+                    threads.push(Vec::new());
+                    threads.len() - 1
+                }
+            },
+        };
+        threads[thread].push(end);
+        thread_of.insert(spans[i].id, thread);
+        tids[i] = thread as u32 + 1;
+    }
+    tids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::PropertyValue;
+
+    fn span(id: u32, parent_id: u32, begin: u64, duration: u64) -> Span {
+        Span {
+            id,
+            parent_id,
+            begin_unix_time_ns: begin,
+            duration_ns: duration,
+            event: "event",
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn encode_events() {
+        let spans = vec![
+            Span {
+                event: "root",
+                properties: vec![("n", PropertyValue::I64(1))],
+                ..span(1, 0, 1000, 10_000)
+            },
+            // Concurrent children
+            span(2, 1, 2000, 5000),
+            span(3, 1, 3000, 5000),
+            span(4, 3, 4000, 1000),
+            // After the first child
+            span(5, 1, 7500, 500),
+        ];
+        assert_eq!(assign_threads(&spans), vec![1, 1, 2, 2, 1]);
+
+        let json = encode(&[(42, spans)]);
+        assert!(json.starts_with(
+            "[\n{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":42,\
+             \"args\":{\"name\":\"trace 0000000000000000000000000000002a\"}},\n\
+             {\"name\":\"root\",\"cat\":\"minitrace\",\"ph\":\"X\",\"ts\":1.000,\
+             \"dur\":10.000,\"pid\":42,\"tid\":1,\"args\":{\"n\":1}},\n"
+        ));
+        assert!(json.ends_with("\"tid\":1,\"args\":{}}\n]\n"));
+        assert_eq!(encode(&[]), "[\n]\n");
+    }
+}
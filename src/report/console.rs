@@ -0,0 +1,229 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::report::{ReportError, Reporter};
+use crate::span::Span;
+
+const DIM: &str = "\x1b[2m";
+const BOLD: &str = "\x1b[1m";
+const CYAN: &str = "\x1b[36m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+/// A [`Reporter`] printing traces to the standard output as trees, for local development.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::report::{ConsoleReporter, ReportPipeline};
+/// use std::time::Duration;
+///
+/// let reporter = ConsoleReporter::new()
+///     .root_event("http *")
+///     .min_duration(Duration::from_millis(10));
+/// let pipeline = ReportPipeline::new(reporter).spawn();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConsoleReporter {
+    root_event: Option<String>,
+    min_duration: Duration,
+    colored: bool,
+}
+
+impl Default for ConsoleReporter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConsoleReporter {
+    pub fn new() -> Self {
+        ConsoleReporter {
+            root_event: None,
+            min_duration: Duration::from_secs(0),
+            colored: true,
+        }
+    }
+
+    /// Print only the traces whose root event matches `pattern`, where `*` matches any
+    /// characters and `?` matches one.
+    pub fn root_event(self, pattern: impl Into<String>) -> Self {
+        Self {
+            root_event: Some(pattern.into()),
+            ..self
+        }
+    }
+
+    /// Print only the traces whose root span lasts at least `min_duration`.
+    pub fn min_duration(self, min_duration: Duration) -> Self {
+        Self {
+            min_duration,
+            ..self
+        }
+    }
+
+    /// Whether to color the output with ANSI escape codes. Defaults to true.
+    pub fn colored(self, colored: bool) -> Self {
+        Self { colored, ..self }
+    }
+
+    // The tree of the trace, or `None` if it's filtered out
+    fn format(&self, trace_id: u128, spans: &[Span]) -> Option<String> {
+        let ids: HashSet<u32> = spans.iter().map(|s| s.id).collect();
+        let mut children: HashMap<u32, Vec<&Span>> = HashMap::new();
+        let mut roots = Vec::new();
+        for span in spans {
+            if span.parent_id != 0 && ids.contains(&span.parent_id) {
+                children.entry(span.parent_id).or_default().push(span);
+            } else {
+                roots.push(span);
+            }
+        }
+        for siblings in children.values_mut() {
+            siblings.sort_by_key(|s| s.begin_unix_time_ns);
+        }
+        roots.sort_by_key(|s| s.begin_unix_time_ns);
+
+        let root = roots
+            .iter()
+            .find(|s| s.parent_id == 0)
+            .or_else(|| roots.first())?;
+        if let Some(pattern) = &self.root_event {
+            if !glob_match(pattern.as_bytes(), root.event.as_bytes()) {
+                return None;
+            }
+        }
+        if Duration::from_nanos(root.duration_ns) < self.min_duration {
+            return None;
+        }
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{}trace {:032x}{} ({} spans)",
+            self.paint(BOLD),
+            trace_id,
+            self.paint(RESET),
+            spans.len()
+        );
+        for root in roots {
+            self.format_subtree(root, root.begin_unix_time_ns, &children, "", &mut out);
+        }
+        Some(out)
+    }
+
+    fn format_subtree(
+        &self,
+        span: &Span,
+        trace_begin: u64,
+        children: &HashMap<u32, Vec<&Span>>,
+        prefix: &str,
+        out: &mut String,
+    ) {
+        let _ = write!(
+            out,
+            "{}{}{}{} {}{:?}{} {}+{:?}{}",
+            prefix,
+            self.paint(CYAN),
+            span.event,
+            self.paint(RESET),
+            self.paint(YELLOW),
+            Duration::from_nanos(span.duration_ns),
+            self.paint(RESET),
+            self.paint(DIM),
+            Duration::from_nanos(span.begin_unix_time_ns.saturating_sub(trace_begin)),
+            self.paint(RESET),
+        );
+        for (k, v) in &span.properties {
+            let _ = write!(out, " {}={}", k, v);
+        }
+        out.push('\n');
+
+        if let Some(spans) = children.get(&span.id) {
+            let child_prefix = format!("{}  ", prefix);
+            for child in spans {
+                self.format_subtree(child, trace_begin, children, &child_prefix, out);
+            }
+        }
+    }
+
+    fn paint(&self, code: &'static str) -> &'static str {
+        if self.colored {
+            code
+        } else {
+            ""
+        }
+    }
+}
+
+impl Reporter for ConsoleReporter {
+    fn report(&self, trace_id: u128, spans: &[Span]) -> Result<(), ReportError> {
+        if let Some(out) = self.format(trace_id, spans) {
+            print!("{}", out);
+        }
+        Ok(())
+    }
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.split_first(), text.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            glob_match(rest, text) || (!text.is_empty() && glob_match(pattern, &text[1..]))
+        }
+        (Some((b'?', rest)), Some((_, text))) => glob_match(rest, text),
+        (Some((p, rest)), Some((t, text))) => p == t && glob_match(rest, text),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(id: u32, parent_id: u32, event: &'static str, duration_ns: u64) -> Span {
+        Span {
+            id,
+            parent_id,
+            begin_unix_time_ns: id as u64,
+            duration_ns,
+            event,
+            properties: vec![],
+            events: vec![],
+        }
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match(b"http *", b"http GET"));
+        assert!(glob_match(b"*get?", b"kv_get1"));
+        assert!(!glob_match(b"http *", b"grpc"));
+        assert!(glob_match(b"*", b""));
+    }
+
+    #[test]
+    fn format() {
+        let spans = vec![
+            span(1, 0, "http GET", 2_000_000),
+            span(2, 1, "db", 1_000_000),
+        ];
+        let reporter = ConsoleReporter::new().colored(false);
+
+        assert_eq!(
+            reporter.format(42, &spans).unwrap(),
+            "trace 0000000000000000000000000000002a (2 spans)\nhttp GET 2ms +0ns\n  db 1ms +1ns\n"
+        );
+        assert!(reporter
+            .clone()
+            .root_event("grpc *")
+            .format(42, &spans)
+            .is_none());
+        assert!(reporter
+            .min_duration(Duration::from_millis(3))
+            .format(42, &spans)
+            .is_none());
+    }
+}
@@ -0,0 +1,398 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::report::{ReportError, Reporter};
+use crate::span::{PropertyValue, Span};
+
+/// A [`Reporter`] appending traces to a file as JSON lines, for collecting traces to be
+/// ingested offline.
+///
+/// The file is rotated when it grows beyond a size or gets older than an interval: `traces.jsonl`
+/// is renamed to `traces.jsonl.1`, `traces.jsonl.1` to `traces.jsonl.2` and so on, and the
+/// oldest one beyond [`max_files`](FileReporter::max_files) is removed.
+///
+/// Each line is a trace, e.g.
+///
+/// ```text
+/// {"trace_id":"0000000000000000000000000000002a","spans":[{"id":1,"parent_id":0,"begin_unix_time_ns":1,"duration_ns":2,"event":"root","properties":{"k":"v"}}]}
+/// ```
+///
+/// or a span, tagged with the trace id, if [`per_span`](FileReporter::per_span) is set.
+///
+/// With a [`resolution`](FileReporter::resolution), the times are written in units of the
+/// resolution to shrink the lines: the `begin` of a span is the difference from the `begin` of
+/// the previous span, and the first one from `base_unix_time_ns`, the earliest begin time of the
+/// trace, e.g. with a resolution of 10µs
+///
+/// ```text
+/// {"trace_id":"0000000000000000000000000000002a","resolution_ns":10000,"base_unix_time_ns":1000,"spans":[{"id":1,"parent_id":0,"begin":0,"duration":25,"event":"root","properties":{}},{"id":2,"parent_id":1,"begin":3,"duration":12,"event":"get","properties":{}}]}
+/// ```
+///
+/// Per span, the `begin` is the begin time in units of the resolution instead.
+pub struct FileReporter {
+    path: PathBuf,
+    max_file_size: u64,
+    rotate_interval: Option<Duration>,
+    max_files: usize,
+    per_span: bool,
+    resolution_ns: Option<u64>,
+
+    file: Mutex<Option<OpenFile>>,
+}
+
+struct OpenFile {
+    file: File,
+    size: u64,
+    opened_at: Instant,
+}
+
+impl FileReporter {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileReporter {
+            path: path.into(),
+            max_file_size: 64 * 1024 * 1024,
+            rotate_interval: None,
+            max_files: 5,
+            per_span: false,
+            resolution_ns: None,
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Rotate the file once it exceeds `max_file_size` bytes. Defaults to 64 MiB.
+    pub fn max_file_size(self, max_file_size: u64) -> Self {
+        Self {
+            max_file_size,
+            ..self
+        }
+    }
+
+    /// Rotate the file once it has been written for `rotate_interval`. Disabled by default.
+    pub fn rotate_interval(self, rotate_interval: Duration) -> Self {
+        Self {
+            rotate_interval: Some(rotate_interval),
+            ..self
+        }
+    }
+
+    /// The number of rotated files kept besides the file being written. Defaults to 5.
+    pub fn max_files(self, max_files: usize) -> Self {
+        Self { max_files, ..self }
+    }
+
+    /// Write a line per span instead of per trace.
+    pub fn per_span(self, per_span: bool) -> Self {
+        Self { per_span, ..self }
+    }
+
+    /// Round the times to multiples of `resolution`, e.g. 10µs, for backends billed by the
+    /// byte. The end times are rounded rather than the durations, so that the spans stay within
+    /// their parents. Disabled by default.
+    pub fn resolution(self, resolution: Duration) -> Self {
+        Self {
+            resolution_ns: Some((resolution.as_nanos() as u64).max(1)),
+            ..self
+        }
+    }
+
+    fn write(&self, trace_id: u128, spans: &[Span]) -> io::Result<()> {
+        let mut lines = String::new();
+        if let Some(resolution_ns) = self.resolution_ns {
+            write_quantized(&mut lines, trace_id, spans, resolution_ns, self.per_span);
+        } else if self.per_span {
+            for span in spans {
+                lines.push_str("{\"trace_id\":");
+                write_trace_id(&mut lines, trace_id);
+                lines.push(',');
+                write_span_fields(&mut lines, span);
+                lines.push_str("}\n");
+            }
+        } else {
+            lines.push_str("{\"trace_id\":");
+            write_trace_id(&mut lines, trace_id);
+            lines.push_str(",\"spans\":[");
+            for (i, span) in spans.iter().enumerate() {
+                if i > 0 {
+                    lines.push(',');
+                }
+                lines.push('{');
+                write_span_fields(&mut lines, span);
+                lines.push('}');
+            }
+            lines.push_str("]}\n");
+        }
+
+        let mut file = self.file.lock().unwrap();
+        if let Some(open) = &*file {
+            let expired = matches!(self.rotate_interval,
+                Some(interval) if open.opened_at.elapsed() >= interval);
+            if open.size >= self.max_file_size || expired {
+                *file = None;
+                self.rotate()?;
+            }
+        }
+        let open = match &mut *file {
+            Some(open) => open,
+            None => file.get_or_insert(self.open()?),
+        };
+
+        open.file.write_all(lines.as_bytes())?;
+        open.size += lines.len() as u64;
+        Ok(())
+    }
+
+    fn open(&self) -> io::Result<OpenFile> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        Ok(OpenFile {
+            size: file.metadata()?.len(),
+            file,
+            opened_at: Instant::now(),
+        })
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        if self.max_files == 0 {
+            return fs::remove_file(&self.path);
+        }
+
+        remove_if_exists(&self.rotated_path(self.max_files))?;
+        for i in (1..self.max_files).rev() {
+            rename_if_exists(&self.rotated_path(i), &self.rotated_path(i + 1))?;
+        }
+        rename_if_exists(&self.path, &self.rotated_path(1))
+    }
+
+    fn rotated_path(&self, i: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", i));
+        path.into()
+    }
+}
+
+impl Reporter for FileReporter {
+    fn report(&self, trace_id: u128, spans: &[Span]) -> Result<(), ReportError> {
+        self.write(trace_id, spans).map_err(ReportError::new)
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn write_quantized(
+    out: &mut String,
+    trace_id: u128,
+    spans: &[Span],
+    resolution_ns: u64,
+    per_span: bool,
+) {
+    let base = if per_span {
+        0
+    } else {
+        spans
+            .iter()
+            .map(|s| s.begin_unix_time_ns)
+            .min()
+            .unwrap_or(0)
+    };
+    // The nearest multiple of the resolution, relative to the base
+    let quantize = |ns: u64| (ns - base).saturating_add(resolution_ns / 2) / resolution_ns;
+
+    if !per_span {
+        out.push_str("{\"trace_id\":");
+        write_trace_id(out, trace_id);
+        let _ = write!(
+            out,
+            ",\"resolution_ns\":{},\"base_unix_time_ns\":{},\"spans\":[",
+            resolution_ns, base
+        );
+    }
+    let mut prev_begin = 0;
+    for (i, span) in spans.iter().enumerate() {
+        let begin = quantize(span.begin_unix_time_ns);
+        let end = quantize(span.begin_unix_time_ns.saturating_add(span.duration_ns));
+        if per_span {
+            out.push_str("{\"trace_id\":");
+            write_trace_id(out, trace_id);
+            let _ = write!(out, ",\"resolution_ns\":{},", resolution_ns);
+            let _ = write!(out, "\"id\":{},\"parent_id\":{},", span.id, span.parent_id);
+            let _ = write!(out, "\"begin\":{},\"duration\":{}", begin, end - begin);
+        } else {
+            if i > 0 {
+                out.push(',');
+            }
+            let delta = begin as i64 - prev_begin as i64;
+            prev_begin = begin;
+            let _ = write!(
+                out,
+                "{{\"id\":{},\"parent_id\":{},",
+                span.id, span.parent_id
+            );
+            let _ = write!(out, "\"begin\":{},\"duration\":{}", delta, end - begin);
+        }
+        out.push_str(",\"event\":");
+        write_str(out, span.event);
+        out.push_str(",\"properties\":");
+        write_properties(out, &span.properties);
+        out.push('}');
+        if per_span {
+            out.push('\n');
+        }
+    }
+    if !per_span {
+        out.push_str("]}\n");
+    }
+}
+
+fn write_trace_id(out: &mut String, trace_id: u128) {
+    let _ = write!(out, "\"{:032x}\"", trace_id);
+}
+
+fn write_span_fields(out: &mut String, span: &Span) {
+    let _ = write!(
+        out,
+        "\"id\":{},\"parent_id\":{},\"begin_unix_time_ns\":{},\"duration_ns\":{},\"event\":",
+        span.id, span.parent_id, span.begin_unix_time_ns, span.duration_ns
+    );
+    write_str(out, span.event);
+    out.push_str(",\"properties\":");
+    write_properties(out, &span.properties);
+}
+
+pub(super) fn write_properties(out: &mut String, properties: &[(&'static str, PropertyValue)]) {
+    out.push('{');
+    for (i, (k, v)) in properties.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_str(out, k);
+        out.push(':');
+        match v {
+            PropertyValue::String(s) => write_str(out, s),
+            PropertyValue::I64(v) => {
+                let _ = write!(out, "{}", v);
+            }
+            PropertyValue::F64(v) if v.is_finite() => {
+                let _ = write!(out, "{}", v);
+            }
+            PropertyValue::F64(_) => out.push_str("null"),
+            PropertyValue::Bool(v) => {
+                let _ = write!(out, "{}", v);
+            }
+            PropertyValue::Binary(_) => write_str(out, &v.to_text()),
+        }
+    }
+    out.push('}');
+}
+
+pub(super) fn write_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate() {
+        let dir = std::env::temp_dir().join(format!("minitrace-file-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("traces.jsonl");
+
+        let span = Span {
+            id: 1,
+            event: "say \"hi\"",
+            properties: vec![("n", PropertyValue::I64(1)), ("s", "a\nb".into())],
+            ..Default::default()
+        };
+        let reporter = FileReporter::new(&path).max_file_size(1).max_files(2);
+        for trace_id in 0..4 {
+            reporter
+                .report(trace_id, std::slice::from_ref(&span))
+                .unwrap();
+        }
+
+        // Each trace exceeds the size, so only the last three are kept
+        let line = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            line,
+            "{\"trace_id\":\"00000000000000000000000000000003\",\"spans\":[{\"id\":1,\"parent_id\":0,\
+             \"begin_unix_time_ns\":0,\"duration_ns\":0,\"event\":\"say \\\"hi\\\"\",\
+             \"properties\":{\"n\":1,\"s\":\"a\\nb\"}}]}\n"
+        );
+        assert!(dir.join("traces.jsonl.2").exists());
+        assert!(!dir.join("traces.jsonl.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resolution() {
+        let span = |id, parent_id, begin, duration| Span {
+            id,
+            parent_id,
+            begin_unix_time_ns: begin,
+            duration_ns: duration,
+            event: "e",
+            ..Default::default()
+        };
+        let spans = [
+            span(1, 0, 1_000, 250_000),
+            span(2, 1, 31_000, 116_000),
+            span(3, 1, 4_000, 2_000),
+        ];
+
+        let mut line = String::new();
+        write_quantized(&mut line, 42, &spans, 10_000, false);
+        assert_eq!(
+            line,
+            "{\"trace_id\":\"0000000000000000000000000000002a\",\"resolution_ns\":10000,\"base_unix_time_ns\":1000,\
+             \"spans\":[{\"id\":1,\"parent_id\":0,\"begin\":0,\"duration\":25,\"event\":\"e\",\
+             \"properties\":{}},{\"id\":2,\"parent_id\":1,\"begin\":3,\"duration\":12,\"event\":\"e\",\
+             \"properties\":{}},{\"id\":3,\"parent_id\":1,\"begin\":-3,\"duration\":1,\"event\":\"e\",\
+             \"properties\":{}}]}\n"
+        );
+
+        let mut lines = String::new();
+        write_quantized(&mut lines, 42, &spans[..1], 10_000, true);
+        assert_eq!(
+            lines,
+            "{\"trace_id\":\"0000000000000000000000000000002a\",\"resolution_ns\":10000,\"id\":1,\"parent_id\":0,\
+             \"begin\":0,\"duration\":25,\"event\":\"e\",\"properties\":{}}\n"
+        );
+    }
+}
@@ -0,0 +1,587 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Reporting collected traces from a background thread, retrying failed reports with
+//! exponential backoff.
+//!
+//! Traces are either submitted once collected, or submitted as [`Collector`]s which the thread
+//! collects in batches, once every interval, when their spans have finished.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use minitrace::report::{ReportError, ReportPipeline, Reporter};
+//! use minitrace::span::Span as SpanRecord;
+//! use minitrace::Span;
+//!
+//! struct Stdout;
+//!
+//! impl Reporter for Stdout {
+//!     fn report(&self, trace_id: u128, spans: &[SpanRecord]) -> Result<(), ReportError> {
+//!         println!("trace {:032x}: {} spans", trace_id, spans.len());
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let pipeline = ReportPipeline::new(Stdout).max_retries(3).spawn();
+//!
+//! let (root_span, collector) = Span::root("root");
+//! drop(root_span);
+//! pipeline.submit(collector.trace_id(), collector.collect());
+//!
+//! // Or leave the collecting to the reporting thread
+//! let (root_span, collector) = Span::root("root");
+//! pipeline.submit_collector(collector);
+//! drop(root_span);
+//! ```
+
+pub mod chrome;
+mod console;
+mod file;
+pub mod speedscope;
+
+pub use self::console::ConsoleReporter;
+pub use self::file::FileReporter;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender, TrySendError};
+
+use crate::local::local_span_line::with_children_suppressed;
+use crate::span::Span;
+use crate::trace::collector::CollectArgs;
+use crate::{Collector, LocalSpan};
+
+/// The event of the root spans of the traces about reporting, see
+/// [`ReportPipeline::trace_self`].
+pub const REPORT_EVENT: &str = "minitrace.report";
+
+/// A destination of collected traces, e.g. a Jaeger agent.
+pub trait Reporter: Send + 'static {
+    /// Report the spans of the trace identified by `trace_id`. Failures marked as retryable
+    /// are retried by [`ReportPipeline`].
+    fn report(&self, trace_id: u128, spans: &[Span]) -> Result<(), ReportError>;
+}
+
+#[derive(Debug)]
+pub struct ReportError {
+    source: Box<dyn Error + Send + Sync + 'static>,
+    retryable: bool,
+}
+
+impl ReportError {
+    /// A failure which may be transient, e.g. an unreachable agent, so the report is retried.
+    pub fn new(source: impl Into<Box<dyn Error + Send + Sync + 'static>>) -> Self {
+        ReportError {
+            source: source.into(),
+            retryable: true,
+        }
+    }
+
+    /// A failure which would happen again, e.g. a batch too large to encode, so the report
+    /// isn't retried.
+    pub fn permanent(source: impl Into<Box<dyn Error + Send + Sync + 'static>>) -> Self {
+        ReportError {
+            source: source.into(),
+            retryable: false,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.retryable
+    }
+}
+
+impl fmt::Display for ReportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to report trace: {}", self.source)
+    }
+}
+
+impl Error for ReportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+type DeadLetter = Box<dyn Fn(u128, Vec<Span>, &ReportError) + Send>;
+
+enum Job {
+    Trace(u128, Vec<Span>),
+    Collector(Collector),
+}
+
+// The state shared by a reporting thread and its handle
+#[derive(Default)]
+struct Shared {
+    // The jobs dropped since the queue was full
+    dropped: AtomicU64,
+    // Set once the handle stopped waiting for the thread to shut down, to stop retrying
+    abandoned: AtomicBool,
+}
+
+/// The configuration of the background thread reporting traces to a [`Reporter`].
+pub struct ReportPipeline {
+    reporter: Box<dyn Reporter>,
+    max_retries: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    dead_letter: Option<DeadLetter>,
+    trace_self: bool,
+    interval: Duration,
+    collect_timeout: Duration,
+    queue_capacity: usize,
+    shutdown_timeout: Duration,
+    shared: Arc<Shared>,
+}
+
+impl ReportPipeline {
+    pub fn new(reporter: impl Reporter) -> Self {
+        ReportPipeline {
+            reporter: Box::new(reporter),
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            dead_letter: None,
+            trace_self: false,
+            interval: Duration::from_secs(1),
+            collect_timeout: Duration::from_secs(60),
+            queue_capacity: 1024,
+            shutdown_timeout: Duration::from_secs(5),
+            shared: Arc::new(Shared::default()),
+        }
+    }
+
+    /// The number of times a failed report is retried before the trace is given up. Defaults
+    /// to 5.
+    pub fn max_retries(self, max_retries: usize) -> Self {
+        Self {
+            max_retries,
+            ..self
+        }
+    }
+
+    /// The delay before the first retry, doubled on every retry up to `max`. Defaults to 100ms
+    /// and 10s.
+    pub fn backoff(self, initial: Duration, max: Duration) -> Self {
+        Self {
+            initial_backoff: initial,
+            max_backoff: max,
+            ..self
+        }
+    }
+
+    /// Call `dead_letter` with the traces given up, e.g. to write them to disk, instead of
+    /// dropping them.
+    pub fn dead_letter(
+        self,
+        dead_letter: impl Fn(u128, Vec<Span>, &ReportError) + Send + 'static,
+    ) -> Self {
+        Self {
+            dead_letter: Some(Box::new(dead_letter)),
+            ..self
+        }
+    }
+
+    /// Trace the reporting of every trace, reporting the trace about it to the same reporter
+    /// afterwards, e.g. to monitor the reporting with the tracing backend. Disabled by default.
+    ///
+    /// The root span [`REPORT_EVENT`] has the properties `report.trace_id` and `report.spans`,
+    /// and a child span `minitrace.report.attempt` per attempt, with the local spans the
+    /// reporter records, e.g. encoding and sending. The traces about reporting are reported
+    /// with the spans suppressed, so they are not traced in turn.
+    pub fn trace_self(self, trace_self: bool) -> Self {
+        Self { trace_self, ..self }
+    }
+
+    /// How often the collectors submitted by [`ReportHandle::submit_collector`] are checked
+    /// and the finished ones collected and reported. Defaults to 1s.
+    pub fn interval(self, interval: Duration) -> Self {
+        Self { interval, ..self }
+    }
+
+    /// Collect a submitted collector after `collect_timeout` even if some of its spans are still
+    /// alive, e.g. leaked, reporting the spans finished so far. Defaults to 60s.
+    pub fn collect_timeout(self, collect_timeout: Duration) -> Self {
+        Self {
+            collect_timeout,
+            ..self
+        }
+    }
+
+    /// The number of traces and collectors queued for the reporting thread, past which the
+    /// submitted ones are dropped and counted by [`ReportHandle::dropped`], e.g. while the
+    /// reporter is retrying against an agent down. Defaults to 1024.
+    pub fn queue_capacity(self, queue_capacity: usize) -> Self {
+        Self {
+            queue_capacity,
+            ..self
+        }
+    }
+
+    /// How long dropping the [`ReportHandle`] waits for the queued traces to be reported.
+    /// Past it, the thread stops retrying and is left to finish in the background. Defaults
+    /// to 5s.
+    pub fn shutdown_timeout(self, shutdown_timeout: Duration) -> Self {
+        Self {
+            shutdown_timeout,
+            ..self
+        }
+    }
+
+    /// Start the reporting thread.
+    pub fn spawn(self) -> ReportHandle {
+        self.try_spawn()
+            .expect("failed to spawn the reporting thread")
+    }
+
+    /// Like [`spawn`](ReportPipeline::spawn), but return the error of spawning the thread.
+    pub fn try_spawn(self) -> io::Result<ReportHandle> {
+        let (tx, rx) = crossbeam::channel::bounded::<Job>(self.queue_capacity);
+        // Disconnected once the thread is done
+        let (done_tx, done_rx) = crossbeam::channel::bounded::<()>(0);
+        let shared = self.shared.clone();
+        let shutdown_timeout = self.shutdown_timeout;
+        let thread = std::thread::Builder::new()
+            .name("minitrace-reporter".to_owned())
+            .spawn(move || {
+                let _done = done_tx;
+                // The submitted collectors and when they were submitted
+                let mut collectors = Vec::new();
+                let mut next_drain = Instant::now() + self.interval;
+                loop {
+                    let timeout = next_drain.saturating_duration_since(Instant::now());
+                    match rx.recv_timeout(timeout) {
+                        Ok(Job::Trace(trace_id, spans)) => self.process(trace_id, spans),
+                        Ok(Job::Collector(collector)) => {
+                            collectors.push((Instant::now(), collector))
+                        }
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                    if Instant::now() >= next_drain {
+                        self.drain(&mut collectors, false);
+                        next_drain = Instant::now() + self.interval;
+                    }
+                }
+                self.drain(&mut collectors, true);
+            })?;
+
+        Ok(ReportHandle {
+            sender: Some(tx),
+            thread: Some(thread),
+            done: done_rx,
+            shared,
+            shutdown_timeout,
+        })
+    }
+
+    // Collect and report the collectors whose spans have finished, or all of them on shutdown
+    fn drain(&self, collectors: &mut Vec<(Instant, Collector)>, shutdown: bool) {
+        let mut i = 0;
+        while i < collectors.len() {
+            let (submitted_at, collector) = &collectors[i];
+            let finished = collector.live_references() == 0;
+            if !finished && !shutdown && submitted_at.elapsed() < self.collect_timeout {
+                i += 1;
+                continue;
+            }
+
+            let (_, collector) = collectors.swap_remove(i);
+            let trace_id = collector.trace_id();
+            let spans = collector.collect_with_args(CollectArgs::default().sync(finished));
+            if !spans.is_empty() {
+                self.process(trace_id, spans);
+            }
+        }
+    }
+
+    fn process(&self, trace_id: u128, spans: Vec<Span>) {
+        if self.trace_self {
+            self.report_traced(trace_id, spans);
+        } else {
+            self.report(trace_id, spans, false);
+        }
+    }
+
+    fn report_traced(&self, trace_id: u128, spans: Vec<Span>) {
+        let (root_span, collector) = crate::Span::root(REPORT_EVENT);
+        let root_span = root_span
+            .with_property(|| ("report.trace_id", format!("{:032x}", trace_id)))
+            .with_typed_property("report.spans", spans.len() as i64);
+        {
+            let _guard = root_span.enter();
+            self.report(trace_id, spans, true);
+        }
+        drop(root_span);
+
+        let self_trace_id = collector.trace_id();
+        let self_spans = collector.collect_with_args(CollectArgs::default().sync(true));
+        with_children_suppressed(|| self.report(self_trace_id, self_spans, false));
+    }
+
+    // Report the spans, with a span per attempt if `traced`
+    fn report(&self, trace_id: u128, spans: Vec<Span>, traced: bool) {
+        let mut backoff = self.initial_backoff;
+        let mut retries = 0;
+        loop {
+            let attempt = if traced {
+                Some(LocalSpan::enter("minitrace.report.attempt"))
+            } else {
+                None
+            };
+            let res = self.reporter.report(trace_id, &spans);
+            drop(attempt);
+            match res {
+                Ok(()) => return,
+                Err(err)
+                    if err.is_retryable()
+                        && retries < self.max_retries
+                        && !self.shared.abandoned.load(Ordering::Relaxed) =>
+                {
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    retries += 1;
+                }
+                Err(err) => {
+                    if let Some(dead_letter) = &self.dead_letter {
+                        dead_letter(trace_id, spans, &err);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The handle of a reporting thread. Dropping it waits for the submitted traces to be
+/// reported, collecting the submitted collectors with the spans finished so far, for at most
+/// the [`shutdown_timeout`](ReportPipeline::shutdown_timeout).
+pub struct ReportHandle {
+    sender: Option<Sender<Job>>,
+    thread: Option<JoinHandle<()>>,
+    done: Receiver<()>,
+    shared: Arc<Shared>,
+    shutdown_timeout: Duration,
+}
+
+impl ReportHandle {
+    /// Queue the spans of a trace for reporting, or drop them if the queue is full.
+    pub fn submit(&self, trace_id: u128, spans: Vec<Span>) {
+        self.send(Job::Trace(trace_id, spans));
+    }
+
+    /// Leave the collector to the reporting thread, which collects and reports the trace once
+    /// its spans have finished, sparing the application a loop waiting for them. The collector
+    /// is dropped if the queue is full.
+    pub fn submit_collector(&self, collector: Collector) {
+        self.send(Job::Collector(collector));
+    }
+
+    /// The number of traces and collectors dropped since the queue was full, see
+    /// [`ReportPipeline::queue_capacity`].
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    fn send(&self, job: Job) {
+        if let Some(sender) = &self.sender {
+            if let Err(TrySendError::Full(_)) = sender.try_send(job) {
+                self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Drop for ReportHandle {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Err(RecvTimeoutError::Timeout) = self.done.recv_timeout(self.shutdown_timeout) {
+            // Leave the thread to give up in the background
+            self.shared.abandoned.store(true, Ordering::Relaxed);
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    struct Flaky {
+        failures: usize,
+        attempts: Arc<AtomicUsize>,
+    }
+
+    impl Reporter for Flaky {
+        fn report(&self, _trace_id: u128, _spans: &[Span]) -> Result<(), ReportError> {
+            if self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+                Err(ReportError::new("agent unavailable"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn retry() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let dead = Arc::new(Mutex::new(Vec::new()));
+
+        let dead_letter = dead.clone();
+        let pipeline = ReportPipeline::new(Flaky {
+            failures: 3,
+            attempts: attempts.clone(),
+        })
+        .max_retries(2)
+        .backoff(Duration::from_millis(1), Duration::from_millis(2))
+        .dead_letter(move |trace_id, spans, _| {
+            dead_letter.lock().unwrap().push((trace_id, spans.len()))
+        });
+
+        // Given up after 3 attempts
+        pipeline.report(1, vec![Span::default()], false);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(*dead.lock().unwrap(), vec![(1, 1)]);
+
+        // Succeeds at the 4th attempt
+        let handle = pipeline.spawn();
+        handle.submit(2, vec![]);
+        drop(handle);
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+        assert_eq!(dead.lock().unwrap().len(), 1);
+    }
+
+    // Reports once released, telling when it starts
+    struct Blocking {
+        started: Sender<()>,
+        release: Receiver<()>,
+    }
+
+    impl Reporter for Blocking {
+        fn report(&self, _trace_id: u128, _spans: &[Span]) -> Result<(), ReportError> {
+            let _ = self.started.send(());
+            let _ = self.release.recv();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn bounded_queue() {
+        let (started_tx, started) = crossbeam::channel::unbounded();
+        let (release, release_rx) = crossbeam::channel::unbounded();
+        let handle = ReportPipeline::new(Blocking {
+            started: started_tx,
+            release: release_rx,
+        })
+        .queue_capacity(1)
+        .shutdown_timeout(Duration::from_millis(10))
+        .spawn();
+
+        handle.submit(1, vec![]);
+        started.recv().unwrap();
+        // Queued while the first trace is being reported, and then dropped
+        handle.submit(2, vec![]);
+        handle.submit(3, vec![]);
+        assert_eq!(handle.dropped(), 1);
+
+        // Doesn't wait for the reporter stuck
+        let begin = Instant::now();
+        drop(handle);
+        assert!(begin.elapsed() < Duration::from_secs(5));
+        drop(release);
+    }
+
+    type Reports = Arc<Mutex<Vec<(u128, Vec<&'static str>)>>>;
+
+    struct Recording {
+        reports: Reports,
+    }
+
+    impl Reporter for Recording {
+        fn report(&self, trace_id: u128, spans: &[Span]) -> Result<(), ReportError> {
+            let _guard = LocalSpan::enter("encode");
+            let mut events: Vec<_> = spans.iter().map(|s| s.event).collect();
+            events.sort_unstable();
+            self.reports.lock().unwrap().push((trace_id, events));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_self() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let handle = ReportPipeline::new(Recording {
+            reports: reports.clone(),
+        })
+        .trace_self(true)
+        .spawn();
+        handle.submit(1, vec![Span::default()]);
+        drop(handle);
+
+        // The trace about reporting is reported, but not traced in turn
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0], (1, vec![""]));
+        assert_eq!(
+            reports[1].1,
+            vec!["encode", "minitrace.report", "minitrace.report.attempt"]
+        );
+    }
+
+    #[test]
+    fn untraced() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let pipeline = ReportPipeline::new(Recording {
+            reports: reports.clone(),
+        });
+
+        // No span about reporting is recorded without `trace_self`
+        let local_collector = crate::LocalCollector::start();
+        pipeline.process(1, vec![Span::default()]);
+        let local_spans = local_collector.collect();
+        let events: Vec<_> = local_spans.spans.iter().map(|s| s.event).collect();
+        assert_eq!(events, vec!["encode"]);
+        assert_eq!(*reports.lock().unwrap(), vec![(1, vec![""])]);
+    }
+
+    #[test]
+    fn submit_collector() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let handle = ReportPipeline::new(Recording {
+            reports: reports.clone(),
+        })
+        .interval(Duration::from_millis(1))
+        .spawn();
+
+        let (root_span, collector) = crate::Span::root("finished");
+        let finished = collector.trace_id();
+        handle.submit_collector(collector);
+        drop(root_span);
+        let begin = Instant::now();
+        while reports.lock().unwrap().is_empty() && begin.elapsed() < Duration::from_secs(10) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(*reports.lock().unwrap(), vec![(finished, vec!["finished"])]);
+
+        // Collected on shutdown with the spans finished so far
+        let (root_span, collector) = crate::Span::root("leaking");
+        let leaked = crate::Span::from_parent("leaked", &root_span);
+        let leaking = collector.trace_id();
+        handle.submit_collector(collector);
+        drop(root_span);
+        drop(handle);
+        assert_eq!(reports.lock().unwrap()[1], (leaking, vec!["leaking"]));
+        drop(leaked);
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Exporting traces in the [speedscope file format], to be explored in
+//! [speedscope](https://www.speedscope.app), e.g. in its left-heavy view merging the repeated
+//! calls of a thread.
+//!
+//! Each thread of a trace is shown as a profile, the spans being spread over threads as by the
+//! [Chrome exporter](crate::report::chrome). The times are in nanoseconds from the beginning of
+//! the trace.
+//!
+//! ```rust
+//! use minitrace::report::speedscope;
+//! use minitrace::Span;
+//!
+//! let (root_span, collector) = Span::root("root");
+//! drop(root_span);
+//!
+//! let json = speedscope::encode(&[(collector.trace_id(), collector.collect())]);
+//! std::fs::write(std::env::temp_dir().join("trace.speedscope.json"), json).unwrap();
+//! ```
+//!
+//! [speedscope file format]: https://github.com/jlfwong/speedscope/wiki/Importing-from-custom-sources
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use crate::report::chrome::assign_threads;
+use crate::report::file::write_str;
+use crate::span::Span;
+
+/// Encode the traces as a speedscope file, with a profile per thread of each trace.
+pub fn encode(traces: &[(u128, Vec<Span>)]) -> String {
+    let mut frames = Frames::default();
+    let mut profiles = String::new();
+    for (trace_id, spans) in traces {
+        write_profiles(&mut profiles, &mut frames, *trace_id, spans);
+    }
+    // The separator after the last profile
+    if profiles.ends_with(',') {
+        profiles.pop();
+    }
+
+    let mut out = String::from(
+        "{\"$schema\":\"https://www.speedscope.app/file-format-schema.json\",\
+         \"exporter\":\"minitrace\",\"shared\":{\"frames\":[",
+    );
+    for (i, name) in frames.names.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("{\"name\":");
+        write_str(&mut out, name);
+        out.push('}');
+    }
+    out.push_str("]},\"profiles\":[");
+    out.push_str(&profiles);
+    out.push_str("]}\n");
+    out
+}
+
+// The event names of the spans, shared by all profiles
+#[derive(Default)]
+struct Frames {
+    names: Vec<&'static str>,
+    indices: HashMap<&'static str, usize>,
+}
+
+impl Frames {
+    fn index_of(&mut self, name: &'static str) -> usize {
+        let names = &mut self.names;
+        *self.indices.entry(name).or_insert_with(|| {
+            names.push(name);
+            names.len() - 1
+        })
+    }
+}
+
+// Write a profile for each thread of the trace, each followed by a separator
+fn write_profiles(out: &mut String, frames: &mut Frames, trace_id: u128, spans: &[Span]) {
+    let start = match spans.iter().map(|s| s.begin_unix_time_ns).min() {
+        Some(start) => start,
+        None => return,
+    };
+    let end = |span: &Span| span.begin_unix_time_ns.saturating_add(span.duration_ns) - start;
+
+    let tids = assign_threads(spans);
+    let mut threads: Vec<Vec<&Span>> = Vec::new();
+    for (span, tid) in spans.iter().zip(tids) {
+        let thread = tid as usize - 1;
+        if threads.len() <= thread {
+            threads.resize_with(thread + 1, Vec::new);
+        }
+        threads[thread].push(span);
+    }
+
+    for (thread, mut spans) in threads.into_iter().enumerate() {
+        // Parents before their children
+        spans.sort_by_key(|s| (s.begin_unix_time_ns, Reverse(s.duration_ns)));
+        let end_value = spans.iter().map(|s| end(s)).max().unwrap_or(0);
+
+        out.push_str("{\"type\":\"evented\",\"name\":");
+        write_str(
+            out,
+            &format!("trace {:032x} thread {}", trace_id, thread + 1),
+        );
+        let _ = write!(
+            out,
+            ",\"unit\":\"nanoseconds\",\"startValue\":0,\"endValue\":{},\"events\":[",
+            end_value
+        );
+
+        // The spans open, the innermost last
+        let mut open: Vec<(usize, u64)> = Vec::new();
+        let mut first = true;
+        let mut event = |out: &mut String, ty: char, frame: usize, at: u64| {
+            if !first {
+                out.push(',');
+            }
+            first = false;
+            let _ = write!(
+                out,
+                "{{\"type\":\"{}\",\"frame\":{},\"at\":{}}}",
+                ty, frame, at
+            );
+        };
+        for span in spans {
+            let begin = span.begin_unix_time_ns - start;
+            while let Some(&(frame, open_end)) = open.last() {
+                if open_end > begin {
+                    break;
+                }
+                event(out, 'C', frame, open_end);
+                open.pop();
+            }
+            let frame = frames.index_of(span.event);
+            event(out, 'O', frame, begin);
+            open.push((frame, end(span)));
+        }
+        while let Some((frame, open_end)) = open.pop() {
+            event(out, 'C', frame, open_end);
+        }
+        out.push_str("]},");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(id: u32, parent_id: u32, begin: u64, duration: u64, event: &'static str) -> Span {
+        Span {
+            id,
+            parent_id,
+            begin_unix_time_ns: begin,
+            duration_ns: duration,
+            event,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn encode_profiles() {
+        let spans = vec![
+            span(1, 0, 1000, 10_000, "root"),
+            // Concurrent children
+            span(2, 1, 2000, 5000, "get"),
+            span(3, 1, 3000, 5000, "get"),
+            span(4, 3, 4000, 1000, "seek"),
+            // Right after the first child
+            span(5, 1, 7000, 500, "seek"),
+        ];
+        let json = encode(&[(42, spans)]);
+        let file: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        let frames: Vec<_> = file["shared"]["frames"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(frames, vec!["root", "get", "seek"]);
+
+        let profiles = file["profiles"].as_array().unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(
+            profiles[0]["name"],
+            "trace 0000000000000000000000000000002a thread 1"
+        );
+        assert_eq!(profiles[0]["endValue"], 10_000);
+        let events = |profile: &serde_json::Value| {
+            profile["events"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|e| {
+                    (
+                        e["type"].as_str().unwrap().to_owned(),
+                        e["frame"].as_u64().unwrap(),
+                        e["at"].as_u64().unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let event = |ty: &str, frame, at| (ty.to_owned(), frame, at);
+        assert_eq!(
+            events(&profiles[0]),
+            vec![
+                event("O", 0, 0),
+                event("O", 1, 1000),
+                event("C", 1, 6000),
+                event("O", 2, 6000),
+                event("C", 2, 6500),
+                event("C", 0, 10_000),
+            ]
+        );
+        assert_eq!(
+            events(&profiles[1]),
+            vec![
+                event("O", 1, 2000),
+                event("O", 2, 3000),
+                event("C", 2, 4000),
+                event("C", 1, 7000),
+            ]
+        );
+
+        let empty: serde_json::Value = serde_json::from_str(&encode(&[])).unwrap();
+        assert!(empty["profiles"].as_array().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Retry loops recorded as spans, so that the attempts and the time spent backing off show up
+//! in traces instead of one long span.
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use minitrace::retry::{traced_retry, RetryPolicy};
+//!
+//! let policy = RetryPolicy::new(3).backoff(Duration::from_millis(1), Duration::from_millis(10));
+//! let res: Result<u32, String> = traced_retry("get region", &policy, |attempt| {
+//!     if attempt < 2 {
+//!         Err("not leader".to_owned())
+//!     } else {
+//!         Ok(42)
+//!     }
+//! });
+//! assert_eq!(res, Ok(42));
+//! ```
+
+use std::fmt::Display;
+use std::time::Duration;
+
+use crate::semconv;
+use crate::LocalSpan;
+
+/// The event of the span of each attempt.
+pub const ATTEMPT_EVENT: &str = "attempt";
+
+/// How many times and how often [`traced_retry`] tries.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Try at most `max_attempts` times, at least once.
+    pub fn new(max_attempts: u32) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+
+    /// The delay before the first retry, doubled on every retry up to `max`. Defaults to 100ms
+    /// and 10s.
+    pub fn backoff(self, initial: Duration, max: Duration) -> Self {
+        Self {
+            initial_backoff: initial,
+            max_backoff: max,
+            ..self
+        }
+    }
+}
+
+/// Call `op` with the attempt number, starting from 1, until it succeeds or the attempts of
+/// `policy` are exhausted, sleeping between attempts. Returns the last result.
+///
+/// It's recorded as a local span `event` with [`retry.attempts`](semconv::RETRY_ATTEMPTS) and
+/// [`retry.outcome`](semconv::RETRY_OUTCOME), `ok` or `exhausted`, and a child span
+/// [`attempt`](ATTEMPT_EVENT) per attempt with [`retry.attempt`](semconv::RETRY_ATTEMPT), the
+/// [`retry.backoff_ns`](semconv::RETRY_BACKOFF_NS) slept before it, its `retry.outcome`, `ok` or
+/// `error`, and the [`error.message`](semconv::ERROR_MESSAGE) of a failure.
+pub fn traced_retry<T, E: Display>(
+    event: &'static str,
+    policy: &RetryPolicy,
+    mut op: impl FnMut(u32) -> Result<T, E>,
+) -> Result<T, E> {
+    let mut guard = LocalSpan::enter(event);
+    let mut backoff = Duration::from_nanos(0);
+    let mut attempt = 1;
+    loop {
+        if attempt > 1 {
+            std::thread::sleep(backoff);
+        }
+
+        let mut attempt_guard = LocalSpan::enter(ATTEMPT_EVENT)
+            .with_typed_property(semconv::RETRY_ATTEMPT, attempt as i64)
+            .with_typed_property(semconv::RETRY_BACKOFF_NS, backoff.as_nanos() as i64);
+        let res = op(attempt);
+        attempt_guard = match &res {
+            Ok(_) => attempt_guard.with_static_property(semconv::RETRY_OUTCOME, "ok"),
+            Err(err) => attempt_guard
+                .with_static_property(semconv::RETRY_OUTCOME, "error")
+                .with_property(|| (semconv::ERROR_MESSAGE, err.to_string())),
+        };
+        drop(attempt_guard);
+
+        let exhausted = attempt >= policy.max_attempts;
+        if res.is_ok() || exhausted {
+            guard = guard
+                .with_typed_property(semconv::RETRY_ATTEMPTS, attempt as i64)
+                .with_static_property(
+                    semconv::RETRY_OUTCOME,
+                    if res.is_ok() { "ok" } else { "exhausted" },
+                );
+            drop(guard);
+            return res;
+        }
+
+        backoff = if attempt == 1 {
+            policy.initial_backoff
+        } else {
+            (backoff * 2).min(policy.max_backoff)
+        };
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::PropertyValue;
+    use crate::Span;
+
+    #[test]
+    fn attempts() {
+        let policy =
+            RetryPolicy::new(3).backoff(Duration::from_millis(1), Duration::from_millis(1));
+        let (root_span, collector) = Span::root("root");
+        {
+            let _g = root_span.enter();
+            let res: Result<(), &str> = traced_retry("rpc", &policy, |_| Err("timeout"));
+            assert_eq!(res, Err("timeout"));
+        }
+        drop(root_span);
+
+        let spans = collector.collect();
+        let rpc = spans.iter().find(|s| s.event == "rpc").unwrap();
+        assert!(rpc
+            .properties
+            .contains(&(semconv::RETRY_OUTCOME, PropertyValue::from("exhausted"))));
+        let attempts: Vec<_> = spans.iter().filter(|s| s.event == ATTEMPT_EVENT).collect();
+        assert_eq!(attempts.len(), 3);
+        assert!(attempts.iter().all(|s| s.parent_id == rpc.id));
+        assert!(attempts[2]
+            .properties
+            .contains(&(semconv::RETRY_BACKOFF_NS, PropertyValue::I64(1_000_000))));
+        assert!(attempts[0]
+            .properties
+            .contains(&(semconv::ERROR_MESSAGE, PropertyValue::from("timeout"))));
+    }
+}
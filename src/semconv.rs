@@ -0,0 +1,174 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Conventional property keys, so that spans recorded by different components carry consistent
+//! properties which can be consumed by shared dashboards.
+//!
+//! Each key comes with a helper building the property, e.g.
+//!
+//! ```rust
+//! use minitrace::{semconv, LocalSpan};
+//!
+//! let _guard = LocalSpan::enter("get").with_property(|| semconv::region_id(42));
+//! ```
+
+use std::fmt::Display;
+
+pub const DB_STATEMENT: &str = "db.statement";
+pub const NET_PEER_NAME: &str = "net.peer.name";
+pub const NET_PEER_PORT: &str = "net.peer.port";
+pub const REGION_ID: &str = "region.id";
+pub const STORE_ID: &str = "store.id";
+pub const ERROR_KIND: &str = "error.kind";
+pub const ERROR_MESSAGE: &str = "error.message";
+pub const HTTP_METHOD: &str = "http.method";
+pub const HTTP_HOST: &str = "http.host";
+pub const HTTP_STATUS_CODE: &str = "http.status_code";
+/// Set to `true` on spans terminated by cancellation.
+pub const CANCELLED: &str = "cancelled";
+/// Set to `true` on spans finished at the deadline of their trace while still running, see
+/// [`Span::root_with_deadline`](crate::Span::root_with_deadline).
+pub const DEADLINE_EXCEEDED: &str = "deadline_exceeded";
+/// Set to `true` on spans ending before they begin, e.g. due to clock skew, which are reported
+/// with zero duration.
+pub const CLOCK_ANOMALY: &str = "clock_anomaly";
+/// Set on the root span of a trace continuing another one, pointing at the root span of the
+/// predecessor as `<trace id>-<span id>` in hex.
+pub const LINK_PREDECESSOR: &str = "link.predecessor";
+/// Set on the root span of a trace continued by another one, pointing at the root span of the
+/// successor.
+pub const LINK_SUCCESSOR: &str = "link.successor";
+/// Set on the root span of a trace continued from another process to the id of the remote
+/// parent span in hex, see [`Span::root_with_context`](crate::Span::root_with_context).
+pub const REMOTE_PARENT: &str = "remote.parent";
+/// Set on a span expecting remote children to the comma-separated ids in hex of those no remote
+/// span is attached to, see [`Span::expect_remote_children`](crate::Span::expect_remote_children).
+pub const REMOTE_MISSING: &str = "remote.missing";
+/// Set on the spans of a trace exported in parts whose parents are in other parts, e.g. the root
+/// span and the spans continuing under spans exported before, to the sequence number of their
+/// part, see [`Config::partial_export`](crate::Config::partial_export).
+pub const PARTIAL_SEQUENCE: &str = "partial.sequence";
+/// Set on the span kept for its siblings of the same event dropped by truncation, counting it
+/// and them.
+pub const TRUNCATED_REPEATS: &str = "truncated.repeats";
+/// Set with [`TRUNCATED_REPEATS`] to the total duration of the span and its dropped siblings.
+pub const TRUNCATED_REPEATS_DURATION_NS: &str = "truncated.repeats_duration_ns";
+/// Set on the spans whose children are dropped by truncation, counting the children.
+pub const TRUNCATED_CHILDREN: &str = "truncated.children";
+/// Set on local spans to the CPU time the thread spent in them, telling spans burning CPU from
+/// spans blocked waiting, see `Config::record_cpu_time` of the `cpu-time` feature.
+pub const CPU_TIME_NS: &str = "cpu_time_ns";
+/// Set on local spans to the number of allocations made by the thread in them, see
+/// `Config::record_allocations` of the `alloc-counters` feature.
+pub const ALLOC_COUNT: &str = "alloc.count";
+/// Set with [`ALLOC_COUNT`] to the bytes allocated.
+pub const ALLOC_BYTES: &str = "alloc.bytes";
+/// The properties of an [`ExecSummary`](crate::ExecSummary) of a coprocessor-style executor.
+pub const EXEC_ITERATIONS: &str = "exec.iterations";
+pub const EXEC_PRODUCED_ROWS: &str = "exec.produced_rows";
+pub const EXEC_TIME_PROCESSED_NS: &str = "exec.time_processed_ns";
+/// The properties of the spans of [`traced_retry`](crate::retry::traced_retry).
+pub const RETRY_ATTEMPT: &str = "retry.attempt";
+pub const RETRY_ATTEMPTS: &str = "retry.attempts";
+pub const RETRY_BACKOFF_NS: &str = "retry.backoff_ns";
+pub const RETRY_OUTCOME: &str = "retry.outcome";
+/// Set to the log records emitted inside a local span as `<level> <target>: <message>`, see
+/// `log_bridge` of the `log` feature.
+pub const LOG_RECORD: &str = "log.record";
+/// Set on the spans of the functions instrumented with a target, e.g.
+/// `#[trace("apply", target = "raftstore")]`, to the target.
+pub const TARGET: &str = "target";
+/// Set on the last span kept of an event under a [`RateLimit`](crate::RateLimit) to the number
+/// of its spans dropped since.
+pub const RATE_LIMITED_SPANS: &str = "rate_limited.spans";
+
+#[inline]
+pub fn db_statement(statement: impl Into<String>) -> (&'static str, String) {
+    (DB_STATEMENT, statement.into())
+}
+
+#[inline]
+pub fn net_peer_name(name: impl Into<String>) -> (&'static str, String) {
+    (NET_PEER_NAME, name.into())
+}
+
+#[inline]
+pub fn net_peer_port(port: u16) -> (&'static str, String) {
+    (NET_PEER_PORT, port.to_string())
+}
+
+#[inline]
+pub fn region_id(id: u64) -> (&'static str, String) {
+    (REGION_ID, id.to_string())
+}
+
+#[inline]
+pub fn store_id(id: u64) -> (&'static str, String) {
+    (STORE_ID, id.to_string())
+}
+
+#[inline]
+pub fn error_kind(kind: impl Into<String>) -> (&'static str, String) {
+    (ERROR_KIND, kind.into())
+}
+
+/// Build both the `error.kind` and `error.message` properties of an error.
+#[inline]
+pub fn error(kind: impl Into<String>, err: &impl Display) -> Vec<(&'static str, String)> {
+    vec![(ERROR_KIND, kind.into()), (ERROR_MESSAGE, err.to_string())]
+}
+
+#[inline]
+pub fn http_method(method: impl Into<String>) -> (&'static str, String) {
+    (HTTP_METHOD, method.into())
+}
+
+#[inline]
+pub fn http_host(host: impl Into<String>) -> (&'static str, String) {
+    (HTTP_HOST, host.into())
+}
+
+#[inline]
+pub fn http_status_code(status: u16) -> (&'static str, String) {
+    (HTTP_STATUS_CODE, status.to_string())
+}
+
+#[inline]
+pub fn link_predecessor(trace_id: u128, span_id: u32) -> (&'static str, String) {
+    (
+        LINK_PREDECESSOR,
+        format!("{:032x}-{:08x}", trace_id, span_id),
+    )
+}
+
+#[inline]
+pub fn link_successor(trace_id: u128, span_id: u32) -> (&'static str, String) {
+    (LINK_SUCCESSOR, format!("{:032x}-{:08x}", trace_id, span_id))
+}
+
+#[inline]
+pub fn remote_parent(span_id: u64) -> (&'static str, String) {
+    (REMOTE_PARENT, format!("{:016x}", span_id))
+}
+
+#[inline]
+pub fn remote_missing(span_ids: &[u32]) -> (&'static str, String) {
+    let ids: Vec<_> = span_ids.iter().map(|id| format!("{:016x}", id)).collect();
+    (REMOTE_MISSING, ids.join(","))
+}
+
+#[inline]
+pub fn partial_sequence(sequence: u32) -> (&'static str, String) {
+    (PARTIAL_SEQUENCE, sequence.to_string())
+}
+
+/// Parse the value of a `link.predecessor` or `link.successor` property into the trace id and
+/// the span id.
+pub fn parse_link(value: &str) -> Option<(u128, u32)> {
+    let mut parts = value.split('-');
+    let trace_id = u128::from_str_radix(parts.next()?, 16).ok()?;
+    let span_id = u32::from_str_radix(parts.next()?, 16).ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((trace_id, span_id))
+}
@@ -0,0 +1,22 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+// The CPU time consumed by the current thread, or zero where it's not available
+#[cfg(unix)]
+#[inline]
+pub(crate) fn thread_cpu_time_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safety: `ts` is a valid `timespec` to write
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) } != 0 {
+        return 0;
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[cfg(not(unix))]
+#[inline]
+pub(crate) fn thread_cpu_time_ns() -> u64 {
+    0
+}
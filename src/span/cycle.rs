@@ -1,23 +1,110 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
+use std::sync::RwLock;
+
+use crate::span::test_clock;
+
 pub use minstant::Anchor;
 pub use minstant::Cycle;
 
+// Refresh the cached anchor periodically to follow adjustments of the system time.
+const ANCHOR_REFRESH_INTERVAL_NS: u64 = 1_000_000_000;
+
+lazy_static! {
+    static ref CACHED_ANCHOR: RwLock<CachedAnchor> = RwLock::new(CachedAnchor::new());
+}
+
+#[derive(Clone, Copy)]
+struct CachedAnchor {
+    anchor: Anchor,
+    created_unix_time_ns: u64,
+}
+
+impl CachedAnchor {
+    fn new() -> Self {
+        let anchor = Anchor::new();
+        CachedAnchor {
+            anchor,
+            created_unix_time_ns: Cycle::now().into_unix_time_ns(anchor),
+        }
+    }
+
+    // Measured by cycles, so checking doesn't read the system time.
+    fn is_expired(&self) -> bool {
+        let now = Cycle::now().into_unix_time_ns(self.anchor);
+        now.saturating_sub(self.created_unix_time_ns) >= ANCHOR_REFRESH_INTERVAL_NS
+    }
+}
+
 pub struct DefaultClock;
 
 impl DefaultClock {
     #[inline]
     pub fn now() -> Cycle {
-        Cycle::now()
+        let now = Cycle::now();
+        if test_clock::is_active() {
+            test_clock::record(now);
+        }
+        now
     }
 
+    /// The unix time of the cycle, or its virtual time if read under a
+    /// [`TestClock`](crate::span::TestClock).
     #[inline]
     pub fn cycle_to_unix_time_ns(cycle: Cycle, anchor: Anchor) -> u64 {
+        if test_clock::is_active() {
+            if let Some(ns) = test_clock::virtual_time_ns(cycle) {
+                return ns;
+            }
+        }
         cycle.into_unix_time_ns(anchor)
     }
 
+    /// A process-wide cached anchor, refreshed every second.
     #[inline]
     pub fn anchor() -> Anchor {
+        let cached = *CACHED_ANCHOR.read().unwrap();
+        if !cached.is_expired() {
+            return cached.anchor;
+        }
+
+        let refreshed = CachedAnchor::new();
+        *CACHED_ANCHOR.write().unwrap() = refreshed;
+        refreshed.anchor
+    }
+
+    /// A newly created anchor, bypassing the cache.
+    #[inline]
+    pub fn fresh_anchor() -> Anchor {
         Anchor::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_anchor() {
+        // Within the refresh interval, the cached anchor is kept
+        let cached = CachedAnchor::new();
+        *CACHED_ANCHOR.write().unwrap() = cached;
+        let cycle = Cycle::now();
+        assert_eq!(
+            cycle.into_unix_time_ns(DefaultClock::anchor()),
+            cycle.into_unix_time_ns(cached.anchor)
+        );
+
+        let expired = CachedAnchor {
+            created_unix_time_ns: 0,
+            ..CachedAnchor::new()
+        };
+        assert!(expired.is_expired());
+        assert!(!CachedAnchor::new().is_expired());
+
+        // An expired anchor is replaced on the next read
+        *CACHED_ANCHOR.write().unwrap() = expired;
+        DefaultClock::anchor();
+        assert!(!CACHED_ANCHOR.read().unwrap().is_expired());
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::span::{PropertyValue, Span, SpanEvent};
+
+/// A batch of spans whose event names and property keys are stored once in dictionaries and
+/// referenced by index, shrinking the batch when few distinct names repeat over many spans. The
+/// spill files of [`FileSpanStorage`](crate::FileSpanStorage) are written this way.
+///
+/// ```rust
+/// use minitrace::span::{DictionaryBatch, Span};
+///
+/// let spans = vec![
+///     Span { id: 1, event: "get", ..Default::default() },
+///     Span { id: 2, parent_id: 1, event: "get", ..Default::default() },
+/// ];
+/// let batch = DictionaryBatch::encode(&spans);
+/// assert_eq!(batch.events, vec!["get"]);
+/// assert_eq!(batch.into_spans().unwrap().len(), 2);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DictionaryBatch {
+    pub events: Vec<&'static str>,
+    pub property_keys: Vec<&'static str>,
+    pub spans: Vec<IndexedSpan>,
+}
+
+/// The error of resolving a [`DictionaryBatch`] whose spans reference a name out of the bounds
+/// of its dictionaries, e.g. a batch decoded from a corrupted file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DictionaryError {
+    pub index: u32,
+}
+
+impl fmt::Display for DictionaryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "index {} out of the bounds of the dictionary",
+            self.index
+        )
+    }
+}
+
+impl std::error::Error for DictionaryError {}
+
+/// A span of a [`DictionaryBatch`], referencing its event name and property keys by their
+/// indices in the dictionaries of the batch.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IndexedSpan {
+    pub id: u32,
+    pub parent_id: u32,
+    pub begin_unix_time_ns: u64,
+    pub duration_ns: u64,
+    pub event: u32,
+    pub properties: Vec<(u32, PropertyValue)>,
+    /// The times of the events of the span, with their names referenced in the dictionary of
+    /// the event names of the spans.
+    pub events: Vec<(u64, u32)>,
+}
+
+impl DictionaryBatch {
+    pub fn encode(spans: &[Span]) -> Self {
+        let mut events = Dictionary::default();
+        let mut property_keys = Dictionary::default();
+
+        let spans = spans
+            .iter()
+            .map(|span| IndexedSpan {
+                id: span.id,
+                parent_id: span.parent_id,
+                begin_unix_time_ns: span.begin_unix_time_ns,
+                duration_ns: span.duration_ns,
+                event: events.index_of(span.event),
+                properties: span
+                    .properties
+                    .iter()
+                    .map(|(k, v)| (property_keys.index_of(k), v.clone()))
+                    .collect(),
+                events: span
+                    .events
+                    .iter()
+                    .map(|e| (e.unix_time_ns, events.index_of(e.name)))
+                    .collect(),
+            })
+            .collect();
+
+        DictionaryBatch {
+            events: events.entries,
+            property_keys: property_keys.entries,
+            spans,
+        }
+    }
+
+    /// Resolve the indices back into the spans of the batch. Fails if an index is out of the
+    /// bounds of its dictionary.
+    pub fn into_spans(self) -> Result<Vec<Span>, DictionaryError> {
+        let DictionaryBatch {
+            events,
+            property_keys,
+            spans,
+        } = self;
+        let name = |dictionary: &[&'static str], index: u32| {
+            dictionary
+                .get(index as usize)
+                .copied()
+                .ok_or(DictionaryError { index })
+        };
+
+        spans
+            .into_iter()
+            .map(|span| {
+                Ok(Span {
+                    id: span.id,
+                    parent_id: span.parent_id,
+                    begin_unix_time_ns: span.begin_unix_time_ns,
+                    duration_ns: span.duration_ns,
+                    event: name(&events, span.event)?,
+                    properties: span
+                        .properties
+                        .into_iter()
+                        .map(|(k, v)| Ok((name(&property_keys, k)?, v)))
+                        .collect::<Result<_, _>>()?,
+                    events: span
+                        .events
+                        .into_iter()
+                        .map(|(unix_time_ns, index)| {
+                            Ok(SpanEvent {
+                                unix_time_ns,
+                                name: name(&events, index)?,
+                            })
+                        })
+                        .collect::<Result<_, _>>()?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct Dictionary {
+    entries: Vec<&'static str>,
+    indices: HashMap<&'static str, u32>,
+}
+
+impl Dictionary {
+    fn index_of(&mut self, name: &'static str) -> u32 {
+        let entries = &mut self.entries;
+        *self.indices.entry(name).or_insert_with(|| {
+            entries.push(name);
+            entries.len() as u32 - 1
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let spans: Vec<Span> = (0..10)
+            .map(|i| Span {
+                id: i + 1,
+                parent_id: i,
+                begin_unix_time_ns: i as u64,
+                duration_ns: 1,
+                event: if i % 2 == 0 { "even" } else { "odd" },
+                properties: vec![("k", PropertyValue::I64(i as i64)), ("k2", "v".into())],
+                events: vec![SpanEvent {
+                    unix_time_ns: i as u64,
+                    name: "odd",
+                }],
+            })
+            .collect();
+
+        let batch = DictionaryBatch::encode(&spans);
+        assert_eq!(batch.events, vec!["even", "odd"]);
+        assert_eq!(batch.property_keys, vec!["k", "k2"]);
+        assert_eq!(batch.spans[3].event, 1);
+
+        let decoded = batch.clone().into_spans().unwrap();
+        assert_eq!(decoded.len(), spans.len());
+        for (a, b) in decoded.iter().zip(&spans) {
+            assert_eq!((a.id, a.parent_id, a.event), (b.id, b.parent_id, b.event));
+            assert_eq!(a.properties, b.properties);
+            assert_eq!(a.events, b.events);
+        }
+
+        let mut corrupted = batch;
+        corrupted.spans[3].events[0].1 = 2;
+        assert_eq!(
+            corrupted.into_spans().unwrap_err(),
+            DictionaryError { index: 2 }
+        );
+    }
+}
@@ -1,22 +1,93 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
+#[cfg(feature = "cpu-time")]
+pub(crate) mod cpu_time;
 mod cycle;
+mod dictionary;
+mod property;
+#[cfg(feature = "serde")]
+mod serde_support;
 mod span_id;
+mod test_clock;
 
 pub(crate) mod span_queue;
 pub(crate) use self::span_id::DefaultIdGenerator;
 
 pub use self::cycle::{Anchor, Cycle, DefaultClock};
+pub use self::dictionary::{DictionaryBatch, DictionaryError, IndexedSpan};
+pub use self::property::PropertyValue;
+#[cfg(feature = "serde")]
+pub use self::serde_support::{OwnedSpan, OwnedSpanEvent};
 pub use self::span_id::SpanId;
+pub use self::test_clock::TestClock;
 
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::semconv;
+
+static CLOCK_ANOMALIES: AtomicU64 = AtomicU64::new(0);
+
+/// The number of spans found ending before they begin since the process started. They are
+/// reported with zero duration and the property [`CLOCK_ANOMALY`](semconv::CLOCK_ANOMALY).
+pub fn clock_anomalies() -> u64 {
+    CLOCK_ANOMALIES.load(Ordering::Relaxed)
+}
+
+// The duration between the timestamps, or zero with the span tagged if the end comes first
+#[inline]
+pub(crate) fn checked_duration_ns(
+    begin_unix_time_ns: u64,
+    end_unix_time_ns: u64,
+    properties: &mut Vec<(&'static str, PropertyValue)>,
+) -> u64 {
+    match end_unix_time_ns.checked_sub(begin_unix_time_ns) {
+        Some(duration_ns) => duration_ns,
+        None => {
+            CLOCK_ANOMALIES.fetch_add(1, Ordering::Relaxed);
+            properties.push((semconv::CLOCK_ANOMALY, "true".into()));
+            0
+        }
+    }
+}
+
+/// A collected span.
+///
+/// With the `serde` feature, deserializing spans leaks each distinct name they hold once, see
+/// `OwnedSpan` for deserializing the spans of other processes.
 #[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Span {
     pub id: u32,
     pub parent_id: u32,
     pub begin_unix_time_ns: u64,
     pub duration_ns: u64,
     pub event: &'static str,
-    pub properties: Vec<(&'static str, String)>,
+    pub properties: Vec<(&'static str, PropertyValue)>,
+    /// The instantaneous events recorded inside the span by [`event`](crate::event), in the
+    /// order they happened.
+    pub events: Vec<SpanEvent>,
+}
+
+/// An instantaneous event recorded inside a span, e.g. a cache miss, see [`event`](crate::event).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SpanEvent {
+    pub unix_time_ns: u64,
+    pub name: &'static str,
+}
+
+// The events recorded by `event` before they're converted into `SpanEvent`s
+pub(crate) type RawEvent = (Cycle, &'static str);
+
+#[inline]
+pub(crate) fn convert_events(events: &[RawEvent], anchor: Anchor) -> Vec<SpanEvent> {
+    events
+        .iter()
+        .map(|(cycle, name)| SpanEvent {
+            unix_time_ns: DefaultClock::cycle_to_unix_time_ns(*cycle, anchor),
+            name,
+        })
+        .collect()
 }
 
 #[derive(Clone, Debug)]
@@ -25,10 +96,16 @@ pub struct RawSpan {
     pub parent_id: SpanId,
     pub begin_cycle: Cycle,
     pub event: &'static str,
-    pub properties: Vec<(&'static str, String)>,
+    pub properties: Vec<(&'static str, PropertyValue)>,
+    pub(crate) events: Vec<RawEvent>,
 
     // Will write this field at post processing
     pub end_cycle: Cycle,
+
+    #[cfg(feature = "cpu-time")]
+    pub(crate) begin_cpu_time_ns: u64,
+    #[cfg(feature = "alloc-counters")]
+    pub(crate) begin_allocations: (u64, u64),
 }
 
 impl RawSpan {
@@ -45,7 +122,12 @@ impl RawSpan {
             begin_cycle: begin_cycles,
             event,
             properties: vec![],
+            events: vec![],
             end_cycle: Cycle::default(),
+            #[cfg(feature = "cpu-time")]
+            begin_cpu_time_ns: 0,
+            #[cfg(feature = "alloc-counters")]
+            begin_allocations: (0, 0),
         }
     }
 
@@ -55,16 +137,19 @@ impl RawSpan {
     }
 
     #[inline]
-    pub fn into_span(self, anchor: Anchor) -> Span {
+    pub fn into_span(mut self, anchor: Anchor) -> Span {
         let begin_unix_time_ns = DefaultClock::cycle_to_unix_time_ns(self.begin_cycle, anchor);
         let end_unix_time_ns = DefaultClock::cycle_to_unix_time_ns(self.end_cycle, anchor);
+        let duration_ns =
+            checked_duration_ns(begin_unix_time_ns, end_unix_time_ns, &mut self.properties);
         Span {
             id: self.id.0,
             parent_id: self.parent_id.0,
             begin_unix_time_ns,
-            duration_ns: end_unix_time_ns - begin_unix_time_ns,
+            duration_ns,
             event: self.event,
             properties: self.properties,
+            events: convert_events(&self.events, anchor),
         }
     }
 }
@@ -0,0 +1,125 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+
+/// The value of a span property.
+///
+/// Reporters supporting typed values, e.g. Jaeger, keep the type, and the others report the
+/// value as a string.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PropertyValue {
+    String(Cow<'static, str>),
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+    /// Reference-counted bytes, e.g. an encoded key range, shared by the spans and the
+    /// collectors instead of being copied until a reporter serializes them.
+    Binary(Arc<[u8]>),
+}
+
+impl PropertyValue {
+    #[inline]
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            PropertyValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The value as a string, with binary values hex encoded, for reporters supporting only
+    /// string values.
+    pub fn to_text(&self) -> Cow<'_, str> {
+        match self {
+            PropertyValue::String(s) => Cow::Borrowed(s),
+            _ => Cow::Owned(self.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for PropertyValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PropertyValue::String(s) => f.write_str(s),
+            PropertyValue::I64(v) => write!(f, "{}", v),
+            PropertyValue::F64(v) => write!(f, "{}", v),
+            PropertyValue::Bool(v) => write!(f, "{}", v),
+            PropertyValue::Binary(bytes) => {
+                for b in bytes.iter() {
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<String> for PropertyValue {
+    #[inline]
+    fn from(s: String) -> Self {
+        PropertyValue::String(Cow::Owned(s))
+    }
+}
+
+impl From<&'static str> for PropertyValue {
+    #[inline]
+    fn from(s: &'static str) -> Self {
+        PropertyValue::String(Cow::Borrowed(s))
+    }
+}
+
+impl From<i64> for PropertyValue {
+    #[inline]
+    fn from(v: i64) -> Self {
+        PropertyValue::I64(v)
+    }
+}
+
+impl From<i32> for PropertyValue {
+    #[inline]
+    fn from(v: i32) -> Self {
+        PropertyValue::I64(v.into())
+    }
+}
+
+impl From<u32> for PropertyValue {
+    #[inline]
+    fn from(v: u32) -> Self {
+        PropertyValue::I64(v.into())
+    }
+}
+
+impl From<f64> for PropertyValue {
+    #[inline]
+    fn from(v: f64) -> Self {
+        PropertyValue::F64(v)
+    }
+}
+
+impl From<bool> for PropertyValue {
+    #[inline]
+    fn from(v: bool) -> Self {
+        PropertyValue::Bool(v)
+    }
+}
+
+impl From<Arc<[u8]>> for PropertyValue {
+    #[inline]
+    fn from(bytes: Arc<[u8]>) -> Self {
+        PropertyValue::Binary(bytes)
+    }
+}
+
+impl PartialEq<str> for PropertyValue {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == Some(other)
+    }
+}
+
+impl PartialEq<&str> for PropertyValue {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == Some(*other)
+    }
+}
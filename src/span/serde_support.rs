@@ -0,0 +1,129 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+
+use crate::span::{PropertyValue, Span, SpanEvent};
+use crate::trace::interner;
+
+/// A [`Span`] deserialized with owned names, leaking nothing.
+///
+/// Deserializing a `Span` goes through it and interns the names: each distinct event name,
+/// property key and span event name is leaked once, so that it can be a `&'static str`, and
+/// deserialization fails once the process has interned too many. A process deserializing the
+/// spans of others, e.g. a collecting service, should deserialize them as `OwnedSpan`s instead.
+///
+/// ```rust
+/// use minitrace::span::{OwnedSpan, Span};
+///
+/// let json = serde_json::to_string(&Span { id: 1, event: "get", ..Default::default() }).unwrap();
+/// let span: OwnedSpan = serde_json::from_str(&json).unwrap();
+/// assert_eq!(span.event, "get");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct OwnedSpan {
+    pub id: u32,
+    pub parent_id: u32,
+    pub begin_unix_time_ns: u64,
+    pub duration_ns: u64,
+    pub event: String,
+    pub properties: Vec<(String, PropertyValue)>,
+    // Absent from the spans serialized before spans had events
+    #[serde(default)]
+    pub events: Vec<OwnedSpanEvent>,
+}
+
+/// A [`SpanEvent`] deserialized with an owned name, see [`OwnedSpan`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct OwnedSpanEvent {
+    pub unix_time_ns: u64,
+    pub name: String,
+}
+
+impl<'de> Deserialize<'de> for Span {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let span = OwnedSpan::deserialize(deserializer)?;
+        let intern = |name| {
+            interner::intern(name).ok_or_else(|| D::Error::custom("too many distinct names"))
+        };
+        Ok(Span {
+            id: span.id,
+            parent_id: span.parent_id,
+            begin_unix_time_ns: span.begin_unix_time_ns,
+            duration_ns: span.duration_ns,
+            event: intern(span.event)?,
+            properties: span
+                .properties
+                .into_iter()
+                .map(|(key, value)| Ok((intern(key)?, value)))
+                .collect::<Result<_, _>>()?,
+            events: span
+                .events
+                .into_iter()
+                .map(|event| {
+                    Ok(SpanEvent {
+                        unix_time_ns: event.unix_time_ns,
+                        name: intern(event.name)?,
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::span::{PropertyValue, Span, SpanEvent};
+    use crate::{Trace, TraceResult};
+
+    #[test]
+    fn round_trip() {
+        let root = Span {
+            id: 1,
+            parent_id: 0,
+            begin_unix_time_ns: 1_000,
+            duration_ns: 500,
+            event: "root",
+            properties: vec![
+                ("string", "v".into()),
+                ("i64", PropertyValue::I64(-1)),
+                ("f64", PropertyValue::F64(0.5)),
+                ("bool", PropertyValue::Bool(true)),
+                (
+                    "binary",
+                    PropertyValue::Binary(Arc::from(&[0xab, 0xcd][..])),
+                ),
+            ],
+            events: vec![SpanEvent {
+                unix_time_ns: 1_200,
+                name: "retry",
+            }],
+        };
+        let child = Span {
+            id: 2,
+            parent_id: 1,
+            event: "get",
+            ..Default::default()
+        };
+
+        let json =
+            serde_json::to_string(&TraceResult::new(vec![root.clone(), child.clone()])).unwrap();
+        let result: TraceResult = serde_json::from_str(&json).unwrap();
+        assert_eq!(result.spans_by_event("get").len(), 1);
+        let span = &result.spans_by_event("root")[0];
+        assert_eq!(span.duration_ns, 500);
+        assert_eq!(span.properties, root.properties);
+        assert_eq!(span.events, root.events);
+
+        let trace = Trace {
+            root,
+            spans: vec![child],
+        };
+        let json = serde_json::to_string(&trace).unwrap();
+        let trace: Trace = serde_json::from_str(&json).unwrap();
+        assert_eq!(trace.root.event, "root");
+        assert_eq!(trace.spans[0].parent_id, 1);
+    }
+}
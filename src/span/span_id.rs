@@ -1,7 +1,10 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
 use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::SystemTime;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Default)]
 pub struct SpanId(pub u32);
@@ -23,7 +26,44 @@ thread_local! {
     static LOCAL_ID_GENERATOR: Cell<(u16, u16)> = Cell::new((next_id_prefix(), 0))
 }
 
+thread_local! {
+    static LOCAL_TRACE_ID_STATE: Cell<u64> = Cell::new(trace_id_seed())
+}
+
+fn trace_id_seed() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    // `RandomState` is randomly keyed per thread, which separates threads starting at the same time.
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(now);
+    hasher.finish()
+}
+
 impl DefaultIdGenerator {
+    /// Create a non-zero pseudo-random 128-bit trace id
+    pub fn next_trace_id() -> u128 {
+        LOCAL_TRACE_ID_STATE.with(|state| {
+            // splitmix64
+            let next = || {
+                let s = state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+                state.set(s);
+
+                let mut z = s;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                z ^ (z >> 31)
+            };
+            loop {
+                let trace_id = (next() as u128) << 64 | next() as u128;
+                if trace_id != 0 {
+                    return trace_id;
+                }
+            }
+        })
+    }
+
     #[inline]
     /// Create a non-zero `SpanId`
     pub fn next_id() -> SpanId {
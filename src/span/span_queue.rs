@@ -1,34 +1,200 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
-use crate::span::cycle::DefaultClock;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "alloc-counters")]
+use crate::alloc;
+use crate::config::{Config, SpanQueueGrowth};
+use crate::hooks::RawSpanMeta;
+use crate::semconv;
+#[cfg(feature = "cpu-time")]
+use crate::span::cpu_time;
+use crate::span::cycle::{Cycle, DefaultClock};
 use crate::span::span_id::{DefaultIdGenerator, SpanId};
-use crate::span::RawSpan;
+use crate::span::{PropertyValue, RawEvent, RawSpan};
+use crate::stats;
+use crate::trace::rate_limit::RateLimit;
 
 pub struct SpanQueue {
     span_queue: Vec<RawSpan>,
     next_parent_id: SpanId,
+
+    // Properties are buffered here, indexed by their spans, and moved into the spans when the
+    // queue is taken. It saves allocating a property list per span on the hot path.
+    properties: Vec<(usize, &'static str, PropertyValue)>,
+    // Events are buffered alike, indexed by their spans or by `None` if no span was open, in
+    // which case they belong to the span the queue is collected under
+    events: Vec<(Option<usize>, RawEvent)>,
+
+    growth: SpanQueueGrowth,
+    // Whether the buffers have been pretouched, and are to be kept rather than handed out
+    pretouched: bool,
+    rate_limits: Vec<ThreadRateLimit>,
+
+    #[cfg(feature = "cpu-time")]
+    record_cpu_time: bool,
+    #[cfg(feature = "alloc-counters")]
+    record_allocations: bool,
+}
+
+// The state of a `RateLimit::PerSecondPerThread`
+struct ThreadRateLimit {
+    event: &'static str,
+    per_second: usize,
+    window_start: Instant,
+    kept: usize,
+    // The index of the last span kept, until the queue is taken
+    last_kept: Option<usize>,
+    // The spans dropped since the last span kept
+    dropped: i64,
 }
 
 pub struct SpanHandle {
     pub(crate) index: usize,
 }
 
+// The spans recorded since a nested local collector started, taken apart from those of the
+// suspended outer one
+pub struct Frame {
+    start: usize,
+    events_start: usize,
+    next_parent_id: SpanId,
+}
+
 impl SpanQueue {
-    pub fn with_capacity(capacity: usize) -> Self {
-        Self {
-            span_queue: Vec::with_capacity(capacity),
+    pub fn new(config: &Config) -> Self {
+        let mut span_queue = Self {
+            span_queue: Vec::with_capacity(config.span_queue_capacity),
             next_parent_id: SpanId::new(0),
+            properties: Vec::with_capacity(config.span_queue_capacity),
+            events: Vec::new(),
+            growth: config.span_queue_growth,
+            pretouched: false,
+            rate_limits: config
+                .rate_limits
+                .iter()
+                .filter_map(|(event, limit)| match limit {
+                    RateLimit::PerSecondPerThread(per_second) => Some(ThreadRateLimit {
+                        event,
+                        per_second: (*per_second).max(1),
+                        window_start: Instant::now(),
+                        kept: 0,
+                        last_kept: None,
+                        dropped: 0,
+                    }),
+                    RateLimit::PerTrace(_) => None,
+                })
+                .collect(),
+            #[cfg(feature = "cpu-time")]
+            record_cpu_time: config.record_cpu_time,
+            #[cfg(feature = "alloc-counters")]
+            record_allocations: config.record_allocations,
+        };
+
+        if config.pretouch_span_queue {
+            span_queue.pretouch();
+        }
+
+        span_queue
+    }
+
+    // Whether a span of the event is to be started under the per-thread rate limits
+    #[inline]
+    pub fn admit(&mut self, event: &'static str) -> bool {
+        self.rate_limits.is_empty() || self.admit_rate_limited(event)
+    }
+
+    fn admit_rate_limited(&mut self, event: &'static str) -> bool {
+        let index = self.span_queue.len();
+        let limit = match self.rate_limits.iter_mut().find(|l| l.event == event) {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        let now = Instant::now();
+        if now.duration_since(limit.window_start) >= Duration::from_secs(1) {
+            limit.window_start = now;
+            limit.kept = 0;
+        }
+        if limit.kept >= limit.per_second {
+            limit.dropped += 1;
+            return false;
+        }
+
+        limit.kept += 1;
+        let dropped = std::mem::take(&mut limit.dropped);
+        if dropped > 0 {
+            let index = limit.last_kept.unwrap_or(index);
+            reserve(&mut self.properties, self.growth, 1);
+            self.properties.push((
+                index,
+                semconv::RATE_LIMITED_SPANS,
+                PropertyValue::I64(dropped),
+            ));
+        }
+        limit.last_kept = Some(index);
+        true
+    }
+
+    // Count the spans dropped since the last kept spans from `start` on, which are about to be
+    // taken, and forget those
+    fn flush_rate_limits(&mut self, start: usize) {
+        for limit in &mut self.rate_limits {
+            match limit.last_kept {
+                Some(index) if index >= start => {
+                    if limit.dropped > 0 {
+                        reserve(&mut self.properties, self.growth, 1);
+                        self.properties.push((
+                            index,
+                            semconv::RATE_LIMITED_SPANS,
+                            PropertyValue::I64(limit.dropped),
+                        ));
+                        limit.dropped = 0;
+                    }
+                    limit.last_kept = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Forget the kept spans from `start` on, which are about to be discarded
+    fn forget_rate_limits(&mut self, start: usize) {
+        for limit in &mut self.rate_limits {
+            if matches!(limit.last_kept, Some(index) if index >= start) {
+                limit.last_kept = None;
+            }
         }
     }
 
     #[inline]
     pub fn start_span(&mut self, event: &'static str) -> SpanHandle {
+        reserve(&mut self.span_queue, self.growth, 1);
+
         let span = RawSpan::begin_with(
             DefaultIdGenerator::next_id(),
             self.next_parent_id,
             DefaultClock::now(),
             event,
         );
+        #[cfg(feature = "cpu-time")]
+        let span = if self.record_cpu_time {
+            RawSpan {
+                begin_cpu_time_ns: cpu_time::thread_cpu_time_ns(),
+                ..span
+            }
+        } else {
+            span
+        };
+        #[cfg(feature = "alloc-counters")]
+        let span = if self.record_allocations {
+            RawSpan {
+                begin_allocations: alloc::thread_allocations(),
+                ..span
+            }
+        } else {
+            span
+        };
         self.next_parent_id = span.id;
 
         let index = self.span_queue.len();
@@ -37,15 +203,64 @@ impl SpanQueue {
         SpanHandle { index }
     }
 
+    // The span started, for the hooks to be called once the queue is released
     #[inline]
-    pub fn finish_span(&mut self, span_handle: SpanHandle) {
+    pub fn started(&self, span_handle: &SpanHandle) -> RawSpanMeta {
+        let span = &self.span_queue[span_handle.index];
+        RawSpanMeta {
+            id: span.id,
+            parent_id: span.parent_id,
+            event: span.event,
+            cycle: span.begin_cycle,
+        }
+    }
+
+    // Finish the span, returning it for the hooks to be called once the queue is released
+    #[inline]
+    pub fn finish_span(&mut self, span_handle: SpanHandle) -> RawSpanMeta {
         debug_assert!(span_handle.index < self.span_queue.len());
         debug_assert_eq!(self.next_parent_id, self.span_queue[span_handle.index].id);
 
         let span = &mut self.span_queue[span_handle.index];
         span.end_with(DefaultClock::now());
+        let ended = RawSpanMeta {
+            id: span.id,
+            parent_id: span.parent_id,
+            event: span.event,
+            cycle: span.end_cycle,
+        };
+        stats::observe(span.event, span.begin_cycle, span.end_cycle);
+        #[cfg(feature = "cpu-time")]
+        if self.record_cpu_time {
+            let cpu_time_ns = cpu_time::thread_cpu_time_ns().saturating_sub(span.begin_cpu_time_ns);
+            reserve(&mut self.properties, self.growth, 1);
+            self.properties.push((
+                span_handle.index,
+                semconv::CPU_TIME_NS,
+                PropertyValue::I64(cpu_time_ns as i64),
+            ));
+        }
+        #[cfg(feature = "alloc-counters")]
+        if self.record_allocations {
+            let (count, bytes) = alloc::thread_allocations();
+            let (begin_count, begin_bytes) = span.begin_allocations;
+            reserve(&mut self.properties, self.growth, 2);
+            self.properties.extend_from_slice(&[
+                (
+                    span_handle.index,
+                    semconv::ALLOC_COUNT,
+                    PropertyValue::I64(count.saturating_sub(begin_count) as i64),
+                ),
+                (
+                    span_handle.index,
+                    semconv::ALLOC_BYTES,
+                    PropertyValue::I64(bytes.saturating_sub(begin_bytes) as i64),
+                ),
+            ]);
+        }
 
         self.next_parent_id = span.parent_id;
+        ended
     }
 
     #[inline]
@@ -56,27 +271,331 @@ impl SpanQueue {
     ) {
         debug_assert!(span_handle.index < self.span_queue.len());
 
-        let span = &mut self.span_queue[span_handle.index];
-        span.properties.extend(properties);
+        let index = span_handle.index;
+        for (k, v) in properties {
+            reserve(&mut self.properties, self.growth, 1);
+            self.properties.push((index, k, PropertyValue::from(v)));
+        }
     }
 
     #[inline]
-    pub fn add_property(&mut self, span_handle: &SpanHandle, property: (&'static str, String)) {
+    pub fn add_property(
+        &mut self,
+        span_handle: &SpanHandle,
+        key: &'static str,
+        value: PropertyValue,
+    ) {
         debug_assert!(span_handle.index < self.span_queue.len());
 
-        let span = &mut self.span_queue[span_handle.index];
-        span.properties.push(property);
+        reserve(&mut self.properties, self.growth, 1);
+        self.properties.push((span_handle.index, key, value));
+    }
+
+    // The innermost span started but not finished, if any
+    #[cfg(feature = "log")]
+    #[inline]
+    pub fn open_span(&self) -> Option<SpanHandle> {
+        let id = self.next_parent_id;
+        if id == SpanId::new(0) {
+            return None;
+        }
+        self.span_queue
+            .iter()
+            .rposition(|span| span.id == id)
+            .map(|index| SpanHandle { index })
     }
 
+    // Record an event in the innermost span started but not finished
     #[inline]
-    pub fn take_queue(&mut self) -> Vec<RawSpan> {
+    pub fn add_event(&mut self, name: &'static str) {
+        let id = self.next_parent_id;
+        let index = if id == SpanId::new(0) {
+            None
+        } else {
+            self.span_queue.iter().rposition(|span| span.id == id)
+        };
+        self.events.push((index, (DefaultClock::now(), name)));
+    }
+
+    // Take the spans, and the events recorded while no span was open
+    #[inline]
+    pub fn take_queue(&mut self) -> (Vec<RawSpan>, Vec<RawEvent>) {
         self.next_parent_id = SpanId::new(0);
-        self.span_queue.split_off(0)
+        self.flush_rate_limits(0);
+        for (index, key, value) in self.properties.drain(..) {
+            self.span_queue[index].properties.push((key, value));
+        }
+        // A pretouched buffer is kept for the next spans, at the cost of moving the spans out
+        let mut spans = if self.pretouched {
+            self.span_queue.drain(..).collect()
+        } else {
+            self.span_queue.split_off(0)
+        };
+        let events = Self::move_events(&mut spans, 0, self.events.drain(..));
+        (spans, events)
+    }
+
+    // Move the events into the spans starting at `start` in the queue, returning those
+    // belonging to no span
+    fn move_events(
+        spans: &mut [RawSpan],
+        start: usize,
+        events: impl Iterator<Item = (Option<usize>, RawEvent)>,
+    ) -> Vec<RawEvent> {
+        let mut unowned = Vec::new();
+        for (index, event) in events {
+            match index {
+                Some(index) => spans[index - start].events.push(event),
+                None => unowned.push(event),
+            }
+        }
+        unowned
     }
 
     #[inline]
     pub fn clear(&mut self) {
         self.next_parent_id = SpanId::new(0);
+        self.forget_rate_limits(0);
         self.span_queue.clear();
+        self.properties.clear();
+        self.events.clear();
+    }
+
+    // The events of the spans started but not finished since the last reset or frame,
+    // outermost first
+    pub fn open_spans(&self) -> Vec<&'static str> {
+        let mut events = Vec::new();
+        let mut id = self.next_parent_id;
+        for span in self.span_queue.iter().rev() {
+            if id == SpanId::new(0) {
+                break;
+            }
+            if span.id == id {
+                events.push(span.event);
+                id = span.parent_id;
+            }
+        }
+        events.reverse();
+        events
+    }
+
+    // Start a frame whose top-level spans have no parent, suspending the spans recorded so far
+    #[inline]
+    pub fn push_frame(&mut self) -> Frame {
+        let frame = Frame {
+            start: self.span_queue.len(),
+            events_start: self.events.len(),
+            next_parent_id: self.next_parent_id,
+        };
+        self.next_parent_id = SpanId::new(0);
+        frame
+    }
+
+    // Take the spans of the frame, and the events recorded while none of them was open, and
+    // resume the suspended spans
+    pub fn take_frame(&mut self, frame: Frame) -> (Vec<RawSpan>, Vec<RawEvent>) {
+        self.flush_rate_limits(frame.start);
+        let mut spans = self.span_queue.split_off(frame.start);
+
+        // Move out the properties of the frame's spans, keeping the order of the rest
+        let mut kept = 0;
+        for i in 0..self.properties.len() {
+            if self.properties[i].0 >= frame.start {
+                let (index, key, value) =
+                    std::mem::replace(&mut self.properties[i], (0, "", PropertyValue::from("")));
+                spans[index - frame.start].properties.push((key, value));
+            } else {
+                self.properties.swap(kept, i);
+                kept += 1;
+            }
+        }
+        self.properties.truncate(kept);
+        let events = Self::move_events(
+            &mut spans,
+            frame.start,
+            self.events.drain(frame.events_start..),
+        );
+
+        self.next_parent_id = frame.next_parent_id;
+        (spans, events)
+    }
+
+    pub fn discard_frame(&mut self, frame: Frame) {
+        self.forget_rate_limits(frame.start);
+        self.span_queue.truncate(frame.start);
+        self.properties.retain(|(index, _, _)| *index < frame.start);
+        self.events.truncate(frame.events_start);
+        self.next_parent_id = frame.next_parent_id;
+    }
+
+    // Fault in the pages of the buffers by filling them up once.
+    fn pretouch(&mut self) {
+        let capacity = self.span_queue.capacity();
+        self.span_queue.resize_with(capacity, || {
+            RawSpan::begin_with(SpanId::new(0), SpanId::new(0), Cycle::default(), "")
+        });
+        self.span_queue.clear();
+
+        let capacity = self.properties.capacity();
+        self.properties
+            .resize_with(capacity, || (0, "", PropertyValue::from("")));
+        self.properties.clear();
+
+        self.pretouched = true;
+    }
+}
+
+// Make room for `additional` more items, growing by the step of a linear growth policy, if any,
+// rather than doubling the buffer
+#[inline]
+fn reserve<T>(buffer: &mut Vec<T>, growth: SpanQueueGrowth, additional: usize) {
+    if let SpanQueueGrowth::Linear(step) = growth {
+        if buffer.capacity() - buffer.len() < additional {
+            buffer.reserve_exact(step.max(additional));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear_growth() {
+        let config = Config::default()
+            .span_queue_capacity(4)
+            .span_queue_growth(SpanQueueGrowth::Linear(4))
+            .pretouch_span_queue(true);
+        let mut span_queue = SpanQueue::new(&config);
+
+        for _ in 0..5 {
+            let handle = span_queue.start_span("span");
+            span_queue.finish_span(handle);
+        }
+        assert_eq!(span_queue.span_queue.capacity(), 8);
+        assert_eq!(span_queue.take_queue().0.len(), 5);
+
+        let handle = span_queue.start_span("span");
+        for _ in 0..5 {
+            span_queue.add_property(&handle, "k", "v".into());
+        }
+        span_queue.finish_span(handle);
+        assert_eq!(span_queue.properties.capacity(), 8);
+    }
+
+    #[test]
+    fn pretouched_buffer_kept() {
+        let config = Config::default()
+            .span_queue_capacity(4)
+            .pretouch_span_queue(true);
+        let mut span_queue = SpanQueue::new(&config);
+        let buffer = span_queue.span_queue.as_ptr();
+
+        // Each collection leaves the pretouched buffer to the next one
+        for _ in 0..2 {
+            let handle = span_queue.start_span("span");
+            span_queue.finish_span(handle);
+            assert_eq!(span_queue.take_queue().0.len(), 1);
+            assert_eq!(span_queue.span_queue.as_ptr(), buffer);
+            assert_eq!(span_queue.span_queue.capacity(), 4);
+        }
+    }
+
+    #[cfg(feature = "cpu-time")]
+    #[test]
+    fn cpu_time() {
+        let mut span_queue = SpanQueue::new(&Config::default().record_cpu_time(true));
+
+        let handle = span_queue.start_span("spin");
+        let begin = std::time::Instant::now();
+        while begin.elapsed() < std::time::Duration::from_millis(5) {}
+        span_queue.finish_span(handle);
+
+        let (spans, _) = span_queue.take_queue();
+        let cpu_time_ns = match spans[0].properties[..] {
+            [(semconv::CPU_TIME_NS, PropertyValue::I64(v))] => v,
+            _ => panic!("no cpu time in {:?}", spans[0].properties),
+        };
+        if cfg!(unix) {
+            assert!(cpu_time_ns >= 1_000_000);
+        }
+    }
+
+    #[cfg(feature = "alloc-counters")]
+    #[global_allocator]
+    static GLOBAL: alloc::CountingAllocator = alloc::CountingAllocator::system();
+
+    #[cfg(feature = "alloc-counters")]
+    #[test]
+    fn allocations() {
+        let mut span_queue = SpanQueue::new(&Config::default().record_allocations(true));
+
+        let handle = span_queue.start_span("alloc");
+        let buf = std::hint::black_box(vec![0u8; 1000]);
+        drop(buf);
+        span_queue.finish_span(handle);
+
+        let (spans, _) = span_queue.take_queue();
+        let property = |key| {
+            spans[0].properties.iter().find_map(|(k, v)| match v {
+                PropertyValue::I64(v) if *k == key => Some(*v),
+                _ => None,
+            })
+        };
+        assert!(property(semconv::ALLOC_COUNT).unwrap() >= 1);
+        assert!(property(semconv::ALLOC_BYTES).unwrap() >= 1000);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let config = Config::default().rate_limit("hot", RateLimit::PerSecondPerThread(2));
+        let mut span_queue = SpanQueue::new(&config);
+
+        for _ in 0..5 {
+            if span_queue.admit("hot") {
+                let handle = span_queue.start_span("hot");
+                span_queue.finish_span(handle);
+            }
+        }
+        assert!(span_queue.admit("cold"));
+
+        let (spans, _) = span_queue.take_queue();
+        assert_eq!(spans.len(), 2);
+        assert!(spans[0].properties.is_empty());
+        assert_eq!(
+            spans[1].properties,
+            vec![(semconv::RATE_LIMITED_SPANS, PropertyValue::I64(3))]
+        );
+    }
+
+    #[test]
+    fn frame() {
+        let mut span_queue = SpanQueue::new(&Config::default());
+
+        let outer = span_queue.start_span("outer");
+        let frame = span_queue.push_frame();
+        let inner = span_queue.start_span("inner");
+        span_queue.add_property(&inner, "k", PropertyValue::from("inner"));
+        span_queue.add_property(&outer, "k", PropertyValue::from("outer"));
+        span_queue.finish_span(inner);
+
+        let (spans, _) = span_queue.take_frame(frame);
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].parent_id, SpanId::new(0));
+        assert_eq!(
+            spans[0].properties,
+            vec![("k", PropertyValue::from("inner"))]
+        );
+
+        let child = span_queue.start_span("child");
+        span_queue.finish_span(child);
+        span_queue.finish_span(outer);
+        let (spans, _) = span_queue.take_queue();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[1].parent_id, spans[0].id);
+        assert_eq!(
+            spans[0].properties,
+            vec![("k", PropertyValue::from("outer"))]
+        );
     }
 }
@@ -0,0 +1,154 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Barrier, Mutex, RwLock};
+
+use minstant::{Anchor, Cycle};
+
+lazy_static! {
+    static ref CLOCKS: RwLock<Vec<Arc<Inner>>> = RwLock::new(Vec::new());
+    // Maps cycles to integers ordered like them
+    static ref KEY_ANCHOR: Anchor = Anchor::new();
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// Whether there are test clocks, sparing `DefaultClock` the lookups when there are none
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static ATTACHED: RefCell<Option<Arc<Inner>>> = const { RefCell::new(None) };
+}
+
+struct Inner {
+    id: u64,
+    dropped: AtomicBool,
+    now_ns: AtomicU64,
+    barrier: Barrier,
+    // The virtual time of each cycle read by the attached threads
+    cycles: Mutex<HashMap<u64, u64>>,
+}
+
+/// A virtual clock for deterministic tests, under which durations of spans are exactly the
+/// time advanced by [`advance`](TestClock::advance) in between, e.g. to assert the timeline of a
+/// trace produced by several threads.
+///
+/// Only the threads [attached](TestClock::attach) follow the virtual time, starting at the unix
+/// time 0, so tests running concurrently on the real clock are unaffected. Spans must be
+/// collected before the clock is dropped, after which the threads are detached.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::sync::Arc;
+/// use minitrace::span::TestClock;
+/// use minitrace::{LocalSpan, Span};
+///
+/// // The main thread and a producer
+/// let clock = Arc::new(TestClock::new(2));
+/// clock.attach();
+///
+/// let (root_span, collector) = Span::root("root");
+/// let producer = {
+///     let child_span = Span::from_parent("produce", &root_span);
+///     let clock = clock.clone();
+///     std::thread::spawn(move || {
+///         clock.attach();
+///         let _guard = child_span.enter();
+///         clock.advance(100);
+///     })
+/// };
+/// clock.advance(100);
+/// producer.join().unwrap();
+/// drop(root_span);
+///
+/// let spans = collector.collect();
+/// assert!(spans.iter().all(|s| s.begin_unix_time_ns == 0 && s.duration_ns == 100));
+/// ```
+pub struct TestClock {
+    inner: Arc<Inner>,
+}
+
+impl TestClock {
+    /// A clock advanced together by `threads` threads.
+    pub fn new(threads: usize) -> Self {
+        let inner = Arc::new(Inner {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            dropped: AtomicBool::new(false),
+            now_ns: AtomicU64::new(0),
+            barrier: Barrier::new(threads),
+            cycles: Mutex::new(HashMap::new()),
+        });
+        let mut clocks = CLOCKS.write().unwrap();
+        clocks.push(inner.clone());
+        ACTIVE.store(true, Ordering::Relaxed);
+        TestClock { inner }
+    }
+
+    /// Make the current thread follow the clock until it's dropped, replacing any clock the
+    /// thread followed.
+    pub fn attach(&self) {
+        ATTACHED.with(|attached| *attached.borrow_mut() = Some(self.inner.clone()));
+    }
+
+    /// Advance the virtual time by `ns`, once all the threads of the clock have called it with
+    /// the same `ns`. Time read by any of them before the call is earlier than after.
+    pub fn advance(&self, ns: u64) {
+        // No thread reads the time while it's advanced
+        if self.inner.barrier.wait().is_leader() {
+            self.inner.now_ns.fetch_add(ns, Ordering::SeqCst);
+        }
+        self.inner.barrier.wait();
+    }
+
+    /// The virtual unix time.
+    pub fn now_ns(&self) -> u64 {
+        self.inner.now_ns.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for TestClock {
+    fn drop(&mut self) {
+        self.inner.dropped.store(true, Ordering::Relaxed);
+        let mut clocks = CLOCKS.write().unwrap();
+        clocks.retain(|c| c.id != self.inner.id);
+        ACTIVE.store(!clocks.is_empty(), Ordering::Relaxed);
+    }
+}
+
+#[inline]
+pub(crate) fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+// Record the virtual time of the cycle if the current thread follows a test clock
+#[cold]
+pub(crate) fn record(cycle: Cycle) {
+    ATTACHED.with(|attached| {
+        let mut attached = attached.borrow_mut();
+        if let Some(inner) = &*attached {
+            if inner.dropped.load(Ordering::Relaxed) {
+                *attached = None;
+                return;
+            }
+            let now_ns = inner.now_ns.load(Ordering::SeqCst);
+            inner
+                .cycles
+                .lock()
+                .unwrap()
+                .insert(cycle.into_unix_time_ns(*KEY_ANCHOR), now_ns);
+        }
+    });
+}
+
+#[cold]
+pub(crate) fn virtual_time_ns(cycle: Cycle) -> Option<u64> {
+    let key = cycle.into_unix_time_ns(*KEY_ANCHOR);
+    CLOCKS
+        .read()
+        .unwrap()
+        .iter()
+        .find_map(|inner| inner.cycles.lock().unwrap().get(&key).copied())
+}
@@ -0,0 +1,314 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Live histograms of the durations of the spans of each event, updated as the spans finish
+//! whether their traces are collected or not, e.g. to export latency metrics from the existing
+//! instrumentation. Enabled by [`Config::live_histograms`](crate::Config::live_histograms).
+//!
+//! Each thread records into its own histograms without locking, and they are merged when read.
+//!
+//! ```rust
+//! use minitrace::{set_config, Config, LocalSpan, Span};
+//!
+//! set_config(Config::default().live_histograms(true));
+//!
+//! let (root_span, _collector) = Span::root("request");
+//! {
+//!     let _g = root_span.enter();
+//!     let _l = LocalSpan::enter("get");
+//! }
+//!
+//! let histogram = minitrace::stats::histogram("get").unwrap();
+//! assert!(histogram.count() >= 1);
+//! let p99 = histogram.quantile(0.99);
+//! ```
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::span::{Cycle, DefaultClock};
+
+// The buckets of a power of two are 2^SUB_BUCKET_BITS, so that a bucket is at most 1/8 of its
+// values wide. Values below 2^SUB_BUCKET_BITS have a bucket each.
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+thread_local! {
+    static LOCAL_HISTOGRAMS: RefCell<LocalHistograms> = RefCell::new(LocalHistograms::default());
+}
+
+#[derive(Default)]
+struct Registry {
+    // The histograms of the running threads
+    live: HashMap<&'static str, Vec<Arc<AtomicHistogram>>>,
+    // The histograms of the threads exited, merged
+    retired: HashMap<&'static str, Histogram>,
+}
+
+#[derive(Default)]
+struct LocalHistograms {
+    histograms: HashMap<&'static str, Arc<AtomicHistogram>>,
+}
+
+impl Drop for LocalHistograms {
+    fn drop(&mut self) {
+        let mut registry = REGISTRY.lock().unwrap();
+        for (event, histogram) in self.histograms.drain() {
+            if let Some(live) = registry.live.get_mut(event) {
+                live.retain(|h| !Arc::ptr_eq(h, &histogram));
+            }
+            registry
+                .retired
+                .entry(event)
+                .or_insert_with(Histogram::new)
+                .merge(&histogram);
+        }
+    }
+}
+
+// Only written by its thread
+struct AtomicHistogram {
+    count: AtomicU64,
+    sum_ns: AtomicU64,
+    min_ns: AtomicU64,
+    max_ns: AtomicU64,
+    buckets: Box<[AtomicU64]>,
+}
+
+impl AtomicHistogram {
+    fn new() -> Self {
+        AtomicHistogram {
+            count: AtomicU64::new(0),
+            sum_ns: AtomicU64::new(0),
+            min_ns: AtomicU64::new(u64::MAX),
+            max_ns: AtomicU64::new(0),
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    #[inline]
+    fn observe(&self, duration_ns: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ns.fetch_add(duration_ns, Ordering::Relaxed);
+        self.min_ns.fetch_min(duration_ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(duration_ns, Ordering::Relaxed);
+        self.buckets[bucket(duration_ns)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.sum_ns.store(0, Ordering::Relaxed);
+        self.min_ns.store(u64::MAX, Ordering::Relaxed);
+        self.max_ns.store(0, Ordering::Relaxed);
+        for bucket in self.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A snapshot of the durations of the spans of an event, with a relative error of at most 1/8.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Histogram {
+    count: u64,
+    sum_ns: u64,
+    min_ns: u64,
+    max_ns: u64,
+    buckets: Vec<u64>,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            count: 0,
+            sum_ns: 0,
+            min_ns: u64::MAX,
+            max_ns: 0,
+            buckets: vec![0; BUCKETS],
+        }
+    }
+
+    fn merge(&mut self, other: &AtomicHistogram) {
+        self.count += other.count.load(Ordering::Relaxed);
+        self.sum_ns = self
+            .sum_ns
+            .wrapping_add(other.sum_ns.load(Ordering::Relaxed));
+        self.min_ns = self.min_ns.min(other.min_ns.load(Ordering::Relaxed));
+        self.max_ns = self.max_ns.max(other.max_ns.load(Ordering::Relaxed));
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += other.load(Ordering::Relaxed);
+        }
+    }
+
+    /// The number of spans.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_nanos(self.sum_ns)
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::from_secs(0),
+            count => Duration::from_nanos(self.sum_ns / count),
+        }
+    }
+
+    pub fn min(&self) -> Duration {
+        match self.count {
+            0 => Duration::from_secs(0),
+            _ => Duration::from_nanos(self.min_ns),
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max_ns)
+    }
+
+    /// The duration which the `q` quantile of the spans last at most, e.g. `0.99` for p99.
+    pub fn quantile(&self, q: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::from_secs(0);
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let ns = upper_bound(i).min(self.max_ns).max(self.min_ns);
+                return Duration::from_nanos(ns);
+            }
+        }
+        self.max()
+    }
+}
+
+/// The histogram of the spans of the event finished since the histograms were enabled or
+/// [`reset`], or `None` if there is none.
+pub fn histogram(event: &str) -> Option<Histogram> {
+    let registry = REGISTRY.lock().unwrap();
+    let mut histogram = registry.retired.get(event).cloned();
+    for live in registry.live.get(event).into_iter().flatten() {
+        histogram.get_or_insert_with(Histogram::new).merge(live);
+    }
+    histogram.filter(|h| h.count > 0)
+}
+
+/// The events having histograms, sorted.
+pub fn events() -> Vec<&'static str> {
+    let registry = REGISTRY.lock().unwrap();
+    let mut events: Vec<_> = registry
+        .live
+        .keys()
+        .chain(registry.retired.keys())
+        .copied()
+        .collect();
+    events.sort_unstable();
+    events.dedup();
+    events
+}
+
+/// Clear the histograms, e.g. to start a new interval of metrics.
+pub fn reset() {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.retired.clear();
+    for histogram in registry.live.values().flatten() {
+        histogram.reset();
+    }
+}
+
+pub(crate) fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+#[inline]
+pub(crate) fn observe(event: &'static str, begin: Cycle, end: Cycle) {
+    if ENABLED.load(Ordering::Relaxed) {
+        observe_slow(event, begin, end);
+    }
+}
+
+#[inline(never)]
+fn observe_slow(event: &'static str, begin: Cycle, end: Cycle) {
+    let anchor = DefaultClock::anchor();
+    let duration_ns = DefaultClock::cycle_to_unix_time_ns(end, anchor)
+        .saturating_sub(DefaultClock::cycle_to_unix_time_ns(begin, anchor));
+
+    // Not recorded while the thread exits
+    let _ = LOCAL_HISTOGRAMS.try_with(|local| {
+        let mut local = local.borrow_mut();
+        match local.histograms.get(event) {
+            Some(histogram) => histogram.observe(duration_ns),
+            None => {
+                let histogram = Arc::new(AtomicHistogram::new());
+                histogram.observe(duration_ns);
+                REGISTRY
+                    .lock()
+                    .unwrap()
+                    .live
+                    .entry(event)
+                    .or_default()
+                    .push(histogram.clone());
+                local.histograms.insert(event, histogram);
+            }
+        }
+    });
+}
+
+fn bucket(ns: u64) -> usize {
+    if ns < SUB_BUCKETS as u64 {
+        return ns as usize;
+    }
+    let exp = 63 - ns.leading_zeros();
+    let mantissa = (ns >> (exp - SUB_BUCKET_BITS)) as usize;
+    (exp - SUB_BUCKET_BITS) as usize * SUB_BUCKETS + mantissa
+}
+
+// The largest value of the bucket
+fn upper_bound(i: usize) -> u64 {
+    if i < SUB_BUCKETS {
+        return i as u64;
+    }
+    let shift = (i / SUB_BUCKETS - 1) as u32;
+    let mantissa = (i % SUB_BUCKETS + SUB_BUCKETS) as u64;
+    ((mantissa + 1) << shift).wrapping_sub(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        for ns in (0..100_000).chain(vec![u64::MAX / 3, u64::MAX - 1, u64::MAX]) {
+            let i = bucket(ns);
+            assert!(ns <= upper_bound(i), "{}", ns);
+            assert!(i == 0 || ns > upper_bound(i - 1), "{}", ns);
+        }
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+        assert_eq!(upper_bound(BUCKETS - 1), u64::MAX);
+
+        let mut histogram = Histogram::new();
+        let h = AtomicHistogram::new();
+        for ns in 1..=1000 {
+            h.observe(ns * 1000);
+        }
+        histogram.merge(&h);
+        assert_eq!(histogram.count(), 1000);
+        assert_eq!(histogram.min(), Duration::from_micros(1));
+        assert_eq!(histogram.max(), Duration::from_micros(1000));
+        assert_eq!(histogram.quantile(1.0), Duration::from_micros(1000));
+        let p50 = histogram.quantile(0.5).as_nanos() as f64;
+        assert!((p50 - 500_000.0).abs() / 500_000.0 <= 0.125, "{}", p50);
+    }
+}
@@ -1,12 +1,18 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 
-use crossbeam::channel::Sender;
+use crossbeam::channel::{Receiver, Sender};
 
+use crate::config::config;
 use crate::local::local_collector::LocalSpans;
-use crate::span::{RawSpan, SpanId};
+use crate::semconv;
+use crate::span::{DefaultClock, RawSpan, SpanId};
+use crate::trace::deadline::Deadline;
+use crate::trace::partial::PartialExport;
+use crate::trace::{late_spans, root_spans};
 
 #[derive(Clone, Debug)]
 pub enum SpanCollection {
@@ -15,28 +21,360 @@ pub enum SpanCollection {
         parent_id_of_root: SpanId,
     },
     Span(RawSpan),
+    // Spans timed by the caller, see `ManualSpan`
+    Manual(crate::span::Span),
+    // Collected by another process, see `Span::add_remote_spans`
+    Remote(Vec<crate::span::Span>),
+    // See `Span::expect_remote_children`
+    RemoteChildren {
+        parent_id: SpanId,
+        span_ids: Vec<u32>,
+    },
+    // Sent by `CollectorHandle::cancel` to wake up a waiting collector
+    Cancelled,
+    // Sent when the last `SpanSender` of a lease is dropped
+    Disconnected {
+        lease: u64,
+    },
+}
+
+// Bumped whenever a collector closes, so that acquirers found open at a generation are known to
+// be still open by a single atomic load as long as the generation is unchanged
+static CLOSE_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+pub fn close_generation() -> usize {
+    CLOSE_GENERATION.load(Ordering::SeqCst)
+}
+
+// Close the acquirers sharing `closed`
+pub fn close(closed: &AtomicBool) {
+    closed.store(true, Ordering::SeqCst);
+    CLOSE_GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+// The number of spans of a span collection
+#[inline]
+pub(crate) fn span_count(span_collection: &SpanCollection) -> usize {
+    match span_collection {
+        SpanCollection::LocalSpans { local_spans, .. } => local_spans.spans.len(),
+        SpanCollection::Span(_) | SpanCollection::Manual(_) => 1,
+        SpanCollection::Remote(spans) => spans.len(),
+        SpanCollection::RemoteChildren { .. }
+        | SpanCollection::Cancelled
+        | SpanCollection::Disconnected { .. } => 0,
+    }
+}
+
+// A span collection tagged with the id of the trace it belongs to
+pub type Submission = (u128, SpanCollection);
+
+// The sending half of a collector's channel shared by the acquirers of a trace. A pooled
+// channel outlives the trace, so dropping the last one tells a waiting collector that no more
+// spans will arrive, in place of the channel disconnecting.
+#[derive(Debug)]
+pub struct SpanSender {
+    sender: Sender<Submission>,
+    // The number of times the channel has been recycled before
+    lease: u64,
+    summary: Arc<SummaryState>,
+    // The baggage of the trace, see `Span::set_baggage`
+    baggage: Mutex<Vec<(String, String)>>,
+    // The receiving half of the channel and the export of the parts, see `Config::partial_export`
+    partial_export: Option<(Receiver<Submission>, Arc<PartialExport>)>,
+    // Tracks the spans still running, see `Span::root_with_deadline` and
+    // `CollectorHandle::cancel`
+    deadline: Option<Deadline>,
+}
+
+impl SpanSender {
+    pub fn new(sender: Sender<Submission>, lease: u64, summary: Arc<SummaryState>) -> Self {
+        SpanSender {
+            sender,
+            lease,
+            summary,
+            baggage: Mutex::new(Vec::new()),
+            partial_export: None,
+            deadline: None,
+        }
+    }
+
+    pub(crate) fn with_partial_export(
+        mut self,
+        receiver: Receiver<Submission>,
+        partial_export: Arc<PartialExport>,
+    ) -> Self {
+        self.partial_export = Some((receiver, partial_export));
+        self
+    }
+
+    pub(crate) fn with_deadline(mut self, deadline: Deadline) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    #[inline]
+    pub fn send(&self, submission: Submission) {
+        if let Some(deadline) = &self.deadline {
+            if !deadline.admit(&submission.1) {
+                return;
+            }
+        }
+        self.summary.record(&submission.1);
+        if let SpanCollection::Span(span) = &submission.1 {
+            root_spans::publish(submission.0, span);
+        }
+        let trace_id = submission.0;
+        self.sender.send(submission).ok();
+        if let Some((receiver, partial_export)) = &self.partial_export {
+            partial_export.export_pending(trace_id, receiver, &self.sender, &self.summary);
+        }
+        if let Some(deadline) = &self.deadline {
+            if deadline.is_exceeded() {
+                self.expire_deadline();
+            }
+        }
+    }
+
+    #[inline]
+    fn track(&self, span: &RawSpan) {
+        if let Some(deadline) = &self.deadline {
+            deadline.track(span);
+        }
+    }
+
+    // Finish the outstanding spans of a trace past its deadline and wake up its collector
+    pub(crate) fn expire_deadline(&self) {
+        if self.finish_outstanding(semconv::DEADLINE_EXCEEDED) {
+            self.sender.send((0, SpanCollection::Cancelled)).ok();
+        }
+    }
+
+    // Finish the outstanding spans of a cancelled trace and wake up its collector
+    pub(crate) fn cancel(&self) {
+        self.finish_outstanding(semconv::CANCELLED);
+        self.sender.send((0, SpanCollection::Cancelled)).ok();
+    }
+
+    // Return whether the outstanding spans have been finished now, i.e. not before
+    fn finish_outstanding(&self, marker: &'static str) -> bool {
+        let deadline = match &self.deadline {
+            Some(deadline) => deadline,
+            None => return false,
+        };
+        match deadline.expire(marker) {
+            Some(spans) => {
+                for span in spans {
+                    let span_collection = SpanCollection::Span(span);
+                    self.summary.record(&span_collection);
+                    self.sender.send((deadline.trace_id, span_collection)).ok();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The size and the duration of a trace, available as soon as its root span finishes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceSummary {
+    /// The number of spans reported so far, including the root span.
+    pub span_count: usize,
+    /// The duration of the root span.
+    pub duration: Duration,
+}
+
+// Counters updated by the acquirers of a trace when submitting, so that the summary doesn't
+// need the spans to be received from the channel
+#[derive(Debug)]
+pub struct SummaryState {
+    span_count: AtomicUsize,
+    // `u64::MAX` until the root span finishes
+    duration_ns: AtomicU64,
+    // The spans sent but not received from the channel yet
+    pending_spans: AtomicUsize,
+    // The number of parts exported, and whether a part is being exported
+    partials: AtomicU32,
+    exporting: AtomicBool,
+}
+
+impl Default for SummaryState {
+    fn default() -> Self {
+        SummaryState {
+            span_count: AtomicUsize::new(0),
+            duration_ns: AtomicU64::new(u64::MAX),
+            pending_spans: AtomicUsize::new(0),
+            partials: AtomicU32::new(0),
+            exporting: AtomicBool::new(false),
+        }
+    }
+}
+
+impl SummaryState {
+    #[inline]
+    fn record(&self, span_collection: &SpanCollection) {
+        let count = span_count(span_collection);
+        self.span_count.fetch_add(count, Ordering::Relaxed);
+        self.pending_spans.fetch_add(count, Ordering::Relaxed);
+        if let SpanCollection::Span(span) = span_collection {
+            if span.parent_id.0 == 0 {
+                let anchor = DefaultClock::anchor();
+                let duration_ns =
+                    DefaultClock::cycle_to_unix_time_ns(span.end_cycle, anchor).saturating_sub(
+                        DefaultClock::cycle_to_unix_time_ns(span.begin_cycle, anchor),
+                    );
+                self.duration_ns.store(duration_ns, Ordering::Release);
+            }
+        }
+    }
+
+    // Account for spans received from the channel
+    pub(crate) fn drain(&self, span_count: usize) {
+        let _ = self
+            .pending_spans
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pending| {
+                Some(pending.saturating_sub(span_count))
+            });
+    }
+
+    #[inline]
+    pub(crate) fn pending_spans(&self) -> usize {
+        self.pending_spans.load(Ordering::Relaxed)
+    }
+
+    // Whether the caller has to export a part, in which case it must call `end_partial` after
+    pub(crate) fn begin_partial(&self) -> bool {
+        !self.exporting.swap(true, Ordering::Acquire)
+    }
+
+    // The sequence number of the next part
+    pub(crate) fn next_partial(&self) -> u32 {
+        self.partials.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) fn end_partial(&self) {
+        self.exporting.store(false, Ordering::Release);
+    }
+
+    // The number of parts exported before the trace is collected
+    pub(crate) fn partials(&self) -> u32 {
+        self.partials.load(Ordering::Relaxed)
+    }
+
+    pub fn summary(&self) -> Option<TraceSummary> {
+        let duration_ns = self.duration_ns.load(Ordering::Acquire);
+        if duration_ns == u64::MAX {
+            return None;
+        }
+        Some(TraceSummary {
+            span_count: self.span_count.load(Ordering::Relaxed),
+            duration: Duration::from_nanos(duration_ns),
+        })
+    }
+
+    pub fn reset(&self) {
+        self.span_count.store(0, Ordering::Relaxed);
+        self.duration_ns.store(u64::MAX, Ordering::Relaxed);
+        self.pending_spans.store(0, Ordering::Relaxed);
+        self.partials.store(0, Ordering::Relaxed);
+        self.exporting.store(false, Ordering::Relaxed);
+    }
+}
+
+impl Drop for SpanSender {
+    fn drop(&mut self) {
+        self.send((0, SpanCollection::Disconnected { lease: self.lease }));
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Acquirer {
-    sender: Arc<Sender<SpanCollection>>,
+    sender: Arc<SpanSender>,
     closed: Arc<AtomicBool>,
+    trace_id: u128,
 }
 
 impl Acquirer {
-    pub fn new(sender: Arc<Sender<SpanCollection>>, closed: Arc<AtomicBool>) -> Self {
-        Acquirer { sender, closed }
+    pub fn new(sender: Arc<SpanSender>, closed: Arc<AtomicBool>, trace_id: u128) -> Self {
+        Acquirer {
+            sender,
+            closed,
+            trace_id,
+        }
     }
 
     pub fn submit(&self, span_collection: SpanCollection) {
         if self.is_shutdown() {
+            if config().aggregate_late_spans {
+                late_spans::aggregate(&span_collection);
+            }
             return;
         }
 
-        self.sender.send(span_collection).ok();
+        self.sender.send((self.trace_id, span_collection));
+    }
+
+    // Track a span started to report to the acquirer, see `Span::root_with_deadline`
+    #[inline]
+    pub(crate) fn track(&self, span: &RawSpan) {
+        self.sender.track(span);
     }
 
     pub fn is_shutdown(&self) -> bool {
         self.closed.load(Ordering::SeqCst)
     }
+
+    #[inline]
+    pub fn trace_id(&self) -> u128 {
+        self.trace_id
+    }
+
+    pub fn set_baggage(&self, key: String, value: String) {
+        let mut baggage = self.sender.baggage.lock().unwrap();
+        match baggage.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => baggage.push((key, value)),
+        }
+    }
+
+    pub fn baggage(&self, key: &str) -> Option<String> {
+        let baggage = self.sender.baggage.lock().unwrap();
+        baggage
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+    }
+
+    /// The number of live handles to the same collector, including this one.
+    #[inline]
+    pub fn references(&self) -> usize {
+        Arc::strong_count(&self.sender)
+    }
+
+    pub fn downgrade(&self) -> WeakAcquirer {
+        WeakAcquirer {
+            sender: Arc::downgrade(&self.sender),
+            closed: self.closed.clone(),
+            trace_id: self.trace_id,
+        }
+    }
+}
+
+// An acquirer which doesn't keep the trace open, see `SpanHandle`
+#[derive(Clone, Debug)]
+pub struct WeakAcquirer {
+    sender: Weak<SpanSender>,
+    closed: Arc<AtomicBool>,
+    trace_id: u128,
+}
+
+impl WeakAcquirer {
+    pub fn upgrade(&self) -> Option<Acquirer> {
+        Some(Acquirer {
+            sender: self.sender.upgrade()?,
+            closed: self.closed.clone(),
+            trace_id: self.trace_id,
+        })
+    }
 }
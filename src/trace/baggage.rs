@@ -0,0 +1,79 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::local::span_guard::AttachedSpan;
+use crate::Span;
+
+impl Span {
+    /// Set an entry of the baggage of the traces of the span, shared by all their spans, e.g. a
+    /// flag sent by the client in the baggage of a [`TraceContext`], see [`flag`].
+    ///
+    /// [`TraceContext`]: crate::propagation::TraceContext
+    pub fn with_baggage(self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.set_baggage(key, value);
+        self
+    }
+
+    pub fn set_baggage(&self, key: impl Into<String>, value: impl Into<String>) {
+        if let Some(inner) = &self.inner {
+            let (key, value) = (key.into(), value.into());
+            for (_, acq) in &inner.to_report {
+                acq.set_baggage(key.clone(), value.clone());
+            }
+        }
+    }
+
+    /// An entry of the baggage of the traces of the span, from the first trace having it.
+    pub fn baggage(&self, key: &str) -> Option<String> {
+        self.inner
+            .as_ref()?
+            .to_report
+            .iter()
+            .find_map(|(_, acq)| acq.baggage(key))
+    }
+}
+
+/// An entry of the baggage of the traces of the span attached to the current thread, see
+/// [`Span::set_baggage`].
+pub fn baggage(key: &str) -> Option<String> {
+    AttachedSpan::baggage(key)
+}
+
+/// Whether the flag `name` is set to `true` or `1` in the baggage of the traces of the span
+/// attached to the current thread, so that a client can turn on more verbose instrumentation for
+/// a single request.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::propagation::{SpanContext, TraceContext};
+/// use minitrace::{LocalSpan, Span};
+///
+/// fn execute(sql: &str) {
+///     let mut guard = LocalSpan::enter("execute");
+///     if minitrace::flag("capture_sql") {
+///         guard = guard.with_property(|| ("sql", sql.to_owned()));
+///     }
+/// }
+///
+/// // The context received from the client
+/// let context = TraceContext::new(SpanContext::new(42, 7)).with_baggage("capture_sql", "true");
+///
+/// let (root_span, collector) = Span::root_with_trace_id("query", context.span_context.trace_id);
+/// for (key, value) in context.baggage {
+///     root_span.set_baggage(key, value);
+/// }
+/// {
+///     let _guard = root_span.enter();
+///     execute("SELECT 1");
+/// }
+/// drop(root_span);
+///
+/// let spans = collector.collect();
+/// assert!(spans.iter().any(|s| s.event == "execute" && !s.properties.is_empty()));
+/// ```
+pub fn flag(name: &str) -> bool {
+    match baggage(name) {
+        Some(value) => value.eq_ignore_ascii_case("true") || value == "1",
+        None => false,
+    }
+}
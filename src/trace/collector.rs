@@ -1,30 +1,280 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
-use crossbeam::channel::Receiver;
-
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::iter;
+use std::ops::Range;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::RecvTimeoutError;
 
+use crate::config::config;
+use crate::local::local_collector::LocalSpans;
+use crate::semconv;
 use crate::span::Span;
-use crate::span::{Anchor, DefaultClock};
-use crate::trace::acquirer::SpanCollection;
+use crate::span::{convert_events, Anchor, DefaultClock, DefaultIdGenerator, SpanEvent, SpanId};
+use crate::trace::acquirer::{
+    self, Acquirer, SpanCollection, SpanSender, Submission, TraceSummary,
+};
+use crate::trace::deadline::Deadline;
+use crate::trace::pool::{self, Channel};
+use crate::trace::rate_limit;
+use crate::trace::storage::SpanStorage;
+use crate::trace::trace_result::TraceResult;
+use crate::trace::truncation::{CollapseRepeated, TruncationStrategy};
+use crate::trace::validate::debug_validate;
+use crate::trace::{partial, registry, remote_children, snapshot};
 
 pub struct Collector {
-    receiver: Receiver<SpanCollection>,
+    channel: Channel,
+    sender: Weak<SpanSender>,
+    cancelled: Arc<AtomicBool>,
+    trace_id: u128,
+
+    // The key of the trace in the registry of active traces
+    registry_key: Option<usize>,
+
+    // Keeps a forest open to new roots until it's collected
+    forest_sender: Option<Arc<SpanSender>>,
+
+    // Whether the end of the trace has been received while spilling
+    finished: bool,
+
+    // See `Span::root_with_context`
+    remote_parent_span_id: Option<u64>,
+
+    // See `Span::root_with_deadline`
+    deadline: Option<Instant>,
+}
+
+/// A handle to cancel a [`Collector`](Collector) from another thread, e.g. a watchdog of
+/// requests exceeding their deadline.
+#[derive(Clone, Debug)]
+pub struct CollectorHandle {
+    sender: Weak<SpanSender>,
     closed: Arc<AtomicBool>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CollectorHandle {
+    /// Stop accepting spans and make the collector return what has been reported so far, even
+    /// if it's waiting in a synchronous collection. The spans still running are finished now,
+    /// except the local spans not submitted yet, and without the properties added after they
+    /// started.
+    ///
+    /// The spans finished by the cancellation and the top-level spans of such a partial trace
+    /// carry the property [`("cancelled", "true")`](crate::semconv::CANCELLED).
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        acquirer::close(&self.closed);
+        if let Some(sender) = self.sender.upgrade() {
+            sender.cancel();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
 }
 
 impl Collector {
-    pub(crate) fn new(receiver: Receiver<SpanCollection>, closed: Arc<AtomicBool>) -> Self {
-        Collector { receiver, closed }
+    pub(crate) fn new(
+        channel: Channel,
+        sender: Weak<SpanSender>,
+        trace_id: u128,
+        registry_key: Option<usize>,
+    ) -> Self {
+        Collector {
+            channel,
+            sender,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            trace_id,
+            registry_key,
+            forest_sender: None,
+            finished: false,
+            remote_parent_span_id: None,
+            deadline: None,
+        }
+    }
+
+    pub(crate) fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    // Receive the next submission, finishing the outstanding spans once the deadline passes
+    fn recv(&self) -> Option<Submission> {
+        if let Some(deadline) = self.deadline {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.channel.receiver.recv_timeout(timeout) {
+                Err(RecvTimeoutError::Timeout) => self.expire_deadline(),
+                received => return received.ok(),
+            }
+        }
+        self.channel.receiver.recv().ok()
+    }
+
+    fn expire_deadline(&self) {
+        if let Some(sender) = self.sender.upgrade() {
+            sender.expire_deadline();
+        }
+    }
+
+    pub(crate) fn with_remote_parent(mut self, remote_parent_span_id: u64) -> Self {
+        self.remote_parent_span_id = Some(remote_parent_span_id);
+        self
+    }
+
+    /// The id of the span of another process the trace continues, given to
+    /// [`Span::root_with_context`](crate::Span::root_with_context), to be passed to reporters
+    /// as the parent of the root spans, e.g. the `root_parent_span_id` of the Jaeger reporter.
+    /// The collected root spans keep 0 as their parent id.
+    pub fn remote_parent_span_id(&self) -> Option<u64> {
+        self.remote_parent_span_id
+    }
+
+    /// Create a collector of a forest of traces, e.g. one per chunk of a batch job, whose root
+    /// spans are started over time by [`start_root`](Collector::start_root) and collected
+    /// together by [`collect_forest`](Collector::collect_forest).
+    ///
+    /// The traces share the trace id of the collector.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minitrace::{CollectArgs, Collector, LocalSpan};
+    ///
+    /// let collector = Collector::forest();
+    /// for _ in 0..3 {
+    ///     let root_span = collector.start_root("chunk");
+    ///     let _guard = root_span.enter();
+    ///     let _local_guard = LocalSpan::enter("upload");
+    /// }
+    ///
+    /// let traces = collector.collect_forest(CollectArgs::default().sync(true));
+    /// assert_eq!(traces.len(), 3);
+    /// assert!(traces.iter().all(|trace| trace.spans.len() == 1));
+    /// ```
+    pub fn forest() -> Self {
+        Self::forest_with_trace_id(DefaultIdGenerator::next_trace_id())
+    }
+
+    pub fn forest_with_trace_id(trace_id: u128) -> Self {
+        let channel = pool::take();
+        let tx = Arc::new(channel.span_sender().with_deadline(Deadline::new(
+            None,
+            channel.closed.clone(),
+            trace_id,
+        )));
+        let mut collector = Collector::new(channel, Arc::downgrade(&tx), trace_id, None);
+        collector.forest_sender = Some(tx);
+        collector
+    }
+
+    /// Start another root span reporting to this collector, or an empty span if every span of
+    /// the trace has finished and the collector isn't a [`forest`](Collector::forest).
+    pub fn start_root(&self, event: &'static str) -> crate::Span {
+        let sender = match self.sender.upgrade() {
+            Some(sender) => sender,
+            None => return crate::Span::empty(),
+        };
+        let acquirer = Acquirer::new(sender, self.channel.closed.clone(), self.trace_id);
+        let span = crate::Span::new(iter::once((SpanId::new(0), &acquirer)), event);
+        if snapshot::has_snapshots() {
+            span.with_properties(snapshot::capture)
+        } else {
+            span
+        }
+    }
+
+    pub fn handle(&self) -> CollectorHandle {
+        CollectorHandle {
+            sender: self.sender.clone(),
+            closed: self.channel.closed.clone(),
+            cancelled: self.cancelled.clone(),
+        }
+    }
+
+    /// The number of live spans and attached span guards still able to report to this
+    /// collector.
+    ///
+    /// A synchronous [`collect`](Collector::collect_with_args) waits until it drops to zero, so
+    /// a non-zero value after the traced routine is done usually points at a forgotten span
+    /// keeping the trace alive.
+    #[inline]
+    pub fn live_references(&self) -> usize {
+        self.sender.strong_count()
+    }
+
+    /// The number of spans and the duration of the trace, once its root span has finished.
+    ///
+    /// Unlike [`collect`](Collector::collect), it's cheap and leaves the spans to be collected,
+    /// e.g. to decide whether the trace is worth reporting.
+    #[inline]
+    pub fn summary(&self) -> Option<TraceSummary> {
+        self.channel.summary.summary()
+    }
+
+    /// The id of the trace collected by this collector.
+    #[inline]
+    pub fn trace_id(&self) -> u128 {
+        self.trace_id
     }
 
     pub fn collect(self) -> Vec<Span> {
-        self.collect_with_args(CollectArgs {
-            sync: false,
-            duration_threshold: None,
-        })
+        self.collect_with_args(CollectArgs::default())
+    }
+
+    /// Collect the spans like [`collect_with_args`](Collector::collect_with_args), grouped by
+    /// their root spans in the order the roots begin.
+    pub fn collect_forest(self, args: CollectArgs) -> Vec<Trace> {
+        let spans = self.collect_with_args(args);
+
+        // The parents of the spans whose parents are collected
+        let ids: HashSet<u32> = spans.iter().map(|s| s.id).collect();
+        let parents: HashMap<u32, u32> = spans
+            .iter()
+            .filter(|s| ids.contains(&s.parent_id))
+            .map(|s| (s.id, s.parent_id))
+            .collect();
+        // A path longer than the number of spans has met a cycle, whose spans have no root
+        let root_of = |mut id: u32| {
+            for _ in 0..=parents.len() {
+                match parents.get(&id) {
+                    Some(parent_id) => id = *parent_id,
+                    None => return Some(id),
+                }
+            }
+            None
+        };
+
+        let mut traces: Vec<Trace> = Vec::new();
+        let mut descendants: HashMap<u32, Vec<Span>> = HashMap::new();
+        for span in spans {
+            if parents.contains_key(&span.id) {
+                if let Some(root) = root_of(span.id) {
+                    descendants.entry(root).or_default().push(span);
+                }
+            } else {
+                traces.push(Trace {
+                    root: span,
+                    spans: Vec::new(),
+                });
+            }
+        }
+        for trace in &mut traces {
+            trace.spans = descendants.remove(&trace.root.id).unwrap_or_default();
+        }
+        traces.sort_by_key(|t| t.root.begin_unix_time_ns);
+        traces
+    }
+
+    /// Collect the spans like [`collect_with_args`](Collector::collect_with_args), indexed by
+    /// their events.
+    pub fn collect_indexed(self, args: CollectArgs) -> TraceResult {
+        TraceResult::new(self.collect_with_args(args))
     }
 
     /// Collects spans from traced routines.
@@ -32,18 +282,54 @@ impl Collector {
     /// If passing `duration_threshold`, all spans will be reserved only when duration of the root
     /// span exceeds `duration_threshold`, otherwise only one span, the root span, will be returned.
     pub fn collect_with_args(
-        self,
+        mut self,
         CollectArgs {
             sync,
             duration_threshold,
+            correct_clock_skew,
+            max_spans,
+            truncation,
+            drop_descendants_of,
+            keep_subtrees_of,
         }: CollectArgs,
     ) -> Vec<Span> {
-        let span_collections: Vec<_> = if sync {
-            self.receiver.iter().collect()
+        // A forest is complete once the roots started so far finish
+        self.forest_sender.take();
+
+        if matches!(self.deadline, Some(deadline) if Instant::now() >= deadline) {
+            self.expire_deadline();
+        }
+
+        let lease = self.channel.lease;
+        let span_collections: Vec<_> = if sync && !self.finished {
+            iter::from_fn(|| self.recv())
+                .map(|(_, sc)| sc)
+                // skip the disconnection of the trace the channel served before
+                .filter(
+                    |sc| !matches!(sc, SpanCollection::Disconnected { lease: l } if *l != lease),
+                )
+                .take_while(|sc| {
+                    !matches!(
+                        sc,
+                        SpanCollection::Cancelled | SpanCollection::Disconnected { .. }
+                    )
+                })
+                .collect()
         } else {
-            self.receiver.try_iter().collect()
+            self.channel
+                .receiver
+                .try_iter()
+                .map(|(_, sc)| sc)
+                .filter(|sc| {
+                    !matches!(
+                        sc,
+                        SpanCollection::Cancelled | SpanCollection::Disconnected { .. }
+                    )
+                })
+                .collect()
         };
-        self.closed.store(true, Ordering::SeqCst);
+        acquirer::close(&self.channel.closed);
+        let partials = self.channel.summary.partials();
 
         let anchor = DefaultClock::anchor();
         if let Some(duration) = duration_threshold {
@@ -54,30 +340,113 @@ impl Collector {
             }) {
                 let root_span = root_span.clone().into_span(anchor);
                 if root_span.duration_ns < duration.as_nanos() as _ {
-                    return vec![root_span];
+                    let mut spans = vec![root_span];
+                    if partials > 0 {
+                        partial::mark(&mut spans, partials);
+                    }
+                    return spans;
                 }
             }
         }
 
-        Self::amend(span_collections, anchor)
+        let mut spans = Self::amend(span_collections, anchor, correct_clock_skew);
+        if partials > 0 {
+            partial::mark(&mut spans, partials);
+        }
+        if self.cancelled.load(Ordering::SeqCst) {
+            Self::mark_cancelled(&mut spans);
+        }
+        if !drop_descendants_of.is_empty() || !keep_subtrees_of.is_empty() {
+            spans = Self::filter_subtrees(spans, &drop_descendants_of, &keep_subtrees_of);
+        }
+        let spans = match max_spans {
+            Some(max_spans) if spans.len() > max_spans => match truncation {
+                Some(truncation) => truncation.truncate(spans, max_spans),
+                None => CollapseRepeated.truncate(spans, max_spans),
+            },
+            _ => spans,
+        };
+        debug_validate(&spans);
+        spans
+    }
+
+    /// Move the spans reported so far to `storage`, returning how many, to bound the memory held
+    /// by a huge trace. Call it periodically while the trace runs, then
+    /// [`collect_into`](Collector::collect_into) for the rest.
+    ///
+    /// Clock skew is corrected within each batch spilled only.
+    pub fn spill(&mut self, storage: &mut dyn SpanStorage) -> io::Result<usize> {
+        let lease = self.channel.lease;
+        let mut span_collections = Vec::new();
+        for (_, sc) in self.channel.receiver.try_iter() {
+            match sc {
+                // skip the disconnection of the trace the channel served before
+                SpanCollection::Disconnected { lease: l } if l != lease => {}
+                SpanCollection::Cancelled | SpanCollection::Disconnected { .. } => {
+                    self.finished = true;
+                    break;
+                }
+                sc => span_collections.push(sc),
+            }
+        }
+        self.channel
+            .summary
+            .drain(span_collections.iter().map(acquirer::span_count).sum());
+
+        let spans = Self::amend(span_collections, DefaultClock::anchor(), false);
+        debug_validate(&spans);
+        let len = spans.len();
+        if len > 0 {
+            storage.append(spans)?;
+        }
+        Ok(len)
+    }
+
+    /// Collect the spans not [`spill`](Collector::spill)ed yet into `storage`. The duration
+    /// threshold, the subtree filters and the truncation of `args` apply to these spans only.
+    pub fn collect_into(self, args: CollectArgs, storage: &mut dyn SpanStorage) -> io::Result<()> {
+        let spans = self.collect_with_args(args);
+        if spans.is_empty() {
+            return Ok(());
+        }
+        storage.append(spans)
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        self.forest_sender.take();
+
+        if let Some(key) = self.registry_key.take() {
+            registry::unregister(key);
+        }
+
+        // Reuse the channel only if neither spans nor handles of this trace are left
+        if self.sender.strong_count() == 0 && Arc::strong_count(&self.channel.closed) == 1 {
+            pool::recycle(self.channel.clone());
+        }
     }
 }
 
 impl Collector {
     #[inline]
-    fn amend(span_collections: Vec<SpanCollection>, anchor: Anchor) -> Vec<Span> {
-        let capacity = span_collections
-            .iter()
-            .map(|sc| match sc {
-                SpanCollection::LocalSpans {
-                    local_spans: raw_spans,
-                    ..
-                } => raw_spans.spans.len(),
-                SpanCollection::Span(_) => 1,
-            })
-            .sum();
+    pub(crate) fn amend(
+        span_collections: Vec<SpanCollection>,
+        anchor: Anchor,
+        correct_clock_skew: bool,
+    ) -> Vec<Span> {
+        let capacity = span_collections.iter().map(acquirer::span_count).sum();
 
         let mut spans = Vec::with_capacity(capacity);
+        // The indices of the spans timed by the caller
+        let mut manual = Vec::new();
+        // The indices of the spans collected by other processes, and the remote children expected
+        let mut remote = Vec::new();
+        let mut expected = Vec::new();
+        // The index ranges of the spans reported together, i.e. timed by the same thread
+        let mut sets = Vec::new();
+        // The events recorded on the threads where the spans they belong to were attached
+        let mut events = Vec::new();
 
         for span_collection in span_collections {
             match span_collection {
@@ -85,41 +454,267 @@ impl Collector {
                     local_spans: raw_spans,
                     parent_id_of_root: span_id,
                 } => {
+                    sets.push(spans.len()..spans.len() + raw_spans.spans.len());
+                    if !raw_spans.events.is_empty() {
+                        events.push((span_id.0, convert_events(&raw_spans.events, anchor)));
+                    }
                     for span in &raw_spans.spans {
-                        let begin_unix_time_ns =
-                            DefaultClock::cycle_to_unix_time_ns(span.begin_cycle, anchor);
-                        let end_unix_time_ns = if span.end_cycle.is_zero() {
-                            DefaultClock::cycle_to_unix_time_ns(raw_spans.end_time, anchor)
-                        } else {
-                            DefaultClock::cycle_to_unix_time_ns(span.end_cycle, anchor)
-                        };
-                        let parent_id = if span.parent_id.0 == 0 {
-                            span_id.0
-                        } else {
-                            span.parent_id.0
-                        };
-                        spans.push(Span {
-                            id: span.id.0,
-                            parent_id,
-                            begin_unix_time_ns,
-                            duration_ns: end_unix_time_ns - begin_unix_time_ns,
-                            event: span.event,
-                            properties: span.properties.clone(),
-                        });
+                        let properties = span
+                            .properties
+                            .iter()
+                            .map(|(k, v)| (*k, v.clone()))
+                            .collect();
+                        spans.push(LocalSpans::convert(
+                            span,
+                            raw_spans.end_time,
+                            anchor,
+                            span_id.0,
+                            properties,
+                        ));
                     }
                 }
-                SpanCollection::Span(span) => spans.push(span.into_span(anchor)),
+                SpanCollection::Span(span) => {
+                    sets.push(spans.len()..spans.len() + 1);
+                    spans.push(span.into_span(anchor));
+                }
+                SpanCollection::Manual(span) => {
+                    manual.push(spans.len());
+                    spans.push(span);
+                }
+                SpanCollection::Remote(remote_spans) => {
+                    remote.extend(spans.len()..spans.len() + remote_spans.len());
+                    spans.extend(remote_spans);
+                }
+                SpanCollection::RemoteChildren {
+                    parent_id,
+                    span_ids,
+                } => expected.push((parent_id, span_ids)),
+                SpanCollection::Cancelled | SpanCollection::Disconnected { .. } => {}
             }
         }
 
+        if !events.is_empty() {
+            Self::attach_events(&mut spans, events);
+        }
+        if correct_clock_skew {
+            Self::correct_clock_skew(&mut spans, &sets);
+        }
+        if !manual.is_empty() {
+            Self::clamp_manual(&mut spans, &manual);
+        }
+        if !expected.is_empty() {
+            remote_children::mark_missing(&mut spans, &expected, &remote);
+        }
+        let config = config();
+        if !config.rate_limits.is_empty() {
+            rate_limit::apply_per_trace(&mut spans, &config.rate_limits);
+        }
+        if let Some(hashed_properties) = config.hashed_properties {
+            hashed_properties.apply(&mut spans);
+        }
+
         spans
     }
+
+    // Delay the spans of a set whose top-level spans begin before their parents, which happens
+    // when the clocks of the threads are slightly apart. The spans of a set are shifted together
+    // to keep their relative timing, and their ancestors are widened to cover them.
+    fn correct_clock_skew(spans: &mut [Span], sets: &[Range<usize>]) {
+        let index: HashMap<u32, usize> = spans.iter().enumerate().map(|(i, s)| (s.id, i)).collect();
+        let mut set_of = vec![None; spans.len()];
+        for (i, set) in sets.iter().enumerate() {
+            for j in set.clone() {
+                set_of[j] = Some(i);
+            }
+        }
+
+        // The top-level spans of each set with their parents, the sets below each set, and the
+        // number of sets above each set not delayed yet
+        let mut edges = vec![Vec::new(); sets.len()];
+        let mut below = vec![Vec::new(); sets.len()];
+        let mut above = vec![0; sets.len()];
+        for (i, set) in sets.iter().enumerate() {
+            for j in set.clone() {
+                if let Some(&parent) = index.get(&spans[j].parent_id) {
+                    if !set.contains(&parent) {
+                        edges[i].push((j, parent));
+                        if let Some(p) = set_of[parent] {
+                            below[p].push(i);
+                            above[i] += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Delay the sets from the top down, each once its parents are in place
+        let mut ready: Vec<usize> = (0..sets.len()).filter(|&i| above[i] == 0).collect();
+        let mut delayed = Vec::new();
+        while let Some(i) = ready.pop() {
+            let offset = edges[i]
+                .iter()
+                .filter_map(|&(child, parent)| {
+                    spans[parent]
+                        .begin_unix_time_ns
+                        .checked_sub(spans[child].begin_unix_time_ns)
+                })
+                .max()
+                .unwrap_or(0);
+            if offset > 0 {
+                for span in &mut spans[sets[i].clone()] {
+                    span.begin_unix_time_ns += offset;
+                    for event in &mut span.events {
+                        event.unix_time_ns += offset;
+                    }
+                }
+                delayed.push(i);
+            }
+            for &j in &below[i] {
+                above[j] -= 1;
+                if above[j] == 0 {
+                    ready.push(j);
+                }
+            }
+        }
+
+        // Widen the ancestors of the delayed sets ending after them
+        for i in delayed {
+            for &(child, parent) in &edges[i] {
+                let end = spans[child].begin_unix_time_ns + spans[child].duration_ns;
+                let mut ancestor = Some(parent);
+                // At most as many steps as spans, in case of a cycle
+                for _ in 0..spans.len() {
+                    let span = match ancestor {
+                        Some(a) => &mut spans[a],
+                        None => break,
+                    };
+                    if span.begin_unix_time_ns + span.duration_ns >= end {
+                        break;
+                    }
+                    span.duration_ns = end.saturating_sub(span.begin_unix_time_ns);
+                    ancestor = index.get(&span.parent_id).copied();
+                }
+            }
+        }
+    }
+
+    // Clamp the spans timed by the caller into their parents
+    fn clamp_manual(spans: &mut [Span], manual: &[usize]) {
+        let intervals: HashMap<u32, (u64, u64)> = spans
+            .iter()
+            .map(|s| {
+                (
+                    s.id,
+                    (s.begin_unix_time_ns, s.begin_unix_time_ns + s.duration_ns),
+                )
+            })
+            .collect();
+        for &i in manual {
+            let span = &mut spans[i];
+            if let Some(&(parent_begin, parent_end)) = intervals.get(&span.parent_id) {
+                let end = (span.begin_unix_time_ns + span.duration_ns)
+                    .max(parent_begin)
+                    .min(parent_end);
+                let begin = span.begin_unix_time_ns.max(parent_begin).min(end);
+                span.begin_unix_time_ns = begin;
+                span.duration_ns = end - begin;
+            }
+        }
+    }
+
+    // Drop the descendants of the spans of `drop_descendants_of`, then, if `keep_subtrees_of`
+    // is not empty, keep only the spans of it with their ancestors and descendants
+    fn filter_subtrees(
+        spans: Vec<Span>,
+        drop_descendants_of: &[&'static str],
+        keep_subtrees_of: &[&'static str],
+    ) -> Vec<Span> {
+        let index_of: HashMap<u32, usize> =
+            spans.iter().enumerate().map(|(i, s)| (s.id, i)).collect();
+        let parent_of: Vec<Option<usize>> = spans
+            .iter()
+            .map(|s| index_of.get(&s.parent_id).copied())
+            .collect();
+        // The ancestors of a span, nearest first, stopping at a cycle
+        let ancestors =
+            |i: usize| iter::successors(parent_of[i], |&a| parent_of[a]).take(spans.len());
+
+        let mut keep: Vec<bool> = (0..spans.len())
+            .map(|i| !ancestors(i).any(|a| drop_descendants_of.contains(&spans[a].event)))
+            .collect();
+
+        if !keep_subtrees_of.is_empty() {
+            let mut in_subtree = vec![false; spans.len()];
+            for i in (0..spans.len()).filter(|&i| keep[i]) {
+                if keep_subtrees_of.contains(&spans[i].event) {
+                    in_subtree[i] = true;
+                    for a in ancestors(i) {
+                        in_subtree[a] = true;
+                    }
+                } else if ancestors(i).any(|a| keep_subtrees_of.contains(&spans[a].event)) {
+                    in_subtree[i] = true;
+                }
+            }
+            for (keep, in_subtree) in keep.iter_mut().zip(in_subtree) {
+                *keep &= in_subtree;
+            }
+        }
+
+        spans
+            .into_iter()
+            .zip(keep)
+            .filter_map(|(span, keep)| if keep { Some(span) } else { None })
+            .collect()
+    }
+
+    fn attach_events(spans: &mut [Span], events: Vec<(u32, Vec<SpanEvent>)>) {
+        let indices: HashMap<u32, usize> =
+            spans.iter().enumerate().map(|(i, s)| (s.id, i)).collect();
+        for (span_id, events) in events {
+            if let Some(&index) = indices.get(&span_id) {
+                let span = &mut spans[index];
+                span.events.extend(events);
+                span.events.sort_by_key(|event| event.unix_time_ns);
+            }
+        }
+    }
+
+    fn mark_cancelled(spans: &mut [Span]) {
+        let ids: HashSet<u32> = spans.iter().map(|s| s.id).collect();
+        for span in spans {
+            // The spans running at the cancellation are marked already
+            if !ids.contains(&span.parent_id)
+                && !span
+                    .properties
+                    .iter()
+                    .any(|(k, _)| *k == semconv::CANCELLED)
+            {
+                span.properties.push((semconv::CANCELLED, "true".into()));
+            }
+        }
+    }
+}
+
+/// A tree of spans collected by [`Collector::collect_forest`].
+///
+/// Deserializing it leaks the names of its spans like deserializing a [`Span`] does.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Trace {
+    pub root: Span,
+    /// The descendants of the root span.
+    pub spans: Vec<Span>,
 }
 
 #[derive(Default, Debug)]
 pub struct CollectArgs {
     sync: bool,
     duration_threshold: Option<Duration>,
+    correct_clock_skew: bool,
+    max_spans: Option<usize>,
+    truncation: Option<Box<dyn TruncationStrategy>>,
+    drop_descendants_of: Vec<&'static str>,
+    keep_subtrees_of: Vec<&'static str>,
 }
 
 impl CollectArgs {
@@ -133,4 +728,146 @@ impl CollectArgs {
             ..self
         }
     }
+
+    /// Whether to delay the spans recorded on a thread whose clock is behind the clock of the
+    /// thread recording their parent, so that no span begins before its parent. The parents
+    /// are widened to cover the delayed spans. Disabled by default.
+    pub fn correct_clock_skew(self, correct_clock_skew: bool) -> Self {
+        Self {
+            correct_clock_skew,
+            ..self
+        }
+    }
+
+    /// Truncate the trace to at most `max_spans` spans, by [`CollapseRepeated`] unless another
+    /// [`truncation`](CollectArgs::truncation) strategy is given.
+    pub fn max_spans(self, max_spans: usize) -> Self {
+        Self {
+            max_spans: Some(max_spans),
+            ..self
+        }
+    }
+
+    /// Drop the descendants of the spans of `event`, keeping the spans themselves, e.g. to cut
+    /// off the details of a layer. It can be called for several events.
+    pub fn drop_descendants_of(mut self, event: &'static str) -> Self {
+        self.drop_descendants_of.push(event);
+        self
+    }
+
+    /// Keep only the spans of `event` with their descendants, and their ancestors to connect
+    /// them to the root, e.g. to extract the storage layer of a large trace. It can be called
+    /// for several events, keeping the subtrees of any of them.
+    pub fn keep_subtrees_of(mut self, event: &'static str) -> Self {
+        self.keep_subtrees_of.push(event);
+        self
+    }
+
+    pub fn truncation(self, truncation: impl TruncationStrategy + 'static) -> Self {
+        Self {
+            truncation: Some(Box::new(truncation)),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(id: u32, parent_id: u32, begin_unix_time_ns: u64) -> Span {
+        Span {
+            id,
+            parent_id,
+            begin_unix_time_ns,
+            duration_ns: 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn correct_clock_skew() {
+        // The root, then a thread 5ns behind with two spans, then one more 6ns behind it, whose
+        // span outlasts its ancestors once delayed
+        let mut spans = vec![
+            Span {
+                duration_ns: 8,
+                ..span(1, 0, 100)
+            },
+            span(2, 1, 95),
+            span(3, 2, 97),
+            Span {
+                duration_ns: 20,
+                ..span(4, 3, 96)
+            },
+        ];
+        // The sets given bottom-up, which takes a single pass all the same
+        Collector::correct_clock_skew(&mut spans, &[3..4, 1..3, 0..1]);
+
+        let begins: Vec<_> = spans.iter().map(|s| s.begin_unix_time_ns).collect();
+        assert_eq!(begins, vec![100, 100, 102, 102]);
+        let ends: Vec<_> = spans
+            .iter()
+            .map(|s| s.begin_unix_time_ns + s.duration_ns)
+            .collect();
+        assert_eq!(ends, vec![122, 122, 122, 122]);
+    }
+
+    #[test]
+    fn filter_subtrees() {
+        // root -> sql -> storage -> rocksdb, and root -> log
+        let events = ["root", "sql", "storage", "rocksdb", "log"];
+        let parents = [0, 1, 2, 3, 1];
+        let spans: Vec<_> = (0..5)
+            .map(|i| Span {
+                event: events[i],
+                ..span(i as u32 + 1, parents[i], 0)
+            })
+            .collect();
+        let filtered = |drop: &[&'static str], keep: &[&'static str]| {
+            Collector::filter_subtrees(spans.clone(), drop, keep)
+                .iter()
+                .map(|s| s.event)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            filtered(&["storage"], &[]),
+            vec!["root", "sql", "storage", "log"]
+        );
+        assert_eq!(
+            filtered(&[], &["storage"]),
+            vec!["root", "sql", "storage", "rocksdb"]
+        );
+        assert_eq!(filtered(&["sql"], &["storage"]), Vec::<&str>::new());
+
+        // Spans which are their own ancestors don't hang the filter
+        let cycle = vec![span(1, 2, 0), span(2, 1, 0)];
+        assert_eq!(Collector::filter_subtrees(cycle, &["x"], &["y"]).len(), 0);
+    }
+
+    #[test]
+    fn correct_clock_skew_of_cycle() {
+        // Two sets, each the parent of the other, are left as they are
+        let mut spans = vec![span(1, 2, 100), span(2, 1, 90)];
+        Collector::correct_clock_skew(&mut spans, &[0..1, 1..2]);
+        let begins: Vec<_> = spans.iter().map(|s| s.begin_unix_time_ns).collect();
+        assert_eq!(begins, vec![100, 90]);
+
+        // A delayed set below spans which are their own ancestors widens each of them once
+        let mut spans = vec![
+            span(1, 2, 100),
+            span(2, 1, 100),
+            Span {
+                duration_ns: 20,
+                ..span(3, 1, 90)
+            },
+        ];
+        Collector::correct_clock_skew(&mut spans, std::slice::from_ref(&(2..3)));
+        let ends: Vec<_> = spans
+            .iter()
+            .map(|s| s.begin_unix_time_ns + s.duration_ns)
+            .collect();
+        assert_eq!(ends, vec![120, 120, 120]);
+    }
 }
@@ -0,0 +1,93 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::span::{Cycle, DefaultClock, RawSpan, SpanId};
+use crate::trace::acquirer::{self, SpanCollection};
+
+// The deadline of a trace started by `Span::root_with_deadline`, if any, tracking the spans of
+// the trace still running to finish them once it passes or the trace is cancelled
+#[derive(Debug)]
+pub(crate) struct Deadline {
+    pub(crate) at: Option<Instant>,
+    pub(crate) trace_id: u128,
+    closed: Arc<AtomicBool>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // The spans started but not submitted yet, by their ids
+    open: HashMap<u32, (SpanId, Cycle, &'static str)>,
+    expired: bool,
+}
+
+impl Deadline {
+    pub(crate) fn new(at: Option<Instant>, closed: Arc<AtomicBool>, trace_id: u128) -> Self {
+        Deadline {
+            at,
+            trace_id,
+            closed,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_exceeded(&self) -> bool {
+        matches!(self.at, Some(at) if Instant::now() >= at)
+    }
+
+    pub(crate) fn track(&self, span: &RawSpan) {
+        let mut state = self.state.lock().unwrap();
+        if !state.expired {
+            state
+                .open
+                .insert(span.id.0, (span.parent_id, span.begin_cycle, span.event));
+        }
+    }
+
+    // Whether a submission is to be sent, i.e. it doesn't carry spans arriving after the
+    // outstanding spans have been finished in their place
+    pub(crate) fn admit(&self, span_collection: &SpanCollection) -> bool {
+        match span_collection {
+            SpanCollection::Span(span) => {
+                let mut state = self.state.lock().unwrap();
+                state.open.remove(&span.id.0);
+                !state.expired
+            }
+            SpanCollection::LocalSpans { .. }
+            | SpanCollection::Manual(_)
+            | SpanCollection::Remote(_) => !self.state.lock().unwrap().expired,
+            SpanCollection::RemoteChildren { .. }
+            | SpanCollection::Cancelled
+            | SpanCollection::Disconnected { .. } => true,
+        }
+    }
+
+    // Close the trace and finish its outstanding spans now with the property `(marker, "true")`,
+    // or return `None` if it's been done
+    pub(crate) fn expire(&self, marker: &'static str) -> Option<Vec<RawSpan>> {
+        let mut state = self.state.lock().unwrap();
+        if state.expired {
+            return None;
+        }
+        state.expired = true;
+        acquirer::close(&self.closed);
+
+        let now = DefaultClock::now();
+        let spans = state
+            .open
+            .drain()
+            .map(|(id, (parent_id, begin_cycle, event))| {
+                let mut span = RawSpan::begin_with(SpanId::new(id), parent_id, begin_cycle, event);
+                span.end_with(now);
+                span.properties.push((marker, "true".into()));
+                span
+            })
+            .collect();
+        Some(spans)
+    }
+}
@@ -0,0 +1,31 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::local::local_span_line;
+
+/// Record a point-in-time event, e.g. a cache miss or a retry, in the innermost local span open
+/// on the current thread, or else in the span attached to it by [`Span::enter`]. Outside of a
+/// trace, it does nothing.
+///
+/// The events of a span are collected in [`Span::events`](crate::span::Span::events), and
+/// reported as Jaeger logs or OpenTelemetry events.
+///
+/// ```rust
+/// use minitrace::{LocalSpan, Span};
+///
+/// let (root_span, collector) = Span::root("get");
+/// {
+///     let _guard = root_span.enter();
+///     let _local_guard = LocalSpan::enter("read_block");
+///     minitrace::event("cache_miss");
+/// }
+/// drop(root_span);
+///
+/// let spans = collector.collect();
+/// let read_block = spans.iter().find(|s| s.event == "read_block").unwrap();
+/// assert_eq!(read_block.events[0].name, "cache_miss");
+/// ```
+///
+/// [`Span::enter`]: crate::Span::enter
+pub fn event(name: &'static str) {
+    local_span_line::add_event(name);
+}
@@ -0,0 +1,157 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::semconv;
+use crate::span::{self, DefaultIdGenerator, PropertyValue};
+use crate::trace::acquirer::SpanCollection;
+use crate::Span;
+
+/// The execution summary of an executor of a coprocessor-style pipeline, e.g. a table scan
+/// under a selection under an aggregation, the data TiDB's `EXPLAIN ANALYZE` shows.
+///
+/// The same summary can be attached to a span with [`properties`](ExecSummary::properties), or
+/// recorded as spans with [`Span::record_exec_summaries`].
+///
+/// # Examples
+///
+/// ```rust
+/// use std::time::{Duration, SystemTime};
+/// use minitrace::{ExecSummary, Span};
+///
+/// let (root_span, collector) = Span::root("coprocessor");
+/// let summaries = [
+///     ExecSummary::new("TableFullScan")
+///         .iterations(3)
+///         .produced_rows(1024)
+///         .time_processed(Duration::from_micros(800))
+///         .time_breakdown("exec.time.wait_ns", Duration::from_micros(300)),
+///     ExecSummary::new("Selection")
+///         .iterations(3)
+///         .produced_rows(10)
+///         .time_processed(Duration::from_micros(900)),
+/// ];
+/// root_span.record_exec_summaries(&summaries, SystemTime::now());
+/// drop(root_span);
+///
+/// let spans = collector.collect();
+/// let selection = spans.iter().find(|s| s.event == "Selection").unwrap();
+/// let scan = spans.iter().find(|s| s.event == "TableFullScan").unwrap();
+/// assert_eq!(scan.parent_id, selection.id);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExecSummary {
+    pub executor: &'static str,
+    pub iterations: u64,
+    pub produced_rows: u64,
+    pub time_processed_ns: u64,
+    pub time_breakdown: Vec<(&'static str, u64)>,
+}
+
+impl ExecSummary {
+    pub fn new(executor: &'static str) -> Self {
+        ExecSummary {
+            executor,
+            ..Default::default()
+        }
+    }
+
+    /// The number of times the executor has been called for a batch of rows.
+    pub fn iterations(self, iterations: u64) -> Self {
+        Self { iterations, ..self }
+    }
+
+    pub fn produced_rows(self, produced_rows: u64) -> Self {
+        Self {
+            produced_rows,
+            ..self
+        }
+    }
+
+    /// The time spent in the executor, including in the executors below it.
+    pub fn time_processed(self, time_processed: Duration) -> Self {
+        Self {
+            time_processed_ns: time_processed.as_nanos() as u64,
+            ..self
+        }
+    }
+
+    /// A part of the processed time, e.g. waiting for the storage, recorded as the property
+    /// `key` in nanoseconds.
+    pub fn time_breakdown(mut self, key: &'static str, time: Duration) -> Self {
+        self.time_breakdown.push((key, time.as_nanos() as u64));
+        self
+    }
+
+    /// The summary as properties: [`exec.iterations`](semconv::EXEC_ITERATIONS),
+    /// [`exec.produced_rows`](semconv::EXEC_PRODUCED_ROWS),
+    /// [`exec.time_processed_ns`](semconv::EXEC_TIME_PROCESSED_NS) and the time breakdown.
+    pub fn properties(&self) -> Vec<(&'static str, PropertyValue)> {
+        let mut properties = vec![
+            (
+                semconv::EXEC_ITERATIONS,
+                PropertyValue::I64(self.iterations as i64),
+            ),
+            (
+                semconv::EXEC_PRODUCED_ROWS,
+                PropertyValue::I64(self.produced_rows as i64),
+            ),
+            (
+                semconv::EXEC_TIME_PROCESSED_NS,
+                PropertyValue::I64(self.time_processed_ns as i64),
+            ),
+        ];
+        properties.extend(
+            self.time_breakdown
+                .iter()
+                .map(|(key, ns)| (*key, PropertyValue::I64(*ns as i64))),
+        );
+        properties
+    }
+}
+
+impl Span {
+    /// Record the summaries of a pipeline of executors, innermost first as in a DAG request, as
+    /// nested children of the span, each named after its executor and carrying its
+    /// [`properties`](ExecSummary::properties).
+    ///
+    /// The spans end at `end`, e.g. when the response is built, and last their processed time.
+    /// They are clamped into their parents, so that an executor never outlasts the one above.
+    pub fn record_exec_summaries(&self, summaries: &[ExecSummary], end: SystemTime) {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return,
+        };
+        let end_unix_time_ns = end
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+
+        let mut parent_id = inner.span_id;
+        let mut parent_begin_unix_time_ns = 0;
+        for summary in summaries.iter().rev() {
+            let id = DefaultIdGenerator::next_id();
+            let begin_unix_time_ns = end_unix_time_ns
+                .saturating_sub(summary.time_processed_ns)
+                .max(parent_begin_unix_time_ns);
+            let mut properties = summary.properties();
+            let duration_ns =
+                span::checked_duration_ns(begin_unix_time_ns, end_unix_time_ns, &mut properties);
+
+            for (_, acq) in inner.to_report.iter().filter(|(_, acq)| !acq.is_shutdown()) {
+                acq.submit(SpanCollection::Manual(crate::span::Span {
+                    id: id.0,
+                    parent_id: parent_id.0,
+                    begin_unix_time_ns,
+                    duration_ns,
+                    event: summary.executor,
+                    properties: properties.clone(),
+                    events: Vec::new(),
+                }));
+            }
+
+            parent_id = id;
+            parent_begin_unix_time_ns = begin_unix_time_ns;
+        }
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::span::{PropertyValue, Span};
+
+/// The property keys whose values are sensitive, e.g. user keys or SQL literals, and the secret
+/// to hash them with, see [`Config::hashed_properties`](crate::Config::hashed_properties).
+///
+/// A value is replaced by the string `"{hash:016x}:{len}"`, its SipHash-2-4 keyed by the secret
+/// and its length in bytes, so that equal values can still be grouped within the traces hashed
+/// with the same secret. Integers, floats and booleans are hashed in their little-endian bytes.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::{Config, HashedProperties};
+///
+/// let secret = *b"0123456789abcdef";
+/// minitrace::set_config(
+///     Config::default().hashed_properties(HashedProperties::new(secret).key("user.key")),
+/// );
+/// # minitrace::set_config(Config::default());
+/// ```
+#[derive(Clone)]
+pub struct HashedProperties {
+    secret: [u8; 16],
+    keys: HashSet<&'static str>,
+}
+
+impl HashedProperties {
+    pub fn new(secret: [u8; 16]) -> Self {
+        HashedProperties {
+            secret,
+            keys: HashSet::new(),
+        }
+    }
+
+    /// Hash the values of the property `key`.
+    pub fn key(mut self, key: &'static str) -> Self {
+        self.keys.insert(key);
+        self
+    }
+
+    /// The string recording `value`.
+    pub fn hash(&self, value: &PropertyValue) -> String {
+        let scalar;
+        let bytes: &[u8] = match value {
+            PropertyValue::String(s) => s.as_bytes(),
+            PropertyValue::Binary(b) => b,
+            PropertyValue::I64(v) => {
+                scalar = v.to_le_bytes();
+                &scalar
+            }
+            PropertyValue::F64(v) => {
+                scalar = v.to_le_bytes();
+                &scalar
+            }
+            PropertyValue::Bool(v) => &[*v as u8][..],
+        };
+        format!("{:016x}:{}", siphash24(&self.secret, bytes), bytes.len())
+    }
+
+    pub(crate) fn apply(&self, spans: &mut [Span]) {
+        for span in spans {
+            for (key, value) in &mut span.properties {
+                if self.keys.contains(key) {
+                    *value = self.hash(value).into();
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for HashedProperties {
+    // Leave out the secret
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HashedProperties")
+            .field("keys", &self.keys)
+            .finish()
+    }
+}
+
+fn siphash24(key: &[u8; 16], data: &[u8]) -> u64 {
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    fn compress(v: &mut [u64; 4], m: u64) {
+        v[3] ^= m;
+        round(v);
+        round(v);
+        v[0] ^= m;
+    }
+
+    let k0 = u64::from_le_bytes(key[..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(key[8..].try_into().unwrap());
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        compress(&mut v, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    let mut last = [0; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = data.len() as u8;
+    compress(&mut v, u64::from_le_bytes(last));
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn siphash() {
+        // The reference vectors of SipHash-2-4 with the key 00 01 .. 0f
+        let key: [u8; 16] = std::array::from_fn(|i| i as u8);
+        let data: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash24(&key, &[]), 0x726fdb47dd0e0e31);
+        assert_eq!(siphash24(&key, &data), 0xa129ca6149be45e5);
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+// The most distinct names interned by the process. The names decoded may come from an untrusted
+// source, e.g. the frames of another process, so past it the names are refused rather than
+// leaked without bound.
+const MAX_NAMES: usize = 1 << 16;
+
+lazy_static! {
+    static ref NAMES: Mutex<Interner> = Mutex::new(Interner::new(MAX_NAMES));
+}
+
+// The names built at runtime, e.g. read back from storages, decoded or renamed, leaked once each
+// to be `&'static str`s like the names of spans
+struct Interner {
+    names: HashSet<&'static str>,
+    capacity: usize,
+}
+
+impl Interner {
+    fn new(capacity: usize) -> Self {
+        Interner {
+            names: HashSet::new(),
+            capacity,
+        }
+    }
+
+    fn intern(&mut self, name: String) -> Option<&'static str> {
+        if let Some(name) = self.names.get(name.as_str()) {
+            return Some(name);
+        }
+        if self.names.len() >= self.capacity {
+            return None;
+        }
+        let name: &'static str = Box::leak(name.into_boxed_str());
+        self.names.insert(name);
+        Some(name)
+    }
+}
+
+// The interned name, or `None` if the process has interned too many distinct names already
+pub(crate) fn intern(name: String) -> Option<&'static str> {
+    NAMES.lock().unwrap().intern(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded() {
+        let mut interner = Interner::new(2);
+        let a = interner.intern("a".to_owned()).unwrap();
+        assert!(std::ptr::eq(a, interner.intern("a".to_owned()).unwrap()));
+        assert!(interner.intern("b".to_owned()).is_some());
+        assert!(interner.intern("c".to_owned()).is_none());
+        // The names interned before stay available
+        assert_eq!(interner.intern("b".to_owned()), Some("b"));
+    }
+}
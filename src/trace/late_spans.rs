@@ -0,0 +1,134 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::span::{DefaultClock, RawSpan};
+use crate::trace::acquirer::SpanCollection;
+
+// Upper bounds of the buckets are 1us * 2^i, the last bucket is unbounded
+const BUCKETS: usize = 33;
+
+lazy_static! {
+    static ref HISTOGRAMS: Mutex<HashMap<&'static str, Histogram>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Copy)]
+struct Histogram {
+    count: u64,
+    sum_ns: u64,
+    buckets: [u64; BUCKETS],
+}
+
+/// The durations of the spans of an event which finished after their collector had been
+/// collected, see [`Config::aggregate_late_spans`](crate::Config::aggregate_late_spans).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LateSpanHistogram {
+    pub event: &'static str,
+    pub count: u64,
+    pub sum: Duration,
+    /// Non-cumulative counts of buckets as `(upper bound, count)`, with `Duration::MAX`
+    /// bounding the last one.
+    pub buckets: Vec<(Duration, u64)>,
+}
+
+/// Snapshot the histograms of late spans, sorted by event.
+pub fn late_span_histograms() -> Vec<LateSpanHistogram> {
+    let histograms = HISTOGRAMS.lock().unwrap();
+    let mut res: Vec<_> = histograms
+        .iter()
+        .map(|(event, h)| LateSpanHistogram {
+            event,
+            count: h.count,
+            sum: Duration::from_nanos(h.sum_ns),
+            buckets: h
+                .buckets
+                .iter()
+                .enumerate()
+                .map(|(i, count)| (upper_bound(i), *count))
+                .collect(),
+        })
+        .collect();
+    res.sort_by_key(|h| h.event);
+    res
+}
+
+pub fn reset_late_span_histograms() {
+    HISTOGRAMS.lock().unwrap().clear();
+}
+
+pub(crate) fn aggregate(span_collection: &SpanCollection) {
+    let anchor = DefaultClock::anchor();
+    let duration_ns = |span: &RawSpan, end_cycle| {
+        DefaultClock::cycle_to_unix_time_ns(end_cycle, anchor).saturating_sub(
+            DefaultClock::cycle_to_unix_time_ns(span.begin_cycle, anchor),
+        )
+    };
+
+    let mut histograms = HISTOGRAMS.lock().unwrap();
+    let mut observe = |event, duration_ns: u64| {
+        let h = histograms.entry(event).or_insert(Histogram {
+            count: 0,
+            sum_ns: 0,
+            buckets: [0; BUCKETS],
+        });
+        h.count += 1;
+        h.sum_ns = h.sum_ns.saturating_add(duration_ns);
+        h.buckets[bucket(duration_ns)] += 1;
+    };
+
+    match span_collection {
+        SpanCollection::Span(span) => observe(span.event, duration_ns(span, span.end_cycle)),
+        SpanCollection::LocalSpans { local_spans, .. } => {
+            for span in &local_spans.spans {
+                let end_cycle = if span.end_cycle.is_zero() {
+                    local_spans.end_time
+                } else {
+                    span.end_cycle
+                };
+                observe(span.event, duration_ns(span, end_cycle));
+            }
+        }
+        SpanCollection::Manual(span) => observe(span.event, span.duration_ns),
+        // Timed by other processes
+        SpanCollection::Remote(_)
+        | SpanCollection::RemoteChildren { .. }
+        | SpanCollection::Cancelled
+        | SpanCollection::Disconnected { .. } => {}
+    }
+}
+
+fn bucket(duration_ns: u64) -> usize {
+    let us = duration_ns / 1_000;
+    // the smallest `i` such that `us <= 2^i`
+    let i = if us <= 1 {
+        0
+    } else {
+        (64 - (us - 1).leading_zeros()) as usize
+    };
+    i.min(BUCKETS - 1)
+}
+
+fn upper_bound(i: usize) -> Duration {
+    if i == BUCKETS - 1 {
+        Duration::MAX
+    } else {
+        Duration::from_micros(1 << i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1_000), 0);
+        assert_eq!(bucket(1_001_000), 10);
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+        assert!(Duration::from_nanos(1_001_000) <= upper_bound(bucket(1_001_000)));
+        assert!(Duration::from_nanos(1_001_000) > upper_bound(bucket(1_001_000) - 1));
+    }
+}
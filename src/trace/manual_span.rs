@@ -0,0 +1,109 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::span::{self, DefaultIdGenerator, PropertyValue, SpanId};
+use crate::trace::acquirer::{Acquirer, SpanCollection};
+use crate::Span;
+
+/// A child span whose begin and end are measured by something else than the CPU running the
+/// code, e.g. a DMA engine, a GPU or the completion callback of an asynchronous disk read.
+///
+/// It's reported when [`finish`](ManualSpan::finish)ed and discarded if dropped before.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::time::{Duration, SystemTime};
+/// use minitrace::Span;
+///
+/// let (root_span, collector) = Span::root("root");
+///
+/// let dma = root_span.child_manual("dma");
+/// // ... later, in the completion callback
+/// let end = SystemTime::now();
+/// dma.finish(end - Duration::from_micros(30), end);
+/// ```
+#[must_use]
+#[derive(Debug)]
+pub struct ManualSpan {
+    span_id: SpanId,
+    event: &'static str,
+    properties: Vec<(&'static str, PropertyValue)>,
+    to_report: Vec<(SpanId, Acquirer)>,
+}
+
+impl Span {
+    /// Create a child span whose begin and end are supplied by the caller, see
+    /// [`ManualSpan`](ManualSpan).
+    pub fn child_manual(&self, event: &'static str) -> ManualSpan {
+        let to_report = self
+            .inner
+            .iter()
+            .flat_map(|inner| {
+                inner
+                    .to_report
+                    .iter()
+                    .filter(|(_, acq)| !acq.is_shutdown())
+                    .map(move |(_, acq)| (inner.span_id, acq.clone()))
+            })
+            .collect();
+        ManualSpan {
+            span_id: DefaultIdGenerator::next_id(),
+            event,
+            properties: Vec::new(),
+            to_report,
+        }
+    }
+}
+
+impl ManualSpan {
+    pub fn with_property<F: FnOnce() -> (&'static str, String)>(mut self, property: F) -> Self {
+        if !self.to_report.is_empty() {
+            let (key, value) = property();
+            self.properties.push((key, value.into()));
+        }
+        self
+    }
+
+    /// Add a property keeping the type of its value, e.g. `i64`, so that reporters supporting
+    /// typed values don't report it as a string.
+    pub fn with_typed_property(
+        mut self,
+        key: &'static str,
+        value: impl Into<PropertyValue>,
+    ) -> Self {
+        if !self.to_report.is_empty() {
+            self.properties.push((key, value.into()));
+        }
+        self
+    }
+
+    /// Report the span with the given interval. An end before the begin is taken as the begin.
+    ///
+    /// At collection, the interval is clamped into the parent's when the parent is collected
+    /// together, so that a skewed external clock doesn't render the span outside of its parent.
+    pub fn finish(self, begin: SystemTime, end: SystemTime) {
+        let unix_time_ns = |t: SystemTime| {
+            t.duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or_default()
+        };
+        let begin_unix_time_ns = unix_time_ns(begin);
+        let mut properties = self.properties.clone();
+        let duration_ns =
+            span::checked_duration_ns(begin_unix_time_ns, unix_time_ns(end), &mut properties);
+
+        for (parent_id, acq) in &self.to_report {
+            acq.submit(SpanCollection::Manual(crate::span::Span {
+                id: self.span_id.0,
+                parent_id: parent_id.0,
+                begin_unix_time_ns,
+                duration_ns,
+                event: self.event,
+                properties: properties.clone(),
+                events: Vec::new(),
+            }))
+        }
+    }
+}
@@ -1,6 +1,33 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
 pub mod acquirer;
+pub mod baggage;
 pub mod collector;
+pub(crate) mod deadline;
+pub mod event;
+pub mod exec_summary;
+pub mod hashed_properties;
+pub(crate) mod interner;
+pub mod late_spans;
 pub mod local_span;
+pub mod manual_span;
+pub mod naming;
+pub mod normalizer;
+pub mod orphan;
+pub mod partial;
+pub(crate) mod pool;
+pub mod rate_limit;
+pub mod registry;
+pub mod remote_children;
+pub mod root_spans;
+pub mod sampler;
+pub mod shared_collector;
+pub mod snapshot;
 pub mod span;
+pub mod span_handle;
+pub mod span_token;
+pub mod storage;
+pub mod target;
+pub mod trace_result;
+pub mod truncation;
+pub mod validate;
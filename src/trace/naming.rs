@@ -0,0 +1,216 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::config::config;
+use crate::trace::interner::intern;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub type RenameFn = dyn Fn(&'static str, &str) -> Option<String> + Send + Sync;
+
+/// A policy naming the events of the functions instrumented by `#[trace]` and `#[trace_async]`
+/// throughout the binary, so that crates converge on consistent names without touching every
+/// attribute, see [`Config::naming_policy`](crate::Config::naming_policy).
+///
+/// The policy applies once per instrumented function, the first time it runs, so it should be
+/// configured before tracing starts. Events given to [`LocalSpan`](crate::LocalSpan) and
+/// [`Span`](crate::Span) directly are left as they are.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::NamingPolicy;
+///
+/// let policy = NamingPolicy::new().snake_case(true).crate_prefix("::");
+/// assert_eq!(policy.name("tikv::storage", "GetRegion"), "tikv::get_region");
+/// ```
+#[derive(Default)]
+pub struct NamingPolicy {
+    custom: Option<Box<RenameFn>>,
+    snake_case: bool,
+    crate_prefix: Option<&'static str>,
+}
+
+impl NamingPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rename events with a function of the module path of the function and the event, applied
+    /// first. Returning `None` leaves the event unchanged.
+    pub fn custom(
+        self,
+        rename: impl Fn(&'static str, &str) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            custom: Some(Box::new(rename)),
+            ..self
+        }
+    }
+
+    /// Convert events to snake case, e.g. `GetRegion` and `get region` to `get_region`.
+    pub fn snake_case(self, snake_case: bool) -> Self {
+        Self { snake_case, ..self }
+    }
+
+    /// Prefix events with the name of the crate of the function followed by `separator`, unless
+    /// they start with it already.
+    pub fn crate_prefix(self, separator: &'static str) -> Self {
+        Self {
+            crate_prefix: Some(separator),
+            ..self
+        }
+    }
+
+    /// The name of `event` of a function in the module `module_path`.
+    pub fn name(&self, module_path: &'static str, event: &'static str) -> &'static str {
+        let mut name = None;
+        if let Some(rename) = &self.custom {
+            name = rename(module_path, event);
+        }
+        if self.snake_case {
+            name = Some(snake_case(name.as_deref().unwrap_or(event)));
+        }
+        if let Some(separator) = self.crate_prefix {
+            let current = name.as_deref().unwrap_or(event);
+            let krate = module_path.split("::").next().unwrap_or(module_path);
+            let prefix = format!("{}{}", krate, separator);
+            if !current.starts_with(&prefix) {
+                name = Some(prefix + current);
+            }
+        }
+
+        match name {
+            Some(name) if name != event => intern(name).unwrap_or(event),
+            _ => event,
+        }
+    }
+}
+
+impl fmt::Debug for NamingPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NamingPolicy")
+            .field("custom", &self.custom.is_some())
+            .field("snake_case", &self.snake_case)
+            .field("crate_prefix", &self.crate_prefix)
+            .finish()
+    }
+}
+
+fn snake_case(event: &str) -> String {
+    let chars: Vec<char> = event.chars().collect();
+    let mut res = String::with_capacity(event.len() + 4);
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_whitespace() || c == '-' || c == '_' {
+            if !res.is_empty() && !res.ends_with('_') {
+                res.push('_');
+            }
+            continue;
+        }
+        if c.is_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_lower = matches!(chars.get(i + 1), Some(c) if c.is_lowercase());
+            // A word starts at `aB`, the `B` of `1Bc` and the `C` of `ABCd`
+            let starts_word = prev.is_lowercase() || (prev.is_alphanumeric() && next_lower);
+            if starts_word && !res.ends_with('_') {
+                res.push('_');
+            }
+        }
+        res.extend(c.to_lowercase());
+    }
+    res.trim_end_matches('_').to_owned()
+}
+
+pub(crate) fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+// The event of an instrumented function, named by the policy on its first run. Created by
+// `#[trace]` and `#[trace_async]`.
+#[doc(hidden)]
+pub struct EventName {
+    event: &'static str,
+    module_path: &'static str,
+    named: AtomicPtr<&'static str>,
+}
+
+impl EventName {
+    pub const fn new(event: &'static str, module_path: &'static str) -> Self {
+        EventName {
+            event,
+            module_path,
+            named: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    #[inline]
+    pub fn get(&self) -> &'static str {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return self.event;
+        }
+        let named = self.named.load(Ordering::Acquire);
+        if named.is_null() {
+            return self.name();
+        }
+        // Safety: set once below to a leaked box never freed
+        unsafe { *named }
+    }
+
+    #[cold]
+    fn name(&self) -> &'static str {
+        let name = match config().naming_policy {
+            Some(policy) => policy.name(self.module_path, self.event),
+            None => self.event,
+        };
+        let named = Box::into_raw(Box::new(name));
+        match self.named.compare_exchange(
+            ptr::null_mut(),
+            named,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => name,
+            Err(existing) => {
+                // Safety: `named` has just been leaked and lost the race to be published
+                drop(unsafe { Box::from_raw(named) });
+                // Safety: as in `get`
+                unsafe { *existing }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy() {
+        let policy = NamingPolicy::new().snake_case(true).crate_prefix(".");
+        assert_eq!(policy.name("tikv", "get region"), "tikv.get_region");
+        assert_eq!(
+            policy.name("tikv::raft", "tikv.HTTPServer"),
+            "tikv.http_server"
+        );
+        assert_eq!(policy.name("tikv", "kv-get 2PC"), "tikv.kv_get_2pc");
+        assert_eq!(
+            policy.name("tikv", "raft.Step2Apply"),
+            "tikv.raft.step2_apply"
+        );
+
+        let policy = NamingPolicy::new()
+            .custom(|module_path, event| {
+                if module_path.ends_with("::sql") {
+                    Some(format!("sql: {}", event))
+                } else {
+                    None
+                }
+            })
+            .snake_case(true);
+        assert_eq!(policy.name("tidb::sql", "Parse"), "sql:_parse");
+        assert_eq!(policy.name("tidb::kv", "get"), "get");
+    }
+}
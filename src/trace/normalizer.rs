@@ -0,0 +1,173 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::borrow::Cow;
+
+use crate::span::Span;
+use crate::trace::interner::intern;
+
+// The name of the events rewritten past the bound of the names built at runtime
+const OVERFLOW_NAME: &str = "{other}";
+
+pub type RewriteFn = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+/// A rule rewriting high-cardinality event names.
+pub enum Rule {
+    /// Replace every run of ASCII digits with `{id}`, e.g. `region 42 get` -> `region {id} get`.
+    CollapseDigits,
+    /// Rename every event starting with `prefix` to `name`.
+    Prefix {
+        prefix: &'static str,
+        name: &'static str,
+    },
+    /// Rewrite an event with a user-provided function. Returning `None` leaves it unchanged.
+    Custom(Box<RewriteFn>),
+}
+
+/// Normalizes event names of collected spans before exporting them, protecting tracing backends
+/// from cardinality explosions.
+///
+/// Rules are applied in order, each on the output of the previous one. The names built by the
+/// rules are kept for the life of the process, up to a bound past which the events are renamed
+/// `{other}`, e.g. with a [`Rule::Custom`] not collapsing the names.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::{Normalizer, Rule};
+///
+/// let normalizer = Normalizer::new()
+///     .rule(Rule::Prefix { prefix: "sql:", name: "sql" })
+///     .rule(Rule::CollapseDigits);
+///
+/// let mut spans = vec![minitrace::span::Span {
+///     event: "get region 42",
+///     ..Default::default()
+/// }];
+/// normalizer.normalize(&mut spans);
+/// assert_eq!(spans[0].event, "get region {id}");
+/// ```
+#[derive(Default)]
+pub struct Normalizer {
+    rules: Vec<Rule>,
+}
+
+impl Normalizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn normalize(&self, spans: &mut [Span]) {
+        for span in spans {
+            span.event = self.normalize_event(span.event);
+        }
+    }
+
+    pub fn normalize_event(&self, event: &'static str) -> &'static str {
+        let mut normalized = Cow::Borrowed(event);
+        for rule in &self.rules {
+            let rewritten = match rule {
+                Rule::CollapseDigits => collapse_digits(&normalized).map(Cow::Owned),
+                Rule::Prefix { prefix, name } if normalized.starts_with(prefix) => {
+                    Some(Cow::Borrowed(*name))
+                }
+                Rule::Prefix { .. } => None,
+                Rule::Custom(f) => f(&normalized).map(Cow::Owned),
+            };
+            if let Some(rewritten) = rewritten {
+                normalized = rewritten;
+            }
+        }
+
+        match normalized {
+            Cow::Borrowed(normalized) => normalized,
+            Cow::Owned(normalized) if normalized == event => event,
+            // The names built are interned, which `CollapseDigits` keeps bounded but a `Custom`
+            // rule may not
+            Cow::Owned(normalized) => intern(normalized).unwrap_or(OVERFLOW_NAME),
+        }
+    }
+}
+
+fn collapse_digits(event: &str) -> Option<String> {
+    if !event.bytes().any(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let mut res = String::with_capacity(event.len());
+    let mut in_digits = false;
+    for c in event.chars() {
+        if c.is_ascii_digit() {
+            if !in_digits {
+                res.push_str("{id}");
+            }
+            in_digits = true;
+        } else {
+            res.push(c);
+            in_digits = false;
+        }
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix() {
+        let normalizer = Normalizer::new().rule(Rule::Prefix {
+            prefix: "sql:",
+            name: "sql",
+        });
+        let name = "sql";
+        assert!(std::ptr::eq(
+            normalizer.normalize_event("sql:select 1"),
+            name
+        ));
+        assert_eq!(normalizer.normalize_event("get"), "get");
+    }
+
+    #[test]
+    fn custom() {
+        let normalizer = Normalizer::new().rule(Rule::Custom(Box::new(|event| {
+            event.strip_suffix(" (retry)").map(ToOwned::to_owned)
+        })));
+        assert_eq!(normalizer.normalize_event("get (retry)"), "get");
+        assert_eq!(normalizer.normalize_event("put"), "put");
+    }
+
+    #[test]
+    fn chained() {
+        let normalizer = Normalizer::new()
+            .rule(Rule::CollapseDigits)
+            .rule(Rule::Prefix {
+                prefix: "region {id}",
+                name: "region",
+            })
+            .rule(Rule::Custom(Box::new(|event| Some(event.to_uppercase()))));
+        assert_eq!(normalizer.normalize_event("region 42 get"), "REGION");
+        assert_eq!(normalizer.normalize_event("store 7"), "STORE {ID}");
+    }
+
+    #[test]
+    fn unchanged() {
+        let event = "get region";
+        let normalizer = Normalizer::new()
+            .rule(Rule::CollapseDigits)
+            .rule(Rule::Prefix {
+                prefix: "sql:",
+                name: "sql",
+            })
+            .rule(Rule::Custom(Box::new(|event| Some(event.to_owned()))));
+        assert!(std::ptr::eq(normalizer.normalize_event(event), event));
+        assert!(std::ptr::eq(
+            Normalizer::new().normalize_event(event),
+            event
+        ));
+    }
+}
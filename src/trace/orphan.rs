@@ -0,0 +1,173 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::ThreadId;
+
+use crate::span::DefaultIdGenerator;
+use crate::trace::shared_collector::{RootStarter, SharedCollector};
+use crate::Span;
+
+// The number of orphan spans of a thread remembered until they're collected. The oldest ones
+// are forgotten past it, e.g. those never finishing, and their spans are discarded.
+const MAX_PENDING_PER_THREAD: usize = 1024;
+
+lazy_static! {
+    static ref ORPHANS: Mutex<Orphans> = Mutex::new(Orphans::default());
+}
+
+thread_local! {
+    static LOCAL_ORPHANS: LocalOrphans = LocalOrphans::register();
+}
+
+// Mirrors `Config::collect_orphan_spans`, sparing the spans created without a parent a read of
+// the config
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Default)]
+struct Orphans {
+    collector: SharedCollector,
+    // The threads which have created orphan spans
+    threads: Vec<Arc<ThreadOrphans>>,
+}
+
+struct ThreadOrphans {
+    thread_id: ThreadId,
+    thread_name: Option<String>,
+    // The trace ids of the orphan spans created on the thread and not collected yet, oldest
+    // first. Only the thread and `collect_orphan_spans` lock it.
+    pending: Mutex<VecDeque<u128>>,
+}
+
+// The state of a thread creating orphan spans, saving each of them the global lock
+struct LocalOrphans {
+    starter: RootStarter,
+    thread: Arc<ThreadOrphans>,
+}
+
+impl LocalOrphans {
+    fn register() -> Self {
+        let current = std::thread::current();
+        let thread = Arc::new(ThreadOrphans {
+            thread_id: current.id(),
+            thread_name: current.name().map(ToOwned::to_owned),
+            pending: Mutex::new(VecDeque::new()),
+        });
+
+        let mut orphans = ORPHANS.lock().unwrap();
+        orphans.threads.push(thread.clone());
+        LocalOrphans {
+            starter: orphans.collector.starter(),
+            thread,
+        }
+    }
+}
+
+/// The orphan spans created on a thread, see
+/// [`Config::collect_orphan_spans`](crate::Config::collect_orphan_spans).
+#[derive(Clone, Debug)]
+pub struct OrphanSpans {
+    pub thread_id: ThreadId,
+    pub thread_name: Option<String>,
+    /// Each orphan span is the root of its own trace, which is listed after its descendants.
+    pub spans: Vec<crate::span::Span>,
+}
+
+/// Return the orphan spans finished since the last call, grouped by the threads creating them.
+pub fn collect_orphan_spans() -> Vec<OrphanSpans> {
+    let mut orphans = ORPHANS.lock().unwrap();
+    let orphans = &mut *orphans;
+
+    let traces = orphans.collector.collect_grouped();
+    let collected: HashSet<u128> = traces.iter().map(|(trace_id, _)| *trace_id).collect();
+    let mut thread_of = HashMap::with_capacity(traces.len());
+    for (i, thread) in orphans.threads.iter().enumerate() {
+        let mut pending = thread.pending.lock().unwrap();
+        for &trace_id in pending.iter().filter(|id| collected.contains(id)) {
+            thread_of.insert(trace_id, i);
+        }
+        pending.retain(|id| !collected.contains(id));
+    }
+
+    let mut groups: Vec<OrphanSpans> = Vec::new();
+    let mut group_of: HashMap<usize, usize> = HashMap::new();
+    for (trace_id, spans) in traces {
+        let i = match thread_of.get(&trace_id) {
+            Some(&i) => i,
+            None => continue,
+        };
+        match group_of.get(&i) {
+            Some(&g) => groups[g].spans.extend(spans),
+            None => {
+                let thread = &orphans.threads[i];
+                group_of.insert(i, groups.len());
+                groups.push(OrphanSpans {
+                    thread_id: thread.thread_id,
+                    thread_name: thread.thread_name.clone(),
+                    spans,
+                });
+            }
+        }
+    }
+
+    // Forget the exited threads, once nothing of theirs is pending
+    orphans
+        .threads
+        .retain(|t| Arc::strong_count(t) > 1 || !t.pending.lock().unwrap().is_empty());
+    groups
+}
+
+#[inline]
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn start_orphan(event: &'static str) -> Span {
+    let trace_id = DefaultIdGenerator::next_trace_id();
+    let started = LOCAL_ORPHANS.try_with(|local| {
+        {
+            let mut pending = local.thread.pending.lock().unwrap();
+            if pending.len() == MAX_PENDING_PER_THREAD {
+                pending.pop_front();
+            }
+            pending.push_back(trace_id);
+        }
+        local.starter.start_root_with_trace_id(event, trace_id)
+    });
+
+    // The thread is exiting
+    started.unwrap_or_else(|_| Span::empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_pending() {
+        let _lock = crate::tests::CONFIG_LOCK.lock().unwrap();
+        let thread_id = std::thread::spawn(|| {
+            for _ in 0..MAX_PENDING_PER_THREAD + 1 {
+                drop(start_orphan("orphan"));
+            }
+            let pending = LOCAL_ORPHANS.with(|local| local.thread.pending.lock().unwrap().len());
+            assert_eq!(pending, MAX_PENDING_PER_THREAD);
+            std::thread::current().id()
+        })
+        .join()
+        .unwrap();
+
+        let orphans = collect_orphan_spans();
+        let group = orphans.iter().find(|g| g.thread_id == thread_id).unwrap();
+        assert_eq!(group.spans.len(), MAX_PENDING_PER_THREAD);
+
+        // The thread has exited, and nothing of it is pending anymore
+        let orphans = ORPHANS.lock().unwrap();
+        assert!(orphans.threads.iter().all(|t| t.thread_id != thread_id));
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crossbeam::channel::{Receiver, Sender};
+
+use crate::semconv;
+use crate::span::{DefaultClock, Span};
+use crate::trace::acquirer::{span_count, SpanCollection, Submission, SummaryState};
+use crate::trace::collector::Collector;
+
+lazy_static! {
+    static ref PARTIAL_EXPORT: RwLock<Option<Arc<PartialExport>>> = RwLock::new(None);
+}
+
+// Mirrors `Config::partial_export`, sparing the root spans a read of the lock when it's unset
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// A part of a trace exported before the trace is collected, since its collector was holding
+/// too many spans, see [`Config::partial_export`](crate::Config::partial_export).
+#[derive(Clone, Debug)]
+pub struct PartialTrace {
+    pub trace_id: u128,
+    /// The position of the part in the trace, from 0. The spans collected at last follow the
+    /// parts exported, as the part numbered by their count.
+    pub sequence: u32,
+    /// The spans whose parents are in other parts, e.g. all but the root span, carry the
+    /// property [`partial.sequence`](semconv::PARTIAL_SEQUENCE) set to `sequence`.
+    pub spans: Vec<Span>,
+}
+
+pub type ExportFn = dyn Fn(PartialTrace) + Send + Sync;
+
+pub(crate) struct PartialExport {
+    pub(crate) max_pending_spans: usize,
+    pub(crate) export: Box<ExportFn>,
+}
+
+impl fmt::Debug for PartialExport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PartialExport")
+            .field("max_pending_spans", &self.max_pending_spans)
+            .finish()
+    }
+}
+
+pub(crate) fn set(partial_export: Option<Arc<PartialExport>>) {
+    ENABLED.store(partial_export.is_some(), Ordering::Relaxed);
+    *PARTIAL_EXPORT.write().unwrap() = partial_export;
+}
+
+// The export of the parts of the traces starting now
+#[inline]
+pub(crate) fn current() -> Option<Arc<PartialExport>> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    PARTIAL_EXPORT.read().unwrap().clone()
+}
+
+impl PartialExport {
+    // Export the spans pending in the channel of a trace as a part once there are too many
+    #[inline]
+    pub(crate) fn export_pending(
+        &self,
+        trace_id: u128,
+        receiver: &Receiver<Submission>,
+        sender: &Sender<Submission>,
+        summary: &SummaryState,
+    ) {
+        if summary.pending_spans() < self.max_pending_spans || !summary.begin_partial() {
+            return;
+        }
+
+        let mut span_collections = Vec::new();
+        // Left for the collector, e.g. a cancellation, or the remote spans matched against the
+        // remote children expected, which don't count as pending anymore
+        let mut others = Vec::new();
+        let mut count = 0;
+        for (_, sc) in receiver.try_iter() {
+            count += span_count(&sc);
+            match sc {
+                SpanCollection::LocalSpans { .. }
+                | SpanCollection::Span(_)
+                | SpanCollection::Manual(_) => span_collections.push(sc),
+                sc => others.push(sc),
+            }
+        }
+        for sc in others {
+            sender.send((trace_id, sc)).ok();
+        }
+        summary.drain(count);
+
+        if !span_collections.is_empty() {
+            let mut spans = Collector::amend(span_collections, DefaultClock::anchor(), false);
+            let sequence = summary.next_partial();
+            mark(&mut spans, sequence);
+            (self.export)(PartialTrace {
+                trace_id,
+                sequence,
+                spans,
+            });
+        }
+        summary.end_partial();
+    }
+}
+
+// Set the sequence number of a part on the spans whose parents aren't in it
+pub(crate) fn mark(spans: &mut [Span], sequence: u32) {
+    let ids: HashSet<u32> = spans.iter().map(|s| s.id).collect();
+    for span in spans.iter_mut().filter(|s| !ids.contains(&s.parent_id)) {
+        let (key, value) = semconv::partial_sequence(sequence);
+        span.properties.push((key, value.into()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::span::SpanId;
+    use crate::trace::acquirer::Acquirer;
+    use crate::trace::pool;
+
+    #[test]
+    fn export_pending() {
+        let parts = Arc::new(Mutex::new(Vec::new()));
+        let partial_export = Arc::new(PartialExport {
+            max_pending_spans: 3,
+            export: Box::new({
+                let parts = parts.clone();
+                move |part| parts.lock().unwrap().push(part)
+            }),
+        });
+
+        // `Span::root` with the export set by `Config::partial_export`
+        let channel = pool::take();
+        let tx = Arc::new(
+            channel
+                .span_sender()
+                .with_partial_export(channel.receiver.clone(), partial_export),
+        );
+        let collector = Collector::new(channel.clone(), Arc::downgrade(&tx), 7, None);
+        let acquirer = Acquirer::new(tx, channel.closed.clone(), 7);
+        let root_span = crate::Span::new(iter::once((SpanId::new(0), &acquirer)), "root");
+        drop(acquirer);
+
+        for _ in 0..4 {
+            drop(crate::Span::from_parent("child", &root_span));
+        }
+        drop(root_span);
+        let spans = collector.collect();
+
+        let parts = parts.lock().unwrap();
+        assert_eq!(parts.len(), 1);
+        assert_eq!((parts[0].trace_id, parts[0].sequence), (7, 0));
+        assert_eq!(parts[0].spans.len(), 3);
+        assert_eq!(spans.len(), 2);
+
+        let sequence = |span: &Span| {
+            span.properties
+                .iter()
+                .find(|(k, _)| *k == semconv::PARTIAL_SEQUENCE)
+                .map(|(_, v)| v.clone())
+        };
+        assert!(parts[0]
+            .spans
+            .iter()
+            .all(|s| sequence(s) == Some("0".into())));
+        let root = spans.iter().find(|s| s.event == "root").unwrap();
+        assert_eq!(sequence(root), Some("1".into()));
+        assert!(spans
+            .iter()
+            .all(|s| s.event == "root" || sequence(s).is_none()));
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crossbeam::channel::{Receiver, Sender};
+
+use crate::config::config;
+use crate::trace::acquirer::{SpanSender, Submission, SummaryState};
+use crate::trace::partial;
+
+thread_local! {
+    static POOL: RefCell<Pool> = RefCell::new(Pool::new(config().collector_pool_capacity));
+}
+
+// The channel and the closed flag backing a `Collector`, recycled across traces when no span
+// can report to them anymore
+#[derive(Clone, Debug)]
+pub(crate) struct Channel {
+    pub(crate) sender: Sender<Submission>,
+    pub(crate) receiver: Receiver<Submission>,
+    pub(crate) closed: Arc<AtomicBool>,
+    pub(crate) lease: u64,
+    pub(crate) summary: Arc<SummaryState>,
+}
+
+impl Channel {
+    pub(crate) fn span_sender(&self) -> SpanSender {
+        let sender = SpanSender::new(self.sender.clone(), self.lease, self.summary.clone());
+        match partial::current() {
+            Some(partial_export) => {
+                sender.with_partial_export(self.receiver.clone(), partial_export)
+            }
+            None => sender,
+        }
+    }
+}
+
+struct Pool {
+    channels: Vec<Channel>,
+    capacity: usize,
+}
+
+impl Pool {
+    fn new(capacity: usize) -> Self {
+        Pool {
+            channels: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+}
+
+pub(crate) fn take() -> Channel {
+    POOL.try_with(|pool| pool.borrow_mut().channels.pop())
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| {
+            let (sender, receiver) = crossbeam::channel::unbounded();
+            Channel {
+                sender,
+                receiver,
+                closed: Arc::new(AtomicBool::new(false)),
+                lease: 0,
+                summary: Default::default(),
+            }
+        })
+}
+
+// The caller must make sure that no acquirer or collector handle refers to the channel
+pub(crate) fn recycle(mut channel: Channel) {
+    // Drop the submissions left by the previous trace, e.g. those arriving after
+    // an asynchronous collection
+    for _ in channel.receiver.try_iter() {}
+    channel.closed.store(false, Ordering::SeqCst);
+    channel.summary.reset();
+    channel.lease += 1;
+
+    POOL.try_with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.channels.len() < pool.capacity {
+            pool.channels.push(channel);
+        }
+    })
+    .ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_channel() {
+        let channel = take();
+        channel.closed.store(true, Ordering::SeqCst);
+        let lease = channel.lease;
+        let receiver = channel.receiver.clone();
+        recycle(channel);
+
+        let channel = take();
+        assert!(channel.receiver.same_channel(&receiver));
+        assert!(!channel.closed.load(Ordering::SeqCst));
+        assert_eq!(channel.lease, lease + 1);
+    }
+}
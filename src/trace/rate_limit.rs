@@ -0,0 +1,109 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashMap;
+
+use crate::semconv;
+use crate::span::{PropertyValue, Span};
+
+/// A limit of the number of spans of a hot event, see [`Config::rate_limit`]. The spans beyond
+/// the limit are dropped and counted by the property
+/// [`rate_limited.spans`](semconv::RATE_LIMITED_SPANS) of the last span kept, so that one hot
+/// call site doesn't dominate the trace but still shows up. Limits are at least one span.
+///
+/// [`Config::rate_limit`]: crate::Config::rate_limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimit {
+    /// Keep the first spans of each trace, applied when the trace is collected. The children of
+    /// a dropped span are moved to its parent.
+    PerTrace(usize),
+    /// Keep at most so many local spans per second on each thread, applied when they start. The
+    /// children of a dropped span are recorded under its parent. The spans dropped after the
+    /// last kept span has been collected are counted by the next one kept.
+    PerSecondPerThread(usize),
+}
+
+// Apply the `PerTrace` limits to the spans of a trace
+pub(crate) fn apply_per_trace(spans: &mut Vec<Span>, limits: &[(&'static str, RateLimit)]) {
+    // The parents of the dropped spans by their ids
+    let mut dropped = HashMap::new();
+    for (event, limit) in limits {
+        let max = match limit {
+            RateLimit::PerTrace(max) => (*max).max(1),
+            RateLimit::PerSecondPerThread(_) => continue,
+        };
+        let mut indices: Vec<usize> = (0..spans.len())
+            .filter(|i| spans[*i].event == *event)
+            .collect();
+        if indices.len() <= max {
+            continue;
+        }
+        indices.sort_by_key(|i| (spans[*i].begin_unix_time_ns, spans[*i].id));
+        for i in &indices[max..] {
+            dropped.insert(spans[*i].id, spans[*i].parent_id);
+        }
+        add_dropped(&mut spans[indices[max - 1]], (indices.len() - max) as i64);
+    }
+    if dropped.is_empty() {
+        return;
+    }
+
+    spans.retain(|span| !dropped.contains_key(&span.id));
+    for span in spans.iter_mut() {
+        while let Some(parent_id) = dropped.get(&span.parent_id) {
+            span.parent_id = *parent_id;
+        }
+    }
+}
+
+fn add_dropped(span: &mut Span, count: i64) {
+    for (key, value) in &mut span.properties {
+        if let (&semconv::RATE_LIMITED_SPANS, PropertyValue::I64(v)) = (&*key, &mut *value) {
+            *v += count;
+            return;
+        }
+    }
+    span.properties
+        .push((semconv::RATE_LIMITED_SPANS, PropertyValue::I64(count)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(id: u32, parent_id: u32, begin_unix_time_ns: u64, event: &'static str) -> Span {
+        Span {
+            id,
+            parent_id,
+            begin_unix_time_ns,
+            duration_ns: 1,
+            event,
+            properties: vec![],
+            events: vec![],
+        }
+    }
+
+    #[test]
+    fn per_trace() {
+        let mut spans = vec![
+            span(1, 0, 0, "root"),
+            span(4, 1, 3, "get"),
+            span(2, 1, 1, "get"),
+            span(3, 1, 2, "get"),
+            span(5, 4, 4, "decode"),
+        ];
+        apply_per_trace(
+            &mut spans,
+            &[
+                ("get", RateLimit::PerTrace(2)),
+                ("root", RateLimit::PerSecondPerThread(0)),
+            ],
+        );
+
+        let ids: Vec<_> = spans.iter().map(|s| (s.id, s.parent_id)).collect();
+        assert_eq!(ids, vec![(1, 0), (2, 1), (3, 1), (5, 1)]);
+        assert_eq!(
+            spans[2].properties,
+            vec![(semconv::RATE_LIMITED_SPANS, PropertyValue::I64(1))]
+        );
+    }
+}
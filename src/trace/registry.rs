@@ -0,0 +1,210 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, Weak};
+use std::time::{Duration, SystemTime};
+
+use crate::trace::acquirer::SpanSender;
+use crate::Span;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_KEY: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref ACTIVE_TRACES: Mutex<HashMap<usize, Entry>> = Mutex::new(HashMap::new());
+    static ref ACTIVE_TASKS: Mutex<HashMap<usize, TaskEntry>> = Mutex::new(HashMap::new());
+}
+
+struct Entry {
+    trace_id: u128,
+    event: &'static str,
+    begin: SystemTime,
+    sender: Weak<SpanSender>,
+}
+
+struct TaskEntry {
+    trace_ids: Vec<u128>,
+    task: ActiveTask,
+}
+
+/// A trace whose root span has been created and whose collector hasn't been dropped yet.
+#[derive(Clone, Debug)]
+pub struct ActiveTrace {
+    pub trace_id: u128,
+    /// The event of the root span.
+    pub event: &'static str,
+    pub begin: SystemTime,
+    /// The number of live spans and attached span guards of the trace, roughly the number of
+    /// threads and tasks working on it.
+    pub live_references: usize,
+}
+
+impl ActiveTrace {
+    pub fn age(&self) -> Duration {
+        self.begin.elapsed().unwrap_or_default()
+    }
+}
+
+/// A future bound to a span by [`in_span`](crate::FutureExt::in_span), created while the
+/// registry of active traces is enabled and not finished yet.
+#[derive(Clone, Debug)]
+pub struct ActiveTask {
+    /// The event of the span bound to the future.
+    pub event: &'static str,
+    pub begin: SystemTime,
+    /// The end of the last poll, or `None` if it's being polled or never polled.
+    pub last_poll: Option<SystemTime>,
+    /// The events of the [`in_local_span`](crate::FutureExt::in_local_span) and nested
+    /// [`in_span`](crate::FutureExt::in_span) futures pending at the end of the last poll,
+    /// outermost first. The futures polled concurrently, e.g. by `join!`, follow each other.
+    pub stack: Vec<&'static str>,
+}
+
+impl ActiveTask {
+    pub fn age(&self) -> Duration {
+        self.begin.elapsed().unwrap_or_default()
+    }
+
+    /// The time since the last poll, or `None` if it's being polled or never polled.
+    pub fn idle(&self) -> Option<Duration> {
+        self.last_poll.map(|t| t.elapsed().unwrap_or_default())
+    }
+}
+
+/// The active tasks of a trace, see [`active_tasks`](active_tasks).
+#[derive(Clone, Debug)]
+pub struct TraceTasks {
+    pub trace_id: u128,
+    /// The trace if it's registered, i.e. its root span was created while the registry was
+    /// enabled.
+    pub trace: Option<ActiveTrace>,
+    /// The tasks of the trace, oldest first.
+    pub tasks: Vec<ActiveTask>,
+}
+
+/// Enable or disable the registry of active traces queried by [`active_traces`](active_traces).
+/// It's disabled by default.
+///
+/// Only traces created while it's enabled are registered.
+pub fn set_active_traces_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// List the active traces, oldest first.
+pub fn active_traces() -> Vec<ActiveTrace> {
+    let active_traces = ACTIVE_TRACES.lock().unwrap();
+    let mut res: Vec<_> = active_traces
+        .values()
+        .map(|entry| ActiveTrace {
+            trace_id: entry.trace_id,
+            event: entry.event,
+            begin: entry.begin,
+            live_references: entry.sender.strong_count(),
+        })
+        .collect();
+    res.sort_by_key(|t| t.begin);
+    res
+}
+
+/// List the active tasks by their traces, the ones with the oldest tasks first, e.g. to find
+/// the requests stuck in production. A task is listed under every trace its span belongs to.
+///
+/// See [`debug::dump_tasks`](crate::debug::dump_tasks) for a printable dump.
+pub fn active_tasks() -> Vec<TraceTasks> {
+    let mut by_trace: HashMap<u128, Vec<ActiveTask>> = HashMap::new();
+    for entry in ACTIVE_TASKS.lock().unwrap().values() {
+        for trace_id in &entry.trace_ids {
+            by_trace
+                .entry(*trace_id)
+                .or_default()
+                .push(entry.task.clone());
+        }
+    }
+
+    let traces = active_traces();
+    let mut res: Vec<_> = by_trace
+        .into_iter()
+        .map(|(trace_id, mut tasks)| {
+            tasks.sort_by_key(|t| t.begin);
+            TraceTasks {
+                trace_id,
+                trace: traces.iter().find(|t| t.trace_id == trace_id).cloned(),
+                tasks,
+            }
+        })
+        .collect();
+    res.sort_by_key(|t| t.tasks[0].begin);
+    res
+}
+
+#[inline]
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn register(
+    trace_id: u128,
+    event: &'static str,
+    sender: Weak<SpanSender>,
+) -> Option<usize> {
+    if !enabled() {
+        return None;
+    }
+
+    let key = NEXT_KEY.fetch_add(1, Ordering::Relaxed);
+    ACTIVE_TRACES.lock().unwrap().insert(
+        key,
+        Entry {
+            trace_id,
+            event,
+            begin: SystemTime::now(),
+            sender,
+        },
+    );
+    Some(key)
+}
+
+pub(crate) fn unregister(key: usize) {
+    ACTIVE_TRACES.lock().unwrap().remove(&key);
+}
+
+pub(crate) fn register_task(span: &Span) -> Option<usize> {
+    if !enabled() || span.is_empty() {
+        return None;
+    }
+    let trace_ids = span.references().into_iter().map(|(id, _)| id).collect();
+    let event = span.event();
+
+    let key = NEXT_KEY.fetch_add(1, Ordering::Relaxed);
+    ACTIVE_TASKS.lock().unwrap().insert(
+        key,
+        TaskEntry {
+            trace_ids,
+            task: ActiveTask {
+                event,
+                begin: SystemTime::now(),
+                last_poll: None,
+                stack: Vec::new(),
+            },
+        },
+    );
+    Some(key)
+}
+
+pub(crate) fn task_polling(key: usize) {
+    if let Some(entry) = ACTIVE_TASKS.lock().unwrap().get_mut(&key) {
+        entry.task.last_poll = None;
+    }
+}
+
+pub(crate) fn task_pending(key: usize, stack: Vec<&'static str>) {
+    if let Some(entry) = ACTIVE_TASKS.lock().unwrap().get_mut(&key) {
+        entry.task.last_poll = Some(SystemTime::now());
+        entry.task.stack = stack;
+    }
+}
+
+pub(crate) fn unregister_task(key: usize) {
+    ACTIVE_TASKS.lock().unwrap().remove(&key);
+}
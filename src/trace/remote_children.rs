@@ -0,0 +1,103 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashSet;
+
+use crate::semconv;
+use crate::span::SpanId;
+use crate::trace::acquirer::SpanCollection;
+use crate::Span;
+
+impl Span {
+    /// Declare the spans of other processes expected to return the spans of their part of the
+    /// trace, e.g. the spans whose contexts a coordinator sends to the nodes it fans out to.
+    ///
+    /// The spans returned are merged into the trace by
+    /// [`add_remote_spans`](Span::add_remote_spans). At collection, the span gets the property
+    /// [`remote.missing`](semconv::REMOTE_MISSING) listing the expected span ids to which no
+    /// remote span is attached, e.g. the nodes lost or timed out, so that an incomplete
+    /// distributed trace tells which parts are missing.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minitrace::propagation::SpanContext;
+    /// use minitrace::{semconv, Span};
+    ///
+    /// let (root_span, collector) = Span::root("fan-out");
+    /// let rpcs: Vec<_> = (0..2).map(|_| Span::from_parent("rpc", &root_span)).collect();
+    /// let ids: Vec<_> = rpcs
+    ///     .iter()
+    ///     .map(|rpc| SpanContext::from_span(rpc).unwrap().span_id as u32)
+    ///     .collect();
+    /// root_span.expect_remote_children(ids.clone());
+    ///
+    /// // The first node returns its spans, the second one times out.
+    /// let context = SpanContext::new(collector.trace_id(), ids[0] as u64);
+    /// let (node_span, node_collector) = Span::root_with_context("node", context);
+    /// drop(node_span);
+    /// root_span.add_remote_spans(node_collector.collect());
+    ///
+    /// drop((root_span, rpcs));
+    /// let spans = collector.collect();
+    /// let root = spans.iter().find(|s| s.event == "fan-out").unwrap();
+    /// assert!(root
+    ///     .properties
+    ///     .contains(&(semconv::REMOTE_MISSING, format!("{:016x}", ids[1]).into())));
+    /// ```
+    pub fn expect_remote_children(&self, span_ids: impl IntoIterator<Item = u32>) {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return,
+        };
+        let span_ids: Vec<_> = span_ids.into_iter().collect();
+        for (_, acq) in inner.to_report.iter().filter(|(_, acq)| !acq.is_shutdown()) {
+            acq.submit(SpanCollection::RemoteChildren {
+                parent_id: inner.span_id,
+                span_ids: span_ids.clone(),
+            });
+        }
+    }
+
+    /// Merge the spans collected by another process continuing the trace, e.g. returned in the
+    /// response of an RPC, into the trace of the span. They are reported as they are, without
+    /// clock skew correction.
+    pub fn add_remote_spans(&self, spans: Vec<crate::span::Span>) {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return,
+        };
+        for (_, acq) in inner.to_report.iter().filter(|(_, acq)| !acq.is_shutdown()) {
+            acq.submit(SpanCollection::Remote(spans.clone()));
+        }
+    }
+}
+
+// Mark the spans expecting remote children with the ids of the children no remote span is
+// attached to
+pub(crate) fn mark_missing(
+    spans: &mut [crate::span::Span],
+    expected: &[(SpanId, Vec<u32>)],
+    remote: &[usize],
+) {
+    // The remote root spans point at their parents by `remote.parent`
+    let returned: HashSet<u64> = remote
+        .iter()
+        .flat_map(|&i| &spans[i].properties)
+        .filter(|(k, _)| *k == semconv::REMOTE_PARENT)
+        .filter_map(|(_, v)| u64::from_str_radix(v.as_str()?, 16).ok())
+        .collect();
+    for (parent_id, span_ids) in expected {
+        let missing: Vec<_> = span_ids
+            .iter()
+            .filter(|&&id| !returned.contains(&u64::from(id)))
+            .copied()
+            .collect();
+        if missing.is_empty() {
+            continue;
+        }
+        if let Some(span) = spans.iter_mut().find(|s| s.id == parent_id.0) {
+            let (key, value) = semconv::remote_missing(&missing);
+            span.properties.push((key, value.into()));
+        }
+    }
+}
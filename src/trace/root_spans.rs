@@ -0,0 +1,168 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crossbeam::channel::{Receiver, Sender, TrySendError};
+
+use crate::semconv;
+use crate::span::{DefaultClock, RawSpan};
+
+lazy_static! {
+    static ref SUBSCRIBERS: RwLock<Vec<Subscriber>> = RwLock::new(Vec::new());
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// Whether there are subscribers, sparing the spans a lock when there are none
+static SUBSCRIBED: AtomicBool = AtomicBool::new(false);
+
+struct Subscriber {
+    id: u64,
+    sender: Sender<RootSpanSummary>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// A finished root span, without its trace, see [`subscribe_root_spans`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RootSpanSummary {
+    pub trace_id: u128,
+    pub span_id: u32,
+    pub event: &'static str,
+    pub begin_unix_time_ns: u64,
+    pub duration_ns: u64,
+    pub status: RootSpanStatus,
+}
+
+/// The outcome of a root span, told by its properties.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RootSpanStatus {
+    Ok,
+    /// It has the property [`error.kind`](semconv::ERROR_KIND) or
+    /// [`error.message`](semconv::ERROR_MESSAGE).
+    Error,
+    /// It has the property [`cancelled`](semconv::CANCELLED).
+    Cancelled,
+}
+
+/// The receiving end of [`subscribe_root_spans`]. Dropping it unsubscribes.
+pub struct RootSpans {
+    id: u64,
+    receiver: Receiver<RootSpanSummary>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl RootSpans {
+    /// The next root span finished, if any.
+    pub fn try_recv(&self) -> Option<RootSpanSummary> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// Wait up to `timeout` for the next root span to finish.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<RootSpanSummary> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+
+    /// The root spans finished since the last call, without waiting.
+    pub fn drain(&self) -> Vec<RootSpanSummary> {
+        self.receiver.try_iter().collect()
+    }
+
+    /// The number of root spans dropped because the subscription was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for RootSpans {
+    fn drop(&mut self) {
+        let mut subscribers = SUBSCRIBERS.write().unwrap();
+        subscribers.retain(|s| s.id != self.id);
+        SUBSCRIBED.store(!subscribers.is_empty(), Ordering::Relaxed);
+    }
+}
+
+/// Subscribe to the root spans of all traces as they finish, e.g. for latency heatmaps or live
+/// monitoring, without collecting the traces. Up to `capacity` root spans are buffered, beyond
+/// which they are dropped and counted by [`RootSpans::dropped`].
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::{subscribe_root_spans, RootSpanStatus, Span};
+///
+/// let root_spans = subscribe_root_spans(1024);
+///
+/// let (root_span, _collector) = Span::root("request");
+/// let trace_id = root_span.trace_id().unwrap();
+/// drop(root_span);
+///
+/// let summary = root_spans
+///     .drain()
+///     .into_iter()
+///     .find(|s| s.trace_id == trace_id)
+///     .unwrap();
+/// assert_eq!(summary.event, "request");
+/// assert_eq!(summary.status, RootSpanStatus::Ok);
+/// ```
+pub fn subscribe_root_spans(capacity: usize) -> RootSpans {
+    let (sender, receiver) = crossbeam::channel::bounded(capacity);
+    let dropped = Arc::new(AtomicU64::new(0));
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut subscribers = SUBSCRIBERS.write().unwrap();
+    subscribers.push(Subscriber {
+        id,
+        sender,
+        dropped: dropped.clone(),
+    });
+    SUBSCRIBED.store(true, Ordering::Relaxed);
+    RootSpans {
+        id,
+        receiver,
+        dropped,
+    }
+}
+
+#[inline]
+pub(crate) fn publish(trace_id: u128, span: &RawSpan) {
+    if span.parent_id.0 == 0 && SUBSCRIBED.load(Ordering::Relaxed) {
+        publish_slow(trace_id, span);
+    }
+}
+
+#[cold]
+fn publish_slow(trace_id: u128, span: &RawSpan) {
+    let anchor = DefaultClock::anchor();
+    let begin_unix_time_ns = DefaultClock::cycle_to_unix_time_ns(span.begin_cycle, anchor);
+    let end_unix_time_ns = DefaultClock::cycle_to_unix_time_ns(span.end_cycle, anchor);
+    let status = if span
+        .properties
+        .iter()
+        .any(|(k, _)| *k == semconv::CANCELLED)
+    {
+        RootSpanStatus::Cancelled
+    } else if span
+        .properties
+        .iter()
+        .any(|(k, _)| *k == semconv::ERROR_KIND || *k == semconv::ERROR_MESSAGE)
+    {
+        RootSpanStatus::Error
+    } else {
+        RootSpanStatus::Ok
+    };
+    let summary = RootSpanSummary {
+        trace_id,
+        span_id: span.id.0,
+        event: span.event,
+        begin_unix_time_ns,
+        duration_ns: end_unix_time_ns.saturating_sub(begin_unix_time_ns),
+        status,
+    };
+
+    for subscriber in SUBSCRIBERS.read().unwrap().iter() {
+        if let Err(TrySendError::Full(_)) = subscriber.sender.try_send(summary.clone()) {
+            subscriber.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
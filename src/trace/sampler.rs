@@ -0,0 +1,261 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::any::Any;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::span::DefaultIdGenerator;
+use crate::trace::pool;
+use crate::{Collector, Span};
+
+/// Sample a fixed ratio of traces, deciding by the trace id alone, so that services tracing the
+/// same distributed request make the same decision without coordination.
+///
+/// A trace is sampled if the [splitmix64](https://prng.di.unimi.it/splitmix64.c) finalizer of
+/// the lower 64 bits of its id is less than `ratio * 2^64`. Services written in other languages
+/// sample consistently by doing the same.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::TraceIdRatioSampler;
+///
+/// let sampler = TraceIdRatioSampler::new(0.1);
+///
+/// let (root_span, collector) = sampler.root("request");
+/// // An unsampled trace has an empty root span, and the collector returns no span.
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceIdRatioSampler {
+    // `None` samples every trace, whose threshold would be 2^64
+    threshold: Option<u64>,
+}
+
+impl TraceIdRatioSampler {
+    /// Create a sampler keeping `ratio` of the traces, clamped into `[0, 1]`.
+    pub fn new(ratio: f64) -> Self {
+        let ratio = if ratio.is_nan() {
+            0.0
+        } else {
+            ratio.clamp(0.0, 1.0)
+        };
+        let threshold = if ratio >= 1.0 {
+            None
+        } else {
+            Some((ratio * 2f64.powi(64)) as u64)
+        };
+        TraceIdRatioSampler { threshold }
+    }
+
+    pub fn should_sample(&self, trace_id: u128) -> bool {
+        match self.threshold {
+            Some(threshold) => trace_hash(trace_id) < threshold,
+            None => true,
+        }
+    }
+
+    /// Start a new trace if it's sampled. See [`Span::root`](Span::root).
+    pub fn root(&self, event: &'static str) -> (Span, Collector) {
+        self.root_with_trace_id(event, DefaultIdGenerator::next_trace_id())
+    }
+
+    /// Start the trace identified by `trace_id` if it's sampled, e.g. with the trace id
+    /// propagated by the upstream service. See [`Span::root_with_trace_id`](Span::root_with_trace_id).
+    pub fn root_with_trace_id(&self, event: &'static str, trace_id: u128) -> (Span, Collector) {
+        if self.should_sample(trace_id) {
+            Span::root_with_trace_id(event, trace_id)
+        } else {
+            (Span::empty(), unsampled_collector(trace_id))
+        }
+    }
+}
+
+/// The decision of a [`ContextSampler`] on a trace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Sample,
+    Drop,
+    /// Leave it to the ratio of the sampler, see [`ContextSampler::fallback`].
+    Ratio,
+}
+
+/// Sample traces by the context of the requests starting them, e.g. tenants, statement types
+/// or explicit "trace me" flags, passed by the caller as `&dyn Any`.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::{ContextSampler, Decision, TraceIdRatioSampler};
+///
+/// struct Request {
+///     trace_me: bool,
+/// }
+///
+/// let sampler = ContextSampler::new(|_event, context| match context.downcast_ref::<Request>() {
+///     Some(request) if request.trace_me => Decision::Sample,
+///     _ => Decision::Ratio,
+/// })
+/// .fallback(TraceIdRatioSampler::new(0.0));
+///
+/// let (root_span, _collector) = sampler.root("request", &Request { trace_me: true });
+/// assert!(!root_span.is_empty());
+/// let (root_span, _collector) = sampler.root("request", &Request { trace_me: false });
+/// assert!(root_span.is_empty());
+/// ```
+#[derive(Clone)]
+pub struct ContextSampler {
+    decide: Arc<DecideFn>,
+    fallback: TraceIdRatioSampler,
+}
+
+impl ContextSampler {
+    pub fn new(
+        decide: impl Fn(&'static str, &dyn Any) -> Decision + Send + Sync + 'static,
+    ) -> Self {
+        ContextSampler {
+            decide: Arc::new(decide),
+            fallback: TraceIdRatioSampler::new(1.0),
+        }
+    }
+
+    /// The sampler deciding on the traces left to [`Decision::Ratio`]. Defaults to sampling
+    /// every trace.
+    pub fn fallback(self, fallback: TraceIdRatioSampler) -> Self {
+        Self { fallback, ..self }
+    }
+
+    pub fn should_sample(&self, event: &'static str, context: &dyn Any, trace_id: u128) -> bool {
+        match (self.decide)(event, context) {
+            Decision::Sample => true,
+            Decision::Drop => false,
+            Decision::Ratio => self.fallback.should_sample(trace_id),
+        }
+    }
+
+    /// Start a new trace if it's sampled. See [`Span::root`](Span::root).
+    pub fn root(&self, event: &'static str, context: &dyn Any) -> (Span, Collector) {
+        self.root_with_trace_id(event, context, DefaultIdGenerator::next_trace_id())
+    }
+
+    /// Start the trace identified by `trace_id` if it's sampled. See
+    /// [`Span::root_with_trace_id`](Span::root_with_trace_id).
+    pub fn root_with_trace_id(
+        &self,
+        event: &'static str,
+        context: &dyn Any,
+        trace_id: u128,
+    ) -> (Span, Collector) {
+        if self.should_sample(event, context, trace_id) {
+            Span::root_with_trace_id(event, trace_id)
+        } else {
+            (Span::empty(), unsampled_collector(trace_id))
+        }
+    }
+}
+
+impl fmt::Debug for ContextSampler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContextSampler")
+            .field("fallback", &self.fallback)
+            .finish()
+    }
+}
+
+type DecideFn = dyn Fn(&'static str, &dyn Any) -> Decision + Send + Sync;
+
+// A collector which no span reports to
+pub(crate) fn unsampled_collector(trace_id: u128) -> Collector {
+    let channel = pool::take();
+    // Dropping the only sender tells a synchronous collection that there are no more spans.
+    let sender = Arc::new(channel.span_sender());
+    Collector::new(channel, Arc::downgrade(&sender), trace_id, None)
+}
+
+/// A stable hash of a trace id, which [`TraceIdRatioSampler`] samples a trace by. Logging
+/// frameworks keep the logs of `ratio` of the traces, the ones sampled at the same ratio, by
+/// keeping the logs with `trace_hash(trace_id) < ratio * 2^64`.
+///
+/// Only the lower 64 bits of the id are hashed, so that the traces of services still using 64-bit
+/// trace ids, which propagate the lower 64 bits of a 128-bit id, are sampled alike. Trace ids
+/// differing only in their upper 64 bits hash alike.
+#[inline]
+pub fn trace_hash(trace_id: u128) -> u64 {
+    mix(trace_id as u64)
+}
+
+/// A stable hash of a span, identified by its trace id and span id, e.g. for sampling logs per
+/// span consistently across processes.
+#[inline]
+pub fn context_hash(trace_id: u128, span_id: u32) -> u64 {
+    mix(trace_hash(trace_id) ^ span_id as u64)
+}
+
+#[inline]
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CollectArgs;
+
+    #[test]
+    fn ratio() {
+        let sampler = TraceIdRatioSampler::new(0.25);
+        let sampled = (0..10000u128)
+            .filter(|id| sampler.should_sample(*id))
+            .count();
+        assert!((2300..2700).contains(&sampled), "{}", sampled);
+
+        // decisions are consistent across samplers
+        let other = TraceIdRatioSampler::new(0.25);
+        assert!((0..10000u128).all(|id| sampler.should_sample(id) == other.should_sample(id)));
+
+        // a trace sampled at a ratio is sampled at any larger ratio
+        let larger = TraceIdRatioSampler::new(0.5);
+        assert!((0..10000u128).all(|id| !sampler.should_sample(id) || larger.should_sample(id)));
+
+        assert!((0..100u128).all(|id| TraceIdRatioSampler::new(1.0).should_sample(id)));
+        assert!((0..100u128).all(|id| !TraceIdRatioSampler::new(0.0).should_sample(id)));
+    }
+
+    #[test]
+    fn context() {
+        let sampler = ContextSampler::new(|event, context| match context.downcast_ref::<&str>() {
+            Some(&"vip") => Decision::Sample,
+            _ if event == "noise" => Decision::Drop,
+            _ => Decision::Ratio,
+        })
+        .fallback(TraceIdRatioSampler::new(0.0));
+
+        assert!(sampler.should_sample("noise", &"vip", 1));
+        assert!(!sampler.should_sample("noise", &"other", 1));
+        assert!(!sampler.should_sample("request", &(), 1));
+        assert!(ContextSampler::new(|_, _| Decision::Ratio).should_sample("request", &(), 1));
+    }
+
+    #[test]
+    fn hash() {
+        // splitmix64 of the ids, stable across versions and languages
+        assert_eq!(trace_hash(0), 0);
+        assert_eq!(trace_hash(1), 0x5692_161d_100b_05e5);
+        assert_eq!(trace_hash(0xff << 64 | 1), trace_hash(1));
+        assert_ne!(context_hash(1, 1), context_hash(1, 2));
+        assert_ne!(context_hash(1, 1), context_hash(2, 1));
+    }
+
+    #[test]
+    fn unsampled() {
+        let sampler = TraceIdRatioSampler::new(0.0);
+        let (span, collector) = sampler.root_with_trace_id("root", 42);
+        assert!(span.is_empty());
+        assert_eq!(collector.trace_id(), 42);
+        drop(span);
+        assert!(collector
+            .collect_with_args(CollectArgs::default().sync(true))
+            .is_empty());
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::iter;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::Receiver;
+
+use crate::span::{DefaultClock, DefaultIdGenerator, SpanId};
+use crate::trace::acquirer::{self, Acquirer, SpanCollection, SpanSender, Submission};
+use crate::trace::collector::Collector;
+use crate::trace::snapshot;
+use crate::Span;
+
+// The number of finished traces remembered to tell their late spans from those of running traces
+const FINISHED_HISTORY: usize = 4096;
+
+/// A long-lived collector receiving spans of many traces, e.g. one per request, saving the
+/// construction of a channel per trace.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::{LocalSpan, SharedCollector};
+///
+/// let mut collector = SharedCollector::new();
+///
+/// for _ in 0..3 {
+///     let root_span = collector.start_root("request");
+///     let _guard = root_span.enter();
+///     let _local_guard = LocalSpan::enter("handle");
+/// }
+///
+/// let traces = collector.collect_grouped();
+/// assert_eq!(traces.len(), 3);
+/// assert!(traces.iter().all(|(_, spans)| spans.len() == 2));
+/// ```
+pub struct SharedCollector {
+    sender: Arc<SpanSender>,
+    receiver: Receiver<Submission>,
+    closed: Arc<AtomicBool>,
+
+    // Spans of traces whose root span hasn't finished yet, and when their first spans arrived
+    pending: HashMap<u128, (Instant, Vec<SpanCollection>)>,
+    finished: HashSet<u128>,
+    finished_order: VecDeque<u128>,
+
+    max_pending_age: Duration,
+    max_pending_traces: usize,
+    evicted_spans: u64,
+    correct_clock_skew: bool,
+}
+
+impl Default for SharedCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SharedCollector {
+    pub fn new() -> Self {
+        let (tx, rx) = crossbeam::channel::unbounded();
+        SharedCollector {
+            sender: Arc::new(SpanSender::new(tx, 0, Default::default())),
+            receiver: rx,
+            closed: Arc::new(AtomicBool::new(false)),
+            pending: HashMap::new(),
+            finished: HashSet::new(),
+            finished_order: VecDeque::new(),
+            max_pending_age: Duration::from_secs(60),
+            max_pending_traces: 4096,
+            evicted_spans: 0,
+            correct_clock_skew: false,
+        }
+    }
+
+    /// Drop the spans of the traces kept unfinished for longer than `max_age`, e.g. those whose
+    /// root span never finishes or those arriving after their trace has been forgotten, and the
+    /// spans of the oldest traces past `max_traces` unfinished ones. They're counted by
+    /// [`evicted_spans`](SharedCollector::evicted_spans). Defaults to 60s and 4096 traces.
+    pub fn max_pending(mut self, max_age: Duration, max_traces: usize) -> Self {
+        self.max_pending_age = max_age;
+        self.max_pending_traces = max_traces;
+        self
+    }
+
+    /// Whether to correct the clock skew between threads like
+    /// [`CollectArgs::correct_clock_skew`](crate::CollectArgs::correct_clock_skew). Disabled by
+    /// default.
+    pub fn correct_clock_skew(mut self, correct_clock_skew: bool) -> Self {
+        self.correct_clock_skew = correct_clock_skew;
+        self
+    }
+
+    /// The number of spans dropped since their traces were kept unfinished for too long, see
+    /// [`max_pending`](SharedCollector::max_pending).
+    pub fn evicted_spans(&self) -> u64 {
+        self.evicted_spans
+    }
+
+    /// Start a new trace reporting to this collector.
+    pub fn start_root(&self, event: &'static str) -> Span {
+        self.start_root_with_trace_id(event, DefaultIdGenerator::next_trace_id())
+    }
+
+    /// Start a new trace identified by `trace_id` reporting to this collector.
+    pub fn start_root_with_trace_id(&self, event: &'static str, trace_id: u128) -> Span {
+        start_root(&self.sender, &self.closed, event, trace_id)
+    }
+
+    // Start the traces reporting to this collector without borrowing it
+    pub(crate) fn starter(&self) -> RootStarter {
+        RootStarter {
+            sender: self.sender.clone(),
+            closed: self.closed.clone(),
+        }
+    }
+
+    /// Return the spans received so far, grouped by trace id, for every trace whose root span
+    /// has finished. Spans of unfinished traces are kept until a later call.
+    ///
+    /// Spans finishing after their root span, e.g. those of detached tasks, are returned by a
+    /// later call under the same trace id, as long as the trace is among the last 4096 finished
+    /// ones.
+    pub fn collect_grouped(&mut self) -> Vec<(u128, Vec<crate::span::Span>)> {
+        let mut finished = Vec::new();
+        for (trace_id, span_collection) in self.receiver.try_iter() {
+            let is_root = matches!(&span_collection, SpanCollection::Span(s) if s.parent_id.0 == 0);
+            self.pending
+                .entry(trace_id)
+                .or_insert_with(|| (Instant::now(), Vec::new()))
+                .1
+                .push(span_collection);
+            if is_root || self.finished.contains(&trace_id) {
+                finished.push(trace_id);
+            }
+            if is_root && self.finished.insert(trace_id) {
+                self.finished_order.push_back(trace_id);
+                if self.finished_order.len() > FINISHED_HISTORY {
+                    let oldest = self.finished_order.pop_front().unwrap();
+                    self.finished.remove(&oldest);
+                }
+            }
+        }
+
+        let anchor = DefaultClock::anchor();
+        let correct_clock_skew = self.correct_clock_skew;
+        let traces = finished
+            .into_iter()
+            .filter_map(|trace_id| {
+                let (_, span_collections) = self.pending.remove(&trace_id)?;
+                Some((
+                    trace_id,
+                    Collector::amend(span_collections, anchor, correct_clock_skew),
+                ))
+            })
+            .collect();
+        self.evict();
+        traces
+    }
+
+    // Drop the traces kept unfinished for too long, and the oldest ones past the limit
+    fn evict(&mut self) {
+        let max_age = self.max_pending_age;
+        let mut evicted = Vec::new();
+        self.pending.retain(|_, (since, span_collections)| {
+            let keep = since.elapsed() <= max_age;
+            if !keep {
+                evicted.push(std::mem::take(span_collections));
+            }
+            keep
+        });
+        if self.pending.len() > self.max_pending_traces {
+            let mut oldest: Vec<(Instant, u128)> = self
+                .pending
+                .iter()
+                .map(|(trace_id, (since, _))| (*since, *trace_id))
+                .collect();
+            oldest.sort_unstable();
+            let excess = self.pending.len() - self.max_pending_traces;
+            for (_, trace_id) in oldest.into_iter().take(excess) {
+                evicted.extend(self.pending.remove(&trace_id).map(|(_, scs)| scs));
+            }
+        }
+        self.evicted_spans += evicted
+            .iter()
+            .flatten()
+            .map(acquirer::span_count)
+            .sum::<usize>() as u64;
+    }
+}
+
+impl Drop for SharedCollector {
+    fn drop(&mut self) {
+        acquirer::close(&self.closed);
+    }
+}
+pub(crate) struct RootStarter {
+    sender: Arc<SpanSender>,
+    closed: Arc<AtomicBool>,
+}
+
+impl RootStarter {
+    pub(crate) fn start_root_with_trace_id(&self, event: &'static str, trace_id: u128) -> Span {
+        start_root(&self.sender, &self.closed, event, trace_id)
+    }
+}
+
+fn start_root(
+    sender: &Arc<SpanSender>,
+    closed: &Arc<AtomicBool>,
+    event: &'static str,
+    trace_id: u128,
+) -> Span {
+    let acquirer = Acquirer::new(sender.clone(), closed.clone(), trace_id);
+    let span = Span::new(iter::once((SpanId::new(0), &acquirer)), event);
+    if snapshot::has_snapshots() {
+        span.with_properties(snapshot::capture)
+    } else {
+        span
+    }
+}
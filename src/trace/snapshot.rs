@@ -0,0 +1,53 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+type SnapshotFn = dyn Fn() -> String + Send + Sync;
+
+static HAS_SNAPSHOTS: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref SNAPSHOTS: RwLock<Vec<(&'static str, Arc<SnapshotFn>)>> = RwLock::new(Vec::new());
+}
+
+/// Register a callback capturing some dynamic state, e.g. the number of threads, the memory
+/// usage or the length of a task queue. It's called whenever a root span is created and its
+/// result is recorded as a property of the root span, giving context to why a trace was slow.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+///
+/// let pending_tasks = Arc::new(AtomicUsize::new(0));
+/// let pending = pending_tasks.clone();
+/// minitrace::register_snapshot("pending_tasks", move || {
+///     pending.load(Ordering::Relaxed).to_string()
+/// });
+/// ```
+pub fn register_snapshot<F>(key: &'static str, snapshot: F)
+where
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    SNAPSHOTS.write().unwrap().push((key, Arc::new(snapshot)));
+    HAS_SNAPSHOTS.store(true, Ordering::Relaxed);
+}
+
+/// Unregister all the callbacks registered by [`register_snapshot`](register_snapshot).
+pub fn clear_snapshots() {
+    SNAPSHOTS.write().unwrap().clear();
+    HAS_SNAPSHOTS.store(false, Ordering::Relaxed);
+}
+
+#[inline]
+pub(crate) fn has_snapshots() -> bool {
+    HAS_SNAPSHOTS.load(Ordering::Relaxed)
+}
+
+pub(crate) fn capture() -> Vec<(&'static str, String)> {
+    // Clone the callbacks out to call them without holding the lock.
+    let snapshots = SNAPSHOTS.read().unwrap().clone();
+    snapshots.iter().map(|(key, f)| (*key, f())).collect()
+}
@@ -1,14 +1,17 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
 use std::iter;
-use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::hooks::{self, RawSpanMeta};
 use crate::local::local_collector::LocalSpans;
-use crate::span::RawSpan;
-use crate::span::{DefaultClock, DefaultIdGenerator, SpanId};
-use crate::trace::acquirer::{Acquirer, SpanCollection};
-use crate::Collector;
+use crate::span::{Cycle, DefaultClock, DefaultIdGenerator, SpanId};
+use crate::span::{PropertyValue, RawSpan};
+use crate::trace::acquirer::{self, Acquirer, SpanCollection};
+use crate::trace::deadline::Deadline;
+use crate::trace::{pool, registry, sampler, snapshot};
+use crate::{semconv, stats, Collector};
 
 #[must_use]
 #[derive(Debug)]
@@ -22,6 +25,12 @@ pub(crate) struct SpanInner {
 
     // Report `RawSpan` to `Acquirer` when `SpanInner` is dropping
     pub(crate) to_report: Vec<(RawSpan, Acquirer)>,
+
+    // The close generation at which the acquirers were open
+    pub(crate) generation: usize,
+
+    #[cfg(feature = "exemplar")]
+    pub(crate) exemplar_histograms: crate::exemplar::ExemplarHistograms,
 }
 
 impl Span {
@@ -29,38 +38,202 @@ impl Span {
     pub(crate) fn new<'a>(
         acquirers: impl Iterator<Item = (SpanId, &'a Acquirer)>,
         event: &'static str,
+    ) -> Self {
+        let generation = acquirer::close_generation();
+        Self::new_with_generation(
+            acquirers.filter(|(_, acq)| !acq.is_shutdown()),
+            event,
+            generation,
+        )
+    }
+
+    // Create a span reporting to `acquirers`, known to be open at the close generation
+    // `generation`
+    #[inline]
+    pub(crate) fn new_with_generation<'a>(
+        acquirers: impl Iterator<Item = (SpanId, &'a Acquirer)>,
+        event: &'static str,
+        generation: usize,
     ) -> Self {
         let span_id = DefaultIdGenerator::next_id();
         let now = DefaultClock::now();
 
-        let mut to_report = Vec::new();
-        for (parent_span_id, acq) in acquirers {
-            if !acq.is_shutdown() {
-                to_report.push((
+        let to_report: Vec<_> = acquirers
+            .map(|(parent_span_id, acq)| {
+                (
                     RawSpan::begin_with(span_id, parent_span_id, now, event),
                     acq.clone(),
-                ))
-            }
-        }
+                )
+            })
+            .collect();
 
         if to_report.is_empty() {
             Self { inner: None }
         } else {
+            for (span, acq) in &to_report {
+                acq.track(span);
+            }
+            hooks::span_started(&RawSpanMeta {
+                id: span_id,
+                parent_id: to_report[0].0.parent_id,
+                event,
+                cycle: now,
+            });
             Self {
-                inner: Some(SpanInner { span_id, to_report }),
+                inner: Some(SpanInner {
+                    span_id,
+                    to_report,
+                    generation,
+                    #[cfg(feature = "exemplar")]
+                    exemplar_histograms: Default::default(),
+                }),
             }
         }
     }
 
     pub fn root(event: &'static str) -> (Self, Collector) {
-        let (tx, rx) = crossbeam::channel::unbounded();
-        let closed = Arc::new(AtomicBool::new(false));
-        let acquirer = Acquirer::new(Arc::new(tx), closed.clone());
-        let span = Self::new(iter::once((SpanId::new(0), &acquirer)), event);
-        let collector = Collector::new(rx, closed);
+        Self::root_with_trace_id(event, DefaultIdGenerator::next_trace_id())
+    }
+
+    /// Create a root span of the trace identified by `trace_id`.
+    ///
+    /// It's useful when the trace id has to be stable across retries, e.g. derived from a
+    /// connection id and a statement counter, so that the trace can be joined with logs by id.
+    pub fn root_with_trace_id(event: &'static str, trace_id: u128) -> (Self, Collector) {
+        Self::root_with_deadline_at(event, trace_id, None)
+    }
+
+    /// Create a root span whose trace lives at most `max_lifetime`, protecting the memory from
+    /// spans leaked by buggy code paths.
+    ///
+    /// Once the deadline passes, the next span submitted or a collector waiting or collecting
+    /// finishes the spans of the trace still running, with the property
+    /// [`deadline_exceeded`](semconv::DEADLINE_EXCEEDED) and without the properties added after
+    /// they started. The trace then accepts no more spans, and a synchronous collection returns.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    ///
+    /// use minitrace::{semconv, CollectArgs, Span};
+    ///
+    /// let (root_span, collector) = Span::root_with_deadline("request", Duration::from_millis(10));
+    /// // Leaked by a buggy code path
+    /// std::mem::forget(Span::from_parent("leaked", &root_span));
+    /// drop(root_span);
+    ///
+    /// // Returns at the deadline instead of waiting for the leaked span forever
+    /// let spans = collector.collect_with_args(CollectArgs::default().sync(true));
+    /// let leaked = spans.iter().find(|s| s.event == "leaked").unwrap();
+    /// assert!(leaked
+    ///     .properties
+    ///     .contains(&(semconv::DEADLINE_EXCEEDED, "true".into())));
+    /// ```
+    pub fn root_with_deadline(event: &'static str, max_lifetime: Duration) -> (Self, Collector) {
+        let deadline = Instant::now() + max_lifetime;
+        Self::root_with_deadline_at(event, DefaultIdGenerator::next_trace_id(), Some(deadline))
+    }
+
+    fn root_with_deadline_at(
+        event: &'static str,
+        trace_id: u128,
+        deadline: Option<Instant>,
+    ) -> (Self, Collector) {
+        let channel = pool::take();
+        let tx = Arc::new(channel.span_sender().with_deadline(Deadline::new(
+            deadline,
+            channel.closed.clone(),
+            trace_id,
+        )));
+        let registry_key = registry::register(trace_id, event, Arc::downgrade(&tx));
+        let closed = channel.closed.clone();
+        let mut collector = Collector::new(channel, Arc::downgrade(&tx), trace_id, registry_key);
+        if let Some(deadline) = deadline {
+            collector = collector.with_deadline(deadline);
+        }
+        let acquirer = Acquirer::new(tx, closed, trace_id);
+        let mut span = Self::new(iter::once((SpanId::new(0), &acquirer)), event);
+        if snapshot::has_snapshots() {
+            span = span.with_properties(snapshot::capture);
+        }
         (span, collector)
     }
 
+    /// Create a root span continuing the trace of a remote parent span, e.g. extracted from the
+    /// `traceparent` header of an incoming request, see
+    /// [`SpanContext`](crate::propagation::SpanContext).
+    ///
+    /// The trace gets the id of the remote trace, and the root span points at the remote parent
+    /// by the property [`remote.parent`](semconv::REMOTE_PARENT). Its parent id stays 0, the
+    /// remote parent is handed to reporters by
+    /// [`Collector::remote_parent_span_id`](Collector::remote_parent_span_id).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minitrace::propagation::SpanContext;
+    /// use minitrace::Span;
+    ///
+    /// let traceparent = "00-00000000000000004bf92f3577b34da6-00f067aa0ba902b7-01";
+    /// let context = SpanContext::from_traceparent(traceparent).unwrap();
+    ///
+    /// let (root_span, collector) = Span::root_with_context("rpc", context);
+    /// drop(root_span);
+    /// assert_eq!(collector.trace_id(), 0x4bf9_2f35_77b3_4da6);
+    /// assert_eq!(collector.remote_parent_span_id(), Some(0x00f0_67aa_0ba9_02b7));
+    /// ```
+    pub fn root_with_context(
+        event: &'static str,
+        context: crate::propagation::SpanContext,
+    ) -> (Self, Collector) {
+        let (span, collector) = Self::root_with_trace_id(event, context.trace_id);
+        (
+            span.with_property(|| semconv::remote_parent(context.span_id)),
+            collector.with_remote_parent(context.span_id),
+        )
+    }
+
+    /// Finish the root span of a trace too long to keep open, e.g. a DDL job running for hours,
+    /// and start a successor trace reporting to a new collector, so that a long operation can
+    /// be reported as a chain of traces.
+    ///
+    /// The root spans are linked both ways by the properties
+    /// [`link.successor`](semconv::LINK_SUCCESSOR) and
+    /// [`link.predecessor`](semconv::LINK_PREDECESSOR). The collector of the predecessor is
+    /// still to be collected.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minitrace::Span;
+    ///
+    /// let (span, collector) = Span::root("ddl job");
+    /// let (span, next_collector) = span.follow_up("ddl job");
+    /// let spans = collector.collect();
+    /// ```
+    pub fn follow_up(self, event: &'static str) -> (Self, Collector) {
+        let predecessor = self
+            .inner
+            .as_ref()
+            .map(|inner| inner.span_id)
+            .zip(self.trace_id());
+        let (successor, collector) = Self::root(event);
+
+        match (predecessor, &successor.inner) {
+            (Some((span_id, trace_id)), Some(inner)) => {
+                let successor_span_id = inner.span_id;
+                drop(self.with_property(|| {
+                    semconv::link_successor(collector.trace_id(), successor_span_id.0)
+                }));
+                let successor =
+                    successor.with_property(|| semconv::link_predecessor(trace_id, span_id.0));
+                (successor, collector)
+            }
+            _ => (successor, collector),
+        }
+    }
+
     #[inline]
     pub fn empty() -> Self {
         Self { inner: None }
@@ -71,6 +244,84 @@ impl Span {
         self.inner.is_none()
     }
 
+    // Whether any collector the span reports to is open, by a single atomic load unless a
+    // collector has closed since the span was created
+    #[inline]
+    pub(crate) fn is_open(&self) -> bool {
+        match &self.inner {
+            Some(inner) => {
+                inner.generation == acquirer::close_generation()
+                    || inner.to_report.iter().any(|(_, acq)| !acq.is_shutdown())
+            }
+            None => false,
+        }
+    }
+
+    /// The id of the trace which the span belongs to. If the span belongs to multiple traces,
+    /// the id of the first one is returned.
+    #[inline]
+    pub fn trace_id(&self) -> Option<u128> {
+        self.inner
+            .as_ref()
+            .and_then(|inner| inner.to_report.first())
+            .map(|(_, acq)| acq.trace_id())
+    }
+
+    /// A stable hash of the ids of the trace and the span, see
+    /// [`context_hash`](crate::context_hash). If the span belongs to multiple traces, the first
+    /// one is hashed.
+    #[inline]
+    pub fn context_hash(&self) -> Option<u64> {
+        let inner = self.inner.as_ref()?;
+        let (_, acq) = inner.to_report.first()?;
+        Some(sampler::context_hash(acq.trace_id(), inner.span_id.0))
+    }
+
+    // The event of the span, or an empty string for an empty span
+    #[inline]
+    pub(crate) fn event(&self) -> &'static str {
+        self.inner
+            .as_ref()
+            .and_then(|inner| inner.to_report.first())
+            .map(|(span, _)| span.event)
+            .unwrap_or_default()
+    }
+
+    /// The number of live handles to each collector the span reports to, as
+    /// `(trace id, references)` pairs. See [`Collector::live_references`].
+    pub fn references(&self) -> Vec<(u128, usize)> {
+        self.inner
+            .iter()
+            .flat_map(|inner| inner.to_report.iter())
+            .map(|(_, acq)| (acq.trace_id(), acq.references()))
+            .collect()
+    }
+
+    /// Describe the span and the collectors it reports to, for debugging.
+    pub fn dump(&self) -> String {
+        use std::fmt::Write;
+
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return "Span(empty)".to_owned(),
+        };
+
+        let mut res = format!("Span(id: {})", inner.span_id.0);
+        for (span, acq) in &inner.to_report {
+            write!(
+                &mut res,
+                "\n  event: {:?}, parent id: {}, trace id: {}, shutdown: {}, references: {}",
+                span.event,
+                span.parent_id.0,
+                acq.trace_id(),
+                acq.is_shutdown(),
+                acq.references()
+            )
+            .ok();
+        }
+        res
+    }
+
     #[inline]
     pub fn from_parent(event: &'static str, span: &Span) -> Self {
         Self::from_parents(event, iter::once(span))
@@ -95,6 +346,123 @@ impl Span {
         )
     }
 
+    #[inline]
+    pub fn with_properties<I: IntoIterator<Item = (&'static str, String)>, F: FnOnce() -> I>(
+        mut self,
+        properties: F,
+    ) -> Self {
+        if let Some(inner) = &mut self.inner {
+            let properties: Vec<_> = properties()
+                .into_iter()
+                .map(|(k, v)| (k, PropertyValue::from(v)))
+                .collect();
+            for (span, _) in &mut inner.to_report {
+                span.properties.extend(properties.iter().cloned());
+            }
+        }
+        self
+    }
+
+    #[inline]
+    pub fn with_property<F: FnOnce() -> (&'static str, String)>(mut self, property: F) -> Self {
+        self.add_property(property);
+        self
+    }
+
+    /// Like [`with_property`](Span::with_property), but for a span held by reference, e.g. by
+    /// a request passed along the stages of a pipeline running on different threads, each
+    /// adding what it learns before the last one finishes the span.
+    #[inline]
+    pub fn add_property<F: FnOnce() -> (&'static str, String)>(&mut self, property: F) {
+        if self.inner.is_some() {
+            let (key, value) = property();
+            self.add_typed_property(key, value);
+        }
+    }
+
+    /// Add a property holding bytes, which are shared instead of copied until a reporter
+    /// serializes them.
+    #[inline]
+    pub fn with_binary_property(self, key: &'static str, value: impl Into<Arc<[u8]>>) -> Self {
+        self.with_typed_property(key, PropertyValue::Binary(value.into()))
+    }
+
+    /// Add a property keeping the type of its value, e.g. `i64`, so that reporters supporting
+    /// typed values don't report it as a string.
+    #[inline]
+    pub fn with_typed_property(
+        mut self,
+        key: &'static str,
+        value: impl Into<PropertyValue>,
+    ) -> Self {
+        self.add_typed_property(key, value);
+        self
+    }
+
+    /// Like [`with_typed_property`](Span::with_typed_property), but for a span held by
+    /// reference.
+    #[inline]
+    pub fn add_typed_property(&mut self, key: &'static str, value: impl Into<PropertyValue>) {
+        if let Some(inner) = &mut self.inner {
+            let value = value.into();
+            for (span, _) in &mut inner.to_report {
+                span.properties.push((key, value.clone()));
+            }
+        }
+    }
+
+    /// Finish the span now. A span is otherwise finished when dropped, on whichever thread
+    /// holds it at that time.
+    #[inline]
+    pub fn finish(self) {}
+
+    /// Record pre-measured intervals, e.g. the latency of each key of a scan, as children of the
+    /// span with the same event, sent to the collectors as a single batch. The intervals are
+    /// measured by [`DefaultClock::now`](crate::span::DefaultClock::now).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minitrace::span::DefaultClock;
+    /// use minitrace::Span;
+    ///
+    /// let (root_span, collector) = Span::root("scan");
+    /// let mut latencies = Vec::new();
+    /// for _key in 0..3 {
+    ///     let begin = DefaultClock::now();
+    ///     // ... read the key
+    ///     latencies.push((begin, DefaultClock::now()));
+    /// }
+    /// root_span.record_batch("get", &latencies);
+    /// drop(root_span);
+    ///
+    /// assert_eq!(collector.collect().len(), 4);
+    /// ```
+    pub fn record_batch(&self, event: &'static str, intervals: &[(Cycle, Cycle)]) {
+        if self.inner.is_none() || intervals.is_empty() {
+            return;
+        }
+
+        let spans = intervals
+            .iter()
+            .map(|&(begin, end)| {
+                let mut span = RawSpan::begin_with(
+                    DefaultIdGenerator::next_id(),
+                    SpanId::new(0),
+                    begin,
+                    event,
+                );
+                span.end_with(end);
+                span
+            })
+            .collect();
+        self.mount_local_spans(Arc::new(LocalSpans {
+            spans,
+            end_time: DefaultClock::now(),
+            events: Vec::new(),
+        }));
+    }
+
     #[inline]
     pub fn mount_local_spans(&self, local_spans: Arc<LocalSpans>) {
         if let Some(inner) = &self.inner {
@@ -111,6 +479,21 @@ impl Span {
 impl Drop for SpanInner {
     fn drop(&mut self) {
         let now = DefaultClock::now();
+
+        #[cfg(feature = "exemplar")]
+        self.exemplar_histograms
+            .observe(self.span_id, &self.to_report, now);
+
+        if let Some((span, _)) = self.to_report.first() {
+            hooks::span_ended(&RawSpanMeta {
+                id: self.span_id,
+                parent_id: span.parent_id,
+                event: span.event,
+                cycle: now,
+            });
+            stats::observe(span.event, span.begin_cycle, now);
+        }
+
         for (mut span, collector) in self.to_report.drain(..) {
             span.end_with(now);
             collector.submit(SpanCollection::Span(span))
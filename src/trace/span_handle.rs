@@ -0,0 +1,91 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::sync::Arc;
+
+use crate::span::SpanId;
+use crate::trace::acquirer::WeakAcquirer;
+use crate::Span;
+
+/// A cheap, clonable reference to a [`Span`] to start its children from, e.g. in the tasks
+/// spawned by the owner of the span, see [`Span::handle`].
+///
+/// Unlike the span, a handle doesn't own anything: the span still finishes when its owner drops
+/// it, and a handle doesn't keep the trace open. The children started from a handle after the
+/// trace is complete are empty.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::{CollectArgs, Span};
+///
+/// let (root_span, collector) = Span::root("request");
+/// let handle = root_span.handle();
+///
+/// let workers: Vec<_> = (0..2)
+///     .map(|_| {
+///         let handle = handle.clone();
+///         std::thread::spawn(move || {
+///             let _span = Span::from_handle("worker", &handle);
+///         })
+///     })
+///     .collect();
+/// for worker in workers {
+///     worker.join().unwrap();
+/// }
+///
+/// drop(root_span);
+/// // The handle left doesn't keep the collection waiting.
+/// let spans = collector.collect_with_args(CollectArgs::default().sync(true));
+/// assert_eq!(spans.len(), 3);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SpanHandle {
+    inner: Option<Arc<HandleInner>>,
+}
+
+#[derive(Debug)]
+struct HandleInner {
+    span_id: SpanId,
+    acquirers: Vec<WeakAcquirer>,
+}
+
+impl SpanHandle {
+    /// Whether the children started from the handle are empty since the span is.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_none()
+    }
+}
+
+impl Span {
+    /// A handle to start the children of the span from without owning it, see
+    /// [`SpanHandle`](SpanHandle).
+    pub fn handle(&self) -> SpanHandle {
+        let inner = self.inner.as_ref().map(|inner| {
+            Arc::new(HandleInner {
+                span_id: inner.span_id,
+                acquirers: inner
+                    .to_report
+                    .iter()
+                    .map(|(_, acq)| acq.downgrade())
+                    .collect(),
+            })
+        });
+        SpanHandle { inner }
+    }
+
+    /// Start a child of the span referred to by `handle`. The child is empty if the trace is
+    /// complete.
+    pub fn from_handle(event: &'static str, handle: &SpanHandle) -> Self {
+        let inner = match &handle.inner {
+            Some(inner) => inner,
+            None => return Span::empty(),
+        };
+        let acquirers: Vec<_> = inner
+            .acquirers
+            .iter()
+            .filter_map(|acq| acq.upgrade())
+            .collect();
+        Self::new(acquirers.iter().map(|acq| (inner.span_id, acq)), event)
+    }
+}
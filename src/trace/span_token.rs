@@ -0,0 +1,60 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashMap;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::Span;
+
+// Starting from 1, never reused, so that a stale token can't take another span
+static NEXT_TOKEN: AtomicUsize = AtomicUsize::new(1);
+
+lazy_static! {
+    static ref SPANS: Mutex<HashMap<usize, Span>> = Mutex::new(HashMap::new());
+}
+
+/// The opaque target of the pointers returned by [`Span::into_raw`], to pass a span through the
+/// `void *` userdata of a C callback. It's never dereferenced.
+pub enum SpanToken {}
+
+impl Span {
+    /// Park the span and return a token to take it back with [`from_raw`](Span::from_raw), e.g.
+    /// in a C callback receiving the token as its userdata. An empty span is parked as null.
+    ///
+    /// The span isn't finished until it's taken back, so a token never taken back leaks it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::os::raw::c_void;
+    /// use minitrace::Span;
+    ///
+    /// extern "C" fn on_compaction(userdata: *mut c_void) {
+    ///     let span = Span::from_raw(userdata.cast()).unwrap();
+    ///     let _guard = span.enter();
+    /// }
+    ///
+    /// let (root_span, collector) = Span::root("compaction");
+    /// on_compaction(root_span.into_raw().cast());
+    /// assert_eq!(collector.collect().len(), 1);
+    /// ```
+    pub fn into_raw(self) -> *mut SpanToken {
+        if self.is_empty() {
+            return ptr::null_mut();
+        }
+        let token = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed);
+        SPANS.lock().unwrap().insert(token, self);
+        token as *mut SpanToken
+    }
+
+    /// Take back the span parked by [`into_raw`](Span::into_raw). Returns an empty span for a
+    /// null token and `None` for a token taken back already or not returned by `into_raw`,
+    /// instead of a double free.
+    pub fn from_raw(token: *mut SpanToken) -> Option<Span> {
+        if token.is_null() {
+            return Some(Span::empty());
+        }
+        SPANS.lock().unwrap().remove(&(token as usize))
+    }
+}
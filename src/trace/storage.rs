@@ -0,0 +1,387 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::span::{DictionaryBatch, IndexedSpan, PropertyValue, Span, SpanEvent};
+use crate::trace::interner::intern;
+
+/// A place to move the spans of a huge trace out of memory while it's running, see
+/// [`Collector::spill`](crate::Collector::spill).
+pub trait SpanStorage: Send {
+    /// Store a batch of spans.
+    fn append(&mut self, spans: Vec<Span>) -> io::Result<()>;
+
+    /// Take the oldest batch stored, or `None` if all have been taken.
+    fn take_batch(&mut self) -> io::Result<Option<Vec<Span>>>;
+
+    /// Stream the batches stored, oldest first, e.g. to report them one by one.
+    fn batches(&mut self) -> Batches<'_>
+    where
+        Self: Sized,
+    {
+        Batches { storage: self }
+    }
+}
+
+/// The iterator returned by [`SpanStorage::batches`].
+pub struct Batches<'a> {
+    storage: &'a mut dyn SpanStorage,
+}
+
+impl Iterator for Batches<'_> {
+    type Item = io::Result<Vec<Span>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.storage.take_batch().transpose()
+    }
+}
+
+/// A [`SpanStorage`] appending the spans to a file in a compact binary encoding, writing the
+/// event names and property keys of a batch once as a [`DictionaryBatch`]. The file is removed
+/// when the storage is dropped.
+///
+/// Event names and property keys read back are interned, leaking each distinct one once, up to a
+/// bound on the distinct names of the process past which reading fails.
+pub struct FileSpanStorage {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    reader: Option<BufReader<File>>,
+    batches: usize,
+}
+
+impl FileSpanStorage {
+    /// Create the storage at `path`, truncating the file if it exists.
+    pub fn new(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(FileSpanStorage {
+            path,
+            writer: BufWriter::new(file),
+            reader: None,
+            batches: 0,
+        })
+    }
+}
+
+impl SpanStorage for FileSpanStorage {
+    fn append(&mut self, spans: Vec<Span>) -> io::Result<()> {
+        write_batch(&mut self.writer, &DictionaryBatch::encode(&spans))?;
+        self.batches += 1;
+        Ok(())
+    }
+
+    fn take_batch(&mut self) -> io::Result<Option<Vec<Span>>> {
+        if self.batches == 0 {
+            return Ok(None);
+        }
+        self.writer.flush()?;
+        let r = match &mut self.reader {
+            Some(reader) => reader,
+            None => self.reader.insert(BufReader::new(File::open(&self.path)?)),
+        };
+
+        let spans = read_batch(r)?
+            .into_spans()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.batches -= 1;
+        Ok(Some(spans))
+    }
+}
+
+impl Drop for FileSpanStorage {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// The encoding of the spill files
+fn write_batch(w: &mut impl Write, batch: &DictionaryBatch) -> io::Result<()> {
+    for dictionary in &[&batch.events, &batch.property_keys] {
+        write_u32(w, dictionary.len() as u32)?;
+        for name in dictionary.iter() {
+            write_bytes(w, name.as_bytes())?;
+        }
+    }
+    write_u32(w, batch.spans.len() as u32)?;
+    for span in &batch.spans {
+        write_u32(w, span.id)?;
+        write_u32(w, span.parent_id)?;
+        write_u64(w, span.begin_unix_time_ns)?;
+        write_u64(w, span.duration_ns)?;
+        write_u32(w, span.event)?;
+        write_u32(w, span.properties.len() as u32)?;
+        for (key, value) in &span.properties {
+            write_u32(w, *key)?;
+            write_value(w, value)?;
+        }
+        write_u32(w, span.events.len() as u32)?;
+        for (unix_time_ns, name) in &span.events {
+            write_u64(w, *unix_time_ns)?;
+            write_u32(w, *name)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_batch(r: &mut impl Read) -> io::Result<DictionaryBatch> {
+    let mut dictionaries = [Vec::new(), Vec::new()];
+    for dictionary in &mut dictionaries {
+        let len = read_u32(r)? as usize;
+        dictionary.reserve(len.min(1024));
+        for _ in 0..len {
+            dictionary.push(read_name(r)?);
+        }
+    }
+    let [events, property_keys] = dictionaries;
+
+    let len = read_u32(r)? as usize;
+    let mut spans = Vec::with_capacity(len.min(1024));
+    for _ in 0..len {
+        let id = read_u32(r)?;
+        let parent_id = read_u32(r)?;
+        let begin_unix_time_ns = read_u64(r)?;
+        let duration_ns = read_u64(r)?;
+        let event = read_u32(r)?;
+        let properties_len = read_u32(r)? as usize;
+        let mut properties = Vec::with_capacity(properties_len.min(64));
+        for _ in 0..properties_len {
+            let key = read_u32(r)?;
+            properties.push((key, read_value(r)?));
+        }
+        let events_len = read_u32(r)? as usize;
+        let mut events = Vec::with_capacity(events_len.min(64));
+        for _ in 0..events_len {
+            let unix_time_ns = read_u64(r)?;
+            events.push((unix_time_ns, read_u32(r)?));
+        }
+        spans.push(IndexedSpan {
+            id,
+            parent_id,
+            begin_unix_time_ns,
+            duration_ns,
+            event,
+            properties,
+            events,
+        });
+    }
+
+    Ok(DictionaryBatch {
+        events,
+        property_keys,
+        spans,
+    })
+}
+
+// The binary encoding of a batch of spans used by the agent wire format, whose older versions
+// have no events
+pub(crate) fn write_spans(w: &mut impl Write, spans: &[Span], with_events: bool) -> io::Result<()> {
+    write_u32(w, spans.len() as u32)?;
+    for span in spans {
+        write_u32(w, span.id)?;
+        write_u32(w, span.parent_id)?;
+        write_u64(w, span.begin_unix_time_ns)?;
+        write_u64(w, span.duration_ns)?;
+        write_bytes(w, span.event.as_bytes())?;
+        write_u32(w, span.properties.len() as u32)?;
+        for (key, value) in &span.properties {
+            write_bytes(w, key.as_bytes())?;
+            write_value(w, value)?;
+        }
+        if with_events {
+            write_u32(w, span.events.len() as u32)?;
+            for event in &span.events {
+                write_u64(w, event.unix_time_ns)?;
+                write_bytes(w, event.name.as_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn write_value(w: &mut impl Write, value: &PropertyValue) -> io::Result<()> {
+    match value {
+        PropertyValue::String(s) => {
+            w.write_all(&[0])?;
+            write_bytes(w, s.as_bytes())
+        }
+        PropertyValue::I64(v) => {
+            w.write_all(&[1])?;
+            write_u64(w, *v as u64)
+        }
+        PropertyValue::F64(v) => {
+            w.write_all(&[2])?;
+            write_u64(w, v.to_bits())
+        }
+        PropertyValue::Bool(v) => w.write_all(&[3, *v as u8]),
+        PropertyValue::Binary(b) => {
+            w.write_all(&[4])?;
+            write_bytes(w, b)
+        }
+    }
+}
+
+pub(crate) fn read_spans(r: &mut impl Read, with_events: bool) -> io::Result<Vec<Span>> {
+    // Capacities are bounded since the lengths may come from an untrusted source
+    let len = read_u32(r)? as usize;
+    let mut spans = Vec::with_capacity(len.min(1024));
+    for _ in 0..len {
+        let id = read_u32(r)?;
+        let parent_id = read_u32(r)?;
+        let begin_unix_time_ns = read_u64(r)?;
+        let duration_ns = read_u64(r)?;
+        let event = read_name(r)?;
+        let properties_len = read_u32(r)? as usize;
+        let mut properties = Vec::with_capacity(properties_len.min(64));
+        for _ in 0..properties_len {
+            let key = read_name(r)?;
+            properties.push((key, read_value(r)?));
+        }
+        let mut events = Vec::new();
+        if with_events {
+            let events_len = read_u32(r)? as usize;
+            events.reserve(events_len.min(64));
+            for _ in 0..events_len {
+                let unix_time_ns = read_u64(r)?;
+                let name = read_name(r)?;
+                events.push(SpanEvent { unix_time_ns, name });
+            }
+        }
+        spans.push(Span {
+            id,
+            parent_id,
+            begin_unix_time_ns,
+            duration_ns,
+            event,
+            properties,
+            events,
+        });
+    }
+    Ok(spans)
+}
+
+pub(crate) fn read_value(r: &mut impl Read) -> io::Result<PropertyValue> {
+    let mut tag = [0];
+    r.read_exact(&mut tag)?;
+    Ok(match tag[0] {
+        0 => PropertyValue::from(into_string(read_bytes(r)?)?),
+        1 => PropertyValue::I64(read_u64(r)? as i64),
+        2 => PropertyValue::F64(f64::from_bits(read_u64(r)?)),
+        3 => {
+            let mut v = [0];
+            r.read_exact(&mut v)?;
+            PropertyValue::Bool(v[0] != 0)
+        }
+        4 => PropertyValue::Binary(Arc::from(read_bytes(r)?)),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad value")),
+    })
+}
+
+pub(crate) fn write_u32(w: &mut impl Write, v: u32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+pub(crate) fn write_u64(w: &mut impl Write, v: u64) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+pub(crate) fn write_bytes(w: &mut impl Write, b: &[u8]) -> io::Result<()> {
+    write_u32(w, b.len() as u32)?;
+    w.write_all(b)
+}
+
+pub(crate) fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+pub(crate) fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut b = [0; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+fn read_bytes(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = read_u32(r)? as u64;
+    let mut b = Vec::new();
+    r.take(len).read_to_end(&mut b)?;
+    if (b.len() as u64) < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(b)
+}
+
+fn into_string(b: Vec<u8>) -> io::Result<String> {
+    String::from_utf8(b).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+pub(crate) fn read_name(r: &mut impl Read) -> io::Result<&'static str> {
+    intern(into_string(read_bytes(r)?)?)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "too many distinct names"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::collector::CollectArgs;
+    use crate::{Collector, LocalSpan};
+
+    #[test]
+    fn spill() {
+        let path = std::env::temp_dir().join(format!("minitrace-spill-{}", std::process::id()));
+        let mut storage = FileSpanStorage::new(&path).unwrap();
+
+        let (root_span, mut collector): (_, Collector) = crate::Span::root("job");
+        for chunk in 0..3 {
+            let span = crate::Span::from_parent("chunk", &root_span)
+                .with_typed_property("chunk", chunk as i64)
+                .with_binary_property("digest", vec![1u8, 2]);
+            let _g = span.enter();
+            let _l = LocalSpan::enter("upload");
+            drop(_l);
+            drop(_g);
+            drop(span);
+            assert_eq!(collector.spill(&mut storage).unwrap(), 2);
+        }
+        drop(root_span);
+        collector
+            .collect_into(CollectArgs::default().sync(true), &mut storage)
+            .unwrap();
+
+        let batches: Vec<_> = storage.batches().map(Result::unwrap).collect();
+        assert_eq!(batches.len(), 4);
+        let chunk = batches[1].iter().find(|s| s.event == "chunk").unwrap();
+        assert!(chunk.properties.contains(&("chunk", PropertyValue::I64(1))));
+        assert_eq!(batches[3][0].event, "job");
+        assert!(storage.take_batch().unwrap().is_none());
+
+        drop(storage);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn corrupted_batch() {
+        // A span referencing an event name the batch doesn't have
+        let batch = DictionaryBatch {
+            events: vec!["get"],
+            spans: vec![IndexedSpan {
+                event: 1,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        write_batch(&mut buf, &batch).unwrap();
+
+        let read = read_batch(&mut buf.as_slice()).unwrap();
+        assert_eq!(read, batch);
+        assert!(read.into_spans().is_err());
+    }
+}
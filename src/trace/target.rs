@@ -0,0 +1,158 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::config;
+use crate::future::InLocalSpan;
+use crate::semconv;
+use crate::span::DefaultIdGenerator;
+use crate::trace::sampler::unsampled_collector;
+use crate::{Collector, LocalSpan, LocalSpanGuard, Span};
+
+// Bumped by `set_config`, expiring whether each target is enabled as cached by the call sites.
+// Starts at 1, so that 0 means not cached yet.
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+pub(crate) fn invalidate() {
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// Which targets of the functions instrumented by `#[trace(target = "...")]` and
+/// `#[trace_async(target = "...")]` are traced, see
+/// [`Config::target_filter`](crate::Config::target_filter). Functions without a target are
+/// always traced.
+///
+/// A rule on a target applies to the targets below it as well, e.g. `raftstore` to
+/// `raftstore::apply`, and the most specific rule wins.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::TargetFilter;
+///
+/// let filter = TargetFilter::none()
+///     .enable("raftstore")
+///     .disable("raftstore::apply");
+/// assert!(filter.is_enabled("raftstore::store"));
+/// assert!(!filter.is_enabled("raftstore::apply"));
+/// assert!(!filter.is_enabled("coprocessor"));
+/// ```
+#[derive(Clone, Debug)]
+pub struct TargetFilter {
+    default: bool,
+    rules: Vec<(String, bool)>,
+}
+
+impl Default for TargetFilter {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl TargetFilter {
+    /// Trace every target but the disabled ones.
+    pub fn all() -> Self {
+        TargetFilter {
+            default: true,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Trace no target but the enabled ones, e.g. to trace a single subsystem.
+    pub fn none() -> Self {
+        TargetFilter {
+            default: false,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn enable(mut self, target: impl Into<String>) -> Self {
+        self.rules.push((target.into(), true));
+        self
+    }
+
+    pub fn disable(mut self, target: impl Into<String>) -> Self {
+        self.rules.push((target.into(), false));
+        self
+    }
+
+    pub fn is_enabled(&self, target: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|(rule, _)| {
+                target == rule
+                    || (target.starts_with(rule.as_str()) && target[rule.len()..].starts_with("::"))
+            })
+            .max_by_key(|(rule, _)| rule.len())
+            .map_or(self.default, |(_, enabled)| *enabled)
+    }
+}
+
+// Called by `#[trace]` and `#[trace_async]` with a target, one per instrumented function
+#[doc(hidden)]
+pub struct Target {
+    name: &'static str,
+    // The generation it was cached in, shifted left by one, and whether the target is enabled
+    cached: AtomicU64,
+}
+
+impl Target {
+    pub const fn new(name: &'static str) -> Self {
+        Target {
+            name,
+            cached: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        let generation = GENERATION.load(Ordering::Acquire);
+        let cached = self.cached.load(Ordering::Relaxed);
+        if cached >> 1 == generation {
+            return cached & 1 == 1;
+        }
+        self.refresh(generation)
+    }
+
+    #[cold]
+    fn refresh(&self, generation: u64) -> bool {
+        let enabled = match config().target_filter {
+            Some(filter) => filter.is_enabled(self.name),
+            None => true,
+        };
+        self.cached
+            .store(generation << 1 | enabled as u64, Ordering::Relaxed);
+        enabled
+    }
+
+    #[inline]
+    pub fn local_span(&self, event: &'static str) -> Option<LocalSpanGuard> {
+        if self.enabled() {
+            Some(LocalSpan::enter(event).with_static_property(semconv::TARGET, self.name))
+        } else {
+            None
+        }
+    }
+
+    /// A root span, or an empty one with a collector returning no span if the target is
+    /// disabled.
+    #[inline]
+    pub fn root(&self, event: &'static str) -> (Span, Collector) {
+        if self.enabled() {
+            let (span, collector) = Span::root(event);
+            (
+                span.with_typed_property(semconv::TARGET, self.name),
+                collector,
+            )
+        } else {
+            let trace_id = DefaultIdGenerator::next_trace_id();
+            (Span::empty(), unsampled_collector(trace_id))
+        }
+    }
+
+    #[inline]
+    pub fn in_local_span<T>(&self, inner: T, event: &'static str) -> InLocalSpan<T> {
+        let event = if self.enabled() { Some(event) } else { None };
+        InLocalSpan::new(inner, event, Some(self.name))
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::span::Span;
+
+/// The spans of a trace indexed by their events, for looking up the spans of an event
+/// repeatedly without scanning all the spans, e.g. in exporters or assertions on huge traces.
+///
+/// The spans are grouped by event, in the order the events first appear, and keep their order
+/// within each event. With the `serde` feature, it's serialized as its spans.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::{CollectArgs, LocalSpan, Span};
+///
+/// let (root_span, collector) = Span::root("root");
+/// {
+///     let _g = root_span.enter();
+///     for _ in 0..3 {
+///         let _l = LocalSpan::enter("get");
+///     }
+/// }
+/// drop(root_span);
+///
+/// let trace = collector.collect_indexed(CollectArgs::default());
+/// assert_eq!(trace.spans_by_event("get").len(), 3);
+/// assert_eq!(trace.spans_by_event("put").len(), 0);
+/// ```
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "Vec<Span>", into = "Vec<Span>")
+)]
+pub struct TraceResult {
+    spans: Vec<Span>,
+    events: HashMap<&'static str, Range<usize>>,
+}
+
+impl TraceResult {
+    pub fn new(mut spans: Vec<Span>) -> Self {
+        let mut ranks: HashMap<&'static str, usize> = HashMap::new();
+        for span in &spans {
+            let rank = ranks.len();
+            ranks.entry(span.event).or_insert(rank);
+        }
+        // Stable, so the spans of an event keep their order
+        spans.sort_by_cached_key(|s| ranks[s.event]);
+
+        let mut events: HashMap<&'static str, Range<usize>> = HashMap::with_capacity(ranks.len());
+        for (i, span) in spans.iter().enumerate() {
+            events.entry(span.event).or_insert(i..i).end = i + 1;
+        }
+
+        TraceResult { spans, events }
+    }
+
+    /// The spans of the event, or none if there is no such event.
+    pub fn spans_by_event(&self, event: &str) -> &[Span] {
+        match self.events.get(event) {
+            Some(range) => &self.spans[range.clone()],
+            None => &[],
+        }
+    }
+
+    /// The events of the spans, in the order they first appear.
+    pub fn events(&self) -> impl Iterator<Item = &'static str> + '_ {
+        let mut events: Vec<_> = self.events.iter().collect();
+        events.sort_by_key(|(_, range)| range.start);
+        events.into_iter().map(|(event, _)| *event)
+    }
+
+    /// All the spans, grouped by event.
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    pub fn into_spans(self) -> Vec<Span> {
+        self.spans
+    }
+}
+
+impl From<Vec<Span>> for TraceResult {
+    fn from(spans: Vec<Span>) -> Self {
+        Self::new(spans)
+    }
+}
+
+impl From<TraceResult> for Vec<Span> {
+    fn from(result: TraceResult) -> Self {
+        result.spans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans_by_event() {
+        let spans = ["root", "get", "put", "get", "get"]
+            .iter()
+            .enumerate()
+            .map(|(i, event)| Span {
+                id: i as u32 + 1,
+                event,
+                ..Default::default()
+            })
+            .collect();
+
+        let trace = TraceResult::new(spans);
+        let ids = |event| {
+            trace
+                .spans_by_event(event)
+                .iter()
+                .map(|s| s.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids("get"), vec![2, 4, 5]);
+        assert_eq!(ids("root"), vec![1]);
+        assert_eq!(ids("scan"), Vec::<u32>::new());
+        assert_eq!(
+            trace.events().collect::<Vec<_>>(),
+            vec!["root", "get", "put"]
+        );
+        assert_eq!(trace.spans().len(), 5);
+    }
+}
@@ -0,0 +1,209 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use crate::semconv;
+use crate::span::{PropertyValue, Span};
+
+/// The way to cut a trace down to [`CollectArgs::max_spans`](crate::CollectArgs::max_spans).
+pub trait TruncationStrategy: fmt::Debug + Send + Sync {
+    /// Reduce `spans`, more than `max_spans`, to at most `max_spans`.
+    fn truncate(&self, spans: Vec<Span>, max_spans: usize) -> Vec<Span>;
+}
+
+/// Keep the spans beginning first. As a span begins after its parent, the kept spans form a
+/// tree, but the spans dropped are the ones at the end regardless of what they are.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeepFirst;
+
+impl TruncationStrategy for KeepFirst {
+    fn truncate(&self, mut spans: Vec<Span>, max_spans: usize) -> Vec<Span> {
+        spans.sort_by_key(|s| s.begin_unix_time_ns);
+        spans.truncate(max_spans);
+        spans
+    }
+}
+
+/// The default strategy, keeping the structure of the trace interpretable:
+///
+/// 1. Of the siblings with the same event, e.g. the spans of the requests sent in a loop, only
+///    the longest one is kept with its descendants. It carries the number of the siblings
+///    [`truncated.repeats`](semconv::TRUNCATED_REPEATS) and their total duration
+///    [`truncated.repeats_duration_ns`](semconv::TRUNCATED_REPEATS_DURATION_NS).
+/// 2. If it's still too large, the spans closest to the root are kept, with the number of
+///    the children dropped from each span as [`truncated.children`](semconv::TRUNCATED_CHILDREN).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CollapseRepeated;
+
+impl TruncationStrategy for CollapseRepeated {
+    fn truncate(&self, mut spans: Vec<Span>, max_spans: usize) -> Vec<Span> {
+        let ids: HashSet<u32> = spans.iter().map(|s| s.id).collect();
+        let mut roots = Vec::new();
+        let mut children: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, span) in spans.iter().enumerate() {
+            if ids.contains(&span.parent_id) {
+                children.entry(span.parent_id).or_default().push(i);
+            } else {
+                roots.push(i);
+            }
+        }
+
+        // Visit the spans breadth-first, entering only the representatives of repeated events
+        let mut visited = Vec::with_capacity(spans.len());
+        let mut queue: VecDeque<usize> = collapse(&mut spans, &roots).into();
+        while let Some(i) = queue.pop_front() {
+            visited.push(i);
+            if let Some(children) = children.get(&spans[i].id) {
+                queue.extend(collapse(&mut spans, children));
+            }
+        }
+
+        let mut keep = vec![false; spans.len()];
+        for &i in visited.iter().take(max_spans) {
+            keep[i] = true;
+        }
+        let index_of: HashMap<u32, usize> =
+            spans.iter().enumerate().map(|(i, s)| (s.id, i)).collect();
+        let mut dropped_children: HashMap<usize, i64> = HashMap::new();
+        for &i in visited.iter().skip(max_spans) {
+            if let Some(&parent) = index_of.get(&spans[i].parent_id) {
+                if keep[parent] {
+                    *dropped_children.entry(parent).or_default() += 1;
+                }
+            }
+        }
+        for (parent, count) in dropped_children {
+            spans[parent]
+                .properties
+                .push((semconv::TRUNCATED_CHILDREN, PropertyValue::I64(count)));
+        }
+
+        spans
+            .into_iter()
+            .zip(keep)
+            .filter_map(|(span, keep)| if keep { Some(span) } else { None })
+            .collect()
+    }
+}
+
+// Pick the longest of the siblings of each event, tagging it with the aggregate of the others
+fn collapse(spans: &mut [Span], siblings: &[usize]) -> Vec<usize> {
+    // The groups in the order their events first appear, indexed by event
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut index: HashMap<&'static str, usize> = HashMap::new();
+    for &i in siblings {
+        let g = *index.entry(spans[i].event).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[g].push(i);
+    }
+
+    groups
+        .into_iter()
+        .map(|group| {
+            let representative = *group.iter().max_by_key(|&&i| spans[i].duration_ns).unwrap();
+            if group.len() > 1 {
+                let total_duration_ns = group
+                    .iter()
+                    .map(|&i| spans[i].duration_ns)
+                    .fold(0u64, u64::saturating_add);
+                let properties = &mut spans[representative].properties;
+                properties.push((
+                    semconv::TRUNCATED_REPEATS,
+                    PropertyValue::I64(group.len() as i64),
+                ));
+                properties.push((
+                    semconv::TRUNCATED_REPEATS_DURATION_NS,
+                    PropertyValue::I64(total_duration_ns.min(i64::MAX as u64) as i64),
+                ));
+            }
+            representative
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(id: u32, parent_id: u32, event: &'static str, duration_ns: u64) -> Span {
+        Span {
+            id,
+            parent_id,
+            begin_unix_time_ns: id as u64,
+            duration_ns,
+            event,
+            ..Default::default()
+        }
+    }
+
+    fn property(span: &Span, key: &str) -> Option<i64> {
+        span.properties.iter().find_map(|(k, v)| match v {
+            PropertyValue::I64(v) if *k == key => Some(*v),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn collapse_repeated() {
+        // A root sending 10 requests, each decoding a response, then writing the result
+        let mut spans = vec![span(1, 0, "root", 100)];
+        for i in 0..10 {
+            spans.push(span(10 + i, 1, "get", 1 + i as u64));
+            spans.push(span(100 + i, 10 + i, "decode", 1));
+        }
+        spans.push(span(2, 1, "put", 1));
+
+        let truncated = CollapseRepeated.truncate(spans.clone(), 5);
+        let events: Vec<_> = truncated.iter().map(|s| (s.id, s.event)).collect();
+        assert_eq!(
+            events,
+            vec![(1, "root"), (19, "get"), (109, "decode"), (2, "put")]
+        );
+        assert_eq!(
+            property(&truncated[1], semconv::TRUNCATED_REPEATS),
+            Some(10)
+        );
+        assert_eq!(
+            property(&truncated[1], semconv::TRUNCATED_REPEATS_DURATION_NS),
+            Some(55)
+        );
+
+        // The decoding is the farthest from the root
+        let truncated = CollapseRepeated.truncate(spans, 3);
+        let events: Vec<_> = truncated.iter().map(|s| s.event).collect();
+        assert_eq!(events, vec!["root", "get", "put"]);
+        assert_eq!(
+            property(&truncated[1], semconv::TRUNCATED_CHILDREN),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn collapse_long_repeats() {
+        let spans = vec![
+            span(1, 0, "root", u64::MAX),
+            span(2, 1, "get", u64::MAX),
+            span(3, 1, "get", u64::MAX),
+        ];
+
+        let truncated = CollapseRepeated.truncate(spans, 2);
+        assert_eq!(
+            property(&truncated[1], semconv::TRUNCATED_REPEATS_DURATION_NS),
+            Some(i64::MAX)
+        );
+    }
+
+    #[test]
+    fn keep_first() {
+        let spans = vec![
+            span(3, 1, "b", 1),
+            span(1, 0, "root", 10),
+            span(2, 1, "a", 1),
+        ];
+        let ids: Vec<_> = KeepFirst.truncate(spans, 2).iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+    }
+}
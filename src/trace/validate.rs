@@ -0,0 +1,209 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::span::Span;
+
+/// A broken invariant of the spans of a trace, found by [`validate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// More than one span has the id.
+    DuplicateId { id: u32 },
+    /// The spans are ancestors of themselves, each the parent of the next and the last the
+    /// parent of the first.
+    Cycle { ids: Vec<u32> },
+    /// No span is the root, i.e. has no parent among the spans.
+    NoRoot,
+    /// More than one span is a root, e.g. in a forest collected by
+    /// [`collect_forest`](crate::Collector::collect_forest).
+    MultipleRoots { ids: Vec<u32> },
+    /// The span begins before or ends after its parent, e.g. a span spawned on another thread
+    /// outliving its parent.
+    OutsideParent { id: u32, parent_id: u32 },
+}
+
+impl Violation {
+    // Whether it's a bug of the code producing the spans rather than a shape traces may take
+    pub(crate) fn is_structural(&self) -> bool {
+        matches!(
+            self,
+            Violation::DuplicateId { .. } | Violation::Cycle { .. }
+        )
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::DuplicateId { id } => write!(f, "duplicate span id {}", id),
+            Violation::Cycle { ids } => write!(f, "spans {:?} are ancestors of themselves", ids),
+            Violation::NoRoot => f.write_str("no root span"),
+            Violation::MultipleRoots { ids } => write!(f, "multiple root spans {:?}", ids),
+            Violation::OutsideParent { id, parent_id } => write!(
+                f,
+                "span {} is outside the interval of its parent {}",
+                id, parent_id
+            ),
+        }
+    }
+}
+
+/// Check the invariants of the spans of a trace: the ids are unique, there is a single root
+/// span, no span is its own ancestor, and each span is within the interval of its parent.
+///
+/// Useful to test custom [`TruncationStrategy`](crate::TruncationStrategy)s, or code merging
+/// spans, e.g. from several [`Collector`](crate::Collector)s.
+///
+/// # Examples
+///
+/// ```rust
+/// use minitrace::{LocalSpan, Span, Violation};
+///
+/// let (root_span, collector) = Span::root("root");
+/// {
+///     let _g = root_span.enter();
+///     let _l = LocalSpan::enter("child");
+/// }
+/// drop(root_span);
+///
+/// let spans = collector.collect();
+/// assert_eq!(minitrace::validate(&spans), vec![]);
+///
+/// let twice: Vec<_> = spans.iter().chain(&spans).cloned().collect();
+/// assert!(minitrace::validate(&twice).contains(&Violation::DuplicateId { id: spans[0].id }));
+/// ```
+pub fn validate(spans: &[Span]) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    if spans.is_empty() {
+        return violations;
+    }
+
+    // The first span of each id
+    let mut index: HashMap<u32, usize> = HashMap::with_capacity(spans.len());
+    for (i, span) in spans.iter().enumerate() {
+        match index.entry(span.id) {
+            Entry::Vacant(entry) => {
+                entry.insert(i);
+            }
+            Entry::Occupied(_) => violations.push(Violation::DuplicateId { id: span.id }),
+        }
+    }
+    let parent_of = |i: usize| -> Option<usize> {
+        match spans[i].parent_id {
+            0 => None,
+            parent_id => index.get(&parent_id).copied(),
+        }
+    };
+
+    let roots: Vec<u32> = (0..spans.len())
+        .filter(|&i| parent_of(i).is_none())
+        .map(|i| spans[i].id)
+        .collect();
+    match roots.len() {
+        0 => violations.push(Violation::NoRoot),
+        1 => {}
+        _ => violations.push(Violation::MultipleRoots { ids: roots }),
+    }
+
+    // Follow the parents of each span, finding cycles where the path meets itself
+    const UNVISITED: u8 = 0;
+    const ON_PATH: u8 = 1;
+    const VISITED: u8 = 2;
+    let mut state = vec![UNVISITED; spans.len()];
+    let mut path = Vec::new();
+    for i in 0..spans.len() {
+        let mut next = Some(i);
+        while let Some(j) = next {
+            match state[j] {
+                UNVISITED => {
+                    state[j] = ON_PATH;
+                    path.push(j);
+                    next = parent_of(j);
+                }
+                ON_PATH => {
+                    let start = path.iter().position(|&k| k == j).unwrap();
+                    let ids = path[start..].iter().map(|&k| spans[k].id).collect();
+                    violations.push(Violation::Cycle { ids });
+                    break;
+                }
+                _ => break,
+            }
+        }
+        for j in path.drain(..) {
+            state[j] = VISITED;
+        }
+    }
+
+    for (i, span) in spans.iter().enumerate() {
+        if let Some(parent) = parent_of(i) {
+            let parent = &spans[parent];
+            let end = span.begin_unix_time_ns.saturating_add(span.duration_ns);
+            let parent_end = parent.begin_unix_time_ns.saturating_add(parent.duration_ns);
+            if span.begin_unix_time_ns < parent.begin_unix_time_ns || end > parent_end {
+                violations.push(Violation::OutsideParent {
+                    id: span.id,
+                    parent_id: parent.id,
+                });
+            }
+        }
+    }
+
+    violations
+}
+
+// Catch the spans no trace should have, in debug builds
+#[inline]
+pub(crate) fn debug_validate(spans: &[Span]) {
+    if cfg!(debug_assertions) {
+        let violation = validate(spans).into_iter().find(Violation::is_structural);
+        if let Some(violation) = violation {
+            panic!("invalid spans collected: {}", violation);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(id: u32, parent_id: u32, begin: u64, duration: u64) -> Span {
+        Span {
+            id,
+            parent_id,
+            begin_unix_time_ns: begin,
+            duration_ns: duration,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn violations() {
+        let tree = vec![span(1, 0, 0, 10), span(2, 1, 1, 5), span(3, 2, 2, 1)];
+        assert_eq!(validate(&tree), vec![]);
+        assert_eq!(validate(&[]), vec![]);
+
+        assert_eq!(
+            validate(&[span(1, 0, 0, 10), span(2, 1, 5, 10), span(3, 0, 0, 1)]),
+            vec![
+                Violation::MultipleRoots { ids: vec![1, 3] },
+                Violation::OutsideParent {
+                    id: 2,
+                    parent_id: 1
+                },
+            ]
+        );
+        assert_eq!(
+            validate(&[span(1, 3, 0, 0), span(2, 1, 0, 0), span(3, 2, 0, 0)]),
+            vec![Violation::NoRoot, Violation::Cycle { ids: vec![1, 3, 2] }]
+        );
+        assert_eq!(
+            validate(&[span(1, 0, 0, 0), span(2, 2, 0, 0), span(2, 1, 0, 0)]),
+            vec![
+                Violation::DuplicateId { id: 2 },
+                Violation::Cycle { ids: vec![2] }
+            ]
+        );
+    }
+}
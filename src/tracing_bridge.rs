@@ -0,0 +1,221 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Record the spans of the [`tracing`](https://docs.rs/tracing) ecosystem, e.g. those of hyper,
+//! tonic or raft-rs, as minitrace spans, so that the work done inside dependencies shows up in
+//! the traces instead of leaving unexplained gaps. Enabled by the `tracing` feature.
+//!
+//! A `tracing` span becomes a child of the minitrace span of its `tracing` parent, or, at the
+//! top, of the span attached to the current thread by [`Span::enter`](crate::Span::enter). It
+//! lasts until the `tracing` span is closed, and its fields become properties, keeping the types
+//! of integers, floats and booleans. A `tracing` event becomes an instant span.
+//!
+//! ```rust
+//! use minitrace::tracing_bridge::MinitraceLayer;
+//! use minitrace::Span;
+//! use tracing_subscriber::prelude::*;
+//!
+//! let subscriber = tracing_subscriber::registry().with(MinitraceLayer::new());
+//! let _default = tracing::subscriber::set_default(subscriber);
+//!
+//! let (root_span, collector) = Span::root("request");
+//! {
+//!     let _guard = root_span.enter();
+//!     // In a dependency
+//!     let _span = tracing::info_span!("handshake", peer = "10.0.0.1").entered();
+//! }
+//! drop(root_span);
+//!
+//! let spans = collector.collect();
+//! let handshake = spans.iter().find(|s| s.event == "handshake").unwrap();
+//! assert_eq!(handshake.properties[0].0, "peer");
+//! ```
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+use crate::span::PropertyValue;
+use crate::Span;
+
+/// A [`Layer`](tracing_subscriber::layer::Layer) recording `tracing` spans and events as
+/// minitrace spans.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MinitraceLayer {
+    _p: (),
+}
+
+impl MinitraceLayer {
+    pub fn new() -> Self {
+        MinitraceLayer::default()
+    }
+}
+
+// The minitrace span of a `tracing` span, kept in its extensions
+struct BridgedSpan(Span);
+
+// The fields of a span or an event as properties
+#[derive(Default)]
+struct Properties(Vec<(&'static str, PropertyValue)>);
+
+impl Properties {
+    fn add_to(self, span: &mut Span) {
+        for (key, value) in self.0 {
+            span.add_typed_property(key, value);
+        }
+    }
+}
+
+impl Visit for Properties {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.push((field.name(), value.into()));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match i64::try_from(value) {
+            Ok(value) => self.record_i64(field, value),
+            Err(_) => self.record_debug(field, &value),
+        }
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.push((field.name(), value.into()));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.push((field.name(), value.into()));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), value.to_owned().into()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push((field.name(), format!("{:?}", value).into()));
+    }
+}
+
+// A child of the minitrace span of `parent`, or of the span attached to the current thread
+fn child_span<S>(event: &'static str, parent: Option<SpanRef<'_, S>>) -> Span
+where
+    S: for<'a> LookupSpan<'a>,
+{
+    if let Some(parent) = parent {
+        if let Some(BridgedSpan(span)) = parent.extensions().get::<BridgedSpan>() {
+            if !span.is_empty() {
+                return Span::from_parent(event, span);
+            }
+        }
+    }
+    Span::from_local_parent(event)
+}
+
+impl<S> Layer<S> for MinitraceLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut bridged = child_span(attrs.metadata().name(), span.parent());
+        if !bridged.is_empty() {
+            let mut properties = Properties::default();
+            attrs.record(&mut properties);
+            properties.add_to(&mut bridged);
+        }
+        span.extensions_mut().insert(BridgedSpan(bridged));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(BridgedSpan(bridged)) = extensions.get_mut::<BridgedSpan>() {
+            if !bridged.is_empty() {
+                let mut properties = Properties::default();
+                values.record(&mut properties);
+                properties.add_to(bridged);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut span = child_span(event.metadata().name(), ctx.event_span(event));
+        if !span.is_empty() {
+            let mut properties = Properties::default();
+            event.record(&mut properties);
+            properties.add_to(&mut span);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            // Finish the minitrace span now rather than whenever the registry drops it
+            let bridged = span.extensions_mut().remove::<BridgedSpan>();
+            drop(bridged);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn bridge() {
+        let dispatch =
+            tracing::Dispatch::new(tracing_subscriber::registry().with(MinitraceLayer::new()));
+        let _default = tracing::dispatcher::set_default(&dispatch);
+
+        // Nothing is recorded outside of a trace
+        drop(tracing::info_span!("outside").entered());
+
+        let (root_span, collector) = Span::root("root");
+        {
+            let _g = root_span.enter();
+            let outer = tracing::info_span!("outer", region = 7_u64, key = tracing::field::Empty);
+            outer.record("key", "k1");
+            let _outer = outer.enter();
+            let inner = tracing::info_span!("inner");
+            std::thread::spawn(move || {
+                // The registry releases the spans exited by the default dispatcher
+                let _default = tracing::dispatcher::set_default(&dispatch);
+                let _inner = inner.entered();
+                tracing::info!(bytes = 42, "sent");
+            })
+            .join()
+            .unwrap();
+        }
+        drop(root_span);
+
+        let spans = collector.collect();
+        let find = |event| spans.iter().find(|s| s.event == event).unwrap();
+        let root = find("root");
+        let outer = find("outer");
+        let inner = find("inner");
+        assert_eq!(outer.parent_id, root.id);
+        assert_eq!(inner.parent_id, outer.id);
+        assert_eq!(
+            outer.properties,
+            vec![("region", PropertyValue::I64(7)), ("key", "k1".into())]
+        );
+
+        let event = spans
+            .iter()
+            .find(|s| s.parent_id == inner.id)
+            .expect("the event is recorded in the span entered on another thread");
+        assert!(event
+            .properties
+            .contains(&("bytes", PropertyValue::I64(42))));
+        assert!(spans.iter().all(|s| s.event != "outside"));
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! SystemTap SDT probes at span boundaries, enabled by the `usdt` feature on x86_64 and aarch64
+//! Linux. Each probe is a `nop` listed in the `.note.stapsdt` section, which tools like bpftrace
+//! patch when attaching:
+//!
+//! ```text
+//! bpftrace -e 'usdt:./server:minitrace:span_start { printf("%s\n", str(arg2, arg3)); }'
+//! ```
+//!
+//! Both `minitrace:span_start` and `minitrace:span_end` carry the span id, the parent id, and the
+//! address and length of the event name.
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+macro_rules! sdt_probe {
+    ($name:literal, $args:literal, $($operand:tt)*) => {
+        std::arch::asm!(
+            "990: nop",
+            ".pushsection .note.stapsdt, \"\", \"note\"",
+            ".balign 4",
+            ".4byte 992f-991f, 994f-993f, 3",
+            "991: .asciz \"stapsdt\"",
+            "992: .balign 4",
+            "993: .8byte 990b",
+            ".8byte _.stapsdt.base",
+            ".8byte 0",
+            ".asciz \"minitrace\"",
+            concat!(".asciz \"", $name, "\""),
+            concat!(".asciz \"", $args, "\""),
+            "994: .balign 4",
+            ".popsection",
+            ".ifndef _.stapsdt.base",
+            ".pushsection .stapsdt.base, \"aG\", \"progbits\", .stapsdt.base, comdat",
+            ".weak _.stapsdt.base",
+            ".hidden _.stapsdt.base",
+            "_.stapsdt.base: .space 1",
+            ".size _.stapsdt.base, 1",
+            ".popsection",
+            ".endif",
+            $($operand)*
+        )
+    };
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+macro_rules! span_probe {
+    ($name:literal, $id:expr, $parent_id:expr, $event:expr) => {
+        // Safety: the probe is a `nop` and a note, touching neither registers nor memory
+        #[allow(named_asm_labels)]
+        unsafe {
+            sdt_probe!(
+                $name,
+                "8@{0} 8@{1} 8@{2} 8@{3}",
+                in(reg) $id.0 as u64,
+                in(reg) $parent_id.0 as u64,
+                in(reg) $event.as_ptr() as usize,
+                in(reg) $event.len(),
+                options(att_syntax, nomem, nostack, preserves_flags),
+            )
+        }
+    };
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+macro_rules! span_probe {
+    ($name:literal, $id:expr, $parent_id:expr, $event:expr) => {
+        // Safety: the probe is a `nop` and a note, touching neither registers nor memory
+        #[allow(named_asm_labels)]
+        unsafe {
+            sdt_probe!(
+                $name,
+                "8@{0} 8@{1} 8@{2} 8@{3}",
+                in(reg) $id.0 as u64,
+                in(reg) $parent_id.0 as u64,
+                in(reg) $event.as_ptr() as usize,
+                in(reg) $event.len(),
+                options(nomem, nostack, preserves_flags),
+            )
+        }
+    };
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+macro_rules! span_probe {
+    ($name:literal, $id:expr, $parent_id:expr, $event:expr) => {{
+        let _ = ($id, $parent_id, $event);
+    }};
+}
+
+use crate::span::SpanId;
+
+#[inline]
+pub(crate) fn span_start(id: SpanId, parent_id: SpanId, event: &'static str) {
+    span_probe!("span_start", id, parent_id, event);
+}
+
+#[inline]
+pub(crate) fn span_end(id: SpanId, parent_id: SpanId, event: &'static str) {
+    span_probe!("span_end", id, parent_id, event);
+}
+
+#[cfg(all(
+    test,
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_in_binary() {
+        span_start(SpanId::new(1), SpanId::new(0), "probed");
+        span_end(SpanId::new(1), SpanId::new(0), "probed");
+
+        let binary = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let notes = section(&binary, ".note.stapsdt").expect("no probe notes");
+        let contains = |needle: &[u8]| notes.windows(needle.len()).any(|w| w == needle);
+        // The provider and the name of each probe, as laid out in its note
+        assert!(contains(b"minitrace\0span_start\0"));
+        assert!(contains(b"minitrace\0span_end\0"));
+    }
+
+    // The content of the section `name` of a 64-bit little-endian ELF file
+    fn section<'a>(elf: &'a [u8], name: &str) -> Option<&'a [u8]> {
+        let u16_at = |at: usize| u16::from_le_bytes([elf[at], elf[at + 1]]) as usize;
+        let u32_at = |at: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&elf[at..at + 4]);
+            u32::from_le_bytes(bytes) as usize
+        };
+        let u64_at = |at: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&elf[at..at + 8]);
+            u64::from_le_bytes(bytes) as usize
+        };
+
+        let (shoff, shentsize) = (u64_at(0x28), u16_at(0x3a));
+        let header = |i: usize| shoff + i * shentsize;
+        let content = |header: usize| {
+            let offset = u64_at(header + 0x18);
+            &elf[offset..offset + u64_at(header + 0x20)]
+        };
+        let names = content(header(u16_at(0x3e)));
+        (0..u16_at(0x3c)).map(header).find_map(|header| {
+            let start = u32_at(header);
+            let len = names[start..].iter().position(|&b| b == 0)?;
+            if &names[start..start + len] == name.as_bytes() {
+                Some(content(header))
+            } else {
+                None
+            }
+        })
+    }
+}